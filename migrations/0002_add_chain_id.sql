@@ -0,0 +1,5 @@
+-- Rows recorded before multi-chain scanning all came from Sepolia
+ALTER TABLE transaction ADD COLUMN IF NOT EXISTS chain_id BIGINT NOT NULL DEFAULT 11155111;
+ALTER TABLE transaction ALTER COLUMN chain_id DROP DEFAULT;
+
+CREATE INDEX IF NOT EXISTS transaction_chain_id_idx ON transaction (chain_id);
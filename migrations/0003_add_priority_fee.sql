@@ -0,0 +1,4 @@
+-- Max priority fee of EIP-1559 transactions, NULL for legacy ones
+ALTER TABLE transaction ADD COLUMN IF NOT EXISTS priority_fee BIGINT;
+
+CREATE INDEX IF NOT EXISTS transaction_chain_id_created_at_idx ON transaction (chain_id, created_at);
@@ -0,0 +1,19 @@
+-- Per chain aggregates of the transaction table, maintained by the rollup task
+CREATE TABLE IF NOT EXISTS transaction_rollup (
+    granularity VARCHAR NOT NULL,
+    bucket_start TIMESTAMPTZ NOT NULL,
+    chain_id BIGINT NOT NULL,
+    tx_count BIGINT NOT NULL,
+    total_value NUMERIC NOT NULL,
+    mempool_time_p50 DOUBLE PRECISION,
+    mempool_time_p90 DOUBLE PRECISION,
+    mempool_time_p99 DOUBLE PRECISION,
+    gas_price_p50 DOUBLE PRECISION,
+    externally_owned_account_count BIGINT NOT NULL,
+    contract_account_count BIGINT NOT NULL,
+    special_case_contract_count BIGINT NOT NULL,
+    updated_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+    PRIMARY KEY (granularity, chain_id, bucket_start)
+);
+
+CREATE INDEX IF NOT EXISTS transaction_created_at_idx ON transaction (created_at);
@@ -0,0 +1,8 @@
+-- Transactions the scanner has seen in the mempool but not yet seen included or dropped,
+-- kept so their first-seen time survives restarts
+CREATE TABLE IF NOT EXISTS pending_transaction (
+    chain_id BIGINT NOT NULL,
+    tx_hash VARCHAR NOT NULL,
+    first_seen_ms BIGINT NOT NULL,
+    PRIMARY KEY (chain_id, tx_hash)
+);
@@ -0,0 +1,8 @@
+-- Keep the first row stored for each transaction before enforcing uniqueness
+DELETE FROM transaction duplicate
+USING transaction original
+WHERE duplicate.chain_id = original.chain_id
+  AND duplicate.tx_hash = original.tx_hash
+  AND (duplicate.created_at, duplicate.id) > (original.created_at, original.id);
+
+CREATE UNIQUE INDEX IF NOT EXISTS transaction_chain_id_tx_hash_key ON transaction (chain_id, tx_hash);
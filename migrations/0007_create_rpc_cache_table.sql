@@ -0,0 +1,7 @@
+-- RPC responses that can no longer change, such as finalized blocks, persisted by the
+-- response cache when RPC_CACHE_PERSIST is set
+CREATE TABLE IF NOT EXISTS rpc_cache (
+    key VARCHAR PRIMARY KEY,
+    value JSONB NOT NULL,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT NOW()
+);
@@ -0,0 +1,14 @@
+-- Balances of watched addresses recorded every few blocks by the snapshot job. The
+-- token is empty for the native currency, and balances are decimal strings as they
+-- overflow 64 bits
+CREATE TABLE IF NOT EXISTS balance_snapshot (
+    chain_id BIGINT NOT NULL,
+    address VARCHAR NOT NULL,
+    token VARCHAR NOT NULL,
+    block_number BIGINT NOT NULL,
+    block_timestamp BIGINT NOT NULL,
+    balance VARCHAR NOT NULL,
+    PRIMARY KEY (chain_id, address, token, block_number)
+);
+
+CREATE INDEX IF NOT EXISTS balance_snapshot_chain_id_block_number_idx ON balance_snapshot (chain_id, block_number);
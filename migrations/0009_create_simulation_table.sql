@@ -0,0 +1,13 @@
+-- Predicted outcome of pending transactions, replayed against the latest state. Only
+-- the latest simulation of a transaction is kept; `result` holds the full prediction
+-- with its balance and storage diffs
+CREATE TABLE IF NOT EXISTS simulation (
+    chain_id BIGINT NOT NULL,
+    tx_hash VARCHAR NOT NULL,
+    block_number BIGINT NOT NULL,
+    success BOOLEAN NOT NULL,
+    gas_used BIGINT NOT NULL,
+    result JSONB NOT NULL,
+    simulated_at BIGINT NOT NULL,
+    PRIMARY KEY (chain_id, tx_hash)
+);
@@ -0,0 +1,18 @@
+-- Calls contracts made while executing included transactions, from call traces. Values
+-- are decimal strings as they overflow 64 bits
+CREATE TABLE IF NOT EXISTS internal_call (
+    chain_id BIGINT NOT NULL,
+    tx_hash VARCHAR NOT NULL,
+    block_number BIGINT NOT NULL,
+    position BIGINT NOT NULL,
+    depth BIGINT NOT NULL,
+    call_type VARCHAR NOT NULL,
+    from_address VARCHAR NOT NULL,
+    to_address VARCHAR NOT NULL,
+    value VARCHAR NOT NULL,
+    error VARCHAR,
+    PRIMARY KEY (chain_id, tx_hash, position)
+);
+
+CREATE INDEX IF NOT EXISTS internal_call_chain_id_from_address_idx ON internal_call (chain_id, from_address);
+CREATE INDEX IF NOT EXISTS internal_call_chain_id_to_address_idx ON internal_call (chain_id, to_address);
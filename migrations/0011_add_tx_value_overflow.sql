@@ -0,0 +1,2 @@
+-- Values above i64::MAX wei are stored saturated, with this flag set
+ALTER TABLE transaction ADD COLUMN IF NOT EXISTS tx_value_overflow BOOLEAN NOT NULL DEFAULT FALSE;
@@ -0,0 +1,30 @@
+-- SQLite schema of single-node deployments. Timestamps are unix seconds and
+-- contract types are stored as their lowercase names.
+CREATE TABLE IF NOT EXISTS "transaction" (
+    id BLOB PRIMARY KEY NOT NULL,
+    chain_id INTEGER NOT NULL,
+    tx_hash TEXT NOT NULL,
+    block_hash TEXT NOT NULL,
+    block_number INTEGER NOT NULL,
+    from_sender TEXT NOT NULL,
+    to_reciever TEXT NOT NULL,
+    tx_value INTEGER NOT NULL,
+    gas INTEGER NOT NULL,
+    gas_price INTEGER NOT NULL,
+    priority_fee INTEGER,
+    input TEXT NOT NULL,
+    nonce INTEGER NOT NULL,
+    mempool_time INTEGER NOT NULL,
+    contract_type TEXT NOT NULL,
+    created_at INTEGER NOT NULL DEFAULT (CAST(strftime('%s', 'now') AS INTEGER))
+);
+
+CREATE UNIQUE INDEX IF NOT EXISTS transaction_chain_id_tx_hash_key ON "transaction" (chain_id, tx_hash);
+CREATE INDEX IF NOT EXISTS transaction_chain_id_created_at_idx ON "transaction" (chain_id, created_at);
+
+CREATE TABLE IF NOT EXISTS pending_transaction (
+    chain_id INTEGER NOT NULL,
+    tx_hash TEXT NOT NULL,
+    first_seen_ms INTEGER NOT NULL,
+    PRIMARY KEY (chain_id, tx_hash)
+);
@@ -0,0 +1,12 @@
+-- Balances of watched addresses recorded by the snapshot job, as in the Postgres schema.
+CREATE TABLE IF NOT EXISTS balance_snapshot (
+    chain_id INTEGER NOT NULL,
+    address TEXT NOT NULL,
+    token TEXT NOT NULL,
+    block_number INTEGER NOT NULL,
+    block_timestamp INTEGER NOT NULL,
+    balance TEXT NOT NULL,
+    PRIMARY KEY (chain_id, address, token, block_number)
+);
+
+CREATE INDEX IF NOT EXISTS balance_snapshot_chain_id_block_number_idx ON balance_snapshot (chain_id, block_number);
@@ -0,0 +1,11 @@
+-- Predicted outcome of pending transactions, as in the Postgres schema.
+CREATE TABLE IF NOT EXISTS simulation (
+    chain_id INTEGER NOT NULL,
+    tx_hash TEXT NOT NULL,
+    block_number INTEGER NOT NULL,
+    success INTEGER NOT NULL,
+    gas_used INTEGER NOT NULL,
+    result TEXT NOT NULL,
+    simulated_at INTEGER NOT NULL,
+    PRIMARY KEY (chain_id, tx_hash)
+);
@@ -0,0 +1,17 @@
+-- Calls contracts made while executing included transactions, as in the Postgres schema.
+CREATE TABLE IF NOT EXISTS internal_call (
+    chain_id INTEGER NOT NULL,
+    tx_hash TEXT NOT NULL,
+    block_number INTEGER NOT NULL,
+    position INTEGER NOT NULL,
+    depth INTEGER NOT NULL,
+    call_type TEXT NOT NULL,
+    from_address TEXT NOT NULL,
+    to_address TEXT NOT NULL,
+    value TEXT NOT NULL,
+    error TEXT,
+    PRIMARY KEY (chain_id, tx_hash, position)
+);
+
+CREATE INDEX IF NOT EXISTS internal_call_chain_id_from_address_idx ON internal_call (chain_id, from_address);
+CREATE INDEX IF NOT EXISTS internal_call_chain_id_to_address_idx ON internal_call (chain_id, to_address);
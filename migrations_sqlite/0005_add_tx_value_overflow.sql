@@ -0,0 +1,2 @@
+-- Values above i64::MAX wei are stored saturated, with this flag set
+ALTER TABLE "transaction" ADD COLUMN tx_value_overflow INTEGER NOT NULL DEFAULT 0;
@@ -0,0 +1,172 @@
+//! State of an address at a block: nonce, balance, code and chosen storage slots.
+//!
+//! The account type comes from `check_account_type`, as for scanned and backfilled
+//! transactions, so the API and the indexed data classify an address the same way.
+
+use crate::{
+    historical::parse_block_id,
+    mempool::check_contract_type::check_account_type,
+    model::{AppError, AppState},
+    rpc_queries::{get_code_query, get_native_balance_query, get_nonce_query, get_storage_query},
+};
+use alloy::{
+    primitives::{keccak256, Address, ChainId, B256, U256},
+    rpc::types::eth::BlockId,
+};
+use async_graphql::SimpleObject;
+use futures_util::future::try_join_all;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::str::FromStr;
+
+/// Most storage slots a single request may read.
+pub const MAX_SLOTS: usize = 32;
+
+#[derive(Deserialize, Debug, Default)]
+pub struct AccountParams {
+    /// Comma separated storage slots, decimal or `0x` hex
+    pub slots: Option<String>,
+    /// Block number, hash or tag, the latest block by default
+    pub block: Option<String>,
+}
+
+#[derive(Serialize, SimpleObject, Debug, PartialEq)]
+pub struct StorageSlot {
+    pub slot: String,
+    pub value: String,
+}
+
+#[derive(Serialize, SimpleObject, Debug)]
+pub struct Account {
+    pub chain_id: u64,
+    pub address: String,
+    pub nonce: u64,
+    /// In wei, as a decimal string
+    pub balance: String,
+    pub has_code: bool,
+    /// Keccak-256 of the code, that of empty code for accounts without any
+    pub code_hash: String,
+    /// In bytes
+    pub code_size: u64,
+    /// `ExternallyOwnedAccount`, `ContractAccount` or `SpecialCaseContract`
+    pub account_type: String,
+    pub storage: Vec<StorageSlot>,
+}
+
+/// Parses comma separated storage slots, decimal or `0x` hex.
+pub fn parse_slots(value: &str) -> Result<Vec<U256>, AppError> {
+    let slots = value
+        .split(',')
+        .map(str::trim)
+        .filter(|slot| !slot.is_empty())
+        .map(|slot| {
+            U256::from_str(slot)
+                .map_err(|_| AppError::Validation(format!("Invalid storage slot: {}", slot)))
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+
+    if slots.len() > MAX_SLOTS {
+        return Err(AppError::Validation(format!(
+            "At most {} storage slots can be read at once, {} were asked for",
+            MAX_SLOTS,
+            slots.len()
+        )));
+    }
+    Ok(slots)
+}
+
+/// Storage words are shown as 32-byte hex, as `eth_getStorageAt` answers them.
+fn to_word(value: U256) -> String {
+    B256::from(value).to_string()
+}
+
+pub async fn account(
+    state: &AppState,
+    chain_id: ChainId,
+    address: Address,
+    params: &AccountParams,
+) -> Result<Account, AppError> {
+    let block_id = match &params.block {
+        Some(block) => parse_block_id(block)?,
+        None => BlockId::latest(),
+    };
+    let slots = match &params.slots {
+        Some(slots) => parse_slots(slots)?,
+        None => Vec::new(),
+    };
+
+    let nonce = state.providers.call(
+        &state.metrics,
+        chain_id,
+        "eth_getTransactionCount",
+        |provider| get_nonce_query(provider, address, block_id),
+    );
+    let balance = state
+        .providers
+        .call(&state.metrics, chain_id, "eth_getBalance", |provider| {
+            get_native_balance_query(provider, address, block_id)
+        });
+    let code = state
+        .providers
+        .call(&state.metrics, chain_id, "eth_getCode", |provider| {
+            get_code_query(provider, address, block_id)
+        });
+    let storage = try_join_all(slots.iter().map(|slot| {
+        state
+            .providers
+            .call(&state.metrics, chain_id, "eth_getStorageAt", |provider| {
+                get_storage_query(provider, address, *slot, block_id)
+            })
+    }));
+    let (nonce, balance, code, values) = tokio::try_join!(nonce, balance, code, storage)?;
+
+    Ok(Account {
+        chain_id,
+        address: address.to_string(),
+        nonce,
+        balance: balance.to_string(),
+        has_code: !code.is_empty(),
+        code_hash: keccak256(&code).to_string(),
+        code_size: code.len() as u64,
+        account_type: check_account_type(&Value::String(code.to_string()))
+            .as_str()
+            .to_string(),
+        storage: slots
+            .into_iter()
+            .zip(values)
+            .map(|(slot, value)| StorageSlot {
+                slot: to_word(slot),
+                value: to_word(value),
+            })
+            .collect(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_decimal_and_hex_slots() {
+        assert_eq!(
+            parse_slots("0, 0x1, 8").unwrap(),
+            vec![U256::from(0), U256::from(1), U256::from(8)]
+        );
+        assert!(parse_slots("").unwrap().is_empty());
+        assert!(matches!(
+            parse_slots("0x1,zz"),
+            Err(AppError::Validation(_))
+        ));
+
+        let too_many = ["1"; MAX_SLOTS + 1].join(",");
+        assert!(matches!(
+            parse_slots(&too_many),
+            Err(AppError::Validation(_))
+        ));
+    }
+
+    #[test]
+    fn shows_slots_as_words() {
+        assert_eq!(to_word(U256::from(1)), format!("0x{}1", "0".repeat(63)));
+    }
+}
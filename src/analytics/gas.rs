@@ -0,0 +1,214 @@
+//! Fee recommendations derived from the fees and inclusion times observed in the mempool,
+//! rather than from `eth_gasPrice`.
+
+use crate::{model::AppError, store::TransactionStore};
+use alloy::primitives::ChainId;
+use async_graphql::SimpleObject;
+use serde::Serialize;
+use sqlx::prelude::FromRow;
+
+/// Rolling window of observations used when the caller does not pick one.
+pub const DEFAULT_WINDOW_SECONDS: i64 = 900;
+/// Share of transactions paying at least the recommended fee that must meet the target.
+pub const TARGET_CONFIDENCE: f64 = 0.9;
+/// Fewest transactions a recommendation may be based on.
+const MIN_SAMPLES: usize = 5;
+
+#[derive(Debug, Clone, FromRow)]
+pub struct FeeSample {
+    pub gas_price: i64,
+    pub priority_fee: Option<i64>,
+    pub mempool_time: i64,
+}
+
+#[derive(Serialize, SimpleObject, Debug, PartialEq)]
+pub struct Percentiles {
+    pub p25: i64,
+    pub p50: i64,
+    pub p75: i64,
+    pub p90: i64,
+    pub p99: i64,
+}
+
+#[derive(Serialize, SimpleObject, Debug)]
+pub struct GasEstimate {
+    pub chain_id: i64,
+    pub target_seconds: i64,
+    pub window_seconds: i64,
+    pub sample_size: i64,
+    pub recommended_gas_price: Option<i64>,
+    pub recommended_priority_fee: Option<i64>,
+    /// Share of the observed transactions paying at least `recommended_gas_price`
+    /// that were included within the target
+    pub confidence: Option<f64>,
+    pub gas_price: Option<Percentiles>,
+    pub priority_fee: Option<Percentiles>,
+    /// Time to inclusion in milliseconds
+    pub mempool_time: Option<Percentiles>,
+}
+
+/// Recommends a fee for inclusion within `target_seconds` on a chain, based on the
+/// transactions included during the last `window_seconds`.
+pub async fn estimate_gas(
+    store: &dyn TransactionStore,
+    chain_id: ChainId,
+    target_seconds: i64,
+    window_seconds: i64,
+) -> Result<GasEstimate, AppError> {
+    if target_seconds <= 0 || window_seconds <= 0 {
+        return Err(AppError::Validation(
+            "target_seconds and window_seconds must be positive".into(),
+        ));
+    }
+
+    let samples = store.fee_samples(chain_id as i64, window_seconds).await?;
+
+    Ok(estimate(chain_id, &samples, target_seconds, window_seconds))
+}
+
+pub fn estimate(
+    chain_id: ChainId,
+    samples: &[FeeSample],
+    target_seconds: i64,
+    window_seconds: i64,
+) -> GasEstimate {
+    let target_ms = target_seconds * 1000;
+
+    let gas_prices: Vec<(i64, i64)> = samples
+        .iter()
+        .map(|s| (s.gas_price, s.mempool_time))
+        .collect();
+    let priority_fees: Vec<(i64, i64)> = samples
+        .iter()
+        .filter_map(|s| s.priority_fee.map(|fee| (fee, s.mempool_time)))
+        .collect();
+
+    let recommended_gas_price = lowest_fee_meeting_target(&gas_prices, target_ms);
+    let recommended_priority_fee = lowest_fee_meeting_target(&priority_fees, target_ms);
+
+    GasEstimate {
+        chain_id: chain_id as i64,
+        target_seconds,
+        window_seconds,
+        sample_size: samples.len() as i64,
+        recommended_gas_price: recommended_gas_price.map(|(fee, _)| fee),
+        recommended_priority_fee: recommended_priority_fee.map(|(fee, _)| fee),
+        confidence: recommended_gas_price.map(|(_, confidence)| confidence),
+        gas_price: percentiles(gas_prices.iter().map(|(fee, _)| *fee).collect()),
+        priority_fee: percentiles(priority_fees.iter().map(|(fee, _)| *fee).collect()),
+        mempool_time: percentiles(samples.iter().map(|s| s.mempool_time).collect()),
+    }
+}
+
+/// Finds the lowest fee such that at least `TARGET_CONFIDENCE` of the transactions paying
+/// that fee or more were included within `target_ms`. Returns the fee and the share met.
+fn lowest_fee_meeting_target(samples: &[(i64, i64)], target_ms: i64) -> Option<(i64, f64)> {
+    if samples.len() < MIN_SAMPLES {
+        return None;
+    }
+
+    let mut sorted = samples.to_vec();
+    sorted.sort_unstable_by_key(|(fee, _)| *fee);
+
+    // Walk from the most expensive transaction down, so that `met`/`total` always
+    // describe the transactions paying at least the current fee
+    let mut candidate = None;
+    let mut met = 0usize;
+    let mut total = 0usize;
+    for (index, (fee, mempool_time)) in sorted.iter().enumerate().rev() {
+        total += 1;
+        if *mempool_time <= target_ms {
+            met += 1;
+        }
+
+        // Only evaluate once all transactions paying exactly this fee are counted
+        let next_is_same_fee = index > 0 && sorted[index - 1].0 == *fee;
+        if next_is_same_fee || total < MIN_SAMPLES {
+            continue;
+        }
+
+        let confidence = met as f64 / total as f64;
+        if confidence >= TARGET_CONFIDENCE {
+            candidate = Some((*fee, confidence));
+        }
+    }
+
+    candidate
+}
+
+fn percentiles(mut values: Vec<i64>) -> Option<Percentiles> {
+    if values.is_empty() {
+        return None;
+    }
+    values.sort_unstable();
+
+    Some(Percentiles {
+        p25: percentile(&values, 0.25),
+        p50: percentile(&values, 0.50),
+        p75: percentile(&values, 0.75),
+        p90: percentile(&values, 0.90),
+        p99: percentile(&values, 0.99),
+    })
+}
+
+/// Nearest-rank percentile of an ascending, non-empty slice.
+pub fn percentile(sorted: &[i64], p: f64) -> i64 {
+    let rank = (p * sorted.len() as f64).ceil() as usize;
+    sorted[rank.clamp(1, sorted.len()) - 1]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sample(gas_price: i64, mempool_time: i64) -> FeeSample {
+        FeeSample {
+            gas_price,
+            priority_fee: None,
+            mempool_time,
+        }
+    }
+
+    #[test]
+    fn picks_nearest_rank_percentiles() {
+        let values = [1, 2, 3, 4, 5, 6, 7, 8, 9, 10];
+        assert_eq!(percentile(&values, 0.5), 5);
+        assert_eq!(percentile(&values, 0.9), 9);
+        assert_eq!(percentile(&values, 0.99), 10);
+        assert_eq!(percentile(&values, 0.0), 1);
+        assert_eq!(percentile(&[42], 0.25), 42);
+    }
+
+    #[test]
+    fn recommends_lowest_fee_meeting_target() {
+        // Cheap transactions wait ~30s, the rest are included within 6s
+        let samples: Vec<FeeSample> = (1..=10)
+            .map(|gwei| sample(gwei, if gwei <= 4 { 30_000 } else { 6_000 }))
+            .collect();
+
+        let estimate = estimate(1, &samples, 12, DEFAULT_WINDOW_SECONDS);
+        assert_eq!(estimate.sample_size, 10);
+        assert_eq!(estimate.recommended_gas_price, Some(5));
+        assert_eq!(estimate.confidence, Some(1.0));
+        assert_eq!(estimate.recommended_priority_fee, None);
+        assert_eq!(estimate.priority_fee, None);
+
+        // Nothing was included within one second
+        let estimate = estimate_for_target(&samples, 1);
+        assert_eq!(estimate, None);
+    }
+
+    #[test]
+    fn requires_minimum_samples() {
+        let samples: Vec<FeeSample> = (1..MIN_SAMPLES as i64)
+            .map(|gwei| sample(gwei, 1))
+            .collect();
+        let estimate = estimate(1, &samples, 12, DEFAULT_WINDOW_SECONDS);
+        assert_eq!(estimate.recommended_gas_price, None);
+        assert!(estimate.gas_price.is_some());
+    }
+
+    fn estimate_for_target(samples: &[FeeSample], target_seconds: i64) -> Option<i64> {
+        estimate(1, samples, target_seconds, DEFAULT_WINDOW_SECONDS).recommended_gas_price
+    }
+}
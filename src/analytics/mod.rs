@@ -0,0 +1,3 @@
+pub mod gas;
+pub mod rollup;
+pub mod stats;
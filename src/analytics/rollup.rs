@@ -0,0 +1,82 @@
+//! Keeps the `transaction_rollup` table up to date with the transaction table.
+
+use crate::{
+    analytics::stats::{push_bucket, AGGREGATES},
+    model::{AppError, Granularity},
+    store::TransactionStore,
+};
+use log::{error, info};
+use sqlx::{PgPool, Postgres, QueryBuilder};
+use std::{sync::Arc, time::Duration};
+use tokio::time::interval;
+use tokio_util::sync::CancellationToken;
+
+/// How often the rollups are refreshed.
+pub const REFRESH_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Recomputes every bucket from the latest one already rolled up onwards.
+///
+/// Transactions are timestamped when they are stored, so older buckets never change.
+/// On an empty rollup table this aggregates the whole history.
+pub async fn refresh_rollups(pool: &PgPool) -> Result<u64, AppError> {
+    let mut refreshed = 0;
+
+    for granularity in Granularity::ALL {
+        let mut query = QueryBuilder::<Postgres>::new(
+            "INSERT INTO transaction_rollup (granularity, bucket_start, chain_id, total_value, tx_count,
+            mempool_time_p50, mempool_time_p90, mempool_time_p99, gas_price_p50,
+            externally_owned_account_count, contract_account_count, special_case_contract_count)
+            SELECT ",
+        );
+        query.push_bind(granularity.as_str()).push(", ");
+        push_bucket(&mut query, granularity);
+        query
+            .push(", chain_id, COALESCE(SUM(tx_value), 0), ")
+            .push(AGGREGATES)
+            .push(
+                " FROM transaction
+                WHERE created_at >= COALESCE(
+                    (SELECT MAX(bucket_start) FROM transaction_rollup WHERE granularity = ",
+            )
+            .push_bind(granularity.as_str())
+            .push(
+                "), '-infinity')
+                GROUP BY 2, chain_id
+                ON CONFLICT (granularity, chain_id, bucket_start) DO UPDATE SET
+                    total_value = EXCLUDED.total_value,
+                    tx_count = EXCLUDED.tx_count,
+                    mempool_time_p50 = EXCLUDED.mempool_time_p50,
+                    mempool_time_p90 = EXCLUDED.mempool_time_p90,
+                    mempool_time_p99 = EXCLUDED.mempool_time_p99,
+                    gas_price_p50 = EXCLUDED.gas_price_p50,
+                    externally_owned_account_count = EXCLUDED.externally_owned_account_count,
+                    contract_account_count = EXCLUDED.contract_account_count,
+                    special_case_contract_count = EXCLUDED.special_case_contract_count,
+                    updated_at = NOW()",
+            );
+
+        refreshed += query
+            .build()
+            .execute(pool)
+            .await
+            .map_err(|e| AppError::DatabaseError(e.to_string()))?
+            .rows_affected();
+    }
+
+    Ok(refreshed)
+}
+
+/// Refreshes the rollups every `REFRESH_INTERVAL` until `shutdown` is cancelled.
+pub async fn run_rollups(store: Arc<dyn TransactionStore>, shutdown: CancellationToken) {
+    let mut ticker = interval(REFRESH_INTERVAL);
+    loop {
+        tokio::select! {
+            _ = shutdown.cancelled() => return,
+            _ = ticker.tick() => {}
+        }
+        match store.refresh_rollups().await {
+            Ok(refreshed) => info!("Refreshed {} rollup buckets", refreshed),
+            Err(e) => error!("Error refreshing rollups: {:?}", e),
+        }
+    }
+}
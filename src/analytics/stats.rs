@@ -0,0 +1,197 @@
+//! Time-bucketed aggregates of the indexed transactions.
+
+use crate::model::{
+    AppError, ContractType, Granularity, StatsParams, Transaction, TransactionFilter,
+};
+use async_graphql::SimpleObject;
+use serde::Serialize;
+use sqlx::{prelude::FromRow, PgPool, Postgres, QueryBuilder};
+use std::collections::BTreeMap;
+
+#[derive(Serialize, SimpleObject, FromRow, Debug)]
+pub struct StatsBucket {
+    /// Unix timestamp in seconds of the start of the bucket
+    pub bucket_start: i64,
+    pub tx_count: i64,
+    /// Sum of `tx_value`, as a decimal string since it may overflow 64 bits
+    pub total_value: String,
+    /// Time to inclusion percentiles in milliseconds, of transactions seen pending
+    pub mempool_time_p50: Option<f64>,
+    pub mempool_time_p90: Option<f64>,
+    pub mempool_time_p99: Option<f64>,
+    pub gas_price_p50: Option<f64>,
+    pub externally_owned_account_count: i64,
+    pub contract_account_count: i64,
+    pub special_case_contract_count: i64,
+}
+
+/// Aggregate columns shared by the rollup task and on-the-fly queries, `total_value` aside.
+pub(crate) const AGGREGATES: &str = "COUNT(*) AS tx_count,
+    percentile_cont(0.5) WITHIN GROUP (ORDER BY mempool_time) FILTER (WHERE mempool_time > 0) AS mempool_time_p50,
+    percentile_cont(0.9) WITHIN GROUP (ORDER BY mempool_time) FILTER (WHERE mempool_time > 0) AS mempool_time_p90,
+    percentile_cont(0.99) WITHIN GROUP (ORDER BY mempool_time) FILTER (WHERE mempool_time > 0) AS mempool_time_p99,
+    percentile_cont(0.5) WITHIN GROUP (ORDER BY gas_price) AS gas_price_p50,
+    COUNT(*) FILTER (WHERE contract_type = 'externallyownedaccount') AS externally_owned_account_count,
+    COUNT(*) FILTER (WHERE contract_type = 'contractaccount') AS contract_account_count,
+    COUNT(*) FILTER (WHERE contract_type = 'specialcasecontract') AS special_case_contract_count";
+
+/// Returns the buckets matching `filter` from Postgres, oldest first.
+///
+/// Queries that only narrow down the chain are answered from the rollup table, anything
+/// more specific is aggregated from the raw transactions.
+pub async fn transaction_stats(
+    pool: &PgPool,
+    params: &StatsParams,
+    filter: &TransactionFilter,
+) -> Result<Vec<StatsBucket>, AppError> {
+    let mut query = if filter.is_chain_only() {
+        rollup_query(params, filter)
+    } else {
+        raw_query(params, filter)
+    };
+
+    query
+        .build_query_as::<StatsBucket>()
+        .fetch_all(pool)
+        .await
+        .map_err(|e| AppError::DatabaseError(e.to_string()))
+}
+
+/// Aggregates `(stored at, transaction)` rows like the SQL queries do, for backends that
+/// cannot. `stored at` is a unix timestamp in seconds; rows outside `params` are skipped.
+pub fn aggregate<'a>(
+    rows: impl IntoIterator<Item = (i64, &'a Transaction)>,
+    params: &StatsParams,
+) -> Vec<StatsBucket> {
+    let width = params.granularity.seconds();
+    let mut buckets: BTreeMap<i64, Vec<&Transaction>> = BTreeMap::new();
+    for (created_at, transaction) in rows {
+        if params.from.is_some_and(|from| created_at < from)
+            || params.to.is_some_and(|to| created_at >= to)
+        {
+            continue;
+        }
+        buckets
+            .entry(created_at.div_euclid(width) * width)
+            .or_default()
+            .push(transaction);
+    }
+
+    buckets
+        .into_iter()
+        .map(|(bucket_start, transactions)| {
+            let mut mempool_times: Vec<i64> = transactions
+                .iter()
+                .map(|t| t.mempool_time)
+                .filter(|mempool_time| *mempool_time > 0)
+                .collect();
+            mempool_times.sort_unstable();
+            let mut gas_prices: Vec<i64> = transactions.iter().map(|t| t.gas_price).collect();
+            gas_prices.sort_unstable();
+            let count = |contract_type: ContractType| {
+                transactions
+                    .iter()
+                    .filter(|t| t.contract_type == contract_type)
+                    .count() as i64
+            };
+
+            StatsBucket {
+                bucket_start,
+                tx_count: transactions.len() as i64,
+                total_value: transactions
+                    .iter()
+                    .map(|t| t.tx_value as i128)
+                    .sum::<i128>()
+                    .to_string(),
+                mempool_time_p50: percentile_cont(&mempool_times, 0.5),
+                mempool_time_p90: percentile_cont(&mempool_times, 0.9),
+                mempool_time_p99: percentile_cont(&mempool_times, 0.99),
+                gas_price_p50: percentile_cont(&gas_prices, 0.5),
+                externally_owned_account_count: count(ContractType::ExternallyOwnedAccount),
+                contract_account_count: count(ContractType::ContractAccount),
+                special_case_contract_count: count(ContractType::SpecialCaseContract),
+            }
+        })
+        .collect()
+}
+
+/// Interpolates between the closest ranks, like Postgres' `percentile_cont`.
+fn percentile_cont(sorted: &[i64], fraction: f64) -> Option<f64> {
+    if sorted.is_empty() {
+        return None;
+    }
+    let position = fraction * (sorted.len() - 1) as f64;
+    let lower = position.floor() as usize;
+    let upper = position.ceil() as usize;
+    let weight = position - lower as f64;
+    Some(sorted[lower] as f64 + (sorted[upper] - sorted[lower]) as f64 * weight)
+}
+
+fn rollup_query<'a>(
+    params: &StatsParams,
+    filter: &TransactionFilter,
+) -> QueryBuilder<'a, Postgres> {
+    let mut query = QueryBuilder::new(
+        "SELECT EXTRACT(EPOCH FROM bucket_start)::BIGINT AS bucket_start, tx_count,
+        total_value::TEXT AS total_value, mempool_time_p50, mempool_time_p90, mempool_time_p99,
+        gas_price_p50, externally_owned_account_count, contract_account_count,
+        special_case_contract_count
+        FROM transaction_rollup WHERE granularity = ",
+    );
+    query
+        .push_bind(params.granularity.as_str())
+        .push(" AND chain_id = ")
+        .push_bind(filter.chain_id);
+    push_time_range(&mut query, params, "bucket_start");
+    query.push(" ORDER BY bucket_start");
+    query
+}
+
+fn raw_query<'a>(params: &StatsParams, filter: &TransactionFilter) -> QueryBuilder<'a, Postgres> {
+    let mut query = QueryBuilder::new("SELECT EXTRACT(EPOCH FROM ");
+    push_bucket(&mut query, params.granularity);
+    query
+        .push(")::BIGINT AS bucket_start, COALESCE(SUM(tx_value), 0)::TEXT AS total_value, ")
+        .push(AGGREGATES)
+        .push(" FROM transaction WHERE 1=1");
+    filter.push_conditions(&mut query);
+    push_time_range(&mut query, params, "created_at");
+    query.push(" GROUP BY 1 ORDER BY 1");
+    query
+}
+
+pub(crate) fn push_bucket(query: &mut QueryBuilder<'_, Postgres>, granularity: Granularity) {
+    // The granularity is one of a fixed set of literals, so it is safe to inline
+    query
+        .push("date_trunc('")
+        .push(granularity.as_str())
+        .push("', created_at)");
+}
+
+fn push_time_range(query: &mut QueryBuilder<'_, Postgres>, params: &StatsParams, column: &str) {
+    if let Some(from) = params.from {
+        query
+            .push(format!(" AND {} >= to_timestamp(", column))
+            .push_bind(from as f64)
+            .push(")");
+    }
+    if let Some(to) = params.to {
+        query
+            .push(format!(" AND {} < to_timestamp(", column))
+            .push_bind(to as f64)
+            .push(")");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn percentile_cont_interpolates() {
+        assert_eq!(percentile_cont(&[], 0.5), None);
+        assert_eq!(percentile_cont(&[7], 0.99), Some(7.0));
+        assert_eq!(percentile_cont(&[1, 2, 3, 4], 0.5), Some(2.5));
+        assert!((percentile_cont(&[1, 2, 3, 4], 0.9).unwrap() - 3.7).abs() < 1e-9);
+    }
+}
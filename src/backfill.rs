@@ -0,0 +1,152 @@
+//! Indexes the transactions of historical blocks the mempool scanner never saw.
+
+use crate::{
+    internal_calls::capture,
+    mempool::check_contract_type::check_account_type,
+    model::{AppError, AppState, ContractType, Transaction},
+    rate_limit::Priority,
+    rpc_queries::{get_block_with_transactions_query, get_code_query},
+    utils::saturating_tx_value,
+};
+use alloy::{
+    primitives::{Address, ChainId},
+    rpc::types::eth::{BlockId, BlockTransactions, Transaction as AlloyTx},
+};
+use log::{info, warn};
+use serde_json::Value;
+use std::{collections::HashMap, sync::Arc};
+use uuid::Uuid;
+
+/// Stores every transaction of the blocks `from..=to` and returns how many were stored.
+///
+/// Backfilled transactions were never observed pending, so their `mempool_time` is 0. Its
+/// RPC requests yield to interactive ones. With `capture_internal_calls`, transactions to
+/// contracts are also traced for their internal calls. Failed traces are logged and counted
+/// in `internal_call_failures` without stopping the backfill.
+pub async fn backfill(
+    state: &Arc<AppState>,
+    chain_id: ChainId,
+    from: u64,
+    to: u64,
+) -> Result<u64, AppError> {
+    if from > to {
+        return Err(AppError::Validation(format!(
+            "Invalid block range: {} is after {}",
+            from, to
+        )));
+    }
+
+    let mut contract_types: HashMap<Address, ContractType> = HashMap::new();
+    let mut stored = 0;
+
+    for block_number in from..=to {
+        let block = state
+            .providers
+            .call_with_priority(
+                &state.metrics,
+                chain_id,
+                "eth_getBlockByNumber",
+                Priority::Background,
+                |provider| get_block_with_transactions_query(provider, BlockId::from(block_number)),
+            )
+            .await?;
+
+        let transactions = match block.transactions {
+            BlockTransactions::Full(transactions) => transactions,
+            _ => continue,
+        };
+
+        let mut rows = Vec::with_capacity(transactions.len());
+        let mut traced = Vec::new();
+        for tx in transactions {
+            let contract_type = match tx.to {
+                Some(to) => match contract_types.get(&to) {
+                    Some(contract_type) => *contract_type,
+                    None => {
+                        let code = state
+                            .providers
+                            .call_with_priority(
+                                &state.metrics,
+                                chain_id,
+                                "eth_getCode",
+                                Priority::Background,
+                                |provider| get_code_query(provider, to, BlockId::latest()),
+                            )
+                            .await?;
+                        let contract_type = check_account_type(&Value::String(code.to_string()));
+                        contract_types.insert(to, contract_type);
+                        contract_type
+                    }
+                },
+                None => ContractType::ExternallyOwnedAccount,
+            };
+
+            // Only contracts make internal calls
+            if tx.to.is_none() || contract_type != ContractType::ExternallyOwnedAccount {
+                traced.push(tx.hash);
+            }
+            rows.push(to_transaction(chain_id, &tx, contract_type));
+        }
+
+        // One multi-row upsert per block
+        state.store.upsert_batch(&rows).await?;
+        stored += rows.len() as u64;
+
+        if state.capture_internal_calls {
+            // A failed trace only loses the internal calls, not the backfill
+            for tx_hash in traced {
+                if let Err(e) = capture(
+                    state,
+                    chain_id,
+                    tx_hash,
+                    block_number as i64,
+                    Priority::Background,
+                )
+                .await
+                {
+                    warn!(
+                        "Error tracing transaction {} on chain {}: {:?}",
+                        tx_hash, chain_id, e
+                    );
+                    state
+                        .metrics
+                        .internal_call_failures
+                        .with_label_values(&[&chain_id.to_string()])
+                        .inc();
+                }
+            }
+        }
+
+        info!("Backfilled block {} of chain {}", block_number, chain_id);
+    }
+
+    Ok(stored)
+}
+
+fn to_transaction(chain_id: ChainId, tx: &AlloyTx, contract_type: ContractType) -> Transaction {
+    let (tx_value, tx_value_overflow) = saturating_tx_value(tx.value);
+    Transaction {
+        id: Uuid::default(),
+        chain_id: chain_id as i64,
+        tx_hash: tx.hash.to_string(),
+        block_hash: tx
+            .block_hash
+            .map(|hash| hash.to_string())
+            .unwrap_or_default(),
+        block_number: tx.block_number.unwrap_or_default() as i64,
+        from_sender: tx.from.to_string().to_lowercase(),
+        to_reciever: tx
+            .to
+            .map(|to| to.to_string().to_lowercase())
+            .unwrap_or_default(),
+        tx_value,
+        tx_value_overflow,
+        gas: tx.gas as i64,
+        gas_price: tx.gas_price.unwrap_or_default() as i64,
+        priority_fee: tx.max_priority_fee_per_gas.map(|fee| fee as i64),
+        input: tx.input.to_string(),
+        nonce: tx.nonce as i64,
+        mempool_time: 0,
+        contract_type,
+    }
+}
@@ -0,0 +1,303 @@
+//! Native and ERC-20 balances of many addresses, read through Multicall3 `aggregate3`.
+//!
+//! Every balance is one call of the batch, allowed to fail on its own, so a token that
+//! reverts or is not a contract only fails its own entries.
+
+use crate::{
+    chains::MULTICALL3_ADDRESS,
+    model::{AppError, AppState},
+    rpc_queries::{
+        multicall_query,
+        ERC20Abi::balanceOfCall,
+        Multicall3Abi::{self, getEthBalanceCall},
+    },
+};
+use alloy::{
+    primitives::{Address, ChainId, U256},
+    sol_types::SolCall,
+};
+use async_graphql::SimpleObject;
+use serde::{Deserialize, Serialize};
+use std::str::FromStr;
+
+/// Most balances a single request may ask for.
+pub const MAX_BALANCES: usize = 1000;
+/// Calls per `aggregate3`, keeping each `eth_call` within the gas and response size
+/// limits of public nodes.
+const CALLS_PER_BATCH: usize = 200;
+
+#[derive(Deserialize, Debug)]
+pub struct BalancesRequest {
+    pub addresses: Vec<Address>,
+    /// ERC-20 contracts whose balance of every address is read
+    #[serde(default)]
+    pub tokens: Vec<Address>,
+    /// Whether to read the native balance of every address too
+    #[serde(default = "default_native")]
+    pub native: bool,
+}
+
+fn default_native() -> bool {
+    true
+}
+
+#[derive(Serialize, SimpleObject, Debug, PartialEq)]
+pub struct Balance {
+    pub address: String,
+    /// `None` for the native currency
+    pub token: Option<String>,
+    /// In the smallest unit, as a decimal string since balances overflow 64 bits
+    pub balance: Option<String>,
+    /// Why the balance could not be read
+    pub error: Option<String>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Lookup {
+    address: Address,
+    token: Option<Address>,
+}
+
+pub fn parse_address(value: &str) -> Result<Address, AppError> {
+    Address::from_str(value.trim())
+        .map_err(|_| AppError::Validation(format!("Invalid address: {}", value)))
+}
+
+/// Balances to read, grouped by address with the native balance first.
+fn lookups(request: &BalancesRequest) -> Vec<Lookup> {
+    request
+        .addresses
+        .iter()
+        .flat_map(|address| {
+            let native = request.native.then_some(Lookup {
+                address: *address,
+                token: None,
+            });
+            native
+                .into_iter()
+                .chain(request.tokens.iter().map(|token| Lookup {
+                    address: *address,
+                    token: Some(*token),
+                }))
+        })
+        .collect()
+}
+
+fn to_call(lookup: &Lookup, multicall: Address) -> Multicall3Abi::Call3 {
+    let (target, call_data) = match lookup.token {
+        Some(token) => (
+            token,
+            balanceOfCall {
+                account: lookup.address,
+            }
+            .abi_encode(),
+        ),
+        // Multicall3 reads native balances itself
+        None => (
+            multicall,
+            getEthBalanceCall {
+                addr: lookup.address,
+            }
+            .abi_encode(),
+        ),
+    };
+
+    Multicall3Abi::Call3 {
+        target,
+        allowFailure: true,
+        callData: call_data.into(),
+    }
+}
+
+fn decode(lookup: &Lookup, result: &Multicall3Abi::Result) -> Result<U256, String> {
+    if !result.success {
+        return Err("call reverted".to_string());
+    }
+    // A call to an address without code succeeds with empty return data
+    match lookup.token {
+        Some(_) => balanceOfCall::abi_decode_returns(&result.returnData, true)
+            .map(|decoded| decoded._0)
+            .map_err(|_| "not an ERC-20 contract".to_string()),
+        None => getEthBalanceCall::abi_decode_returns(&result.returnData, true)
+            .map(|decoded| decoded.balance)
+            .map_err(|_| "invalid getEthBalance response".to_string()),
+    }
+}
+
+fn to_balance(lookup: &Lookup, result: Result<U256, String>) -> Balance {
+    let (balance, error) = match result {
+        Ok(balance) => (Some(balance.to_string()), None),
+        Err(e) => (None, Some(e)),
+    };
+    Balance {
+        address: lookup.address.to_string(),
+        token: lookup.token.map(|token| token.to_string()),
+        balance,
+        error,
+    }
+}
+
+/// Reads every balance of `request` on `chain_id`.
+pub async fn batch_balances(
+    state: &AppState,
+    chain_id: ChainId,
+    request: &BalancesRequest,
+) -> Result<Vec<Balance>, AppError> {
+    let lookups = lookups(request);
+    if lookups.is_empty() {
+        return Err(AppError::Validation(
+            "Give at least one address, and tokens or native balances".to_string(),
+        ));
+    }
+    if lookups.len() > MAX_BALANCES {
+        return Err(AppError::Validation(format!(
+            "At most {} balances can be read at once, {} were asked for",
+            MAX_BALANCES,
+            lookups.len()
+        )));
+    }
+
+    let multicall = multicall_address(state, chain_id);
+    let calls = lookups
+        .iter()
+        .map(|lookup| to_call(lookup, multicall))
+        .collect();
+    let results = aggregate(state, chain_id, calls).await?;
+
+    Ok(lookups
+        .iter()
+        .zip(&results)
+        .map(|(lookup, result)| to_balance(lookup, decode(lookup, result)))
+        .collect())
+}
+
+pub fn multicall_address(state: &AppState, chain_id: ChainId) -> Address {
+    state
+        .chains
+        .get(chain_id)
+        .map(|chain| chain.multicall3)
+        .unwrap_or(MULTICALL3_ADDRESS)
+}
+
+/// Sends `calls` through Multicall3 `aggregate3`, `CALLS_PER_BATCH` at a time, answering
+/// one result per call.
+pub async fn aggregate(
+    state: &AppState,
+    chain_id: ChainId,
+    calls: Vec<Multicall3Abi::Call3>,
+) -> Result<Vec<Multicall3Abi::Result>, AppError> {
+    let multicall = multicall_address(state, chain_id);
+
+    let mut results = Vec::with_capacity(calls.len());
+    for batch in calls.chunks(CALLS_PER_BATCH) {
+        let answered = state
+            .providers
+            .call(&state.metrics, chain_id, "eth_call", |provider| {
+                multicall_query(provider, multicall, batch.to_vec())
+            })
+            .await?;
+
+        if answered.len() != batch.len() {
+            return Err(AppError::Upstream(format!(
+                "Multicall3 answered {} results for {} calls",
+                answered.len(),
+                batch.len()
+            )));
+        }
+        results.extend(answered);
+    }
+
+    Ok(results)
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use alloy::primitives::Bytes;
+
+    /// Multicall3 answer of a call returning the word `value`, or of a reverted call.
+    pub(crate) fn call_result(value: Option<u64>) -> Multicall3Abi::Result {
+        match value {
+            Some(value) => Multicall3Abi::Result {
+                success: true,
+                returnData: Bytes::from(U256::from(value).to_be_bytes::<32>().to_vec()),
+            },
+            None => Multicall3Abi::Result {
+                success: false,
+                returnData: Bytes::new(),
+            },
+        }
+    }
+
+    fn address(byte: u8) -> Address {
+        Address::repeat_byte(byte)
+    }
+
+    #[test]
+    fn lookups_group_balances_by_address() {
+        let request = BalancesRequest {
+            addresses: vec![address(1), address(2)],
+            tokens: vec![address(9)],
+            native: true,
+        };
+        let planned = lookups(&request);
+
+        assert_eq!(planned.len(), 4);
+        assert_eq!(
+            planned[0],
+            Lookup {
+                address: address(1),
+                token: None
+            }
+        );
+        assert_eq!(planned[1].token, Some(address(9)));
+        assert_eq!(planned[2].address, address(2));
+
+        let tokens_only = BalancesRequest {
+            native: false,
+            ..request
+        };
+        assert!(lookups(&tokens_only).iter().all(|l| l.token.is_some()));
+    }
+
+    #[test]
+    fn native_balances_are_read_by_multicall_itself() {
+        let native = Lookup {
+            address: address(1),
+            token: None,
+        };
+        let token = Lookup {
+            address: address(1),
+            token: Some(address(9)),
+        };
+
+        assert_eq!(
+            to_call(&native, MULTICALL3_ADDRESS).target,
+            MULTICALL3_ADDRESS
+        );
+        assert_eq!(to_call(&token, MULTICALL3_ADDRESS).target, address(9));
+        assert!(to_call(&token, MULTICALL3_ADDRESS).allowFailure);
+    }
+
+    #[test]
+    fn failed_calls_only_fail_their_own_balance() {
+        let token = Lookup {
+            address: address(1),
+            token: Some(address(9)),
+        };
+        let ok = call_result(Some(42));
+        let reverted = call_result(None);
+        let no_code = Multicall3Abi::Result {
+            success: true,
+            returnData: Bytes::new(),
+        };
+
+        assert_eq!(decode(&token, &ok), Ok(U256::from(42)));
+        assert_eq!(decode(&token, &reverted), Err("call reverted".to_string()));
+        assert!(decode(&token, &no_code).is_err());
+
+        let balance = to_balance(&token, decode(&token, &reverted));
+        assert_eq!(balance.balance, None);
+        assert_eq!(balance.error.as_deref(), Some("call reverted"));
+    }
+}
@@ -0,0 +1,439 @@
+//! Chains the service knows about, with their metadata and RPC endpoints.
+//!
+//! Built-in chains can be overridden, and new ones added, by a JSON file listing chains
+//! in the same shape, pointed at by `CHAINS_CONFIG`. Routes, query parameters, GraphQL
+//! arguments and `SCAN_CHAIN_IDS` accept a chain's id or its slug, e.g. `base`.
+
+use crate::model::{AppError, AppState};
+use alloy::primitives::{address, Address, ChainId};
+use async_graphql::{NewType, SimpleObject};
+use axum::{extract::State, Json};
+use serde::{Deserialize, Serialize};
+use std::{convert::Infallible, env::var, fs, str::FromStr, sync::Arc};
+
+/// Address Multicall3 is deployed at on most chains.
+pub const MULTICALL3_ADDRESS: Address = address!("cA11bde05977b3631167028862bE2a173976CA11");
+
+#[derive(Debug, Clone, Serialize, Deserialize, SimpleObject, PartialEq)]
+pub struct NativeCurrency {
+    pub symbol: String,
+    pub decimals: u8,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, SimpleObject, PartialEq)]
+pub struct Chain {
+    pub id: ChainId,
+    pub name: String,
+    /// Lowercase name accepted in place of the id, e.g. `arbitrum-sepolia`
+    pub slug: String,
+    pub native_currency: NativeCurrency,
+    /// Average time between blocks
+    pub block_time_ms: u64,
+    pub explorer_url: Option<String>,
+    #[serde(default = "default_multicall3")]
+    #[graphql(skip)]
+    pub multicall3: Address,
+    /// ERC-20 contracts portfolios always include, on top of those seen in transfers,
+    /// and whose balances are snapshotted
+    #[serde(default)]
+    #[graphql(skip)]
+    pub tokens: Vec<Address>,
+    /// Addresses whose balances are snapshotted every few blocks
+    #[serde(default)]
+    #[graphql(skip)]
+    pub watched: Vec<Address>,
+    /// Environment variable holding the primary endpoint, kept out of config files as
+    /// endpoint urls often embed API keys
+    #[serde(default, skip_serializing)]
+    #[graphql(skip)]
+    pub rpc_url_env: Option<String>,
+    /// Endpoints tried after the one of `rpc_url_env`, in order
+    #[serde(default, skip_serializing)]
+    #[graphql(skip)]
+    pub rpc_urls: Vec<String>,
+}
+
+impl Chain {
+    /// Endpoints of the chain in order of preference.
+    pub fn endpoints(&self) -> Vec<String> {
+        let primary = self
+            .rpc_url_env
+            .as_ref()
+            .and_then(|name| var(name).ok())
+            .map(|url| url.trim().to_string())
+            .filter(|url| !url.is_empty());
+
+        primary
+            .into_iter()
+            .chain(self.rpc_urls.iter().map(|url| url.trim().to_string()))
+            .collect()
+    }
+}
+
+fn default_multicall3() -> Address {
+    MULTICALL3_ADDRESS
+}
+
+/// Chain id or slug as given in a route, resolved with `ChainRegistry::resolve`. A plain
+/// string in GraphQL.
+#[derive(Debug, Clone, Deserialize, NewType)]
+#[serde(transparent)]
+pub struct ChainRef(pub String);
+
+impl FromStr for ChainRef {
+    type Err = Infallible;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        Ok(ChainRef(value.to_string()))
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ChainRegistry {
+    /// Sorted by id
+    chains: Vec<Chain>,
+}
+
+impl ChainRegistry {
+    /// The built-in chains, overridden by those of the file at `CHAINS_CONFIG`, if set and
+    /// not blank.
+    pub fn load() -> Result<Self, AppError> {
+        let overrides = match var("CHAINS_CONFIG") {
+            Ok(path) if !path.trim().is_empty() => {
+                let contents = fs::read_to_string(&path)
+                    .map_err(|e| AppError::Other(format!("Cannot read {}: {}", path, e)))?;
+                serde_json::from_str(&contents)
+                    .map_err(|e| AppError::Other(format!("Invalid chains in {}: {}", path, e)))?
+            }
+            _ => Vec::new(),
+        };
+        Self::new(builtin_chains(), overrides)
+    }
+
+    /// Registry of `chains`, each of `overrides` replacing the chain with the same id.
+    pub fn new(chains: Vec<Chain>, overrides: Vec<Chain>) -> Result<Self, AppError> {
+        let mut chains = chains;
+        for chain in overrides {
+            chains.retain(|existing| existing.id != chain.id);
+            chains.push(chain);
+        }
+        chains.sort_by_key(|chain| chain.id);
+
+        for (index, chain) in chains.iter().enumerate() {
+            if chain.slug.is_empty() || chain.slug.parse::<ChainId>().is_ok() {
+                return Err(AppError::Other(format!(
+                    "Chain {} needs a slug that is not a number",
+                    chain.id
+                )));
+            }
+            if chains[..index]
+                .iter()
+                .any(|other| other.slug.eq_ignore_ascii_case(&chain.slug))
+            {
+                return Err(AppError::Other(format!(
+                    "Slug {} is used by more than one chain",
+                    chain.slug
+                )));
+            }
+        }
+
+        Ok(Self { chains })
+    }
+
+    pub fn all(&self) -> &[Chain] {
+        &self.chains
+    }
+
+    pub fn get(&self, chain_id: ChainId) -> Option<&Chain> {
+        self.chains.iter().find(|chain| chain.id == chain_id)
+    }
+
+    /// Chain id named by `reference`, either a number or a slug. Ids of chains missing
+    /// from the registry are kept, so chains only configured through fallback endpoints
+    /// can still be queried.
+    pub fn resolve(&self, reference: &ChainRef) -> Result<ChainId, AppError> {
+        self.resolve_str(&reference.0)
+    }
+
+    pub fn resolve_str(&self, reference: &str) -> Result<ChainId, AppError> {
+        let reference = reference.trim();
+        if let Ok(chain_id) = reference.parse::<ChainId>() {
+            return Ok(chain_id);
+        }
+        self.chains
+            .iter()
+            .find(|chain| chain.slug.eq_ignore_ascii_case(reference))
+            .map(|chain| chain.id)
+            .ok_or_else(|| AppError::Validation(format!("Unknown chain: {}", reference)))
+    }
+
+    /// Websocket endpoint the scanner subscribes to: the first of the chain's endpoints
+    /// using `ws://` or `wss://`.
+    pub fn web_socket_url(&self, chain_id: ChainId) -> Result<String, AppError> {
+        let chain = self
+            .get(chain_id)
+            .ok_or(AppError::UnsupportedChain(chain_id))?;
+        chain
+            .endpoints()
+            .into_iter()
+            .find(|url| url.starts_with("ws://") || url.starts_with("wss://"))
+            .ok_or_else(|| {
+                AppError::Other(match &chain.rpc_url_env {
+                    Some(name) => format!("No websocket url for {}, set {}", chain.name, name),
+                    None => format!("No websocket url for {}", chain.name),
+                })
+            })
+    }
+}
+
+#[axum::debug_handler]
+pub async fn get_chains(State(state): State<Arc<AppState>>) -> Json<Vec<Chain>> {
+    Json(state.chains.all().to_vec())
+}
+
+fn chain(
+    id: ChainId,
+    name: &str,
+    slug: &str,
+    symbol: &str,
+    block_time_ms: u64,
+    explorer_url: &str,
+    rpc_url_env: &str,
+) -> Chain {
+    Chain {
+        id,
+        name: name.to_string(),
+        slug: slug.to_string(),
+        native_currency: NativeCurrency {
+            symbol: symbol.to_string(),
+            decimals: 18,
+        },
+        block_time_ms,
+        explorer_url: Some(explorer_url.to_string()),
+        multicall3: MULTICALL3_ADDRESS,
+        tokens: Vec::new(),
+        watched: Vec::new(),
+        rpc_url_env: Some(rpc_url_env.to_string()),
+        rpc_urls: Vec::new(),
+    }
+}
+
+pub fn builtin_chains() -> Vec<Chain> {
+    vec![
+        chain(
+            1,
+            "Ethereum",
+            "ethereum",
+            "ETH",
+            12_000,
+            "https://etherscan.io",
+            "MAINNET_WEB_SOCKET_URL",
+        ),
+        chain(
+            10,
+            "OP Mainnet",
+            "optimism",
+            "ETH",
+            2_000,
+            "https://optimistic.etherscan.io",
+            "OP_WEB_SOCKET_URL",
+        ),
+        chain(
+            56,
+            "BNB Smart Chain",
+            "bsc",
+            "BNB",
+            3_000,
+            "https://bscscan.com",
+            "BINANCE_WEB_SOCKET_URL",
+        ),
+        chain(
+            137,
+            "Polygon",
+            "polygon",
+            "POL",
+            2_000,
+            "https://polygonscan.com",
+            "POLYGON_POS_WEB_SOCKET_URL",
+        ),
+        // zkSync's address derivation puts Multicall3 elsewhere
+        Chain {
+            multicall3: address!("F9cda624FBC7e059355ce98a31693d299FACd963"),
+            ..chain(
+                324,
+                "zkSync Era",
+                "zksync",
+                "ETH",
+                1_000,
+                "https://explorer.zksync.io",
+                "ZKSYNC_WEB_SOCKET_URL",
+            )
+        },
+        chain(
+            420,
+            "OP Goerli",
+            "optimism-goerli",
+            "ETH",
+            2_000,
+            "https://goerli-optimism.etherscan.io",
+            "OP_SEPOLIA_WEB_SOCKET_URL",
+        ),
+        chain(
+            1101,
+            "Polygon zkEVM",
+            "polygon-zkevm",
+            "ETH",
+            3_000,
+            "https://zkevm.polygonscan.com",
+            "POLYGON_ZKEVM_WEB_SOCKET_URL",
+        ),
+        chain(
+            8453,
+            "Base",
+            "base",
+            "ETH",
+            2_000,
+            "https://basescan.org",
+            "BASE_WEB_SOCKET_URL",
+        ),
+        chain(
+            42161,
+            "Arbitrum One",
+            "arbitrum",
+            "ETH",
+            250,
+            "https://arbiscan.io",
+            "ARBITRUMONE_WEB_SOCKET_URL",
+        ),
+        chain(
+            84531,
+            "Base Goerli",
+            "base-goerli",
+            "ETH",
+            2_000,
+            "https://goerli.basescan.org",
+            "BASE_SEPOLIA_WEB_SOCKET_URL",
+        ),
+        chain(
+            421614,
+            "Arbitrum Sepolia",
+            "arbitrum-sepolia",
+            "ETH",
+            250,
+            "https://sepolia.arbiscan.io",
+            "ARBITRUM_SEPOLIA_WEB_SOCKET_URL",
+        ),
+        chain(
+            534351,
+            "Scroll Sepolia",
+            "scroll-sepolia",
+            "ETH",
+            3_000,
+            "https://sepolia.scrollscan.com",
+            "SCROLL_SEPOLIA_WEB_SOCKET_URL",
+        ),
+        chain(
+            534352,
+            "Scroll",
+            "scroll",
+            "ETH",
+            3_000,
+            "https://scrollscan.com",
+            "SCROLL_WEB_SOCKET_URL",
+        ),
+        chain(
+            11155111,
+            "Sepolia",
+            "sepolia",
+            "ETH",
+            12_000,
+            "https://sepolia.etherscan.io",
+            "SEPOLIA_WEB_SOCKET_URL",
+        ),
+    ]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn registry() -> ChainRegistry {
+        ChainRegistry::new(builtin_chains(), Vec::new()).unwrap()
+    }
+
+    #[test]
+    fn resolves_ids_and_slugs() {
+        let registry = registry();
+        assert_eq!(registry.resolve_str("8453").unwrap(), 8453);
+        assert_eq!(registry.resolve_str("base").unwrap(), 8453);
+        assert_eq!(registry.resolve_str("Arbitrum-Sepolia").unwrap(), 421614);
+        // Unknown ids are left for the providers to reject
+        assert_eq!(registry.resolve_str("999").unwrap(), 999);
+        assert!(matches!(
+            registry.resolve_str("basee"),
+            Err(AppError::Validation(_))
+        ));
+    }
+
+    #[test]
+    fn overrides_replace_chains_by_id() {
+        let mut base = chain(
+            8453,
+            "Base",
+            "base",
+            "ETH",
+            1_000,
+            "https://example.org",
+            "BASE_URL",
+        );
+        base.rpc_urls = vec!["wss://base.example.org".to_string()];
+        let custom = chain(
+            7777,
+            "Custom",
+            "custom",
+            "CUS",
+            500,
+            "https://example.org",
+            "CUSTOM_URL",
+        );
+
+        let registry = ChainRegistry::new(builtin_chains(), vec![base.clone(), custom]).unwrap();
+
+        assert_eq!(registry.get(8453), Some(&base));
+        assert_eq!(registry.resolve_str("custom").unwrap(), 7777);
+        assert_eq!(registry.all().len(), builtin_chains().len() + 1);
+        assert!(registry
+            .all()
+            .windows(2)
+            .all(|pair| pair[0].id < pair[1].id));
+        assert_eq!(
+            registry.web_socket_url(8453).unwrap(),
+            "wss://base.example.org"
+        );
+    }
+
+    #[test]
+    fn rejects_ambiguous_slugs() {
+        let duplicate = chain(
+            7777,
+            "Base copy",
+            "BASE",
+            "ETH",
+            2_000,
+            "https://example.org",
+            "X",
+        );
+        assert!(ChainRegistry::new(builtin_chains(), vec![duplicate]).is_err());
+
+        let numeric = chain(
+            7777,
+            "Numeric",
+            "10",
+            "ETH",
+            2_000,
+            "https://example.org",
+            "X",
+        );
+        assert!(ChainRegistry::new(builtin_chains(), vec![numeric]).is_err());
+    }
+}
@@ -0,0 +1,66 @@
+//! Command-line interface of the sentinel binary.
+
+use crate::chains::ChainRef;
+use clap::{Parser, Subcommand, ValueEnum};
+use std::path::PathBuf;
+
+#[derive(Parser, Debug)]
+#[command(name = "sentinel", version, about)]
+pub struct Cli {
+    #[command(subcommand)]
+    pub command: Command,
+}
+
+#[derive(Subcommand, Debug)]
+pub enum Command {
+    /// Serve the REST and GraphQL API without scanning any mempool
+    Serve,
+    /// Scan the mempool of one or more chains without serving the API
+    Scan {
+        /// Chain id or slug to scan, may be repeated. Defaults to SCAN_CHAIN_IDS
+        #[arg(long = "chain", value_name = "CHAIN")]
+        chains: Vec<ChainRef>,
+    },
+    /// Index the transactions of an inclusive range of blocks
+    Backfill {
+        /// Id or slug of the chain the blocks belong to
+        #[arg(long, value_name = "CHAIN")]
+        chain: ChainRef,
+        /// First block of the range
+        from: u64,
+        /// Last block of the range
+        to: u64,
+    },
+    /// Export the stored transactions to a file
+    Export {
+        #[arg(long, value_enum, default_value_t = ExportFormat::Csv)]
+        format: ExportFormat,
+        /// Output file. Defaults to `transactions_export.<format>`
+        #[arg(long, short)]
+        output: Option<PathBuf>,
+        /// Only export transactions of this chain, by id or slug
+        #[arg(long, value_name = "CHAIN")]
+        chain: Option<ChainRef>,
+    },
+    /// Apply pending database migrations and exit
+    Migrate,
+    /// Validate the environment configuration and exit
+    CheckConfig,
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq)]
+pub enum ExportFormat {
+    Csv,
+    Jsonl,
+    Parquet,
+}
+
+impl ExportFormat {
+    pub fn extension(&self) -> &'static str {
+        match self {
+            ExportFormat::Csv => "csv",
+            ExportFormat::Jsonl => "jsonl",
+            ExportFormat::Parquet => "parquet",
+        }
+    }
+}
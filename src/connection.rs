@@ -1,19 +1,236 @@
-use crate::model::{AppError, Config};
+use crate::{
+    chains::ChainRegistry,
+    model::{AppError, Config},
+    providers::ProviderConfig,
+    rate_limit::RateLimitConfig,
+    retention::{Retention, RetentionPolicy},
+    rpc_cache::RpcCacheConfig,
+    simulation::SimulationConfig,
+    snapshots::SnapshotConfig,
+    writer::BatchConfig,
+};
+use alloy::primitives::{ChainId, U256};
 use async_tungstenite::{
     tokio::{connect_async, ConnectStream},
     WebSocketStream,
 };
 use log::info;
-use std::env::var;
+use std::{
+    collections::HashMap,
+    env::{var, vars},
+    num::NonZeroU64,
+    str::FromStr,
+    time::Duration,
+};
+
+const FALLBACK_URLS_PREFIX: &str = "RPC_FALLBACK_URLS_";
 
 pub fn load_config() -> Result<Config, AppError> {
+    let chains = ChainRegistry::load()?;
     Ok(Config {
-        web_socket_url: var("SEPOLIA_WEB_SOCKET_URL").unwrap(),
-        db_url: var("DATABASE_URL").unwrap(),
+        db_url: var("DATABASE_URL")?,
         server_url: var("SERVER_ADDRESS").unwrap_or("127.0.0.1::3000".to_string()),
+        scanner_url: var("SCANNER_ADDRESS").unwrap_or("127.0.0.1:7071".to_string()),
+        scan_chain_ids: parse_chain_ids(
+            &chains,
+            &var("SCAN_CHAIN_IDS").unwrap_or("11155111".to_string()),
+        )?,
+        csv_max_bytes: optional_env_u64("CSV_ROTATE_MAX_BYTES")?.unwrap_or(100 * 1024 * 1024),
+        readiness_max_silence_secs: optional_env_u64("READINESS_MAX_SILENCE_SECS")?.unwrap_or(60),
+        shutdown_timeout: Duration::from_secs(
+            optional_env_u64("SHUTDOWN_TIMEOUT_SECS")?.unwrap_or(30),
+        ),
+        batch: BatchConfig {
+            max_rows: optional_env_u64("BATCH_MAX_ROWS")?.unwrap_or(500) as usize,
+            max_delay: Duration::from_millis(
+                optional_env_u64("BATCH_MAX_DELAY_MS")?.unwrap_or(1000),
+            ),
+        },
+        providers: ProviderConfig {
+            endpoints: load_endpoints(&chains)?,
+            request_timeout: Duration::from_secs(
+                optional_env_u64("RPC_TIMEOUT_SECS")?.unwrap_or(10),
+            ),
+            health_check_interval: Duration::from_secs(
+                optional_env_u64("RPC_HEALTH_CHECK_INTERVAL_SECS")?.unwrap_or(15),
+            ),
+            max_lag_blocks: optional_env_u64("RPC_MAX_LAG_BLOCKS")?.unwrap_or(5),
+            rate_limit: RateLimitConfig {
+                compute_units_per_second: optional_env_u64("RPC_COMPUTE_UNITS_PER_SECOND")?
+                    .unwrap_or(330),
+                per_chain: load_per_chain("RPC_COMPUTE_UNITS_PER_SECOND")?,
+                burst_seconds: optional_env_u64("RPC_BURST_SECONDS")?.unwrap_or(1),
+                max_queue: optional_env_u64("RPC_MAX_QUEUED_REQUESTS")?.unwrap_or(100) as usize,
+                interactive_max_wait: Duration::from_millis(
+                    optional_env_u64("RPC_INTERACTIVE_MAX_WAIT_MS")?.unwrap_or(2000),
+                ),
+                background_max_wait: Duration::from_millis(
+                    optional_env_u64("RPC_BACKGROUND_MAX_WAIT_MS")?.unwrap_or(10_000),
+                ),
+            },
+        },
+        rpc_cache: RpcCacheConfig {
+            capacity: optional_env_u64("RPC_CACHE_CAPACITY")?.unwrap_or(10_000) as usize,
+            latest_ttl: Duration::from_secs(
+                optional_env_u64("RPC_CACHE_LATEST_TTL_SECS")?.unwrap_or(2),
+            ),
+            finality_blocks: optional_env_u64("RPC_CACHE_FINALITY_BLOCKS")?.unwrap_or(64),
+            persist: optional_env_bool("RPC_CACHE_PERSIST")?.unwrap_or(false),
+        },
+        retention: RetentionPolicy {
+            transactions: load_retention("RETENTION_TRANSACTIONS_DAYS")?,
+            responses: load_retention("RETENTION_RESPONSES_DAYS")?,
+            csv_days: optional_env_u64("RETENTION_CSV_DAYS")?,
+            interval: Duration::from_secs(
+                optional_env_u64("RETENTION_INTERVAL_SECS")?.unwrap_or(3600),
+            ),
+        },
+        snapshots: load_snapshots()?,
+        simulation: SimulationConfig {
+            min_value_wei: optional_env_u256("SIMULATE_MIN_VALUE_WEI")?,
+        },
+        capture_internal_calls: optional_env_bool("CAPTURE_INTERNAL_CALLS")?.unwrap_or(false),
+        chains,
+    })
+}
+
+/// Lists the endpoints of every chain: those of the chain registry first, then the comma
+/// separated fallbacks of `RPC_FALLBACK_URLS_<chain id>`.
+fn load_endpoints(chains: &ChainRegistry) -> Result<HashMap<ChainId, Vec<String>>, AppError> {
+    let mut endpoints: HashMap<ChainId, Vec<String>> = HashMap::new();
+
+    for chain in chains.all() {
+        let urls = chain.endpoints();
+        if !urls.is_empty() {
+            endpoints.insert(chain.id, urls);
+        }
+    }
+
+    for (key, value) in vars() {
+        if let Some(chain_id) = key.strip_prefix(FALLBACK_URLS_PREFIX) {
+            let chain_id = chain_id
+                .parse::<ChainId>()
+                .map_err(|_| AppError::Other(format!("Invalid chain id in {}", key)))?;
+            endpoints.entry(chain_id).or_default().extend(
+                value
+                    .split(',')
+                    .map(str::trim)
+                    .filter(|url| !url.is_empty())
+                    .map(str::to_string),
+            );
+        }
+    }
+
+    Ok(endpoints)
+}
+
+/// Reads the default retention from `name` and per chain overrides from `<name>_<chain id>`.
+fn load_retention(name: &str) -> Result<Retention, AppError> {
+    Ok(Retention {
+        default_days: optional_env_u64(name)?,
+        per_chain: load_per_chain(name)?,
+    })
+}
+
+/// Reads the snapshot interval from `SNAPSHOT_INTERVAL_BLOCKS` and per chain overrides from
+/// `SNAPSHOT_INTERVAL_BLOCKS_<chain id>`, none of which may be zero.
+fn load_snapshots() -> Result<SnapshotConfig, AppError> {
+    const NAME: &str = "SNAPSHOT_INTERVAL_BLOCKS";
+    let non_zero = |name: &str, blocks: u64| {
+        NonZeroU64::new(blocks)
+            .ok_or_else(|| AppError::Other(format!("{} must be a positive integer", name)))
+    };
+
+    let per_chain = load_per_chain(NAME)?
+        .into_iter()
+        .map(|(chain_id, blocks)| {
+            Ok((
+                chain_id,
+                non_zero(&format!("{}_{}", NAME, chain_id), blocks)?,
+            ))
+        })
+        .collect::<Result<_, AppError>>()?;
+    Ok(SnapshotConfig {
+        interval_blocks: non_zero(NAME, optional_env_u64(NAME)?.unwrap_or(100))?,
+        per_chain,
     })
 }
 
+/// Reads the per chain overrides of `name` from `<name>_<chain id>`.
+fn load_per_chain(name: &str) -> Result<HashMap<ChainId, u64>, AppError> {
+    let prefix = format!("{}_", name);
+    let mut per_chain = HashMap::new();
+
+    for (key, value) in vars() {
+        if let Some(chain_id) = key.strip_prefix(&prefix) {
+            if value.trim().is_empty() {
+                continue;
+            }
+            let chain_id = chain_id
+                .parse::<ChainId>()
+                .map_err(|_| AppError::Other(format!("Invalid chain id in {}", key)))?;
+            per_chain.insert(chain_id, parse_env_u64(&key, &value)?);
+        }
+    }
+
+    Ok(per_chain)
+}
+
+/// Value of the environment variable `name`. Blank values, such as `NAME=` lines copied
+/// from `.env.example`, count as unset.
+fn optional_var(name: &str) -> Option<String> {
+    var(name).ok().filter(|value| !value.trim().is_empty())
+}
+
+fn optional_env_u64(name: &str) -> Result<Option<u64>, AppError> {
+    optional_var(name)
+        .map(|value| parse_env_u64(name, &value))
+        .transpose()
+}
+
+fn optional_env_u256(name: &str) -> Result<Option<U256>, AppError> {
+    optional_var(name)
+        .map(|value| {
+            U256::from_str(value.trim())
+                .map_err(|_| AppError::Other(format!("{} must be a non-negative integer", name)))
+        })
+        .transpose()
+}
+
+fn optional_env_bool(name: &str) -> Result<Option<bool>, AppError> {
+    optional_var(name)
+        .map(|value| match value.trim().to_lowercase().as_str() {
+            "true" | "1" | "yes" => Ok(true),
+            "false" | "0" | "no" => Ok(false),
+            _ => Err(AppError::Other(format!("{} must be true or false", name))),
+        })
+        .transpose()
+}
+
+fn parse_env_u64(name: &str, value: &str) -> Result<u64, AppError> {
+    match value.trim().parse::<u64>() {
+        Ok(parsed) if parsed > 0 => Ok(parsed),
+        _ => Err(AppError::Other(format!(
+            "{} must be a positive integer",
+            name
+        ))),
+    }
+}
+
+/// Parses comma separated chain ids or slugs.
+fn parse_chain_ids(chains: &ChainRegistry, value: &str) -> Result<Vec<ChainId>, AppError> {
+    value
+        .split(',')
+        .map(str::trim)
+        .filter(|id| !id.is_empty())
+        .map(|id| {
+            chains
+                .resolve_str(id)
+                .map_err(|_| AppError::Other(format!("Invalid chain in SCAN_CHAIN_IDS: {}", id)))
+        })
+        .collect()
+}
+
 pub async fn connect_websocket(url: &str) -> Result<WebSocketStream<ConnectStream>, AppError> {
     let (ws_stream, _) = connect_async(url).await?;
     info!("WebSocket connected");
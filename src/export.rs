@@ -0,0 +1,169 @@
+//! Exports stored transactions as CSV, JSON lines or Parquet.
+
+use crate::{
+    cli::ExportFormat,
+    model::{AppError, Transaction, TransactionFilter},
+    store::TransactionStore,
+};
+use alloy::primitives::ChainId;
+use parquet::{
+    data_type::{BoolType, ByteArray, ByteArrayType, DataType, Int64Type},
+    file::{
+        properties::WriterProperties,
+        writer::{SerializedFileWriter, SerializedRowGroupWriter},
+    },
+    schema::parser::parse_message_type,
+};
+use std::{
+    fs::File,
+    io::{BufWriter, Write},
+    path::Path,
+    sync::Arc,
+};
+
+const PARQUET_SCHEMA: &str = "
+message transaction {
+    REQUIRED BYTE_ARRAY id (UTF8);
+    REQUIRED INT64 chain_id;
+    REQUIRED BYTE_ARRAY tx_hash (UTF8);
+    REQUIRED BYTE_ARRAY block_hash (UTF8);
+    REQUIRED INT64 block_number;
+    REQUIRED BYTE_ARRAY from_sender (UTF8);
+    REQUIRED BYTE_ARRAY to_reciever (UTF8);
+    REQUIRED INT64 tx_value;
+    REQUIRED BOOLEAN tx_value_overflow;
+    REQUIRED INT64 gas;
+    REQUIRED INT64 gas_price;
+    OPTIONAL INT64 priority_fee;
+    REQUIRED BYTE_ARRAY input (UTF8);
+    REQUIRED INT64 nonce;
+    REQUIRED INT64 mempool_time;
+    REQUIRED BYTE_ARRAY contract_type (UTF8);
+}
+";
+
+/// Writes the stored transactions, optionally of a single chain, to `output`.
+pub async fn export_transactions(
+    store: &dyn TransactionStore,
+    format: ExportFormat,
+    chain_id: Option<ChainId>,
+    output: &Path,
+) -> Result<usize, AppError> {
+    let filter = TransactionFilter {
+        chain_id: chain_id.map(|chain_id| chain_id as i64),
+        ..Default::default()
+    };
+    let transactions = store.filter(&filter).await?;
+
+    let file = File::create(output)?;
+    match format {
+        ExportFormat::Csv => write_csv(&transactions, file)?,
+        ExportFormat::Jsonl => write_jsonl(&transactions, file)?,
+        ExportFormat::Parquet => write_parquet(&transactions, file)?,
+    }
+
+    Ok(transactions.len())
+}
+
+fn write_csv(transactions: &[Transaction], file: File) -> Result<(), AppError> {
+    let mut writer = csv::Writer::from_writer(file);
+    for transaction in transactions {
+        writer.serialize(transaction)?;
+    }
+    writer.flush()?;
+    Ok(())
+}
+
+fn write_jsonl(transactions: &[Transaction], file: File) -> Result<(), AppError> {
+    let mut writer = BufWriter::new(file);
+    for transaction in transactions {
+        serde_json::to_writer(&mut writer, transaction)?;
+        writer.write_all(b"\n")?;
+    }
+    writer.flush()?;
+    Ok(())
+}
+
+fn write_parquet(transactions: &[Transaction], file: File) -> Result<(), AppError> {
+    let schema = Arc::new(parse_message_type(PARQUET_SCHEMA)?);
+    let properties = Arc::new(WriterProperties::builder().build());
+    let mut writer = SerializedFileWriter::new(file, schema, properties)?;
+    let mut row_group = writer.next_row_group()?;
+
+    let text = |value: &str| ByteArray::from(value.as_bytes().to_vec());
+    let int_column =
+        |field: fn(&Transaction) -> i64| -> Vec<i64> { transactions.iter().map(field).collect() };
+    let text_column = |field: fn(&Transaction) -> String| -> Vec<ByteArray> {
+        transactions.iter().map(|t| text(&field(t))).collect()
+    };
+
+    // Columns have to be written in the order of PARQUET_SCHEMA
+    write_column::<ByteArrayType>(&mut row_group, &text_column(|t| t.id.to_string()))?;
+    write_column::<Int64Type>(&mut row_group, &int_column(|t| t.chain_id))?;
+    write_column::<ByteArrayType>(&mut row_group, &text_column(|t| t.tx_hash.clone()))?;
+    write_column::<ByteArrayType>(&mut row_group, &text_column(|t| t.block_hash.clone()))?;
+    write_column::<Int64Type>(&mut row_group, &int_column(|t| t.block_number))?;
+    write_column::<ByteArrayType>(&mut row_group, &text_column(|t| t.from_sender.clone()))?;
+    write_column::<ByteArrayType>(&mut row_group, &text_column(|t| t.to_reciever.clone()))?;
+    write_column::<Int64Type>(&mut row_group, &int_column(|t| t.tx_value))?;
+    write_column::<BoolType>(
+        &mut row_group,
+        &transactions
+            .iter()
+            .map(|t| t.tx_value_overflow)
+            .collect::<Vec<_>>(),
+    )?;
+    write_column::<Int64Type>(&mut row_group, &int_column(|t| t.gas))?;
+    write_column::<Int64Type>(&mut row_group, &int_column(|t| t.gas_price))?;
+    write_optional_column::<Int64Type>(
+        &mut row_group,
+        &transactions
+            .iter()
+            .map(|t| t.priority_fee)
+            .collect::<Vec<_>>(),
+    )?;
+    write_column::<ByteArrayType>(&mut row_group, &text_column(|t| t.input.clone()))?;
+    write_column::<Int64Type>(&mut row_group, &int_column(|t| t.nonce))?;
+    write_column::<Int64Type>(&mut row_group, &int_column(|t| t.mempool_time))?;
+    write_column::<ByteArrayType>(
+        &mut row_group,
+        &text_column(|t| t.contract_type.as_str().to_string()),
+    )?;
+
+    row_group.close()?;
+    writer.close()?;
+    Ok(())
+}
+
+fn write_column<T: DataType>(
+    row_group: &mut SerializedRowGroupWriter<'_, File>,
+    values: &[T::T],
+) -> Result<(), AppError> {
+    let mut column = row_group
+        .next_column()?
+        .ok_or_else(|| AppError::Other("Parquet schema has fewer columns than written".into()))?;
+    column.typed::<T>().write_batch(values, None, None)?;
+    column.close()?;
+    Ok(())
+}
+
+fn write_optional_column<T: DataType>(
+    row_group: &mut SerializedRowGroupWriter<'_, File>,
+    values: &[Option<T::T>],
+) -> Result<(), AppError>
+where
+    T::T: Clone,
+{
+    // Definition level 1 marks a present value, 0 a null
+    let definition_levels: Vec<i16> = values.iter().map(|v| v.is_some() as i16).collect();
+    let present: Vec<T::T> = values.iter().flatten().cloned().collect();
+
+    let mut column = row_group
+        .next_column()?
+        .ok_or_else(|| AppError::Other("Parquet schema has fewer columns than written".into()))?;
+    column
+        .typed::<T>()
+        .write_batch(&present, Some(&definition_levels), None)?;
+    column.close()?;
+    Ok(())
+}
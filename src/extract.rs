@@ -0,0 +1,63 @@
+//! Request extractors answering rejections with the same JSON error body as handlers.
+//!
+//! axum's own extractors reject malformed input, such as an invalid address in a path or a
+//! body that is not valid JSON, with a plain text response. These wrap them and turn the
+//! rejection into an `AppError`, so every error carries a `code` and a `request_id`.
+
+use crate::model::AppError;
+use axum::{
+    extract::{
+        rejection::{JsonRejection, PathRejection, QueryRejection},
+        FromRequest, FromRequestParts,
+    },
+    http::StatusCode,
+    response::{IntoResponse, Response},
+};
+use serde::Serialize;
+
+#[derive(FromRequestParts)]
+#[from_request(via(axum::extract::Path), rejection(AppError))]
+pub struct Path<T>(pub T);
+
+#[derive(FromRequestParts)]
+#[from_request(via(axum::extract::Query), rejection(AppError))]
+pub struct Query<T>(pub T);
+
+/// Extracts a JSON body like `axum::Json`, and serializes a response like it.
+#[derive(FromRequest)]
+#[from_request(via(axum::Json), rejection(AppError))]
+pub struct Json<T>(pub T);
+
+impl<T: Serialize> IntoResponse for Json<T> {
+    fn into_response(self) -> Response {
+        axum::Json(self.0).into_response()
+    }
+}
+
+/// Client mistakes are validation errors. The few rejections that are the server's
+/// fault, such as a body that could not be read, stay internal errors.
+fn rejection_error(status: StatusCode, message: String) -> AppError {
+    if status.is_server_error() {
+        AppError::Other(message)
+    } else {
+        AppError::Validation(message)
+    }
+}
+
+impl From<PathRejection> for AppError {
+    fn from(rejection: PathRejection) -> Self {
+        rejection_error(rejection.status(), rejection.body_text())
+    }
+}
+
+impl From<QueryRejection> for AppError {
+    fn from(rejection: QueryRejection) -> Self {
+        rejection_error(rejection.status(), rejection.body_text())
+    }
+}
+
+impl From<JsonRejection> for AppError {
+    fn from(rejection: JsonRejection) -> Self {
+        rejection_error(rejection.status(), rejection.body_text())
+    }
+}
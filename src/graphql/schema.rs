@@ -1,56 +1,103 @@
-use crate::model::{AppState, Transaction, TransactionFilter};
-use async_graphql::{Context, EmptyMutation, EmptySubscription, Object, Schema, SimpleObject};
+use crate::{
+    account::{account, Account, AccountParams},
+    analytics::{
+        gas::{estimate_gas, GasEstimate, DEFAULT_WINDOW_SECONDS},
+        stats::StatsBucket,
+    },
+    balances::{batch_balances, parse_address, Balance, BalancesRequest},
+    chains::{Chain, ChainRef},
+    internal_calls::InternalCall,
+    model::{AppError, AppState, Granularity, StatsParams, Transaction, TransactionFilter},
+    nft::{
+        detect_interfaces, erc1155_balance, erc1155_balance_batch, erc721_balance, erc721_owner,
+        erc721_token_uri, parse_token_id, Interfaces,
+    },
+    portfolio::{portfolio, Portfolio},
+    rate_limit::Priority,
+    simulation::{simulate_and_store, Simulation},
+    snapshots::{balance_history, BalanceSeries, HistoryParams},
+};
+use alloy::primitives::{Address, ChainId, TxHash};
+use async_graphql::{
+    Context, EmptyMutation, EmptySubscription, ErrorExtensions, Object, Schema, SimpleObject,
+};
 use std::sync::Arc;
+use uuid::Uuid;
 
 #[derive(SimpleObject)]
 struct GraphQLTransaction {
     id: String,
+    chain_id: i64,
     tx_hash: String,
     block_hash: String,
     block_number: i64,
     from_sender: String,
     to_reciever: String,
     tx_value: i64,
+    tx_value_overflow: bool,
     gas: i64,
     gas_price: i64,
+    priority_fee: Option<i64>,
     input: String,
     nonce: i64,
     mempool_time: i64,
     contract_type: String,
 }
 
+impl From<Transaction> for GraphQLTransaction {
+    fn from(t: Transaction) -> Self {
+        GraphQLTransaction {
+            id: t.id.to_string(),
+            chain_id: t.chain_id,
+            tx_hash: t.tx_hash,
+            block_hash: t.block_hash,
+            block_number: t.block_number,
+            from_sender: t.from_sender,
+            to_reciever: t.to_reciever,
+            tx_value: t.tx_value,
+            tx_value_overflow: t.tx_value_overflow,
+            gas: t.gas,
+            gas_price: t.gas_price,
+            priority_fee: t.priority_fee,
+            input: t.input,
+            nonce: t.nonce,
+            mempool_time: t.mempool_time,
+            contract_type: t.contract_type.as_str().to_string(),
+        }
+    }
+}
+
 pub struct Query;
 
 #[Object]
 impl Query {
+    async fn chains(&self, ctx: &Context<'_>) -> async_graphql::Result<Vec<Chain>> {
+        let state = ctx.data::<Arc<AppState>>()?;
+        Ok(state.chains.all().to_vec())
+    }
+
+    /// Looks a chain up by id or slug.
+    async fn chain(&self, ctx: &Context<'_>, chain: String) -> async_graphql::Result<Chain> {
+        let state = ctx.data::<Arc<AppState>>()?;
+        let chain_id = state
+            .chains
+            .resolve(&ChainRef(chain))
+            .map_err(|e| e.extend())?;
+        state
+            .chains
+            .get(chain_id)
+            .cloned()
+            .ok_or_else(|| AppError::UnsupportedChain(chain_id).extend())
+    }
+
     async fn get_transactions(
         &self,
         ctx: &Context<'_>,
     ) -> async_graphql::Result<Vec<GraphQLTransaction>> {
         let state = ctx.data::<Arc<AppState>>()?;
-        let transactions = sqlx::query_as::<_, Transaction>("SELECT * FROM transaction")
-            .fetch_all(&state.pool)
-            .await
-            .map_err(|e| async_graphql::Error::new(e.to_string()))?;
-
-        Ok(transactions
-            .into_iter()
-            .map(|t| GraphQLTransaction {
-                id: t.id.to_string(),
-                tx_hash: t.tx_hash,
-                block_hash: t.block_hash,
-                block_number: t.block_number,
-                from_sender: t.from_sender,
-                to_reciever: t.to_reciever,
-                tx_value: t.tx_value,
-                gas: t.gas,
-                gas_price: t.gas_price,
-                input: t.input,
-                nonce: t.nonce,
-                mempool_time: t.mempool_time,
-                contract_type: t.contract_type.as_str().to_string(),
-            })
-            .collect())
+        let transactions = state.store.list().await.map_err(|e| e.extend())?;
+
+        Ok(transactions.into_iter().map(Into::into).collect())
     }
 
     async fn get_transaction(
@@ -59,29 +106,40 @@ impl Query {
         id: String,
     ) -> async_graphql::Result<GraphQLTransaction> {
         let state = ctx.data::<Arc<AppState>>()?;
-        let transaction =
-            sqlx::query_as::<_, Transaction>("SELECT * FROM transaction WHERE id = $1")
-                .bind(uuid::Uuid::parse_str(&id)?)
-                .fetch_optional(&state.pool)
-                .await
-                .map_err(|e| async_graphql::Error::new(e.to_string()))?
-                .ok_or_else(|| async_graphql::Error::new("Transaction not found"))?;
-
-        Ok(GraphQLTransaction {
-            id: transaction.id.to_string(),
-            tx_hash: transaction.tx_hash,
-            block_hash: transaction.block_hash,
-            block_number: transaction.block_number,
-            from_sender: transaction.from_sender,
-            to_reciever: transaction.to_reciever,
-            tx_value: transaction.tx_value,
-            gas: transaction.gas,
-            gas_price: transaction.gas_price,
-            input: transaction.input,
-            nonce: transaction.nonce,
-            mempool_time: transaction.mempool_time,
-            contract_type: transaction.contract_type.as_str().to_string(),
-        })
+        let id = Uuid::parse_str(&id).map_err(|_| {
+            AppError::Validation(format!("Invalid transaction id: {}", id)).extend()
+        })?;
+        let transaction = state
+            .store
+            .get_by_id(id)
+            .await
+            .map_err(|e| e.extend())?
+            .ok_or_else(|| AppError::NotFound(format!("Transaction {} not found", id)).extend())?;
+
+        Ok(transaction.into())
+    }
+
+    async fn get_transaction_by_hash(
+        &self,
+        ctx: &Context<'_>,
+        chain: String,
+        tx_hash: String,
+    ) -> async_graphql::Result<GraphQLTransaction> {
+        let state = ctx.data::<Arc<AppState>>()?;
+        let chain_id = state
+            .chains
+            .resolve(&ChainRef(chain))
+            .map_err(|e| e.extend())?;
+        let transaction = state
+            .store
+            .get_by_hash(chain_id as i64, &tx_hash.to_lowercase())
+            .await
+            .map_err(|e| e.extend())?
+            .ok_or_else(|| {
+                AppError::NotFound(format!("Transaction {} not found", tx_hash)).extend()
+            })?;
+
+        Ok(transaction.into())
     }
 
     async fn filter_transactions(
@@ -90,74 +148,372 @@ impl Query {
         filter: TransactionFilter,
     ) -> async_graphql::Result<Vec<GraphQLTransaction>> {
         let state = ctx.data::<Arc<AppState>>()?;
+        let filter = filter
+            .resolve_chain(&state.chains)
+            .map_err(|e| e.extend())?;
+        let transactions = state.store.filter(&filter).await.map_err(|e| e.extend())?;
 
-        let mut query = String::from("SELECT * FROM transactions WHERE 1=1");
-        let mut bindings = vec![];
+        Ok(transactions.into_iter().map(Into::into).collect())
+    }
 
-        if let Some(min) = filter.gas_price_min {
-            query += " AND gas_price >= $1";
-            bindings.push(min.to_string());
-        }
+    /// Reads the native balance of `addresses` unless `native` is false, and their
+    /// balance of every token of `tokens`, in a few Multicall3 calls.
+    async fn balances(
+        &self,
+        ctx: &Context<'_>,
+        chain: String,
+        addresses: Vec<String>,
+        tokens: Option<Vec<String>>,
+        native: Option<bool>,
+    ) -> async_graphql::Result<Vec<Balance>> {
+        let state = ctx.data::<Arc<AppState>>()?;
+        let parse = |values: Vec<String>| {
+            values
+                .iter()
+                .map(|value| parse_address(value))
+                .collect::<Result<Vec<_>, _>>()
+        };
+        let request = BalancesRequest {
+            addresses: parse(addresses).map_err(|e| e.extend())?,
+            tokens: parse(tokens.unwrap_or_default()).map_err(|e| e.extend())?,
+            native: native.unwrap_or(true),
+        };
+        let chain_id = state
+            .chains
+            .resolve(&ChainRef(chain))
+            .map_err(|e| e.extend())?;
 
-        if let Some(max) = filter.gas_price_max {
-            query += &format!(" AND gas_price <= ${}", bindings.len() + 1);
-            bindings.push(max.to_string());
-        }
+        batch_balances(state, chain_id, &request)
+            .await
+            .map_err(|e| e.extend())
+    }
 
-        if let Some(contract_type) = filter.contract_type {
-            query += &format!(" AND contract_type = ${}", bindings.len() + 1);
-            bindings.push(contract_type);
-        }
+    /// Native and token holdings of `address`, with decimals applied.
+    async fn portfolio(
+        &self,
+        ctx: &Context<'_>,
+        chain: String,
+        address: String,
+    ) -> async_graphql::Result<Portfolio> {
+        let state = ctx.data::<Arc<AppState>>()?;
+        let chain_id = state
+            .chains
+            .resolve(&ChainRef(chain))
+            .map_err(|e| e.extend())?;
+        let address = parse_address(&address).map_err(|e| e.extend())?;
 
-        if let Some(min) = filter.block_number_min {
-            query += &format!(" AND block_number >= ${}", bindings.len() + 1);
-            bindings.push(min.to_string());
-        }
+        portfolio(state, chain_id, address)
+            .await
+            .map_err(|e| e.extend())
+    }
 
-        if let Some(max) = filter.block_number_max {
-            query += &format!(" AND block_number <= ${}", bindings.len() + 1);
-            bindings.push(max.to_string());
-        }
+    /// Nonce, balance, code and `slots` of `address`, at the latest block or `block`, a
+    /// number, hash or tag.
+    async fn account(
+        &self,
+        ctx: &Context<'_>,
+        chain: String,
+        address: String,
+        slots: Option<Vec<String>>,
+        block: Option<String>,
+    ) -> async_graphql::Result<Account> {
+        let state = ctx.data::<Arc<AppState>>()?;
+        let chain_id = state
+            .chains
+            .resolve(&ChainRef(chain))
+            .map_err(|e| e.extend())?;
+        let address = parse_address(&address).map_err(|e| e.extend())?;
+        let params = AccountParams {
+            slots: slots.map(|slots| slots.join(",")),
+            block,
+        };
 
-        if let Some(min) = filter.mempool_time_min {
-            query += &format!(" AND mempool_time >= ${}", bindings.len() + 1);
-            bindings.push(min.to_string());
-        }
+        account(state, chain_id, address, &params)
+            .await
+            .map_err(|e| e.extend())
+    }
 
-        if let Some(max) = filter.mempool_time_max {
-            query += &format!(" AND mempool_time <= ${}", bindings.len() + 1);
-            bindings.push(max.to_string());
-        }
+    /// Internal calls of the included transaction `tx_hash`, in call order.
+    async fn internal_calls(
+        &self,
+        ctx: &Context<'_>,
+        chain: String,
+        tx_hash: String,
+    ) -> async_graphql::Result<Vec<InternalCall>> {
+        let state = ctx.data::<Arc<AppState>>()?;
+        let chain_id = state
+            .chains
+            .resolve(&ChainRef(chain))
+            .map_err(|e| e.extend())?;
 
-        let mut db_query = sqlx::query_as::<_, Transaction>(&query);
-        for binding in bindings {
-            db_query = db_query.bind(binding);
-        }
+        state
+            .store
+            .internal_calls(chain_id as i64, &tx_hash)
+            .await
+            .map_err(|e| e.extend())
+    }
+
+    /// Internal calls made from or to `address`, ordered by block.
+    async fn address_internal_calls(
+        &self,
+        ctx: &Context<'_>,
+        chain: String,
+        address: String,
+    ) -> async_graphql::Result<Vec<InternalCall>> {
+        let state = ctx.data::<Arc<AppState>>()?;
+        let chain_id = state
+            .chains
+            .resolve(&ChainRef(chain))
+            .map_err(|e| e.extend())?;
+        let address = parse_address(&address).map_err(|e| e.extend())?;
+
+        state
+            .store
+            .address_internal_calls(chain_id as i64, &address.to_string())
+            .await
+            .map_err(|e| e.extend())
+    }
+
+    /// Replays the pending transaction `tx_hash` against the latest state and stores the
+    /// prediction.
+    async fn simulate(
+        &self,
+        ctx: &Context<'_>,
+        chain: String,
+        tx_hash: String,
+    ) -> async_graphql::Result<Simulation> {
+        let state = ctx.data::<Arc<AppState>>()?;
+        let chain_id = state
+            .chains
+            .resolve(&ChainRef(chain))
+            .map_err(|e| e.extend())?;
+        let tx_hash = tx_hash.parse::<TxHash>().map_err(|_| {
+            AppError::Validation(format!("Invalid transaction hash: {}", tx_hash)).extend()
+        })?;
+
+        simulate_and_store(state, chain_id, tx_hash, Priority::Interactive)
+            .await
+            .map_err(|e| e.extend())
+    }
+
+    /// Token standards `contract` implements, detected through ERC-165.
+    async fn nft_interfaces(
+        &self,
+        ctx: &Context<'_>,
+        chain: String,
+        contract: String,
+    ) -> async_graphql::Result<Interfaces> {
+        let state = ctx.data::<Arc<AppState>>()?;
+        let (chain_id, contract) = nft_target(state, chain, &contract)?;
+
+        detect_interfaces(state, chain_id, contract)
+            .await
+            .map_err(|e| e.extend())
+    }
+
+    /// Owner of an ERC-721 token.
+    async fn erc721_owner(
+        &self,
+        ctx: &Context<'_>,
+        chain: String,
+        contract: String,
+        token_id: String,
+    ) -> async_graphql::Result<String> {
+        let state = ctx.data::<Arc<AppState>>()?;
+        let (chain_id, contract) = nft_target(state, chain, &contract)?;
+        let token_id = parse_token_id(&token_id).map_err(|e| e.extend())?;
+
+        erc721_owner(state, chain_id, contract, token_id)
+            .await
+            .map(|owner| owner.to_string())
+            .map_err(|e| e.extend())
+    }
+
+    /// Number of tokens of an ERC-721 contract held by `owner`, as a decimal string.
+    async fn erc721_balance(
+        &self,
+        ctx: &Context<'_>,
+        chain: String,
+        contract: String,
+        owner: String,
+    ) -> async_graphql::Result<String> {
+        let state = ctx.data::<Arc<AppState>>()?;
+        let (chain_id, contract) = nft_target(state, chain, &contract)?;
+        let owner = parse_address(&owner).map_err(|e| e.extend())?;
+
+        erc721_balance(state, chain_id, contract, owner)
+            .await
+            .map(|balance| balance.to_string())
+            .map_err(|e| e.extend())
+    }
+
+    /// Metadata URI of an ERC-721 token.
+    async fn erc721_token_uri(
+        &self,
+        ctx: &Context<'_>,
+        chain: String,
+        contract: String,
+        token_id: String,
+    ) -> async_graphql::Result<String> {
+        let state = ctx.data::<Arc<AppState>>()?;
+        let (chain_id, contract) = nft_target(state, chain, &contract)?;
+        let token_id = parse_token_id(&token_id).map_err(|e| e.extend())?;
 
-        let transactions = db_query
-            .fetch_all(&state.pool)
-            .await
-            .map_err(|e| async_graphql::Error::new(e.to_string()))?;
-
-        Ok(transactions
-            .into_iter()
-            .map(|t| GraphQLTransaction {
-                id: t.id.to_string(),
-                tx_hash: t.tx_hash,
-                block_hash: t.block_hash,
-                block_number: t.block_number,
-                from_sender: t.from_sender,
-                to_reciever: t.to_reciever,
-                tx_value: t.tx_value,
-                gas: t.gas,
-                gas_price: t.gas_price,
-                input: t.input,
-                nonce: t.nonce,
-                mempool_time: t.mempool_time,
-                contract_type: t.contract_type.as_str().to_string(),
-            })
-            .collect())
+        erc721_token_uri(state, chain_id, contract, token_id)
+            .await
+            .map_err(|e| e.extend())
     }
+
+    /// Balance of `account` in an ERC-1155 token id, as a decimal string.
+    async fn erc1155_balance(
+        &self,
+        ctx: &Context<'_>,
+        chain: String,
+        contract: String,
+        account: String,
+        token_id: String,
+    ) -> async_graphql::Result<String> {
+        let state = ctx.data::<Arc<AppState>>()?;
+        let (chain_id, contract) = nft_target(state, chain, &contract)?;
+        let account = parse_address(&account).map_err(|e| e.extend())?;
+        let token_id = parse_token_id(&token_id).map_err(|e| e.extend())?;
+
+        erc1155_balance(state, chain_id, contract, account, token_id)
+            .await
+            .map(|balance| balance.to_string())
+            .map_err(|e| e.extend())
+    }
+
+    /// Balances of each account in the token id at the same position, read with one
+    /// ERC-1155 `balanceOfBatch` call.
+    async fn erc1155_balances(
+        &self,
+        ctx: &Context<'_>,
+        chain: String,
+        contract: String,
+        accounts: Vec<String>,
+        token_ids: Vec<String>,
+    ) -> async_graphql::Result<Vec<String>> {
+        let state = ctx.data::<Arc<AppState>>()?;
+        let (chain_id, contract) = nft_target(state, chain, &contract)?;
+        let accounts = accounts
+            .iter()
+            .map(|account| parse_address(account))
+            .collect::<Result<_, _>>()
+            .map_err(|e| e.extend())?;
+        let token_ids = token_ids
+            .iter()
+            .map(|id| parse_token_id(id))
+            .collect::<Result<_, _>>()
+            .map_err(|e| e.extend())?;
+
+        erc1155_balance_batch(state, chain_id, contract, accounts, token_ids)
+            .await
+            .map(|balances| balances.iter().map(|balance| balance.to_string()).collect())
+            .map_err(|e| e.extend())
+    }
+
+    /// Snapshotted balance series of a watched address, optionally of one `token` and
+    /// between unix timestamps, flagging changes of at least `delta_percent`.
+    async fn balance_history(
+        &self,
+        ctx: &Context<'_>,
+        chain: String,
+        address: String,
+        token: Option<String>,
+        from: Option<i64>,
+        to: Option<i64>,
+        delta_percent: Option<u64>,
+    ) -> async_graphql::Result<Vec<BalanceSeries>> {
+        let state = ctx.data::<Arc<AppState>>()?;
+        let chain_id = state
+            .chains
+            .resolve(&ChainRef(chain))
+            .map_err(|e| e.extend())?;
+        let address = parse_address(&address).map_err(|e| e.extend())?;
+        let token = token
+            .as_deref()
+            .map(parse_address)
+            .transpose()
+            .map_err(|e| e.extend())?;
+        let params = HistoryParams {
+            token,
+            from,
+            to,
+            delta_percent,
+        };
+
+        balance_history(state, chain_id, address, &params)
+            .await
+            .map_err(|e| e.extend())
+    }
+
+    /// Recommends a fee for inclusion within `target_seconds`, based on observed mempool data.
+    async fn gas_estimate(
+        &self,
+        ctx: &Context<'_>,
+        chain: String,
+        target_seconds: i64,
+        window_seconds: Option<i64>,
+    ) -> async_graphql::Result<GasEstimate> {
+        let state = ctx.data::<Arc<AppState>>()?;
+        let chain_id = state
+            .chains
+            .resolve(&ChainRef(chain))
+            .map_err(|e| e.extend())?;
+        let estimate = estimate_gas(
+            state.store.as_ref(),
+            chain_id,
+            target_seconds,
+            window_seconds.unwrap_or(DEFAULT_WINDOW_SECONDS),
+        )
+        .await
+        .map_err(|e| e.extend())?;
+
+        Ok(estimate)
+    }
+
+    /// Aggregates per minute, hour or day. `from` and `to` are unix timestamps in seconds.
+    async fn transaction_stats(
+        &self,
+        ctx: &Context<'_>,
+        granularity: Granularity,
+        from: Option<i64>,
+        to: Option<i64>,
+        filter: Option<TransactionFilter>,
+    ) -> async_graphql::Result<Vec<StatsBucket>> {
+        let state = ctx.data::<Arc<AppState>>()?;
+        let params = StatsParams {
+            granularity,
+            from,
+            to,
+        };
+        let filter = filter
+            .unwrap_or_default()
+            .resolve_chain(&state.chains)
+            .map_err(|e| e.extend())?;
+        let buckets = state
+            .store
+            .stats(&params, &filter)
+            .await
+            .map_err(|e| e.extend())?;
+
+        Ok(buckets)
+    }
+}
+
+/// Chain and contract an NFT query targets.
+fn nft_target(
+    state: &AppState,
+    chain: String,
+    contract: &str,
+) -> async_graphql::Result<(ChainId, Address)> {
+    let chain_id = state
+        .chains
+        .resolve(&ChainRef(chain))
+        .map_err(|e| e.extend())?;
+    let contract = parse_address(contract).map_err(|e| e.extend())?;
+    Ok((chain_id, contract))
 }
 
 pub type AppSchema = Schema<Query, EmptyMutation, EmptySubscription>;
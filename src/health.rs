@@ -0,0 +1,190 @@
+//! Liveness and readiness of the process, including the state of every scanned chain.
+
+use crate::model::AppState;
+use alloy::primitives::ChainId;
+use axum::{extract::State, http::StatusCode, Json};
+use serde::Serialize;
+use std::{
+    collections::BTreeMap,
+    sync::{Arc, RwLock},
+    time::{Duration, Instant},
+};
+use tokio::time::timeout;
+
+/// Longest the database may take to answer a readiness probe.
+const DB_PING_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// What a chain's scanner last reported.
+#[derive(Debug, Clone, Default)]
+pub struct ChainHealth {
+    pub connected: bool,
+    pub subscribed: bool,
+    pub connected_at: Option<Instant>,
+    pub last_notification_at: Option<Instant>,
+    pub pending_transactions: usize,
+    pub last_error: Option<String>,
+}
+
+#[derive(Serialize, Debug, PartialEq)]
+pub struct ChainStatus {
+    pub chain_id: ChainId,
+    pub connected: bool,
+    pub subscribed: bool,
+    pub seconds_since_last_notification: Option<u64>,
+    pub pending_transactions: usize,
+    pub last_error: Option<String>,
+    pub ready: bool,
+}
+
+impl ChainHealth {
+    /// A chain is ready while it is subscribed and has not been silent for longer than
+    /// `max_silence`, counting from the connection until the first notification.
+    pub fn status(&self, chain_id: ChainId, now: Instant, max_silence: Duration) -> ChainStatus {
+        let silence = self
+            .last_notification_at
+            .or(self.connected_at)
+            .map(|since| now.saturating_duration_since(since));
+        let ready = self.connected
+            && self.subscribed
+            && silence.map_or(false, |silence| silence <= max_silence);
+
+        ChainStatus {
+            chain_id,
+            connected: self.connected,
+            subscribed: self.subscribed,
+            seconds_since_last_notification: self
+                .last_notification_at
+                .map(|since| now.saturating_duration_since(since).as_secs()),
+            pending_transactions: self.pending_transactions,
+            last_error: self.last_error.clone(),
+            ready,
+        }
+    }
+}
+
+pub struct Health {
+    chains: RwLock<BTreeMap<ChainId, ChainHealth>>,
+    max_silence: Duration,
+}
+
+impl Health {
+    pub fn new(max_silence: Duration) -> Self {
+        Self {
+            chains: RwLock::new(BTreeMap::new()),
+            max_silence,
+        }
+    }
+
+    /// Starts reporting on a chain, before its scanner first connects.
+    pub fn register(&self, chain_id: ChainId) {
+        if let Ok(mut chains) = self.chains.write() {
+            chains.entry(chain_id).or_default();
+        }
+    }
+
+    pub fn update(&self, chain_id: ChainId, update: impl FnOnce(&mut ChainHealth)) {
+        if let Ok(mut chains) = self.chains.write() {
+            update(chains.entry(chain_id).or_default());
+        }
+    }
+
+    pub fn statuses(&self) -> Vec<ChainStatus> {
+        let now = Instant::now();
+        match self.chains.read() {
+            Ok(chains) => chains
+                .iter()
+                .map(|(chain_id, health)| health.status(*chain_id, now, self.max_silence))
+                .collect(),
+            Err(_) => Vec::new(),
+        }
+    }
+}
+
+#[derive(Serialize, Debug)]
+pub struct HealthReport {
+    pub ready: bool,
+    pub database: bool,
+    pub chains: Vec<ChainStatus>,
+}
+
+async fn report(state: &AppState) -> HealthReport {
+    let database = matches!(
+        timeout(DB_PING_TIMEOUT, state.store.ping()).await,
+        Ok(Ok(_))
+    );
+    let chains = state.health.statuses();
+    let ready = database && chains.iter().all(|chain| chain.ready);
+
+    HealthReport {
+        ready,
+        database,
+        chains,
+    }
+}
+
+/// Liveness: answers as long as the process does, with the full report.
+pub async fn healthz(State(state): State<Arc<AppState>>) -> Json<HealthReport> {
+    Json(report(&state).await)
+}
+
+/// Readiness: fails when the database is unreachable or a chain is disconnected
+/// or has been silent for too long.
+pub async fn readyz(State(state): State<Arc<AppState>>) -> (StatusCode, Json<HealthReport>) {
+    let report = report(&state).await;
+    let status = if report.ready {
+        StatusCode::OK
+    } else {
+        StatusCode::SERVICE_UNAVAILABLE
+    };
+    (status, Json(report))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn chains_are_ready_while_subscribed_and_recently_notified() {
+        let now = Instant::now();
+        let max_silence = Duration::from_secs(60);
+        let subscribed = ChainHealth {
+            connected: true,
+            subscribed: true,
+            connected_at: Some(now - Duration::from_secs(300)),
+            last_notification_at: Some(now - Duration::from_secs(5)),
+            ..Default::default()
+        };
+        let status = subscribed.status(1, now, max_silence);
+        assert!(status.ready);
+        assert_eq!(status.seconds_since_last_notification, Some(5));
+
+        // Silent for longer than allowed
+        let silent = ChainHealth {
+            last_notification_at: Some(now - Duration::from_secs(120)),
+            ..subscribed.clone()
+        };
+        assert!(!silent.status(1, now, max_silence).ready);
+
+        // Freshly connected, no notification yet
+        let fresh = ChainHealth {
+            connected_at: Some(now - Duration::from_secs(10)),
+            last_notification_at: None,
+            ..subscribed.clone()
+        };
+        assert!(fresh.status(1, now, max_silence).ready);
+
+        // Stuck reconnecting
+        let disconnected = ChainHealth {
+            connected: false,
+            subscribed: false,
+            last_error: Some("WebSocket closed".into()),
+            ..subscribed
+        };
+        let status = disconnected.status(1, now, max_silence);
+        assert!(!status.ready);
+        assert_eq!(status.last_error.as_deref(), Some("WebSocket closed"));
+
+        // Registered but never connected
+        assert!(!ChainHealth::default().status(1, now, max_silence).ready);
+    }
+}
@@ -0,0 +1,163 @@
+//! Resolves the block a historical query is answered at, given as a block number, hash,
+//! tag or unix timestamp.
+//!
+//! Timestamps are resolved to the last block mined at or before them, by binary search
+//! over block headers. Headers come through the RPC cache, so repeated searches on the
+//! same chain mostly hit finalized blocks already cached.
+
+use crate::{
+    model::{AppError, AppState},
+    rpc_cache::cached_block,
+    rpc_queries::get_block_number_query,
+};
+use alloy::{
+    primitives::{ChainId, B256},
+    rpc::types::eth::{BlockId, BlockNumberOrTag},
+};
+use serde::Deserialize;
+use std::{future::Future, str::FromStr};
+
+/// Query parameters picking the block a query is answered at. Without either, the
+/// latest block is used.
+#[derive(Deserialize, Debug, Default)]
+pub struct AtBlock {
+    /// Block number, hash or tag, e.g. `19000000`, `0x…` or `finalized`
+    pub block: Option<String>,
+    /// Unix timestamp in seconds
+    pub timestamp: Option<u64>,
+}
+
+/// Parses a decimal or hex block number, a block hash, or a tag such as `safe`.
+pub fn parse_block_id(value: &str) -> Result<BlockId, AppError> {
+    let value = value.trim();
+    let invalid = || AppError::Validation(format!("Invalid block: {}", value));
+
+    if let Ok(number) = value.parse::<u64>() {
+        return Ok(BlockId::number(number));
+    }
+    if value.len() == 66 {
+        return B256::from_str(value)
+            .map(BlockId::from)
+            .map_err(|_| invalid());
+    }
+    BlockNumberOrTag::from_str(&value.to_lowercase())
+        .map(BlockId::Number)
+        .map_err(|_| invalid())
+}
+
+/// Block the query should be answered at.
+pub async fn resolve_block(
+    state: &AppState,
+    chain_id: ChainId,
+    at: &AtBlock,
+) -> Result<BlockId, AppError> {
+    match (&at.block, at.timestamp) {
+        (Some(_), Some(_)) => Err(AppError::Validation(
+            "Give either a block or a timestamp, not both".to_string(),
+        )),
+        (Some(block), None) => parse_block_id(block),
+        (None, Some(timestamp)) => Ok(BlockId::number(
+            block_at_timestamp(state, chain_id, timestamp).await?,
+        )),
+        (None, None) => Ok(BlockId::latest()),
+    }
+}
+
+/// Number of the last block of `chain_id` mined at or before `timestamp`.
+pub async fn block_at_timestamp(
+    state: &AppState,
+    chain_id: ChainId,
+    timestamp: u64,
+) -> Result<u64, AppError> {
+    let timestamp_of = |number: u64| async move {
+        cached_block(state, chain_id, BlockId::number(number))
+            .await?
+            .map(|block| block.header.timestamp)
+            .ok_or_else(|| AppError::NotFound(format!("Block {} not found", number)))
+    };
+
+    let head = state
+        .providers
+        .call(
+            &state.metrics,
+            chain_id,
+            "eth_blockNumber",
+            get_block_number_query,
+        )
+        .await?;
+    if timestamp_of(head).await? <= timestamp {
+        return Ok(head);
+    }
+    if timestamp_of(0).await? > timestamp {
+        return Err(AppError::Validation(format!(
+            "Timestamp {} is before the first block of chain {}",
+            timestamp, chain_id
+        )));
+    }
+
+    search_block(0, head, timestamp, timestamp_of).await
+}
+
+/// Highest block of `low..=high` whose timestamp is at most `target`, given that the
+/// timestamp of `low` is.
+async fn search_block<F, Fut>(
+    mut low: u64,
+    mut high: u64,
+    target: u64,
+    timestamp_of: F,
+) -> Result<u64, AppError>
+where
+    F: Fn(u64) -> Fut,
+    Fut: Future<Output = Result<u64, AppError>>,
+{
+    while low < high {
+        let middle = low + (high - low + 1) / 2;
+        if timestamp_of(middle).await? <= target {
+            low = middle;
+        } else {
+            high = middle - 1;
+        }
+    }
+    Ok(low)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_numbers_hashes_and_tags() {
+        assert_eq!(
+            parse_block_id("19000000").unwrap(),
+            BlockId::number(19_000_000)
+        );
+        assert_eq!(parse_block_id("0x10").unwrap(), BlockId::number(16));
+        assert_eq!(
+            parse_block_id("Finalized").unwrap(),
+            BlockId::Number(BlockNumberOrTag::Finalized)
+        );
+        assert_eq!(
+            parse_block_id(&format!("0x{}", "ab".repeat(32))).unwrap(),
+            BlockId::from(B256::repeat_byte(0xab))
+        );
+        assert!(matches!(
+            parse_block_id("yesterday"),
+            Err(AppError::Validation(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn finds_the_last_block_at_or_before_a_timestamp() {
+        // Irregular block times, with two blocks sharing a timestamp
+        let timestamps = [100, 112, 124, 124, 160, 172, 190];
+        let timestamp_of = |number: u64| async move { Ok(timestamps[number as usize]) };
+        let search = |target| search_block(0, 6, target, timestamp_of);
+
+        assert_eq!(search(100).await.unwrap(), 0);
+        assert_eq!(search(111).await.unwrap(), 0);
+        assert_eq!(search(124).await.unwrap(), 3);
+        assert_eq!(search(159).await.unwrap(), 3);
+        assert_eq!(search(172).await.unwrap(), 5);
+        assert_eq!(search(500).await.unwrap(), 6);
+    }
+}
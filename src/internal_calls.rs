@@ -0,0 +1,284 @@
+//! Calls contracts make while executing an included transaction, read from call traces.
+//!
+//! `tx_value` only covers the top-level call, so the native currency contracts move is
+//! captured by tracing the transaction with the call tracer and storing each nested call
+//! frame. Only transactions to a contract, or creating one, can make internal calls.
+
+use crate::{
+    model::{AppError, AppState},
+    rate_limit::Priority,
+    rpc_queries::trace_transaction_query,
+};
+use alloy::primitives::{ChainId, TxHash, U256};
+use async_graphql::SimpleObject;
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use sqlx::FromRow;
+use std::{str::FromStr, sync::Arc};
+
+#[derive(Serialize, Deserialize, FromRow, SimpleObject, Debug, Clone, PartialEq)]
+pub struct InternalCall {
+    pub chain_id: i64,
+    pub tx_hash: String,
+    pub block_number: i64,
+    /// Order of the call within its transaction, depth first from 0
+    pub position: i64,
+    /// 1 for the calls the top-level call makes, 2 for the calls they make, and so on
+    pub depth: i64,
+    /// `CALL`, `DELEGATECALL`, `STATICCALL`, `CREATE`, `SELFDESTRUCT`, ...
+    pub call_type: String,
+    pub from_address: String,
+    /// Created contract for creations, beneficiary for self-destructs
+    pub to_address: String,
+    /// In wei, as a decimal string
+    pub value: String,
+    /// Why the call, or one of the calls it is nested in, failed, in which case its value
+    /// was not moved
+    pub error: Option<String>,
+}
+
+/// Nested calls of a `callTracer` frame, depth first. The top-level call itself is left
+/// out, it is the transaction.
+pub fn flatten(
+    chain_id: i64,
+    tx_hash: &str,
+    block_number: i64,
+    frame: &Value,
+) -> Vec<InternalCall> {
+    let mut calls = Vec::new();
+    push_calls(
+        &mut calls,
+        &InternalCall {
+            chain_id,
+            tx_hash: tx_hash.to_lowercase(),
+            block_number,
+            position: 0,
+            depth: 0,
+            call_type: String::new(),
+            from_address: String::new(),
+            to_address: String::new(),
+            value: String::new(),
+            // A reverted transaction reverts every call it made
+            error: frame["error"].as_str().map(str::to_string),
+        },
+        frame,
+    );
+    calls
+}
+
+fn push_calls(calls: &mut Vec<InternalCall>, parent: &InternalCall, frame: &Value) {
+    let Some(children) = frame["calls"].as_array() else {
+        return;
+    };
+    for child in children {
+        let call = InternalCall {
+            position: calls.len() as i64,
+            depth: parent.depth + 1,
+            call_type: child["type"].as_str().unwrap_or_default().to_uppercase(),
+            from_address: address(&child["from"]),
+            to_address: address(&child["to"]),
+            value: child["value"]
+                .as_str()
+                .and_then(|value| U256::from_str(value).ok())
+                .unwrap_or_default()
+                .to_string(),
+            error: child["error"]
+                .as_str()
+                .map(str::to_string)
+                .or_else(|| parent.error.clone()),
+            ..parent.clone()
+        };
+        calls.push(call.clone());
+        push_calls(calls, &call, child);
+    }
+}
+
+fn address(value: &Value) -> String {
+    value.as_str().unwrap_or_default().to_lowercase()
+}
+
+/// Traces the included transaction `tx_hash` and stores its internal calls. Returns how
+/// many were stored.
+pub async fn capture(
+    state: &AppState,
+    chain_id: ChainId,
+    tx_hash: TxHash,
+    block_number: i64,
+    priority: Priority,
+) -> Result<u64, AppError> {
+    let frame = state
+        .providers
+        .call_with_priority(
+            &state.metrics,
+            chain_id,
+            "debug_traceTransaction",
+            priority,
+            |provider| {
+                trace_transaction_query(provider, tx_hash, json!({ "tracer": "callTracer" }))
+            },
+        )
+        .await?;
+
+    let calls = flatten(chain_id as i64, &tx_hash.to_string(), block_number, &frame);
+    let stored = state.store.save_internal_calls(&calls).await?;
+    state
+        .metrics
+        .internal_calls
+        .with_label_values(&[&chain_id.to_string()])
+        .inc_by(stored);
+    Ok(stored)
+}
+
+/// Captures the internal calls of a transaction the scanner saw included in the
+/// background, so the scan does not wait for the trace.
+pub fn enrich(state: Arc<AppState>, chain_id: ChainId, tx_hash: &str, block_number: i64) {
+    let Ok(tx_hash) = TxHash::from_str(tx_hash) else {
+        return;
+    };
+    tokio::spawn(async move {
+        match capture(
+            &state,
+            chain_id,
+            tx_hash,
+            block_number,
+            Priority::Background,
+        )
+        .await
+        {
+            Ok(stored) => info!(
+                "Stored {} internal calls of transaction {} on chain {}",
+                stored, tx_hash, chain_id
+            ),
+            Err(e) => {
+                warn!(
+                    "Error tracing transaction {} on chain {}: {:?}",
+                    tx_hash, chain_id, e
+                );
+                state
+                    .metrics
+                    .internal_call_failures
+                    .with_label_values(&[&chain_id.to_string()])
+                    .inc();
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn flattens_nested_calls_depth_first() {
+        let frame = json!({
+            "type": "CALL",
+            "from": "0xSender",
+            "to": "0xRouter",
+            "value": "0x0",
+            "calls": [
+                {
+                    "type": "DELEGATECALL",
+                    "from": "0xRouter",
+                    "to": "0xLibrary",
+                    "calls": [
+                        { "type": "CALL", "from": "0xRouter", "to": "0xRecipient", "value": "0xde0b6b3a7640000" }
+                    ]
+                },
+                {
+                    "type": "CALL",
+                    "from": "0xRouter",
+                    "to": "0xVault",
+                    "value": "0x1",
+                    "error": "execution reverted"
+                },
+                { "type": "create", "from": "0xRouter", "to": "0xCreated", "value": "0x0" }
+            ]
+        });
+
+        let calls = flatten(1, "0xABC", 42, &frame);
+
+        let summary: Vec<_> = calls
+            .iter()
+            .map(|call| {
+                (
+                    call.position,
+                    call.depth,
+                    call.call_type.as_str(),
+                    call.to_address.as_str(),
+                )
+            })
+            .collect();
+        assert_eq!(
+            summary,
+            [
+                (0, 1, "DELEGATECALL", "0xlibrary"),
+                (1, 2, "CALL", "0xrecipient"),
+                (2, 1, "CALL", "0xvault"),
+                (3, 1, "CREATE", "0xcreated"),
+            ]
+        );
+
+        assert_eq!(calls[0].value, "0");
+        assert_eq!(calls[1].value, "1000000000000000000");
+        assert_eq!(calls[1].from_address, "0xrouter");
+        assert_eq!(calls[1].tx_hash, "0xabc");
+        assert_eq!(calls[1].block_number, 42);
+        assert_eq!(calls[2].error.as_deref(), Some("execution reverted"));
+    }
+
+    #[test]
+    fn calls_nested_in_a_failed_call_failed_too() {
+        let frame = json!({
+            "type": "CALL",
+            "from": "0xSender",
+            "to": "0xRouter",
+            "calls": [
+                {
+                    "type": "CALL",
+                    "from": "0xRouter",
+                    "to": "0xVault",
+                    "error": "execution reverted",
+                    "calls": [
+                        {
+                            "type": "CALL",
+                            "from": "0xVault",
+                            "to": "0xToken",
+                            "calls": [{ "type": "CALL", "from": "0xToken", "to": "0xRecipient", "value": "0x1" }]
+                        },
+                        { "type": "CALL", "from": "0xVault", "to": "0xOracle", "error": "out of gas" }
+                    ]
+                },
+                { "type": "CALL", "from": "0xRouter", "to": "0xFeeCollector", "value": "0x1" }
+            ]
+        });
+
+        let errors: Vec<_> = flatten(1, "0xabc", 42, &frame)
+            .into_iter()
+            .map(|call| call.error)
+            .collect();
+        let reverted = Some("execution reverted".to_string());
+        assert_eq!(
+            errors,
+            [
+                reverted.clone(),
+                reverted.clone(),
+                reverted,
+                Some("out of gas".to_string()),
+                None,
+            ]
+        );
+
+        let failed_transaction =
+            json!({ "error": "execution reverted", "calls": [{ "type": "CALL" }] });
+        assert!(flatten(1, "0xabc", 42, &failed_transaction)[0]
+            .error
+            .is_some());
+    }
+
+    #[test]
+    fn transactions_without_nested_calls_have_none() {
+        let frame = json!({ "type": "CALL", "from": "0x1", "to": "0x2", "value": "0x5" });
+        assert!(flatten(1, "0xabc", 42, &frame).is_empty());
+    }
+}
@@ -1,7 +1,32 @@
+pub mod account;
+pub mod analytics;
+pub mod backfill;
+pub mod balances;
+pub mod chains;
+pub mod cli;
 pub mod connection;
+pub mod export;
+pub mod extract;
 pub mod graphql;
+pub mod health;
+pub mod historical;
+pub mod internal_calls;
 pub mod mempool;
+pub mod metrics;
 pub mod model;
+pub mod nft;
+pub mod portfolio;
+pub mod providers;
+pub mod rate_limit;
+pub mod request_id;
+pub mod retention;
+pub mod rpc_cache;
 pub mod rpc_queries;
+pub mod server;
 pub mod service;
+pub mod shutdown;
+pub mod simulation;
+pub mod snapshots;
+pub mod store;
 pub mod utils;
+pub mod writer;
@@ -1,133 +1,261 @@
-use async_graphql::http::{playground_source, GraphQLPlaygroundConfig};
-use async_graphql_axum::{GraphQLRequest, GraphQLResponse};
-use axum::{
-    http::Method, response::IntoResponse, routing::{get, post}, serve, Extension, Router
-};
+use alloy::primitives::ChainId;
+use clap::Parser;
 use dotenv::dotenv;
 use log::error;
 use sentinel::{
+    analytics::rollup::run_rollups,
+    backfill::backfill,
+    cli::{Cli, Command},
     connection::load_config,
-    graphql::schema::{create_schema, AppSchema},
-    mempool::mempool::scan_mempool,
-    model::{AppError, AppState},
-    service::{
-        create_transaction, filter_transactions, get_block, get_erc20_balance, get_native_balance,
-        get_transaction, get_transaction_by_id, get_transactions,
-    },
+    export::export_transactions,
+    mempool::supervisor::supervise,
+    model::{AppError, AppState, Config},
+    providers::run_health_checks,
+    retention::run_retention,
+    server::{serve, serve_admin},
+    shutdown::{drain, shutdown_signal},
+    snapshots::run_snapshots,
+    store::connect_store,
+    utils::{RotatingCsvWriter, SharedCsvWriter, CSV_PATH, RESPONSES_DIR},
+    writer::BatchWriter,
+};
+use std::{
+    error::Error,
+    path::PathBuf,
+    sync::{Arc, Mutex},
 };
-use sqlx::postgres::PgPoolOptions;
-use std::{error::Error, sync::Arc};
 use tokio::{
     fs::{self},
-    net::TcpListener,
-    signal, task,
-    time::Duration,
+    task,
+    time::{timeout_at, Instant},
 };
-use tower_http::cors::{Any, CorsLayer};
-
-#[axum::debug_handler]
-async fn graphql_handler(schema: Extension<AppSchema>, req: GraphQLRequest) -> GraphQLResponse {
-    schema.execute(req.into_inner()).await.into()
-}
-
-async fn graphql_playground() -> impl IntoResponse {
-    axum::response::Html(playground_source(GraphQLPlaygroundConfig::new("/graphql")))
-}
+use tokio_util::sync::CancellationToken;
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn Error>> {
     dotenv().ok();
 
-    // CORS configuration allowing any origin, method, headers
-    let cors = CorsLayer::new()
-        .allow_origin(Any)
-        .allow_methods([Method::GET, Method::POST])
-        .allow_headers(Any);
+    let cli = Cli::parse();
 
     // Config
     let config = load_config()?;
 
-    // initialize the database connection pool
-    let pool = PgPoolOptions::new()
-        .max_connections(5)
-        .connect(&config.db_url)
-        .await
-        .expect("Error connecting to Postgres");
-
-    sqlx::migrate!("./migrations").run(&pool).await?;
-
-    let app_state = Arc::new(AppState { pool });
-
-    let schema = create_schema(app_state.clone());
-
-    let app = Router::new()
-        .route(
-            "/",
-            get(|| async { "Sentinel! A blockchain indexing tool." }),
-        )
-        .route("/transactions", get(get_transactions))
-        .route("/transactions", post(create_transaction))
-        .route("/transactions/:id", get(get_transaction_by_id))
-        .route("/transactions/filter", get(filter_transactions))
-        .route("/get-block/:chainid/:block_number", get(get_block))
-        .route(
-            "/get-transaction/:chainid/:block_number/:transaction_hash",
-            get(get_transaction),
-        )
-        .route(
-            "/get-native-balance/:chainid/:address",
-            get(get_native_balance),
-        )
-        .route(
-            "/get-erc20-balance/:chainid/:contract_address/:address",
-            get(get_erc20_balance),
-        )
-        .route("/graphql", get(graphql_playground).post(graphql_handler))
-        .layer(Extension(schema))
-        .layer(cors)
-        .with_state(app_state.clone());
-
-    let listener = TcpListener::bind(&config.server_url).await.unwrap();
-    println!("listening on {}", listener.local_addr().unwrap());
-
-    // Spawn the web server on a separate task
-    let server_task = task::spawn(async move {
-        // Create the server for spawning the task
-        serve(listener, app).await.unwrap();
-    });
+    match cli.command {
+        Command::Serve => {
+            let app_state = app_state(&config).await?;
+            let shutdown = CancellationToken::new();
 
-    println!("Web server started!");
+            // Keep the aggregates behind /transactions/stats fresh
+            let rollup_task = task::spawn(run_rollups(app_state.store.clone(), shutdown.clone()));
+            // Keep track of which RPC endpoints are answering and up to date
+            let health_check_task =
+                task::spawn(run_health_checks(app_state.clone(), shutdown.clone()));
 
-    // Ensure the responses directory exists
-    fs::create_dir_all("responses").await?;
+            println!("Web server started!");
+            let server = serve(&config, app_state.clone(), shutdown.clone());
+            tokio::pin!(server);
+            tokio::select! {
+                result = &mut server => result?,
+                _ = shutdown_signal() => {
+                    println!("Shutdown signal received, draining in-flight requests...");
+                    shutdown.cancel();
+                    let deadline = Instant::now() + config.shutdown_timeout;
+                    match timeout_at(deadline, &mut server).await {
+                        Ok(result) => result?,
+                        Err(_) => println!("Shutdown deadline reached, dropping remaining requests"),
+                    }
+                    drain(vec![rollup_task, health_check_task], deadline).await;
+                }
+            }
+            app_state.store.close().await;
+        }
+        Command::Scan { chains } => {
+            let chains = if chains.is_empty() {
+                config.scan_chain_ids.clone()
+            } else {
+                chains
+                    .iter()
+                    .map(|chain| config.chains.resolve(chain))
+                    .collect::<Result<_, _>>()?
+            };
+            let app_state = app_state(&config).await?;
+            scan(&config, app_state, chains).await?;
+        }
+        Command::Backfill { chain, from, to } => {
+            let chain = config.chains.resolve(&chain)?;
+            let app_state = app_state(&config).await?;
+            let stored = backfill(&app_state, chain, from, to).await?;
+            println!(
+                "Backfilled {} transactions from blocks {} to {}",
+                stored, from, to
+            );
+        }
+        Command::Export {
+            format,
+            output,
+            chain,
+        } => {
+            let output = output.unwrap_or_else(|| {
+                PathBuf::from(format!("transactions_export.{}", format.extension()))
+            });
+            let chain = chain
+                .map(|chain| config.chains.resolve(&chain))
+                .transpose()?;
+            let store = connect_store(&config.db_url).await?;
+            let exported = export_transactions(store.as_ref(), format, chain, &output).await?;
+            println!("Exported {} transactions to {}", exported, output.display());
+        }
+        Command::Migrate => {
+            let store = connect_store(&config.db_url).await?;
+            store.migrate().await?;
+            println!("Migrations applied");
+        }
+        Command::CheckConfig => check_config(&config).await?,
+    }
 
-    let mempool_config = config;
+    Ok(())
+}
 
-    let mempool_task = task::spawn(async move {
-        loop {
-            let result: Result<(), AppError> = async {
-                scan_mempool(&mempool_config, &app_state).await?;
-                Ok(())
-            }
-            .await;
-            if let Err(e) = result {
-                error!("Error occurred: {:?}", e);
-                error!("Reconnecting in 5 seconds...");
-                tokio::time::sleep(Duration::from_secs(5)).await;
-            }
+/// Connects to the database and applies pending migrations.
+async fn app_state(config: &Config) -> Result<Arc<AppState>, AppError> {
+    // the backend is picked from the DATABASE_URL scheme
+    let store = connect_store(&config.db_url).await?;
+    store.migrate().await?;
+
+    Ok(Arc::new(AppState::new(store, config)?))
+}
+
+async fn scan(
+    config: &Config,
+    app_state: Arc<AppState>,
+    chains: Vec<ChainId>,
+) -> Result<(), Box<dyn Error>> {
+    // One writer for every chain, so rotation never races
+    let csv_writer: SharedCsvWriter = Arc::new(Mutex::new(RotatingCsvWriter::open(
+        CSV_PATH,
+        config.csv_max_bytes,
+    )?));
+
+    let shutdown = CancellationToken::new();
+
+    // Stops once every scanner is done with its handle
+    let (writer, writer_task) = BatchWriter::spawn(app_state.clone(), config.batch.clone());
+
+    let retention_task = task::spawn(run_retention(
+        app_state.clone(),
+        config.retention.clone(),
+        shutdown.clone(),
+    ));
+
+    // Balance history of the addresses each chain watches
+    let snapshot_tasks: Vec<_> = config
+        .chains
+        .all()
+        .iter()
+        .filter(|chain| !chain.watched.is_empty())
+        .map(|chain| {
+            task::spawn(run_snapshots(
+                app_state.clone(),
+                chain.id,
+                config.snapshots.interval_blocks(chain.id),
+                shutdown.clone(),
+            ))
+        })
+        .collect();
+
+    let admin_state = app_state.clone();
+    let admin_url = config.scanner_url.clone();
+    let admin_shutdown = shutdown.clone();
+    let admin_task = task::spawn(async move {
+        if let Err(e) = serve_admin(&admin_url, admin_state, admin_shutdown).await {
+            error!("Error serving scanner admin routes: {:?}", e);
         }
     });
 
-    println!("Mempool scanning started!");
+    let mut mempool_tasks = Vec::with_capacity(chains.len());
+    for chain_id in chains {
+        let web_socket_url = config.chains.web_socket_url(chain_id)?;
+        let app_state = app_state.clone();
+        let csv_writer = csv_writer.clone();
+        let writer = writer.clone();
+        let shutdown = shutdown.clone();
+        app_state.health.register(chain_id);
+
+        // Ensure the responses directory exists
+        fs::create_dir_all(format!("{}/{}", RESPONSES_DIR, chain_id)).await?;
+
+        mempool_tasks.push(task::spawn(supervise(
+            chain_id,
+            web_socket_url,
+            app_state,
+            csv_writer,
+            writer,
+            shutdown,
+        )));
+
+        println!("Mempool scanning started for chain {}!", chain_id);
+    }
+
+    shutdown_signal().await;
+    println!("Shutdown signal received, draining in-flight work...");
+    shutdown.cancel();
 
-    // shutdown signal
-    signal::ctrl_c().await?;
-    println!("Shutdown signal received, stopping tasks...");
+    // Scanners finish the check in progress and save their pending sets, then
+    // the writer stores what they queued
+    let deadline = Instant::now() + config.shutdown_timeout;
+    let mut aborted = drain(mempool_tasks, deadline).await;
+    drop(writer);
+    let mut background = vec![writer_task, retention_task, admin_task];
+    background.extend(snapshot_tasks);
+    aborted += drain(background, deadline).await;
+    if aborted > 0 {
+        println!("Aborted {} tasks after the shutdown deadline", aborted);
+    }
 
-    // abort tasks
-    server_task.abort();
-    mempool_task.abort();
+    csv_writer
+        .lock()
+        .map_err(|_| AppError::Other("CSV writer lock poisoned".into()))?
+        .flush()?;
+    app_state.store.close().await;
 
     println!("Tasks stopped. Shutting down.");
     Ok(())
 }
+
+async fn check_config(config: &Config) -> Result<(), Box<dyn Error>> {
+    let mut problems = Vec::new();
+
+    println!("Server address: {}", config.server_url);
+
+    let database = match connect_store(&config.db_url).await {
+        Ok(store) => store.ping().await,
+        Err(e) => Err(e),
+    };
+    match database {
+        Ok(()) => println!("Database: reachable"),
+        Err(e) => problems.push(format!("Database: {}", e)),
+    }
+
+    for chain_id in &config.scan_chain_ids {
+        match config.chains.web_socket_url(*chain_id) {
+            Ok(_) => println!("Chain {}: websocket url set", chain_id),
+            Err(e) => problems.push(format!("Chain {}: {}", chain_id, e)),
+        }
+    }
+
+    let mut rpc_chains: Vec<_> = config.providers.endpoints.iter().collect();
+    rpc_chains.sort_by_key(|(chain_id, _)| **chain_id);
+    for (chain_id, urls) in rpc_chains {
+        println!("Chain {}: {} RPC endpoint(s)", chain_id, urls.len());
+    }
+
+    if problems.is_empty() {
+        println!("Configuration is valid");
+        return Ok(());
+    }
+
+    for problem in &problems {
+        eprintln!("{}", problem);
+    }
+    Err(format!("{} configuration problem(s) found", problems.len()).into())
+}
@@ -1,34 +1,74 @@
+use alloy::{
+    primitives::{ChainId, U256},
+    rpc::types::eth::BlockId,
+};
 use async_tungstenite::tungstenite::protocol::Message;
-use axum::{extract::State, Json};
 use futures_util::{SinkExt, StreamExt};
 use log::{error, info, warn};
 use serde_json::{json, Value};
 use std::{
-    collections::{HashMap, HashSet},
+    collections::{HashSet, VecDeque},
+    str::FromStr,
     sync::Arc,
-    time::{Duration, SystemTime, UNIX_EPOCH},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+use tokio::{
+    fs::File,
+    io::AsyncWriteExt,
+    time::{interval, timeout},
 };
-use tokio::{fs::File, io::AsyncWriteExt, time::interval};
+use tokio_util::sync::CancellationToken;
 use uuid::Uuid;
 
 use crate::{
     connection::connect_websocket,
-    mempool::check_contract_type::check_account_type,
-    model::{AppError, AppState, Config, ContractType, Transaction, TxHashResponse},
-    service::create_transaction,
-    utils::{csv_writer, hex_to_int64, trim_str},
+    internal_calls,
+    mempool::{check_contract_type::check_account_type, pending::PendingSet},
+    model::{AppError, AppState, ContractType, Transaction, TxHashResponse},
+    rate_limit::Priority,
+    rpc_cache::cached_block,
+    simulation::{enrich, is_high_value},
+    utils::{hex_to_int64, saturating_tx_value, trim_str, SharedCsvWriter, RESPONSES_DIR},
+    writer::BatchWriter,
 };
 
-pub async fn scan_mempool(config: &Config, state: &Arc<AppState>) -> Result<(), AppError> {
-    let (mut write, read) = connect_websocket(&config.web_socket_url).await?.split();
+/// How often a ping is sent to keep the websocket alive.
+pub const PING_INTERVAL: Duration = Duration::from_secs(15);
+/// A connection that has been silent this long, pongs included, is considered dead.
+pub const KEEPALIVE_TIMEOUT: Duration = Duration::from_secs(45);
+/// How long to wait for the answer to a single RPC call.
+pub const RESPONSE_TIMEOUT: Duration = Duration::from_secs(10);
+/// JSON-RPC id of the `eth_subscribe` request. Lookups use the ids after it.
+const SUBSCRIPTION_ID: u64 = 1;
 
-    // CSV writer
-    let mut writer = csv_writer("transactions.csv").map_err(|e| AppError::IoError(e))?;
+/// Scans the mempool over a single websocket connection.
+///
+/// Returns `Ok` once `shutdown` is cancelled, after finishing the check in progress.
+/// Reconnecting on error is left to the supervisor, which owns `pending`.
+pub async fn scan_mempool(
+    chain_id: ChainId,
+    web_socket_url: &str,
+    state: &Arc<AppState>,
+    csv_writer: &SharedCsvWriter,
+    writer: &BatchWriter,
+    pending: &mut PendingSet,
+    shutdown: &CancellationToken,
+) -> Result<(), AppError> {
+    let stream = tokio::select! {
+        _ = shutdown.cancelled() => return Ok(()),
+        stream = connect_websocket(web_socket_url) => stream?,
+    };
+    let (mut write, read) = stream.split();
+    state.health.update(chain_id, |health| {
+        health.connected = true;
+        health.subscribed = false;
+        health.connected_at = Some(Instant::now());
+    });
 
     // Subscribe to pending transactions
     let subscribe_msg = json!({
         "jsonrpc": "2.0",
-        "id": 1,
+        "id": SUBSCRIPTION_ID,
         "method": "eth_subscribe",
         "params": ["newPendingTransactions"]
     });
@@ -36,103 +76,167 @@ pub async fn scan_mempool(config: &Config, state: &Arc<AppState>) -> Result<(),
     write.send(Message::Text(subscribe_msg.to_string())).await?;
     let mut fused_read = read.fuse();
 
-    // HashMap to store transaction times
-    let mut tx_times: HashMap<String, i64> = HashMap::new();
-    let mut pending_txs: HashSet<String> = HashSet::new();
-    // Ticker that fires every 3 seconds
+    let metrics = &state.metrics;
+    let chain = chain_id.to_string();
+
+    let mut ping_interval = interval(PING_INTERVAL);
+    // Ticker that fires every 3 seconds; the first tick is immediate, which
+    // reconciles anything left pending by a previous connection
     let mut interval = interval(Duration::from_secs(3));
+    let mut last_seen = Instant::now();
+    // Pending transactions already handed to the simulator
+    let mut simulated = HashSet::new();
+    let mut request_id = SUBSCRIPTION_ID;
+    // Messages that arrived while waiting for the answer to a lookup
+    let mut deferred = VecDeque::new();
     loop {
         tokio::select! {
-            Some(message) = fused_read.next() => {
+            _ = shutdown.cancelled() => {
+                info!("Stopping mempool scan for chain {}", chain_id);
+                let _ = write.close().await;
+                return Ok(());
+            }
+            message = fused_read.next() => {
+                let Some(message) = message else {
+                    warn!("WebSocket stream ended");
+                    return Err(AppError::Other("WebSocket stream ended".into()));
+                };
+                last_seen = Instant::now();
                 match message {
-                    Ok(Message::Text(res)) => {
-                        if let Ok(response) = serde_json::from_str::<TxHashResponse>(&res) {
-                            let tx_hash = response.params.result;
-                            let start_time = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_millis() as i64;
-                            tx_times.insert(tx_hash.clone(), start_time);
-                            pending_txs.insert(tx_hash.clone());
-                            info!("New pending transaction: {}", tx_hash);
-                        }
-                    }
-                    Ok(Message::Close(_)) => {
-                        warn!("WebSocket closed");
-                        return Err(AppError::Other("WebSocket closed".into()));
-                    }
+                    Ok(message) => handle_message(state, chain_id, pending, message)?,
                     Err(e) => {
                         error!("Error: {}", e);
                         return Err(AppError::WebSocketError(e));
                     }
-                    _ => {}
                 }
             }
+            _ = ping_interval.tick() => {
+                if last_seen.elapsed() > KEEPALIVE_TIMEOUT {
+                    warn!("No websocket traffic on chain {} for {:?}", chain_id, last_seen.elapsed());
+                    return Err(AppError::Other("WebSocket keepalive timed out".into()));
+                }
+                write.send(Message::Ping(Vec::new())).await?;
+            }
             _ = interval.tick() => {
                 info!("Checking pending transactions...");
+                metrics.pending_transactions.with_label_values(&[&chain]).set(pending.len() as i64);
+                if pending.is_dirty() {
+                    if let Err(e) = pending.flush(state.store.as_ref()).await {
+                        warn!("Error saving pending set for chain {}: {:?}", chain_id, e);
+                    }
+                }
 
-                for tx_hash in pending_txs.clone() {
+                for tx_hash in pending.hashes() {
+                    // Lookups resume on the next tick once the endpoint has budget again
+                    if let Err(e) = state.providers.throttle(metrics, chain_id, web_socket_url, "eth_getTransactionByHash", Priority::Background).await {
+                        warn!("Deferring pending transaction checks of chain {}: {}", chain_id, e);
+                        break;
+                    }
+                    request_id += 1;
                     let tx_data = json!({
                         "jsonrpc": "2.0",
-                        "id": 1,
+                        "id": request_id,
                         "method": "eth_getTransactionByHash",
                         "params": [&tx_hash]
                     });
+                    let started = Instant::now();
                     write.send(Message::Text(tx_data.to_string())).await?;
 
-                    if let Some(Ok(Message::Text(response_text))) = fused_read.next().await {
-                        let tx_response: Value = serde_json::from_str(&response_text)?;
+                    let tx_response = next_response(&mut fused_read, request_id, &mut deferred).await?;
+                    last_seen = Instant::now();
+                    metrics.observe_rpc(chain_id, "eth_getTransactionByHash", started);
 
-                        if let Some(result) = tx_response.get("result") {
-                            if result["blockHash"].is_string() {
-                                let check_contract_code = json!({
-                                    "jsonrpc": "2.0",
-                                    "id": 1,
-                                    "method": "eth_getCode",
-                                    "params": [&result["to"]]
-                                });
+                    if let Some(result) = tx_response.get("result") {
+                        // The node no longer knows the transaction, it was dropped or replaced
+                        if result.is_null() {
+                            pending.remove(&tx_hash);
+                            simulated.remove(&tx_hash);
+                            metrics.transactions_dropped.with_label_values(&[&chain]).inc();
+                            continue;
+                        }
+
+                        if result["blockHash"].is_string() {
+                            // Left pending, so it is looked up again on the next tick
+                            if let Err(e) = state.providers.throttle(metrics, chain_id, web_socket_url, "eth_getCode", Priority::Background).await {
+                                warn!("Deferring transaction {} of chain {}: {}", tx_hash, chain_id, e);
+                                continue;
+                            }
+                            request_id += 1;
+                            let check_contract_code = json!({
+                                "jsonrpc": "2.0",
+                                "id": request_id,
+                                "method": "eth_getCode",
+                                "params": [&result["to"]]
+                            });
 
-                                write.send(Message::Text(check_contract_code.to_string())).await?;
-                                let mut _contract_type: ContractType = ContractType::ExternallyOwnedAccount;
+                            let started = Instant::now();
+                            write.send(Message::Text(check_contract_code.to_string())).await?;
 
-                                // check the contract type
-                                if let Some(Ok(Message::Text(check_contract_code_response_text))) = fused_read.next().await {
-                                    let check_contract_code_tx_response: Value = serde_json::from_str(&check_contract_code_response_text)?;
-                                    let code = &check_contract_code_tx_response["result"];
+                            // check the contract type
+                            let check_contract_code_tx_response = next_response(&mut fused_read, request_id, &mut deferred).await?;
+                            last_seen = Instant::now();
+                            metrics.observe_rpc(chain_id, "eth_getCode", started);
+                            let code = &check_contract_code_tx_response["result"];
+                            let _contract_type: ContractType = check_account_type(code);
 
-                                    _contract_type = check_account_type(&code);
+                            simulated.remove(&tx_hash);
+                            let restored = pending.is_restored(&tx_hash);
+                            if let Some(start_time) = pending.remove(&tx_hash) {
+                                let block_hash = trim_str(&result["blockHash"]);
+                                let block_number = hex_to_int64(&result["blockNumber"])?;
+                                let from_sender = trim_str(&result["from"]);
+                                let to_reciever = trim_str(&result["to"]);
+                                let value = U256::from_str(&trim_str(&result["value"]))
+                                    .map_err(|e| AppError::Other(format!("Invalid value of {}: {}", tx_hash, e)))?;
+                                let (tx_value, tx_value_overflow) = saturating_tx_value(value);
+                                let gas = hex_to_int64(&result["gas"])?;
+                                let gas_price = hex_to_int64(&result["gasPrice"])?;
+                                let priority_fee = match result["maxPriorityFeePerGas"] {
+                                    Value::String(_) => Some(hex_to_int64(&result["maxPriorityFeePerGas"])?),
+                                    _ => None,
+                                };
+                                let input = trim_str(&result["input"]);
+                                let nonce = hex_to_int64(&result["nonce"])?;
+
+                                // Seen before a restart, the downtime must not count: measure up to
+                                // the inclusion block, or record 0 (unmeasured) like backfills do
+                                let end_time = if restored {
+                                    block_time_ms(state, chain_id, block_number).await
+                                } else {
+                                    Some(SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_millis() as i64)
+                                };
+                                let mempool_time = end_time.map_or(0, |end_time| (end_time - start_time).max(0));
+                                metrics.transactions_included.with_label_values(&[&chain]).inc();
+                                if mempool_time > 0 {
+                                    metrics.mempool_time.with_label_values(&[&chain]).observe(mempool_time as f64 / 1000.0);
                                 }
 
-                                if let Some(start_time) = tx_times.remove(&tx_hash) {
-                                    let block_hash = trim_str(&result["blockHash"]);
-                                    let block_number = hex_to_int64(&result["blockNumber"])?;
-                                    let from_sender = trim_str(&result["from"]);
-                                    let to_reciever = trim_str(&result["to"]);
-                                    let tx_value = hex_to_int64(&result["value"])?;
-                                    let gas = hex_to_int64(&result["gas"])?;
-                                    let gas_price = hex_to_int64(&result["gasPrice"])?;
-                                    let input = trim_str(&result["input"]);
-                                    let nonce = hex_to_int64(&result["nonce"])?;
-
-                                    let end_time = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_millis() as i64;
-                                    let mempool_time = end_time - start_time;
-
-                                    let transaction = Transaction {
-                                        id: Uuid::default(),
-                                        tx_hash: tx_hash.clone(),
-                                        block_hash,
-                                        block_number,
-                                        from_sender,
-                                        to_reciever,
-                                        tx_value,
-                                        gas,
-                                        gas_price,
-                                        input,
-                                        nonce,
-                                        mempool_time,
-                                        contract_type: _contract_type,
-                                    };
-
-                                    // Convert block_number to a String
-                                    let blck_number_str = &transaction.block_number.to_string();
-                                    // Write to CSV
+                                let transaction = Transaction {
+                                    id: Uuid::default(),
+                                    chain_id: chain_id as i64,
+                                    tx_hash: tx_hash.clone(),
+                                    block_hash,
+                                    block_number,
+                                    from_sender,
+                                    to_reciever,
+                                    tx_value,
+                                    tx_value_overflow,
+                                    gas,
+                                    gas_price,
+                                    priority_fee,
+                                    input,
+                                    nonce,
+                                    mempool_time,
+                                    contract_type: _contract_type,
+                                };
+
+                                // Convert block_number to a String
+                                let blck_number_str = &transaction.block_number.to_string();
+                                // Write to CSV
+                                {
+                                    let mut writer = csv_writer
+                                        .lock()
+                                        .map_err(|_| AppError::Other("CSV writer lock poisoned".into()))?;
                                     writer.write_record(&[
                                         &tx_hash,
                                         &mempool_time.to_string(),
@@ -140,24 +244,188 @@ pub async fn scan_mempool(config: &Config, state: &Arc<AppState>) -> Result<(),
                                         &blck_number_str,
                                         &_contract_type.as_str().to_string()
                                     ])?;
-
-                                    // Save response to file
-                                    let file_path = format!("responses/{}.json", tx_hash);
-                                    let mut file = File::create(&file_path).await?;
-                                    file.write_all(serde_json::to_string(&transaction)?.as_bytes()).await?;
                                     writer.flush()?;
+                                }
 
-                                    let _ = create_transaction(State(state.clone()), Json(transaction)).await?;
+                                // Save response to file
+                                let file_path = format!("{}/{}/{}.json", RESPONSES_DIR, chain_id, tx_hash);
+                                let mut file = File::create(&file_path).await?;
+                                file.write_all(serde_json::to_string(&transaction)?.as_bytes()).await?;
+                                file.flush().await?;
 
-                                    // Remove from pending txn
-                                    pending_txs.remove(&tx_hash);
+                                // Stored by the batch writer, so the scan does not wait on the database
+                                writer.send(transaction).await?;
 
+                                // Only contracts make internal calls
+                                let calls_contract = _contract_type != ContractType::ExternallyOwnedAccount || result["to"].is_null();
+                                if state.capture_internal_calls && calls_contract {
+                                    internal_calls::enrich(state.clone(), chain_id, &tx_hash, block_number);
                                 }
                             }
+                        } else if is_high_value(&state.simulation, result) && simulated.insert(tx_hash.clone()) {
+                            enrich(state.clone(), chain_id, &tx_hash);
                         }
                     }
                 }
+
+                // Notifications that arrived while the lookups waited for their answers
+                while let Some(message) = deferred.pop_front() {
+                    handle_message(state, chain_id, pending, message)?;
+                }
+            }
+        }
+    }
+}
+
+/// When block `block_number` of `chain_id` was mined, in unix milliseconds.
+async fn block_time_ms(state: &AppState, chain_id: ChainId, block_number: i64) -> Option<i64> {
+    match cached_block(state, chain_id, BlockId::number(block_number as u64)).await {
+        Ok(block) => block.map(|block| block.header.timestamp as i64 * 1000),
+        Err(e) => {
+            warn!(
+                "Cannot read block {} of chain {}: {:?}",
+                block_number, chain_id, e
+            );
+            None
+        }
+    }
+}
+
+/// Handles a message that is not the answer to a lookup: a pending transaction
+/// notification, the subscription acknowledgement or a control frame.
+fn handle_message(
+    state: &AppState,
+    chain_id: ChainId,
+    pending: &mut PendingSet,
+    message: Message,
+) -> Result<(), AppError> {
+    let chain = chain_id.to_string();
+    match message {
+        Message::Text(res) => {
+            if let Ok(response) = serde_json::from_str::<TxHashResponse>(&res) {
+                let tx_hash = response.params.result;
+                let start_time = SystemTime::now()
+                    .duration_since(UNIX_EPOCH)
+                    .unwrap()
+                    .as_millis() as i64;
+                pending.insert(tx_hash.clone(), start_time);
+                let metrics = &state.metrics;
+                metrics
+                    .transactions_discovered
+                    .with_label_values(&[&chain])
+                    .inc();
+                metrics
+                    .pending_transactions
+                    .with_label_values(&[&chain])
+                    .set(pending.len() as i64);
+                state.health.update(chain_id, |health| {
+                    health.subscribed = true;
+                    health.last_notification_at = Some(Instant::now());
+                    health.pending_transactions = pending.len();
+                });
+                info!("New pending transaction: {}", tx_hash);
+            } else if let Ok(ack) = serde_json::from_str::<Value>(&res) {
+                // eth_subscribe answers with the subscription id. Answers to lookups that
+                // timed out arrive here too, and are ignored
+                if ack["id"] == SUBSCRIPTION_ID && ack["result"].is_string() {
+                    state
+                        .health
+                        .update(chain_id, |health| health.subscribed = true);
+                } else if ack["id"] == SUBSCRIPTION_ID && ack["error"].is_object() {
+                    return Err(AppError::Other(format!(
+                        "Subscription rejected: {}",
+                        ack["error"]
+                    )));
+                }
+            }
+        }
+        Message::Close(_) => {
+            warn!("WebSocket closed");
+            return Err(AppError::Other("WebSocket closed".into()));
+        }
+        _ => {}
+    }
+    Ok(())
+}
+
+/// Waits for the answer to the request `id`, treating a silent or closed connection as
+/// dead. Anything else that arrives meanwhile, such as pongs and notifications, is queued
+/// in `deferred` for `handle_message`.
+async fn next_response<S>(
+    read: &mut S,
+    id: u64,
+    deferred: &mut VecDeque<Message>,
+) -> Result<Value, AppError>
+where
+    S: futures_util::Stream<Item = Result<Message, async_tungstenite::tungstenite::Error>> + Unpin,
+{
+    let answer = async {
+        loop {
+            let message = match read.next().await {
+                Some(Ok(message)) => message,
+                Some(Err(e)) => return Err(AppError::WebSocketError(e)),
+                None => return Err(AppError::Other("WebSocket stream ended".into())),
+            };
+            if let Message::Text(text) = &message {
+                if let Ok(response) = serde_json::from_str::<Value>(text) {
+                    if response["id"] == id {
+                        return Ok(response);
+                    }
+                }
+            }
+            if let Message::Close(_) = message {
+                return Err(AppError::Other("WebSocket closed".into()));
             }
+            deferred.push_back(message);
         }
+    };
+    match timeout(RESPONSE_TIMEOUT, answer).await {
+        Ok(response) => response,
+        Err(_) => Err(AppError::Other(
+            "Timed out waiting for an RPC response".into(),
+        )),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures_util::stream;
+
+    #[tokio::test]
+    async fn answers_are_matched_by_id_past_pongs_and_notifications() {
+        let notification = json!({
+            "jsonrpc": "2.0",
+            "method": "eth_subscription",
+            "params": { "subscription": "0x1", "result": "0xabc" }
+        })
+        .to_string();
+        let stale = json!({ "jsonrpc": "2.0", "id": 2, "result": null }).to_string();
+        let answer = json!({ "jsonrpc": "2.0", "id": 3, "result": "0x" }).to_string();
+        let mut read = stream::iter(vec![
+            Ok(Message::Pong(Vec::new())),
+            Ok(Message::Text(notification.clone())),
+            Ok(Message::Text(stale)),
+            Ok(Message::Text(answer)),
+        ]);
+        let mut deferred = VecDeque::new();
+
+        let response = next_response(&mut read, 3, &mut deferred).await.unwrap();
+
+        assert_eq!(response["result"], "0x");
+        assert_eq!(deferred.len(), 3);
+        assert_eq!(deferred[0], Message::Pong(Vec::new()));
+        assert_eq!(deferred[1], Message::Text(notification));
+    }
+
+    #[tokio::test]
+    async fn a_closed_connection_has_no_answer() {
+        let mut read = stream::iter(vec![
+            Ok(Message::Pong(Vec::new())),
+            Ok(Message::Close(None)),
+        ]);
+        let mut deferred = VecDeque::new();
+
+        assert!(next_response(&mut read, 2, &mut deferred).await.is_err());
     }
 }
@@ -1,2 +1,4 @@
 pub mod check_contract_type;
 pub mod mempool;
+pub mod pending;
+pub mod supervisor;
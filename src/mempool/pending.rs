@@ -0,0 +1,152 @@
+use alloy::primitives::ChainId;
+use std::collections::{HashMap, HashSet};
+
+use crate::{model::AppError, store::TransactionStore};
+
+/// Transactions seen in the mempool that have not been included or dropped yet.
+///
+/// Owned by the scanner supervisor so it outlives a single websocket connection:
+/// after a reconnect the next tick reconciles whatever was pending before it.
+/// Changes are written to the store on `flush`, so first-seen
+/// times also survive restarts.
+#[derive(Debug)]
+pub struct PendingSet {
+    chain_id: ChainId,
+    // tx hash -> first seen, unix milliseconds
+    first_seen: HashMap<String, i64>,
+    // changes not flushed yet
+    added: HashSet<String>,
+    removed: HashSet<String>,
+    // read back from the store, first seen by a previous run
+    restored: HashSet<String>,
+}
+
+impl PendingSet {
+    pub fn new(chain_id: ChainId) -> Self {
+        Self {
+            chain_id,
+            first_seen: HashMap::new(),
+            added: HashSet::new(),
+            removed: HashSet::new(),
+            restored: HashSet::new(),
+        }
+    }
+
+    /// Reads back the transactions a previous run left pending on `chain_id`.
+    pub async fn load(store: &dyn TransactionStore, chain_id: ChainId) -> Result<Self, AppError> {
+        let rows = store.load_pending(chain_id as i64).await?;
+
+        let mut pending = Self::new(chain_id);
+        pending
+            .restored
+            .extend(rows.iter().map(|(tx_hash, _)| tx_hash.clone()));
+        pending.first_seen.extend(rows);
+        Ok(pending)
+    }
+
+    /// Writes the changes since the last flush. Failed changes are kept for the next one.
+    pub async fn flush(&mut self, store: &dyn TransactionStore) -> Result<(), AppError> {
+        let added: Vec<(String, i64)> = self
+            .added
+            .iter()
+            .map(|tx_hash| (tx_hash.clone(), self.first_seen[tx_hash]))
+            .collect();
+        let removed: Vec<String> = self.removed.iter().cloned().collect();
+
+        store
+            .save_pending(self.chain_id as i64, &added, &removed)
+            .await?;
+        self.added.clear();
+        self.removed.clear();
+        Ok(())
+    }
+
+    /// Tracks a transaction, keeping the earliest sighting if it was already known.
+    pub fn insert(&mut self, tx_hash: String, first_seen_ms: i64) {
+        if self.first_seen.contains_key(&tx_hash) {
+            return;
+        }
+        // A removal that was never flushed still has its row, which is kept as is
+        if !self.removed.remove(&tx_hash) {
+            self.added.insert(tx_hash.clone());
+        }
+        self.first_seen.insert(tx_hash, first_seen_ms);
+    }
+
+    /// Stops tracking a transaction, returning when it was first seen.
+    pub fn remove(&mut self, tx_hash: &str) -> Option<i64> {
+        let first_seen = self.first_seen.remove(tx_hash)?;
+        self.restored.remove(tx_hash);
+        // Nothing to delete if the insert was never flushed
+        if !self.added.remove(tx_hash) {
+            self.removed.insert(tx_hash.to_string());
+        }
+        Some(first_seen)
+    }
+
+    /// Whether a transaction was first seen by a previous run. The scanner was down for
+    /// part of the time it has been pending since.
+    pub fn is_restored(&self, tx_hash: &str) -> bool {
+        self.restored.contains(tx_hash)
+    }
+
+    pub fn hashes(&self) -> Vec<String> {
+        self.first_seen.keys().cloned().collect()
+    }
+
+    pub fn len(&self) -> usize {
+        self.first_seen.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.first_seen.is_empty()
+    }
+
+    /// Whether there are changes `flush` has yet to write.
+    pub fn is_dirty(&self) -> bool {
+        !self.added.is_empty() || !self.removed.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn keeps_earliest_sighting() {
+        let mut pending = PendingSet::new(1);
+        pending.insert("0xa".into(), 10);
+        pending.insert("0xa".into(), 20);
+
+        assert_eq!(pending.remove("0xa"), Some(10));
+        assert_eq!(pending.remove("0xa"), None);
+    }
+
+    #[test]
+    fn unflushed_changes_cancel_out() {
+        let mut pending = PendingSet::new(1);
+        pending.insert("0xa".into(), 10);
+        pending.remove("0xa");
+        assert!(!pending.is_dirty());
+
+        // A loaded row that is removed then seen again keeps its row
+        pending.first_seen.insert("0xb".into(), 5);
+        pending.remove("0xb");
+        assert!(pending.removed.contains("0xb"));
+        pending.insert("0xb".into(), 30);
+        assert!(!pending.is_dirty());
+    }
+
+    #[test]
+    fn only_loaded_rows_are_restored() {
+        let mut pending = PendingSet::new(1);
+        pending.restored.insert("0xa".into());
+        pending.first_seen.insert("0xa".into(), 5);
+        pending.insert("0xb".into(), 10);
+
+        assert!(pending.is_restored("0xa"));
+        assert!(!pending.is_restored("0xb"));
+        pending.remove("0xa");
+        assert!(!pending.is_restored("0xa"));
+    }
+}
@@ -0,0 +1,178 @@
+use alloy::primitives::ChainId;
+use log::{error, info};
+use rand::Rng;
+use std::{
+    sync::Arc,
+    time::{Duration, Instant},
+};
+use tokio::time::sleep;
+use tokio_util::sync::CancellationToken;
+
+use crate::{
+    mempool::{mempool::scan_mempool, pending::PendingSet},
+    model::AppState,
+    utils::SharedCsvWriter,
+    writer::BatchWriter,
+};
+
+pub const INITIAL_BACKOFF: Duration = Duration::from_secs(1);
+pub const MAX_BACKOFF: Duration = Duration::from_secs(60);
+/// A connection that stayed up this long resets the backoff.
+pub const STABLE_CONNECTION: Duration = Duration::from_secs(60);
+
+/// Exponential backoff with equal jitter: each delay is drawn from the upper
+/// half of the current window, so reconnects spread out without collapsing to zero.
+#[derive(Debug, Clone)]
+pub struct Backoff {
+    initial: Duration,
+    max: Duration,
+    attempt: u32,
+}
+
+impl Backoff {
+    pub fn new(initial: Duration, max: Duration) -> Self {
+        Self {
+            initial,
+            max,
+            attempt: 0,
+        }
+    }
+
+    /// Upper bound of the delay for the current attempt, capped at `max`.
+    pub fn window(&self) -> Duration {
+        let factor = 1u32.checked_shl(self.attempt).unwrap_or(u32::MAX);
+        self.initial.saturating_mul(factor).min(self.max)
+    }
+
+    pub fn next_delay(&mut self) -> Duration {
+        let window = self.window();
+        self.attempt = self.attempt.saturating_add(1);
+
+        let half = window / 2;
+        half + rand::thread_rng().gen_range(Duration::ZERO..=window - half)
+    }
+
+    pub fn reset(&mut self) {
+        self.attempt = 0;
+    }
+}
+
+/// Keeps a chain's mempool scanner running, reconnecting with backoff, until
+/// `shutdown` is cancelled.
+///
+/// The pending set is kept across connections, so transactions seen before a
+/// disconnect are checked again once the scanner is back instead of being lost.
+/// It is flushed to the database on every tick and on shutdown, and reloaded on the
+/// next start, where the first tick reconciles it against the chain.
+pub async fn supervise(
+    chain_id: ChainId,
+    web_socket_url: String,
+    state: Arc<AppState>,
+    csv_writer: SharedCsvWriter,
+    writer: BatchWriter,
+    shutdown: CancellationToken,
+) {
+    let mut pending = match PendingSet::load(state.store.as_ref(), chain_id).await {
+        Ok(pending) => {
+            info!(
+                "Loaded {} pending transactions for chain {}",
+                pending.len(),
+                chain_id
+            );
+            pending
+        }
+        Err(e) => {
+            error!("Error loading pending set for chain {}: {:?}", chain_id, e);
+            PendingSet::new(chain_id)
+        }
+    };
+    let mut backoff = Backoff::new(INITIAL_BACKOFF, MAX_BACKOFF);
+    let chain = chain_id.to_string();
+
+    loop {
+        let started = Instant::now();
+        match scan_mempool(
+            chain_id,
+            &web_socket_url,
+            &state,
+            &csv_writer,
+            &writer,
+            &mut pending,
+            &shutdown,
+        )
+        .await
+        {
+            // Only returned once shutdown is requested
+            Ok(()) => break,
+            Err(e) => {
+                error!("Error occurred on chain {}: {:?}", chain_id, e);
+                state.health.update(chain_id, |health| {
+                    health.connected = false;
+                    health.subscribed = false;
+                    health.last_error = Some(e.to_string());
+                });
+            }
+        }
+
+        if started.elapsed() >= STABLE_CONNECTION {
+            backoff.reset();
+        }
+        let delay = backoff.next_delay();
+        info!(
+            "Reconnecting chain {} in {:?}, {} transactions still pending",
+            chain_id,
+            delay,
+            pending.len()
+        );
+        tokio::select! {
+            _ = shutdown.cancelled() => break,
+            _ = sleep(delay) => {}
+        }
+        state
+            .metrics
+            .websocket_reconnects
+            .with_label_values(&[&chain])
+            .inc();
+    }
+
+    match pending.flush(state.store.as_ref()).await {
+        Ok(()) => info!(
+            "Saved {} pending transactions for chain {}",
+            pending.len(),
+            chain_id
+        ),
+        Err(e) => error!("Error saving pending set for chain {}: {:?}", chain_id, e),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn delays_grow_until_capped() {
+        let mut backoff = Backoff::new(Duration::from_secs(1), Duration::from_secs(8));
+        let windows: Vec<_> = (0..6)
+            .map(|_| {
+                let window = backoff.window();
+                let delay = backoff.next_delay();
+                assert!(delay >= window / 2 && delay <= window);
+                window.as_secs()
+            })
+            .collect();
+
+        assert_eq!(windows, vec![1, 2, 4, 8, 8, 8]);
+    }
+
+    #[test]
+    fn reset_starts_over() {
+        let mut backoff = Backoff::new(Duration::from_secs(1), Duration::from_secs(60));
+        for _ in 0..40 {
+            backoff.next_delay();
+        }
+        assert_eq!(backoff.window(), Duration::from_secs(60));
+
+        backoff.reset();
+        assert_eq!(backoff.window(), Duration::from_secs(1));
+    }
+}
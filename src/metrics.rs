@@ -0,0 +1,316 @@
+//! Prometheus metrics of the scanner and the API, served on `/metrics`.
+
+use crate::model::{AppError, AppState};
+use alloy::primitives::ChainId;
+use axum::{
+    extract::{MatchedPath, Request, State},
+    http::header,
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use prometheus::{
+    Encoder, Histogram, HistogramOpts, HistogramVec, IntCounter, IntCounterVec, IntGauge,
+    IntGaugeVec, Opts, Registry, TextEncoder,
+};
+use std::{sync::Arc, time::Instant};
+
+/// Buckets of the time transactions spend pending, in seconds.
+const MEMPOOL_TIME_BUCKETS: &[f64] = &[
+    1.0, 2.0, 5.0, 10.0, 15.0, 30.0, 60.0, 120.0, 300.0, 600.0, 1800.0,
+];
+
+/// Buckets of the rows written per batch.
+const BATCH_SIZE_BUCKETS: &[f64] = &[1.0, 5.0, 10.0, 25.0, 50.0, 100.0, 250.0, 500.0, 1000.0];
+
+pub struct Metrics {
+    registry: Registry,
+    pub pending_transactions: IntGaugeVec,
+    pub transactions_discovered: IntCounterVec,
+    pub transactions_included: IntCounterVec,
+    pub transactions_dropped: IntCounterVec,
+    pub rpc_requests: IntCounterVec,
+    pub rpc_request_duration: HistogramVec,
+    pub rpc_failovers: IntCounterVec,
+    pub rpc_endpoint_usable: IntGaugeVec,
+    pub rpc_rate_limit_queued: IntGaugeVec,
+    pub rpc_rate_limit_wait: HistogramVec,
+    pub rpc_rate_limit_rejections: IntCounterVec,
+    pub rpc_cache_hits: IntCounterVec,
+    pub rpc_cache_misses: IntCounterVec,
+    pub websocket_reconnects: IntCounterVec,
+    pub db_insert_duration: Histogram,
+    pub db_insert_errors: IntCounter,
+    pub db_batch_size: Histogram,
+    pub db_batch_duration: Histogram,
+    pub db_batch_retries: IntCounter,
+    pub db_write_queue: IntGauge,
+    pub http_request_duration: HistogramVec,
+    pub mempool_time: HistogramVec,
+    pub pruned: IntCounterVec,
+    pub balance_snapshots: IntCounterVec,
+    pub simulations: IntCounterVec,
+    pub internal_calls: IntCounterVec,
+    pub internal_call_failures: IntCounterVec,
+}
+
+impl Metrics {
+    pub fn new() -> Result<Self, AppError> {
+        let registry = Registry::new_custom(Some("sentinel".into()), None)?;
+
+        let metrics = Self {
+            pending_transactions: IntGaugeVec::new(
+                Opts::new(
+                    "pending_transactions",
+                    "Transactions waiting to be included",
+                ),
+                &["chain"],
+            )?,
+            transactions_discovered: IntCounterVec::new(
+                Opts::new(
+                    "transactions_discovered_total",
+                    "Pending transactions announced by the websocket",
+                ),
+                &["chain"],
+            )?,
+            transactions_included: IntCounterVec::new(
+                Opts::new(
+                    "transactions_included_total",
+                    "Pending transactions seen included in a block",
+                ),
+                &["chain"],
+            )?,
+            transactions_dropped: IntCounterVec::new(
+                Opts::new(
+                    "transactions_dropped_total",
+                    "Pending transactions that disappeared without being included",
+                ),
+                &["chain"],
+            )?,
+            rpc_requests: IntCounterVec::new(
+                Opts::new("rpc_requests_total", "JSON-RPC requests sent upstream"),
+                &["chain", "method"],
+            )?,
+            rpc_request_duration: HistogramVec::new(
+                HistogramOpts::new(
+                    "rpc_request_duration_seconds",
+                    "Latency of JSON-RPC requests sent upstream",
+                ),
+                &["chain", "method"],
+            )?,
+            rpc_failovers: IntCounterVec::new(
+                Opts::new(
+                    "rpc_failovers_total",
+                    "JSON-RPC requests retried on the next endpoint of a chain",
+                ),
+                &["chain"],
+            )?,
+            rpc_endpoint_usable: IntGaugeVec::new(
+                Opts::new(
+                    "rpc_endpoint_usable",
+                    "Whether an endpoint answered its last health check and keeps up with the chain head",
+                ),
+                &["chain", "endpoint"],
+            )?,
+            rpc_rate_limit_queued: IntGaugeVec::new(
+                Opts::new(
+                    "rpc_rate_limit_queued",
+                    "JSON-RPC requests waiting for the budget of an endpoint",
+                ),
+                &["chain", "priority"],
+            )?,
+            rpc_rate_limit_wait: HistogramVec::new(
+                HistogramOpts::new(
+                    "rpc_rate_limit_wait_seconds",
+                    "Time JSON-RPC requests waited for the budget of an endpoint",
+                ),
+                &["chain", "priority"],
+            )?,
+            rpc_rate_limit_rejections: IntCounterVec::new(
+                Opts::new(
+                    "rpc_rate_limit_rejections_total",
+                    "JSON-RPC requests not sent to an endpoint because its budget was spent",
+                ),
+                &["chain", "priority", "reason"],
+            )?,
+            rpc_cache_hits: IntCounterVec::new(
+                Opts::new(
+                    "rpc_cache_hits_total",
+                    "RPC responses served from the cache, by kind and tier",
+                ),
+                &["kind", "tier"],
+            )?,
+            rpc_cache_misses: IntCounterVec::new(
+                Opts::new(
+                    "rpc_cache_misses_total",
+                    "RPC responses fetched upstream after a cache miss",
+                ),
+                &["kind"],
+            )?,
+            websocket_reconnects: IntCounterVec::new(
+                Opts::new(
+                    "websocket_reconnects_total",
+                    "Scanner reconnections after a websocket failure",
+                ),
+                &["chain"],
+            )?,
+            db_insert_duration: Histogram::with_opts(HistogramOpts::new(
+                "db_insert_duration_seconds",
+                "Latency of transaction inserts",
+            ))?,
+            db_insert_errors: IntCounter::new(
+                "db_insert_errors_total",
+                "Transaction inserts that failed",
+            )?,
+            db_batch_size: Histogram::with_opts(
+                HistogramOpts::new("db_batch_size", "Transactions written per batch")
+                    .buckets(BATCH_SIZE_BUCKETS.to_vec()),
+            )?,
+            db_batch_duration: Histogram::with_opts(HistogramOpts::new(
+                "db_batch_duration_seconds",
+                "Latency of batched transaction writes",
+            ))?,
+            db_batch_retries: IntCounter::new(
+                "db_batch_retries_total",
+                "Batched writes retried after a transient failure",
+            )?,
+            db_write_queue: IntGauge::new(
+                "db_write_queue",
+                "Transactions waiting for the batch writer",
+            )?,
+            http_request_duration: HistogramVec::new(
+                HistogramOpts::new(
+                    "http_request_duration_seconds",
+                    "Latency of REST and GraphQL requests",
+                ),
+                &["method", "route", "status"],
+            )?,
+            mempool_time: HistogramVec::new(
+                HistogramOpts::new(
+                    "mempool_time_seconds",
+                    "Time transactions spent pending before inclusion",
+                )
+                .buckets(MEMPOOL_TIME_BUCKETS.to_vec()),
+                &["chain"],
+            )?,
+            pruned: IntCounterVec::new(
+                Opts::new("pruned_total", "Records removed by the retention policy"),
+                &["kind"],
+            )?,
+            balance_snapshots: IntCounterVec::new(
+                Opts::new(
+                    "balance_snapshots_total",
+                    "Balances of watched addresses recorded by the snapshot job",
+                ),
+                &["chain"],
+            )?,
+            simulations: IntCounterVec::new(
+                Opts::new(
+                    "simulations_total",
+                    "Pending transactions simulated, by predicted outcome",
+                ),
+                &["chain", "outcome"],
+            )?,
+            internal_calls: IntCounterVec::new(
+                Opts::new(
+                    "internal_calls_total",
+                    "Internal calls of included transactions stored from call traces",
+                ),
+                &["chain"],
+            )?,
+            internal_call_failures: IntCounterVec::new(
+                Opts::new(
+                    "internal_call_failures_total",
+                    "Included transactions whose internal calls could not be captured",
+                ),
+                &["chain"],
+            )?,
+            registry,
+        };
+
+        metrics.register()?;
+        Ok(metrics)
+    }
+
+    fn register(&self) -> Result<(), AppError> {
+        let collectors: Vec<Box<dyn prometheus::core::Collector>> = vec![
+            Box::new(self.pending_transactions.clone()),
+            Box::new(self.transactions_discovered.clone()),
+            Box::new(self.transactions_included.clone()),
+            Box::new(self.transactions_dropped.clone()),
+            Box::new(self.rpc_requests.clone()),
+            Box::new(self.rpc_request_duration.clone()),
+            Box::new(self.rpc_failovers.clone()),
+            Box::new(self.rpc_endpoint_usable.clone()),
+            Box::new(self.rpc_rate_limit_queued.clone()),
+            Box::new(self.rpc_rate_limit_wait.clone()),
+            Box::new(self.rpc_rate_limit_rejections.clone()),
+            Box::new(self.rpc_cache_hits.clone()),
+            Box::new(self.rpc_cache_misses.clone()),
+            Box::new(self.websocket_reconnects.clone()),
+            Box::new(self.db_insert_duration.clone()),
+            Box::new(self.db_insert_errors.clone()),
+            Box::new(self.db_batch_size.clone()),
+            Box::new(self.db_batch_duration.clone()),
+            Box::new(self.db_batch_retries.clone()),
+            Box::new(self.db_write_queue.clone()),
+            Box::new(self.http_request_duration.clone()),
+            Box::new(self.mempool_time.clone()),
+            Box::new(self.pruned.clone()),
+            Box::new(self.balance_snapshots.clone()),
+            Box::new(self.simulations.clone()),
+            Box::new(self.internal_calls.clone()),
+            Box::new(self.internal_call_failures.clone()),
+        ];
+
+        for collector in collectors {
+            self.registry.register(collector)?;
+        }
+        Ok(())
+    }
+
+    /// Records a JSON-RPC request to `chain_id` that was sent at `started`.
+    pub fn observe_rpc(&self, chain_id: ChainId, method: &str, started: Instant) {
+        let chain = chain_id.to_string();
+        self.rpc_requests.with_label_values(&[&chain, method]).inc();
+        self.rpc_request_duration
+            .with_label_values(&[&chain, method])
+            .observe(started.elapsed().as_secs_f64());
+    }
+
+    /// Encodes every metric in the Prometheus text format.
+    pub fn render(&self) -> Result<String, AppError> {
+        let mut buffer = Vec::new();
+        TextEncoder::new().encode(&self.registry.gather(), &mut buffer)?;
+        String::from_utf8(buffer).map_err(|e| AppError::Other(e.to_string()))
+    }
+}
+
+pub async fn get_metrics(State(state): State<Arc<AppState>>) -> Result<Response, AppError> {
+    let body = state.metrics.render()?;
+    Ok(([(header::CONTENT_TYPE, prometheus::TEXT_FORMAT)], body).into_response())
+}
+
+/// Middleware recording the latency of every request by route.
+pub async fn track_requests(
+    State(state): State<Arc<AppState>>,
+    request: Request,
+    next: Next,
+) -> Response {
+    // The route template keeps the label cardinality bounded
+    let route = request
+        .extensions()
+        .get::<MatchedPath>()
+        .map(|path| path.as_str().to_owned())
+        .unwrap_or_else(|| "unmatched".to_owned());
+    let method = request.method().to_string();
+    let started = Instant::now();
+
+    let response = next.run(request).await;
+
+    state
+        .metrics
+        .http_request_duration
+        .with_label_values(&[&method, &route, response.status().as_str()])
+        .observe(started.elapsed().as_secs_f64());
+    response
+}
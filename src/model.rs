@@ -1,12 +1,27 @@
-use async_graphql::InputObject;
+use crate::{
+    chains::{ChainRef, ChainRegistry},
+    health::Health,
+    metrics::Metrics,
+    providers::{ProviderConfig, ProviderRegistry},
+    request_id,
+    retention::RetentionPolicy,
+    rpc_cache::{RpcCache, RpcCacheConfig},
+    simulation::SimulationConfig,
+    snapshots::SnapshotConfig,
+    store::TransactionStore,
+    writer::BatchConfig,
+};
+use alloy::primitives::ChainId;
+use async_graphql::{Enum, ErrorExtensions, InputObject};
 use axum::{
     http::StatusCode,
     response::{IntoResponse, Response},
+    Json,
 };
 use log::error;
 use serde::{Deserialize, Serialize};
-use sqlx::{prelude::FromRow, PgPool, Type};
-use std::env;
+use sqlx::{prelude::FromRow, Database, Encode, QueryBuilder, Type};
+use std::{env, sync::Arc, time::Duration};
 use thiserror::Error;
 use uuid::Uuid;
 
@@ -23,25 +38,38 @@ pub struct TxHashResponse {
     pub params: Params,
 }
 
-#[derive(Serialize, Deserialize, Debug, FromRow)]
+#[derive(Serialize, Deserialize, Debug, Clone, FromRow)]
 pub struct Transaction {
     pub id: Uuid,
+    pub chain_id: i64,
     pub tx_hash: String,
     pub block_hash: String,
     pub block_number: i64,
     pub from_sender: String,
     pub to_reciever: String,
     pub tx_value: i64,
+    /// Set when the value does not fit in `tx_value`, which then holds `i64::MAX`
+    #[serde(default)]
+    pub tx_value_overflow: bool,
     pub gas: i64,
     pub gas_price: i64,
+    pub priority_fee: Option<i64>,
     pub input: String,
     pub nonce: i64,
     pub mempool_time: i64, // time spent in the mempool
     pub contract_type: ContractType,
 }
 
-#[derive(Deserialize, InputObject)]
+#[derive(Deserialize, InputObject, Default)]
 pub struct TransactionFilter {
+    /// Chain id or slug, resolved into `chain_id` by `resolve_chain`
+    #[serde(alias = "chain_id", alias = "chainid")]
+    pub chain: Option<ChainRef>,
+    #[serde(skip)]
+    #[graphql(skip)]
+    pub chain_id: Option<i64>,
+    /// Matches transactions sent from or to the address
+    pub address: Option<String>,
     pub gas_price_min: Option<i64>,
     pub gas_price_max: Option<i64>,
     pub contract_type: Option<String>,
@@ -51,15 +79,208 @@ pub struct TransactionFilter {
     pub mempool_time_max: Option<i64>,
 }
 
+impl TransactionFilter {
+    /// Resolves `chain` through the registry into the `chain_id` stores filter on.
+    pub fn resolve_chain(mut self, chains: &ChainRegistry) -> Result<Self, AppError> {
+        if let Some(chain) = &self.chain {
+            self.chain_id = Some(chains.resolve(chain)? as i64);
+        }
+        Ok(self)
+    }
+
+    /// Appends an ` AND ...` condition to `query` for every field that is set.
+    pub fn push_conditions<'args, DB>(&self, query: &mut QueryBuilder<'args, DB>)
+    where
+        DB: Database,
+        i64: Encode<'args, DB> + Type<DB>,
+        String: Encode<'args, DB> + Type<DB>,
+    {
+        if let Some(chain_id) = self.chain_id {
+            query.push(" AND chain_id = ").push_bind(chain_id);
+        }
+        if let Some(address) = &self.address {
+            let address = address.to_lowercase();
+            query
+                .push(" AND (from_sender = ")
+                .push_bind(address.clone())
+                .push(" OR to_reciever = ")
+                .push_bind(address)
+                .push(")");
+        }
+        if let Some(min) = self.gas_price_min {
+            query.push(" AND gas_price >= ").push_bind(min);
+        }
+        if let Some(max) = self.gas_price_max {
+            query.push(" AND gas_price <= ").push_bind(max);
+        }
+        if let Some(contract_type) = &self.contract_type {
+            query
+                .push(" AND CAST(contract_type AS TEXT) = ")
+                .push_bind(contract_type.to_lowercase());
+        }
+        if let Some(min) = self.block_number_min {
+            query.push(" AND block_number >= ").push_bind(min);
+        }
+        if let Some(max) = self.block_number_max {
+            query.push(" AND block_number <= ").push_bind(max);
+        }
+        if let Some(min) = self.mempool_time_min {
+            query.push(" AND mempool_time >= ").push_bind(min);
+        }
+        if let Some(max) = self.mempool_time_max {
+            query.push(" AND mempool_time <= ").push_bind(max);
+        }
+    }
+
+    /// Evaluates the filter in memory, with the same semantics as `push_conditions`.
+    pub fn matches(&self, transaction: &Transaction) -> bool {
+        if let Some(chain_id) = self.chain_id {
+            if transaction.chain_id != chain_id {
+                return false;
+            }
+        }
+        if let Some(address) = &self.address {
+            let address = address.to_lowercase();
+            if transaction.from_sender != address && transaction.to_reciever != address {
+                return false;
+            }
+        }
+        if let Some(contract_type) = &self.contract_type {
+            if !transaction
+                .contract_type
+                .as_str()
+                .eq_ignore_ascii_case(contract_type)
+            {
+                return false;
+            }
+        }
+        let within = |value: i64, min: Option<i64>, max: Option<i64>| {
+            !matches!(min, Some(min) if value < min) && !matches!(max, Some(max) if value > max)
+        };
+        within(
+            transaction.gas_price,
+            self.gas_price_min,
+            self.gas_price_max,
+        ) && within(
+            transaction.block_number,
+            self.block_number_min,
+            self.block_number_max,
+        ) && within(
+            transaction.mempool_time,
+            self.mempool_time_min,
+            self.mempool_time_max,
+        )
+    }
+
+    /// Whether the filter restricts nothing but the chain.
+    pub fn is_chain_only(&self) -> bool {
+        self.chain_id.is_some()
+            && self.address.is_none()
+            && self.gas_price_min.is_none()
+            && self.gas_price_max.is_none()
+            && self.contract_type.is_none()
+            && self.block_number_min.is_none()
+            && self.block_number_max.is_none()
+            && self.mempool_time_min.is_none()
+            && self.mempool_time_max.is_none()
+    }
+}
+
+#[derive(Deserialize, Enum, Clone, Copy, PartialEq, Eq, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum Granularity {
+    Minute,
+    Hour,
+    Day,
+}
+
+impl Granularity {
+    pub const ALL: [Granularity; 3] = [Granularity::Minute, Granularity::Hour, Granularity::Day];
+
+    /// Postgres `date_trunc` field of the granularity.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Granularity::Minute => "minute",
+            Granularity::Hour => "hour",
+            Granularity::Day => "day",
+        }
+    }
+
+    /// Length of a bucket, which starts on a multiple of it since the epoch.
+    pub fn seconds(&self) -> i64 {
+        match self {
+            Granularity::Minute => 60,
+            Granularity::Hour => 3600,
+            Granularity::Day => 86400,
+        }
+    }
+}
+
+#[derive(Deserialize)]
+pub struct StatsParams {
+    pub granularity: Granularity,
+    /// Unix timestamp in seconds of the first bucket, inclusive
+    pub from: Option<i64>,
+    /// Unix timestamp in seconds of the last bucket, exclusive
+    pub to: Option<i64>,
+}
+
+#[derive(Deserialize)]
+pub struct GasEstimateParams {
+    /// Chain id or slug
+    pub chainid: ChainRef,
+    pub target_seconds: i64,
+    pub window_seconds: Option<i64>,
+}
+
 #[derive(Deserialize, Serialize)]
 pub struct Config {
-    pub web_socket_url: String,
     pub db_url: String,
     pub server_url: String,
+    /// Address `sentinel scan` serves its metrics on
+    pub scanner_url: String,
+    pub scan_chain_ids: Vec<ChainId>,
+    /// Size past which `transactions.csv` is rotated
+    pub csv_max_bytes: u64,
+    pub retention: RetentionPolicy,
+    /// How long a scanned chain may go without notifications before `/readyz` fails
+    pub readiness_max_silence_secs: u64,
+    /// How long shutdown waits for in-flight work before aborting it
+    pub shutdown_timeout: Duration,
+    pub batch: BatchConfig,
+    pub providers: ProviderConfig,
+    pub rpc_cache: RpcCacheConfig,
+    pub snapshots: SnapshotConfig,
+    pub simulation: SimulationConfig,
+    /// Whether included transactions are traced for their internal calls
+    pub capture_internal_calls: bool,
+    pub chains: ChainRegistry,
 }
 
 pub struct AppState {
-    pub pool: PgPool,
+    pub store: Arc<dyn TransactionStore>,
+    pub chains: ChainRegistry,
+    pub providers: ProviderRegistry,
+    pub rpc_cache: RpcCache,
+    pub simulation: SimulationConfig,
+    pub capture_internal_calls: bool,
+    pub metrics: Metrics,
+    pub health: Health,
+}
+
+impl AppState {
+    pub fn new(store: Arc<dyn TransactionStore>, config: &Config) -> Result<Self, AppError> {
+        Ok(Self {
+            store,
+            chains: config.chains.clone(),
+            providers: ProviderRegistry::new(&config.providers),
+            rpc_cache: RpcCache::new(&config.rpc_cache),
+            simulation: config.simulation.clone(),
+            capture_internal_calls: config.capture_internal_calls,
+            metrics: Metrics::new()?,
+            health: Health::new(Duration::from_secs(config.readiness_max_silence_secs)),
+        })
+    }
 }
 
 #[derive(Error, Debug)]
@@ -70,6 +291,10 @@ pub enum AppError {
     JsonError(#[from] serde_json::Error),
     #[error("CSV error: {0}")]
     CsvError(#[from] csv::Error),
+    #[error("Parquet error: {0}")]
+    ParquetError(#[from] parquet::errors::ParquetError),
+    #[error("Metrics error: {0}")]
+    MetricsError(#[from] prometheus::Error),
     #[error("IO error: {0}")]
     IoError(#[from] std::io::Error),
     #[error("Environment variable not found: {0}")]
@@ -78,24 +303,122 @@ pub enum AppError {
     Other(String),
     #[error("Database error: {0}")]
     DatabaseError(String),
+    /// A failure expected to go away on retry, such as a lost connection
+    #[error("Database unavailable: {0}")]
+    DatabaseUnavailable(String),
     #[error("Not found error: {0}")]
     NotFound(String),
+    /// A JSON-RPC node failed or answered with an error
+    #[error("Upstream error: {0}")]
+    Upstream(String),
+    #[error("Unsupported chain id: {0}")]
+    UnsupportedChain(ChainId),
+    /// The request is well formed but its values are not acceptable
+    #[error("Validation error: {0}")]
+    Validation(String),
+    /// The request budget of every endpoint of a chain is spent
+    #[error("Rate limited: {0}")]
+    RateLimited(String),
+}
+
+/// Body of every error response, also exposed as GraphQL error extensions.
+#[derive(Serialize, Debug)]
+pub struct ErrorBody {
+    /// Stable, machine readable, e.g. `unsupported_chain`
+    pub code: &'static str,
+    pub message: String,
+    pub details: Option<serde_json::Value>,
+    pub request_id: Option<String>,
+}
+
+impl AppError {
+    pub fn status(&self) -> StatusCode {
+        match self {
+            AppError::WebSocketError(_) => StatusCode::BAD_REQUEST,
+            AppError::JsonError(_) => StatusCode::BAD_REQUEST,
+            AppError::CsvError(_) => StatusCode::BAD_REQUEST,
+            AppError::ParquetError(_) => StatusCode::INTERNAL_SERVER_ERROR,
+            AppError::MetricsError(_) => StatusCode::INTERNAL_SERVER_ERROR,
+            AppError::IoError(_) => StatusCode::INTERNAL_SERVER_ERROR,
+            AppError::EnvVarError(_) => StatusCode::INTERNAL_SERVER_ERROR,
+            AppError::Other(_) => StatusCode::INTERNAL_SERVER_ERROR,
+            AppError::DatabaseError(_) => StatusCode::INTERNAL_SERVER_ERROR,
+            AppError::DatabaseUnavailable(_) => StatusCode::SERVICE_UNAVAILABLE,
+            AppError::NotFound(_) => StatusCode::NOT_FOUND,
+            AppError::Upstream(_) => StatusCode::BAD_GATEWAY,
+            AppError::UnsupportedChain(_) => StatusCode::BAD_REQUEST,
+            AppError::Validation(_) => StatusCode::UNPROCESSABLE_ENTITY,
+            AppError::RateLimited(_) => StatusCode::TOO_MANY_REQUESTS,
+        }
+    }
+
+    /// Identifies the kind of error independently of its message.
+    pub fn code(&self) -> &'static str {
+        match self {
+            AppError::WebSocketError(_) => "websocket_error",
+            AppError::JsonError(_) => "invalid_json",
+            AppError::CsvError(_) => "csv_error",
+            AppError::ParquetError(_) => "parquet_error",
+            AppError::MetricsError(_) => "metrics_error",
+            AppError::IoError(_) => "io_error",
+            AppError::EnvVarError(_) => "configuration_error",
+            AppError::Other(_) => "internal_error",
+            AppError::DatabaseError(_) => "database_error",
+            AppError::DatabaseUnavailable(_) => "database_unavailable",
+            AppError::NotFound(_) => "not_found",
+            AppError::Upstream(_) => "upstream_error",
+            AppError::UnsupportedChain(_) => "unsupported_chain",
+            AppError::Validation(_) => "validation_error",
+            AppError::RateLimited(_) => "rate_limited",
+        }
+    }
+
+    pub fn details(&self) -> Option<serde_json::Value> {
+        match self {
+            AppError::UnsupportedChain(chain_id) => {
+                Some(serde_json::json!({ "chain_id": chain_id }))
+            }
+            _ => None,
+        }
+    }
+
+    /// The error as returned to clients, tagged with the id of the request being served.
+    pub fn body(&self) -> ErrorBody {
+        ErrorBody {
+            code: self.code(),
+            message: self.to_string(),
+            details: self.details(),
+            request_id: request_id::current(),
+        }
+    }
 }
 
 impl IntoResponse for AppError {
     fn into_response(self) -> Response {
-        let (status, error_message) = match self {
-            AppError::WebSocketError(_) => (StatusCode::BAD_REQUEST, self.to_string()),
-            AppError::JsonError(_) => (StatusCode::BAD_REQUEST, self.to_string()),
-            AppError::CsvError(_) => (StatusCode::BAD_REQUEST, self.to_string()),
-            AppError::IoError(_) => (StatusCode::INTERNAL_SERVER_ERROR, self.to_string()),
-            AppError::EnvVarError(_) => (StatusCode::INTERNAL_SERVER_ERROR, self.to_string()),
-            AppError::Other(_) => (StatusCode::INTERNAL_SERVER_ERROR, self.to_string()),
-            AppError::DatabaseError(_) => (StatusCode::INTERNAL_SERVER_ERROR, self.to_string()),
-            AppError::NotFound(_) => (StatusCode::NOT_FOUND, self.to_string()),
-        };
+        let status = self.status();
+        if status.is_server_error() {
+            error!("Request failed: {:?}", self);
+        }
+
+        (status, Json(self.body())).into_response()
+    }
+}
 
-        (status, error_message).into_response()
+impl ErrorExtensions for AppError {
+    fn extend(&self) -> async_graphql::Error {
+        let body = self.body();
+        async_graphql::Error::new(body.message).extend_with(|_, extensions| {
+            extensions.set("code", body.code);
+            extensions.set("status", self.status().as_u16());
+            if let Some(details) = body.details {
+                if let Ok(details) = async_graphql::Value::from_json(details) {
+                    extensions.set("details", details);
+                }
+            }
+            if let Some(request_id) = body.request_id {
+                extensions.set("request_id", request_id);
+            }
+        })
     }
 }
 
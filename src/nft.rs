@@ -0,0 +1,292 @@
+//! Ownership of ERC-721 and ERC-1155 tokens, and detection of the standard a contract
+//! implements through ERC-165.
+//!
+//! Interface checks are sent in one Multicall3 batch, each allowed to fail, since
+//! contracts that predate ERC-165 revert or answer nothing.
+
+use crate::{
+    balances::aggregate,
+    model::{AppError, AppState},
+    rpc_queries::{
+        get_erc1155_balance_batch_query, get_erc1155_balance_query, get_erc721_balance_query,
+        get_erc721_owner_query, get_erc721_token_uri_query, ERC721Abi::supportsInterfaceCall,
+        Multicall3Abi,
+    },
+};
+use alloy::{
+    primitives::{fixed_bytes, Address, ChainId, FixedBytes, U256},
+    sol_types::SolCall,
+};
+use async_graphql::{Enum, SimpleObject};
+use serde::{Deserialize, Serialize};
+use std::str::FromStr;
+
+/// Most balances a single `balanceOfBatch` may ask for.
+pub const MAX_BATCH_BALANCES: usize = 500;
+
+const ERC165: FixedBytes<4> = fixed_bytes!("01ffc9a7");
+/// Must not be supported by an ERC-165 contract
+const INVALID_INTERFACE: FixedBytes<4> = fixed_bytes!("ffffffff");
+const ERC721: FixedBytes<4> = fixed_bytes!("80ac58cd");
+const ERC721_METADATA: FixedBytes<4> = fixed_bytes!("5b5e139f");
+const ERC1155: FixedBytes<4> = fixed_bytes!("d9b67a26");
+const ERC1155_METADATA_URI: FixedBytes<4> = fixed_bytes!("0e89341c");
+
+/// Interfaces checked, in the order of their results.
+const INTERFACES: [FixedBytes<4>; 6] = [
+    ERC165,
+    INVALID_INTERFACE,
+    ERC721,
+    ERC721_METADATA,
+    ERC1155,
+    ERC1155_METADATA_URI,
+];
+
+#[derive(Enum, Serialize, Deserialize, Copy, Clone, Eq, PartialEq, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum TokenStandard {
+    Erc721,
+    Erc1155,
+    /// Neither, or the contract does not implement ERC-165
+    Unknown,
+}
+
+#[derive(Serialize, SimpleObject, Debug, PartialEq)]
+pub struct Interfaces {
+    pub contract: String,
+    pub standard: TokenStandard,
+    pub erc165: bool,
+    pub erc721: bool,
+    pub erc721_metadata: bool,
+    pub erc1155: bool,
+    pub erc1155_metadata_uri: bool,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct BalanceBatchRequest {
+    pub accounts: Vec<Address>,
+    /// Token ids as decimal or `0x` hex strings, one per account
+    pub ids: Vec<String>,
+}
+
+/// Parses a decimal or `0x` hex token id.
+pub fn parse_token_id(value: &str) -> Result<U256, AppError> {
+    U256::from_str(value.trim())
+        .map_err(|_| AppError::Validation(format!("Invalid token id: {}", value)))
+}
+
+/// Interfaces read from the results of the `INTERFACES` checks. A contract is only taken
+/// to support an interface when it passes the ERC-165 detection first.
+fn interfaces(contract: Address, results: &[Multicall3Abi::Result]) -> Interfaces {
+    let supported = |index: usize| {
+        results
+            .get(index)
+            .filter(|result| result.success)
+            .and_then(|result| {
+                supportsInterfaceCall::abi_decode_returns(&result.returnData, true).ok()
+            })
+            .is_some_and(|decoded| decoded._0)
+    };
+    let erc165 = supported(0) && !supported(1);
+    let supports = |index: usize| erc165 && supported(index);
+
+    let erc721 = supports(2);
+    let erc1155 = supports(4);
+    let standard = if erc721 {
+        TokenStandard::Erc721
+    } else if erc1155 {
+        TokenStandard::Erc1155
+    } else {
+        TokenStandard::Unknown
+    };
+
+    Interfaces {
+        contract: contract.to_string(),
+        standard,
+        erc165,
+        erc721,
+        erc721_metadata: supports(3),
+        erc1155,
+        erc1155_metadata_uri: supports(5),
+    }
+}
+
+/// Detects which token interfaces `contract` implements through ERC-165.
+pub async fn detect_interfaces(
+    state: &AppState,
+    chain_id: ChainId,
+    contract: Address,
+) -> Result<Interfaces, AppError> {
+    let calls = INTERFACES
+        .iter()
+        .map(|interface_id| Multicall3Abi::Call3 {
+            target: contract,
+            allowFailure: true,
+            callData: supportsInterfaceCall {
+                interfaceId: *interface_id,
+            }
+            .abi_encode()
+            .into(),
+        })
+        .collect();
+    let results = aggregate(state, chain_id, calls).await?;
+
+    Ok(interfaces(contract, &results))
+}
+
+pub async fn erc721_owner(
+    state: &AppState,
+    chain_id: ChainId,
+    contract: Address,
+    token_id: U256,
+) -> Result<Address, AppError> {
+    state
+        .providers
+        .call(&state.metrics, chain_id, "eth_call", |provider| {
+            get_erc721_owner_query(provider, contract, token_id)
+        })
+        .await
+}
+
+/// Number of tokens of `contract` held by `owner`.
+pub async fn erc721_balance(
+    state: &AppState,
+    chain_id: ChainId,
+    contract: Address,
+    owner: Address,
+) -> Result<U256, AppError> {
+    state
+        .providers
+        .call(&state.metrics, chain_id, "eth_call", |provider| {
+            get_erc721_balance_query(provider, owner, contract)
+        })
+        .await
+}
+
+pub async fn erc721_token_uri(
+    state: &AppState,
+    chain_id: ChainId,
+    contract: Address,
+    token_id: U256,
+) -> Result<String, AppError> {
+    state
+        .providers
+        .call(&state.metrics, chain_id, "eth_call", |provider| {
+            get_erc721_token_uri_query(provider, contract, token_id)
+        })
+        .await
+}
+
+pub async fn erc1155_balance(
+    state: &AppState,
+    chain_id: ChainId,
+    contract: Address,
+    account: Address,
+    token_id: U256,
+) -> Result<U256, AppError> {
+    state
+        .providers
+        .call(&state.metrics, chain_id, "eth_call", |provider| {
+            get_erc1155_balance_query(provider, account, contract, token_id)
+        })
+        .await
+}
+
+/// Balance of each account in the token id at the same position, in one
+/// `balanceOfBatch` call.
+pub async fn erc1155_balance_batch(
+    state: &AppState,
+    chain_id: ChainId,
+    contract: Address,
+    accounts: Vec<Address>,
+    token_ids: Vec<U256>,
+) -> Result<Vec<U256>, AppError> {
+    if accounts.len() != token_ids.len() {
+        return Err(AppError::Validation(format!(
+            "Give one token id per account, got {} accounts and {} ids",
+            accounts.len(),
+            token_ids.len()
+        )));
+    }
+    if accounts.is_empty() || accounts.len() > MAX_BATCH_BALANCES {
+        return Err(AppError::Validation(format!(
+            "Give between 1 and {} balances",
+            MAX_BATCH_BALANCES
+        )));
+    }
+
+    state
+        .providers
+        .call(&state.metrics, chain_id, "eth_call", |provider| {
+            get_erc1155_balance_batch_query(provider, contract, accounts.clone(), token_ids.clone())
+        })
+        .await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::balances::tests::call_result;
+
+    fn answer(supported: bool) -> Multicall3Abi::Result {
+        call_result(Some(supported as u64))
+    }
+
+    #[test]
+    fn detects_the_standard_through_erc165() {
+        let contract = Address::repeat_byte(7);
+
+        let nft = interfaces(
+            contract,
+            &[
+                answer(true),
+                answer(false),
+                answer(true),
+                answer(true),
+                answer(false),
+                answer(false),
+            ],
+        );
+        assert_eq!(nft.standard, TokenStandard::Erc721);
+        assert!(nft.erc721_metadata);
+        assert!(!nft.erc1155);
+
+        let multi_token = interfaces(
+            contract,
+            &[
+                answer(true),
+                answer(false),
+                answer(false),
+                answer(false),
+                answer(true),
+                answer(true),
+            ],
+        );
+        assert_eq!(multi_token.standard, TokenStandard::Erc1155);
+        assert!(multi_token.erc1155_metadata_uri);
+    }
+
+    #[test]
+    fn contracts_failing_erc165_support_nothing() {
+        let contract = Address::repeat_byte(7);
+
+        let pre_erc165 = interfaces(contract, &[(); 6].map(|_| call_result(None)));
+        assert!(!pre_erc165.erc165);
+        assert_eq!(pre_erc165.standard, TokenStandard::Unknown);
+
+        // Answers true to everything, including the invalid interface
+        let permissive = interfaces(contract, &[(); 6].map(|_| answer(true)));
+        assert!(!permissive.erc165);
+        assert!(!permissive.erc721);
+    }
+
+    #[test]
+    fn parses_decimal_and_hex_token_ids() {
+        assert_eq!(parse_token_id("42").unwrap(), U256::from(42));
+        assert_eq!(parse_token_id("0x2a").unwrap(), U256::from(42));
+        assert!(matches!(
+            parse_token_id("forty-two"),
+            Err(AppError::Validation(_))
+        ));
+    }
+}
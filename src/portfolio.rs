@@ -0,0 +1,245 @@
+//! Holdings of a wallet: its native balance and the ERC-20 tokens it holds.
+//!
+//! Tokens are those the wallet sent or received in Sentinel's indexed transactions, plus
+//! the chain's configured `tokens`. Their balance, decimals and symbol are read in one
+//! Multicall3 batch.
+
+use crate::{
+    balances::aggregate,
+    model::{AppError, AppState},
+    rpc_queries::{
+        get_native_balance_query,
+        ERC20Abi::{balanceOfCall, decimalsCall, symbolCall},
+        Multicall3Abi,
+    },
+};
+use alloy::{
+    primitives::{Address, ChainId, U256},
+    rpc::types::eth::BlockId,
+    sol_types::SolCall,
+};
+use async_graphql::SimpleObject;
+use log::debug;
+use serde::Serialize;
+use std::str::FromStr;
+
+/// Most tokens a portfolio reads, each costing three calls.
+const MAX_TOKENS: usize = 300;
+
+#[derive(Serialize, SimpleObject, Debug, PartialEq)]
+pub struct Holding {
+    /// `None` for the native currency
+    pub token: Option<String>,
+    pub symbol: Option<String>,
+    pub decimals: u8,
+    /// In the smallest unit, as a decimal string
+    pub balance: String,
+    /// `balance` with the decimals applied, e.g. `1.5`
+    pub amount: String,
+}
+
+#[derive(Serialize, SimpleObject, Debug)]
+pub struct Portfolio {
+    pub chain_id: u64,
+    pub address: String,
+    /// Non-zero balances, native first
+    pub holdings: Vec<Holding>,
+    /// Set when the wallet traded more tokens than are read, so some holdings are missing
+    pub truncated: bool,
+}
+
+/// Writes `value` with `decimals` decimals, without trailing zeros.
+pub fn format_units(value: U256, decimals: u8) -> String {
+    let digits = value.to_string();
+    let decimals = decimals as usize;
+    if decimals == 0 {
+        return digits;
+    }
+
+    let padded = format!("{:0>width$}", digits, width = decimals + 1);
+    let (whole, fraction) = padded.split_at(padded.len() - decimals);
+    let fraction = fraction.trim_end_matches('0');
+    if fraction.is_empty() {
+        whole.to_string()
+    } else {
+        format!("{}.{}", whole, fraction)
+    }
+}
+
+fn token_calls(token: Address, owner: Address) -> [Multicall3Abi::Call3; 3] {
+    let call = |call_data: Vec<u8>| Multicall3Abi::Call3 {
+        target: token,
+        allowFailure: true,
+        callData: call_data.into(),
+    };
+    [
+        call(balanceOfCall { account: owner }.abi_encode()),
+        call(decimalsCall {}.abi_encode()),
+        call(symbolCall {}.abi_encode()),
+    ]
+}
+
+/// Holding read from the results of `token_calls`, `None` when the balance is zero or
+/// the contract does not answer like an ERC-20 token.
+fn token_holding(token: Address, results: &[Multicall3Abi::Result]) -> Option<Holding> {
+    let [balance, decimals, symbol] = results else {
+        return None;
+    };
+    let decoded = |result: &Multicall3Abi::Result| result.success.then_some(&result.returnData);
+
+    let balance = balanceOfCall::abi_decode_returns(decoded(balance)?, true)
+        .ok()?
+        ._0;
+    if balance.is_zero() {
+        return None;
+    }
+    let decimals = decimalsCall::abi_decode_returns(decoded(decimals)?, true)
+        .ok()?
+        ._0;
+    // Some older tokens return their symbol as bytes32, which is left out
+    let symbol = decoded(symbol)
+        .and_then(|data| symbolCall::abi_decode_returns(data, true).ok())
+        .map(|decoded| decoded._0);
+
+    Some(Holding {
+        token: Some(token.to_string()),
+        symbol,
+        decimals,
+        balance: balance.to_string(),
+        amount: format_units(balance, decimals),
+    })
+}
+
+/// Tokens to read, at most `MAX_TOKENS`: the configured ones first, then those the wallet
+/// traded, by address. Also returns whether any were left out.
+fn tokens_to_read(configured: &[Address], mut traded: Vec<Address>) -> (Vec<Address>, bool) {
+    let mut tokens = Vec::with_capacity(configured.len() + traded.len());
+    for token in configured {
+        if !tokens.contains(token) {
+            tokens.push(*token);
+        }
+    }
+    traded.sort();
+    traded.dedup();
+    traded.retain(|token| !configured.contains(token));
+    tokens.extend(traded);
+
+    let truncated = tokens.len() > MAX_TOKENS;
+    tokens.truncate(MAX_TOKENS);
+    (tokens, truncated)
+}
+
+pub async fn portfolio(
+    state: &AppState,
+    chain_id: ChainId,
+    address: Address,
+) -> Result<Portfolio, AppError> {
+    let chain = state.chains.get(chain_id);
+
+    let traded = state
+        .store
+        .token_contracts(chain_id as i64, &address.to_string())
+        .await?
+        .iter()
+        .filter_map(|token| Address::from_str(token).ok())
+        .collect();
+    let configured = chain.map_or(&[][..], |chain| &chain.tokens[..]);
+    let (tokens, truncated) = tokens_to_read(configured, traded);
+
+    let native = state
+        .providers
+        .call(&state.metrics, chain_id, "eth_getBalance", |provider| {
+            get_native_balance_query(provider, address, BlockId::latest())
+        })
+        .await?;
+
+    let mut holdings = Vec::new();
+    if !native.is_zero() {
+        let decimals = chain.map_or(18, |chain| chain.native_currency.decimals);
+        holdings.push(Holding {
+            token: None,
+            symbol: chain.map(|chain| chain.native_currency.symbol.clone()),
+            decimals,
+            balance: native.to_string(),
+            amount: format_units(native, decimals),
+        });
+    }
+
+    if !tokens.is_empty() {
+        let calls = tokens
+            .iter()
+            .flat_map(|token| token_calls(*token, address))
+            .collect();
+        let results = aggregate(state, chain_id, calls).await?;
+
+        for (token, results) in tokens.iter().zip(results.chunks(3)) {
+            match token_holding(*token, results) {
+                Some(holding) => holdings.push(holding),
+                None => debug!("No balance of {} held by {}", token, address),
+            }
+        }
+    }
+
+    Ok(Portfolio {
+        chain_id,
+        address: address.to_string(),
+        holdings,
+        truncated,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::balances::tests::call_result;
+
+    fn word(value: u64) -> Multicall3Abi::Result {
+        call_result(Some(value))
+    }
+
+    fn failed() -> Multicall3Abi::Result {
+        call_result(None)
+    }
+
+    #[test]
+    fn applies_decimals_without_trailing_zeros() {
+        assert_eq!(format_units(U256::from(1_500_000u64), 6), "1.5");
+        assert_eq!(format_units(U256::from(1u64), 6), "0.000001");
+        assert_eq!(format_units(U256::from(2_000_000u64), 6), "2");
+        assert_eq!(format_units(U256::from(42u64), 0), "42");
+        assert_eq!(format_units(U256::ZERO, 18), "0");
+    }
+
+    #[test]
+    fn skips_zero_balances_and_non_tokens() {
+        let token = Address::repeat_byte(9);
+
+        let holding = token_holding(token, &[word(2_500_000), word(6), failed()]).unwrap();
+        assert_eq!(holding.amount, "2.5");
+        assert_eq!(holding.decimals, 6);
+        assert_eq!(holding.symbol, None);
+
+        assert_eq!(token_holding(token, &[word(0), word(6), failed()]), None);
+        assert_eq!(token_holding(token, &[failed(), word(6), failed()]), None);
+        assert_eq!(token_holding(token, &[word(1), failed(), failed()]), None);
+    }
+
+    #[test]
+    fn reads_configured_tokens_first_and_reports_truncation() {
+        let configured = [Address::repeat_byte(0xff), Address::repeat_byte(0xfe)];
+        let traded: Vec<_> = (0..MAX_TOKENS as u16)
+            .map(|index| Address::left_padding_from(&index.to_be_bytes()))
+            .chain([Address::repeat_byte(0xff)])
+            .collect();
+
+        let (tokens, truncated) = tokens_to_read(&configured, traded.clone());
+        assert!(truncated);
+        assert_eq!(tokens.len(), MAX_TOKENS);
+        assert_eq!(tokens[..2], configured);
+        assert_eq!(tokens[2], traded[0]);
+
+        let (tokens, truncated) = tokens_to_read(&configured, traded[..3].to_vec());
+        assert!(!truncated);
+        assert_eq!(tokens.len(), 5);
+    }
+}
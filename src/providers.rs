@@ -0,0 +1,510 @@
+//! Long-lived JSON-RPC providers per chain, failing over between endpoints.
+//!
+//! Each chain has an ordered list of HTTP or websocket endpoints. Requests go to the first
+//! endpoint that is neither failing nor lagging behind the highest head seen on the chain,
+//! and move on to the next one when an endpoint cannot be reached or times out. An endpoint
+//! that answers, even with an error or nothing found, is not failed over. Every endpoint has its
+//! own request budget, shared by the API and the scanner.
+
+use crate::{
+    metrics::Metrics,
+    model::{AppError, AppState},
+    rate_limit::{Priority, RateLimitConfig, RateLimiter, Rejection},
+};
+use alloy::{
+    contract,
+    primitives::ChainId,
+    providers::{Provider, ProviderBuilder, RootProvider},
+    transports::{BoxTransport, RpcError, TransportError, TransportErrorKind},
+};
+use futures_util::future::join_all;
+use log::warn;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::HashMap,
+    error::Error,
+    fmt,
+    future::Future,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+use tokio::{
+    sync::OnceCell,
+    time::{interval, timeout},
+};
+use tokio_util::sync::CancellationToken;
+
+pub type ChainProvider = RootProvider<BoxTransport>;
+
+/// Error of a query sent through a `ChainProvider`.
+pub type QueryError = Box<dyn Error + Send + Sync>;
+
+/// Error of a query that found nothing, such as a block past the chain head.
+#[derive(Debug)]
+pub struct NotFound(pub String);
+
+impl fmt::Display for NotFound {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl Error for NotFound {}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ProviderConfig {
+    /// Endpoints of each chain, in order of preference
+    pub endpoints: HashMap<ChainId, Vec<String>>,
+    /// Longest a connection attempt or a single request may take
+    pub request_timeout: Duration,
+    pub health_check_interval: Duration,
+    /// Blocks an endpoint may trail the highest head of its chain before it is avoided
+    pub max_lag_blocks: u64,
+    pub rate_limit: RateLimitConfig,
+}
+
+#[derive(Debug, Default)]
+struct EndpointHealth {
+    /// Set when a request fails, cleared by the next success
+    failing: bool,
+    head: Option<u64>,
+}
+
+struct Endpoint {
+    url: String,
+    provider: OnceCell<ChainProvider>,
+    health: Mutex<EndpointHealth>,
+    limiter: RateLimiter,
+}
+
+impl Endpoint {
+    fn new(url: String, limiter: RateLimiter) -> Self {
+        Self {
+            url,
+            provider: OnceCell::new(),
+            health: Mutex::new(EndpointHealth::default()),
+            limiter,
+        }
+    }
+
+    /// Connects on first use, so an endpoint that is down at startup is retried later.
+    async fn provider(&self, request_timeout: Duration) -> Result<ChainProvider, String> {
+        self.provider
+            .get_or_try_init(|| async {
+                match timeout(
+                    request_timeout,
+                    ProviderBuilder::new().on_builtin(&self.url),
+                )
+                .await
+                {
+                    Ok(Ok(provider)) => Ok(provider),
+                    Ok(Err(e)) => Err(format!("connection failed: {}", e)),
+                    Err(_) => Err("connection timed out".to_string()),
+                }
+            })
+            .await
+            .cloned()
+    }
+
+    fn health(&self) -> (bool, Option<u64>) {
+        match self.health.lock() {
+            Ok(health) => (health.failing, health.head),
+            Err(_) => (true, None),
+        }
+    }
+
+    fn record(&self, failing: bool, head: Option<u64>) {
+        if let Ok(mut health) = self.health.lock() {
+            health.failing = failing;
+            if head.is_some() {
+                health.head = head;
+            }
+        }
+    }
+}
+
+struct ChainEndpoints {
+    endpoints: Vec<Endpoint>,
+}
+
+impl ChainEndpoints {
+    fn usable(&self, max_lag_blocks: u64) -> Vec<bool> {
+        let health: Vec<_> = self.endpoints.iter().map(Endpoint::health).collect();
+        usable(&health, max_lag_blocks)
+    }
+}
+
+/// Which endpoints, given whether each is failing and its last known head, requests
+/// should prefer. An endpoint whose head is unknown is not considered lagging.
+fn usable(health: &[(bool, Option<u64>)], max_lag_blocks: u64) -> Vec<bool> {
+    let best_head = health.iter().filter_map(|(_, head)| *head).max();
+    health
+        .iter()
+        .map(|(failing, head)| {
+            let lagging = match (best_head, head) {
+                (Some(best), Some(head)) => best - head > max_lag_blocks,
+                _ => false,
+            };
+            !failing && !lagging
+        })
+        .collect()
+}
+
+/// Indices of the endpoints in the order they are tried: usable ones first, each group
+/// in configured order. Unusable endpoints remain a last resort.
+fn preference(usable: &[bool]) -> Vec<usize> {
+    let mut order: Vec<usize> = (0..usable.len()).collect();
+    order.sort_by_key(|index| !usable[*index]);
+    order
+}
+
+/// Error to return for a failed query when the endpoint did answer, so any other endpoint
+/// would answer the same: a JSON-RPC error, nothing found, or an answer the query could
+/// not use. `None` when the endpoint could not be reached, which is worth failing over.
+fn request_error(error: &(dyn Error + Send + Sync + 'static)) -> Option<AppError> {
+    if let Some(NotFound(message)) = error.downcast_ref::<NotFound>() {
+        return Some(AppError::NotFound(message.clone()));
+    }
+
+    let rpc_error = error.downcast_ref::<TransportError>().or_else(|| {
+        match error.downcast_ref::<contract::Error>() {
+            Some(contract::Error::TransportError(e)) => Some(e),
+            _ => None,
+        }
+    });
+    match rpc_error {
+        Some(RpcError::Transport(_)) => None,
+        Some(RpcError::NullResp) => Some(AppError::NotFound(error.to_string())),
+        Some(RpcError::DeserError { text, .. }) if text.trim() == "null" => {
+            Some(AppError::NotFound(error.to_string()))
+        }
+        _ => Some(AppError::Upstream(error.to_string())),
+    }
+}
+
+pub struct ProviderRegistry {
+    chains: HashMap<ChainId, ChainEndpoints>,
+    request_timeout: Duration,
+    health_check_interval: Duration,
+    max_lag_blocks: u64,
+}
+
+impl ProviderRegistry {
+    pub fn new(config: &ProviderConfig) -> Self {
+        let chains = config
+            .endpoints
+            .iter()
+            .filter(|(_, urls)| !urls.is_empty())
+            .map(|(chain_id, urls)| {
+                let endpoints = urls
+                    .iter()
+                    .map(|url| {
+                        Endpoint::new(url.clone(), RateLimiter::new(&config.rate_limit, *chain_id))
+                    })
+                    .collect();
+                (*chain_id, ChainEndpoints { endpoints })
+            })
+            .collect();
+
+        Self {
+            chains,
+            request_timeout: config.request_timeout,
+            health_check_interval: config.health_check_interval,
+            max_lag_blocks: config.max_lag_blocks,
+        }
+    }
+
+    pub fn supports(&self, chain_id: ChainId) -> bool {
+        self.chains.contains_key(&chain_id)
+    }
+
+    /// Highest head block the health checks have seen on `chain_id`.
+    pub fn head(&self, chain_id: ChainId) -> Option<u64> {
+        self.chains
+            .get(&chain_id)?
+            .endpoints
+            .iter()
+            .filter_map(|endpoint| endpoint.health().1)
+            .max()
+    }
+
+    /// Waits for the budget of `endpoint` to allow `method`, recording the wait or the
+    /// rejection.
+    async fn acquire(
+        &self,
+        metrics: &Metrics,
+        chain_id: ChainId,
+        endpoint: &Endpoint,
+        method: &str,
+        priority: Priority,
+    ) -> Result<(), Rejection> {
+        let chain = chain_id.to_string();
+        let labels = [chain.as_str(), priority.as_str()];
+        let queued = metrics.rpc_rate_limit_queued.with_label_values(&labels);
+
+        queued.inc();
+        let acquired = endpoint.limiter.acquire(method, priority).await;
+        queued.dec();
+
+        match acquired {
+            Ok(waited) => {
+                metrics
+                    .rpc_rate_limit_wait
+                    .with_label_values(&labels)
+                    .observe(waited.as_secs_f64());
+                Ok(())
+            }
+            Err(rejection) => {
+                metrics
+                    .rpc_rate_limit_rejections
+                    .with_label_values(&[labels[0], labels[1], rejection.as_str()])
+                    .inc();
+                Err(rejection)
+            }
+        }
+    }
+
+    /// Takes the budget of `method` from the endpoint of `chain_id` at `url`, for requests
+    /// sent over a connection of their own such as the scanner's websocket. Endpoints
+    /// outside the registry are not limited.
+    pub async fn throttle(
+        &self,
+        metrics: &Metrics,
+        chain_id: ChainId,
+        url: &str,
+        method: &str,
+        priority: Priority,
+    ) -> Result<(), AppError> {
+        let Some(endpoint) = self
+            .chains
+            .get(&chain_id)
+            .and_then(|chain| chain.endpoints.iter().find(|endpoint| endpoint.url == url))
+        else {
+            return Ok(());
+        };
+
+        self.acquire(metrics, chain_id, endpoint, method, priority)
+            .await
+            .map_err(|rejection| {
+                AppError::RateLimited(format!(
+                    "{} on chain {}: {}",
+                    method,
+                    chain_id,
+                    rejection.as_str()
+                ))
+            })
+    }
+
+    /// Sends `request` to the preferred endpoint of `chain_id` on behalf of a client.
+    /// See `call_with_priority`.
+    pub async fn call<T, F, Fut>(
+        &self,
+        metrics: &Metrics,
+        chain_id: ChainId,
+        method: &str,
+        request: F,
+    ) -> Result<T, AppError>
+    where
+        F: Fn(ChainProvider) -> Fut,
+        Fut: Future<Output = Result<T, QueryError>>,
+    {
+        self.call_with_priority(metrics, chain_id, method, Priority::Interactive, request)
+            .await
+    }
+
+    /// Sends `request` to the preferred endpoint of `chain_id` once its budget allows,
+    /// failing over to the next one when it cannot be reached, times out or has no budget
+    /// left. Answers that are errors or find nothing are returned as they are.
+    /// `method` labels the request in the metrics and sets its cost.
+    pub async fn call_with_priority<T, F, Fut>(
+        &self,
+        metrics: &Metrics,
+        chain_id: ChainId,
+        method: &str,
+        priority: Priority,
+        request: F,
+    ) -> Result<T, AppError>
+    where
+        F: Fn(ChainProvider) -> Fut,
+        Fut: Future<Output = Result<T, QueryError>>,
+    {
+        let chain = self
+            .chains
+            .get(&chain_id)
+            .ok_or(AppError::UnsupportedChain(chain_id))?;
+
+        let mut failures = Vec::new();
+        let mut sent = false;
+        for (attempt, index) in preference(&chain.usable(self.max_lag_blocks))
+            .into_iter()
+            .enumerate()
+        {
+            let endpoint = &chain.endpoints[index];
+            if attempt > 0 {
+                metrics
+                    .rpc_failovers
+                    .with_label_values(&[&chain_id.to_string()])
+                    .inc();
+            }
+
+            // A spent budget says nothing about the health of the endpoint
+            if let Err(rejection) = self
+                .acquire(metrics, chain_id, endpoint, method, priority)
+                .await
+            {
+                failures.push(format!("endpoint {}: {}", index, rejection.as_str()));
+                continue;
+            }
+            sent = true;
+
+            let started = Instant::now();
+            let result = match endpoint.provider(self.request_timeout).await {
+                Ok(provider) => match timeout(self.request_timeout, request(provider)).await {
+                    Ok(Ok(value)) => Ok(value),
+                    Ok(Err(e)) => match request_error(e.as_ref()) {
+                        Some(error) => {
+                            // The endpoint works, the request does not
+                            metrics.observe_rpc(chain_id, method, started);
+                            endpoint.record(false, None);
+                            return Err(error);
+                        }
+                        None => Err(e.to_string()),
+                    },
+                    Err(_) => Err("request timed out".to_string()),
+                },
+                Err(e) => Err(e),
+            };
+            metrics.observe_rpc(chain_id, method, started);
+
+            match result {
+                Ok(value) => {
+                    endpoint.record(false, None);
+                    return Ok(value);
+                }
+                Err(e) => {
+                    warn!(
+                        "{} failed on endpoint {} of chain {}: {}",
+                        method, index, chain_id, e
+                    );
+                    endpoint.record(true, None);
+                    failures.push(format!("endpoint {}: {}", index, e));
+                }
+            }
+        }
+
+        if !sent {
+            return Err(AppError::RateLimited(format!(
+                "no endpoint of chain {} has budget left for {} ({})",
+                chain_id,
+                method,
+                failures.join(", ")
+            )));
+        }
+        Err(AppError::Upstream(format!(
+            "{} failed on every endpoint of chain {} ({})",
+            method,
+            chain_id,
+            failures.join(", ")
+        )))
+    }
+
+    /// Polls the head block of every endpoint, marking those that fail to answer. Endpoints
+    /// without budget to spare are checked next time.
+    pub async fn check_health(&self, metrics: &Metrics) {
+        let checks = self.chains.iter().flat_map(|(chain_id, chain)| {
+            chain.endpoints.iter().map(move |endpoint| async move {
+                if !endpoint.limiter.try_acquire("eth_blockNumber") {
+                    return;
+                }
+                let head = match endpoint.provider(self.request_timeout).await {
+                    Ok(provider) => {
+                        match timeout(self.request_timeout, provider.get_block_number()).await {
+                            Ok(Ok(head)) => Ok(head),
+                            Ok(Err(e)) => Err(e.to_string()),
+                            Err(_) => Err("request timed out".to_string()),
+                        }
+                    }
+                    Err(e) => Err(e),
+                };
+                match head {
+                    Ok(head) => endpoint.record(false, Some(head)),
+                    Err(e) => {
+                        warn!(
+                            "Health check of an endpoint of chain {} failed: {}",
+                            chain_id, e
+                        );
+                        endpoint.record(true, None);
+                    }
+                }
+            })
+        });
+        join_all(checks).await;
+
+        for (chain_id, chain) in &self.chains {
+            let label = chain_id.to_string();
+            for (index, usable) in chain.usable(self.max_lag_blocks).into_iter().enumerate() {
+                metrics
+                    .rpc_endpoint_usable
+                    .with_label_values(&[&label, &index.to_string()])
+                    .set(usable as i64);
+            }
+        }
+    }
+}
+
+/// Checks the health of every endpoint every `health_check_interval` until `shutdown`
+/// is cancelled.
+pub async fn run_health_checks(state: Arc<AppState>, shutdown: CancellationToken) {
+    let mut ticker = interval(state.providers.health_check_interval);
+    loop {
+        tokio::select! {
+            _ = shutdown.cancelled() => return,
+            _ = ticker.tick() => {}
+        }
+        state.providers.check_health(&state.metrics).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn failing_and_lagging_endpoints_are_not_usable() {
+        let health = [
+            (false, Some(100)),
+            (true, Some(101)),
+            (false, Some(90)),
+            (false, None),
+        ];
+        assert_eq!(usable(&health, 5), vec![true, false, false, true]);
+        assert_eq!(usable(&health, 20), vec![true, false, true, true]);
+    }
+
+    #[test]
+    fn only_unreachable_endpoints_are_failed_over() {
+        let unreachable: TransportError = RpcError::Transport(TransportErrorKind::BackendGone);
+        assert!(request_error(&unreachable).is_none());
+
+        let null: TransportError = RpcError::NullResp;
+        assert!(matches!(request_error(&null), Some(AppError::NotFound(_))));
+
+        let missing = NotFound("Block 7 not found".into());
+        assert!(matches!(
+            request_error(&missing),
+            Some(AppError::NotFound(_))
+        ));
+
+        let unreadable: QueryError = "unexpected answer".into();
+        assert!(matches!(
+            request_error(unreadable.as_ref()),
+            Some(AppError::Upstream(_))
+        ));
+    }
+
+    #[test]
+    fn unusable_endpoints_are_tried_last_in_configured_order() {
+        assert_eq!(preference(&[false, true, false, true]), vec![1, 3, 0, 2]);
+        assert_eq!(preference(&[true, true]), vec![0, 1]);
+    }
+}
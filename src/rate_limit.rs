@@ -0,0 +1,310 @@
+//! Token buckets keeping the requests sent to each upstream endpoint within its plan.
+//!
+//! Requests are weighted by the compute units providers bill their method at. Background
+//! work, such as the scanner's lookups and backfills, yields to interactive API requests
+//! and gives up sooner when the budget is exhausted.
+
+use alloy::primitives::ChainId;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Mutex,
+    },
+    time::{Duration, Instant},
+};
+use tokio::time::sleep;
+
+/// How long background requests wait before checking again whether interactive ones
+/// are still queued.
+const YIELD_DELAY: Duration = Duration::from_millis(50);
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RateLimitConfig {
+    /// Sustained budget of every endpoint
+    pub compute_units_per_second: u64,
+    /// Budget of the endpoints of specific chains
+    pub per_chain: HashMap<ChainId, u64>,
+    /// Compute units that may be spent at once after a quiet period, as a multiple of
+    /// the per second budget
+    pub burst_seconds: u64,
+    /// Requests that may wait for an endpoint at once before new ones are rejected
+    pub max_queue: usize,
+    pub interactive_max_wait: Duration,
+    pub background_max_wait: Duration,
+}
+
+impl RateLimitConfig {
+    pub fn compute_units_per_second(&self, chain_id: ChainId) -> u64 {
+        self.per_chain
+            .get(&chain_id)
+            .copied()
+            .unwrap_or(self.compute_units_per_second)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Priority {
+    /// Requests a client is waiting on
+    Interactive,
+    Background,
+}
+
+impl Priority {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Priority::Interactive => "interactive",
+            Priority::Background => "background",
+        }
+    }
+}
+
+/// Why a request was not let through.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Rejection {
+    QueueFull,
+    /// The budget would not allow the request before its maximum wait
+    Exhausted,
+}
+
+impl Rejection {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Rejection::QueueFull => "queue_full",
+            Rejection::Exhausted => "budget_exhausted",
+        }
+    }
+}
+
+/// Compute units providers typically bill a JSON-RPC method at.
+pub fn compute_units(method: &str) -> u32 {
+    match method {
+        "eth_blockNumber" | "eth_chainId" => 10,
+        "eth_getTransactionReceipt" => 15,
+        "eth_getBlockByNumber" | "eth_getBlockByHash" => 16,
+        "eth_getTransactionByHash" => 17,
+        "eth_getBalance" | "eth_getCode" | "eth_getStorageAt" | "eth_getTransactionCount" => 19,
+        "eth_call" => 26,
+        "eth_getLogs" => 75,
+        "eth_estimateGas" => 87,
+        "debug_traceTransaction" | "debug_traceCall" => 309,
+        _ => 20,
+    }
+}
+
+#[derive(Debug)]
+pub struct TokenBucket {
+    capacity: f64,
+    refill_per_second: f64,
+    tokens: f64,
+    updated_at: Instant,
+}
+
+impl TokenBucket {
+    /// A full bucket.
+    pub fn new(capacity: f64, refill_per_second: f64, now: Instant) -> Self {
+        Self {
+            capacity,
+            refill_per_second,
+            tokens: capacity,
+            updated_at: now,
+        }
+    }
+
+    fn refill(&mut self, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.updated_at).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.refill_per_second).min(self.capacity);
+        self.updated_at = now;
+    }
+
+    /// Takes `cost` tokens, or tells how long until there are enough of them. A cost above
+    /// the capacity is capped to it, so it can still go through with a full bucket.
+    pub fn try_take(&mut self, cost: f64, now: Instant) -> Result<(), Duration> {
+        self.refill(now);
+        let cost = cost.min(self.capacity);
+        if self.tokens >= cost {
+            self.tokens -= cost;
+            Ok(())
+        } else {
+            Err(Duration::from_secs_f64(
+                (cost - self.tokens) / self.refill_per_second,
+            ))
+        }
+    }
+}
+
+/// Decrements a counter when a waiting request is done, however it ends.
+struct Waiting<'a>(&'a AtomicUsize);
+
+impl<'a> Waiting<'a> {
+    fn new(counter: &'a AtomicUsize) -> Self {
+        counter.fetch_add(1, Ordering::SeqCst);
+        Self(counter)
+    }
+}
+
+impl Drop for Waiting<'_> {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+pub struct RateLimiter {
+    bucket: Mutex<TokenBucket>,
+    queued: AtomicUsize,
+    interactive_waiting: AtomicUsize,
+    max_queue: usize,
+    interactive_max_wait: Duration,
+    background_max_wait: Duration,
+}
+
+impl RateLimiter {
+    pub fn new(config: &RateLimitConfig, chain_id: ChainId) -> Self {
+        let per_second = config.compute_units_per_second(chain_id) as f64;
+        Self {
+            bucket: Mutex::new(TokenBucket::new(
+                per_second * config.burst_seconds as f64,
+                per_second,
+                Instant::now(),
+            )),
+            queued: AtomicUsize::new(0),
+            interactive_waiting: AtomicUsize::new(0),
+            max_queue: config.max_queue,
+            interactive_max_wait: config.interactive_max_wait,
+            background_max_wait: config.background_max_wait,
+        }
+    }
+
+    /// Takes the compute units of `method` without waiting, if they are available.
+    pub fn try_acquire(&self, method: &str) -> bool {
+        if self.interactive_waiting.load(Ordering::SeqCst) > 0 {
+            return false;
+        }
+        match self.bucket.lock() {
+            Ok(mut bucket) => bucket
+                .try_take(compute_units(method) as f64, Instant::now())
+                .is_ok(),
+            Err(_) => false,
+        }
+    }
+
+    /// Waits until the compute units of `method` are available, returning how long that
+    /// took. Background requests only take tokens while no interactive request waits.
+    pub async fn acquire(&self, method: &str, priority: Priority) -> Result<Duration, Rejection> {
+        let started = Instant::now();
+        let deadline = started
+            + match priority {
+                Priority::Interactive => self.interactive_max_wait,
+                Priority::Background => self.background_max_wait,
+            };
+        let cost = compute_units(method) as f64;
+
+        if self.queued.load(Ordering::SeqCst) >= self.max_queue {
+            return Err(Rejection::QueueFull);
+        }
+        let _queued = Waiting::new(&self.queued);
+        let _interactive =
+            (priority == Priority::Interactive).then(|| Waiting::new(&self.interactive_waiting));
+
+        loop {
+            let wait = if priority == Priority::Background
+                && self.interactive_waiting.load(Ordering::SeqCst) > 0
+            {
+                YIELD_DELAY
+            } else {
+                let taken = match self.bucket.lock() {
+                    Ok(mut bucket) => bucket.try_take(cost, Instant::now()),
+                    Err(_) => return Err(Rejection::Exhausted),
+                };
+                match taken {
+                    Ok(()) => return Ok(started.elapsed()),
+                    Err(wait) => wait,
+                }
+            };
+
+            if Instant::now() + wait > deadline {
+                return Err(Rejection::Exhausted);
+            }
+            sleep(wait).await;
+        }
+    }
+
+    /// Requests currently waiting for this endpoint.
+    pub fn queued(&self) -> usize {
+        self.queued.load(Ordering::SeqCst)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(compute_units_per_second: u64, max_queue: usize) -> RateLimitConfig {
+        RateLimitConfig {
+            compute_units_per_second,
+            per_chain: HashMap::from([(8453, 1000)]),
+            burst_seconds: 1,
+            max_queue,
+            interactive_max_wait: Duration::from_secs(1),
+            background_max_wait: Duration::ZERO,
+        }
+    }
+
+    #[test]
+    fn bucket_refills_at_its_rate() {
+        let now = Instant::now();
+        let mut bucket = TokenBucket::new(100.0, 50.0, now);
+
+        assert!(bucket.try_take(60.0, now).is_ok());
+        assert_eq!(bucket.try_take(60.0, now), Err(Duration::from_millis(400)));
+        assert!(bucket
+            .try_take(60.0, now + Duration::from_millis(400))
+            .is_ok());
+    }
+
+    #[test]
+    fn bucket_never_holds_more_than_its_capacity() {
+        let now = Instant::now();
+        let mut bucket = TokenBucket::new(100.0, 50.0, now);
+
+        let later = now + Duration::from_secs(60);
+        assert!(bucket.try_take(100.0, later).is_ok());
+        assert!(bucket.try_take(1.0, later).is_err());
+        // Costs above the capacity wait for a full bucket rather than forever
+        assert_eq!(bucket.try_take(500.0, later), Err(Duration::from_secs(2)));
+    }
+
+    #[test]
+    fn chains_can_override_the_budget() {
+        let config = config(300, 10);
+        assert_eq!(config.compute_units_per_second(1), 300);
+        assert_eq!(config.compute_units_per_second(8453), 1000);
+    }
+
+    #[tokio::test]
+    async fn background_requests_give_up_when_the_budget_is_spent() {
+        let limiter = RateLimiter::new(&config(20, 10), 1);
+
+        assert!(limiter
+            .acquire("eth_getTransactionByHash", Priority::Background)
+            .await
+            .is_ok());
+        assert_eq!(
+            limiter
+                .acquire("eth_getTransactionByHash", Priority::Background)
+                .await,
+            Err(Rejection::Exhausted)
+        );
+        assert_eq!(limiter.queued(), 0);
+    }
+
+    #[tokio::test]
+    async fn rejects_requests_past_the_queue_limit() {
+        let limiter = RateLimiter::new(&config(20, 0), 1);
+        assert_eq!(
+            limiter.acquire("eth_call", Priority::Interactive).await,
+            Err(Rejection::QueueFull)
+        );
+    }
+}
@@ -0,0 +1,263 @@
+//! Enforces how long raw transactions and their internal calls, balance snapshots,
+//! simulations, JSON responses and rotated CSV files are kept.
+
+use crate::{
+    model::{AppError, AppState},
+    store::{PruneScope, Pruned, TransactionStore},
+    utils::{CSV_PATH, RESPONSES_DIR},
+};
+use alloy::primitives::ChainId;
+use log::{error, info};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::HashMap,
+    fs, io,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::{Duration, SystemTime},
+};
+use tokio::time::interval;
+use tokio_util::sync::CancellationToken;
+
+/// Number of days data is kept for, by chain.
+#[derive(Deserialize, Serialize, Debug, Default, Clone)]
+pub struct Retention {
+    /// Applies to chains without an override. `None` keeps data forever
+    pub default_days: Option<u64>,
+    pub per_chain: HashMap<ChainId, u64>,
+}
+
+impl Retention {
+    pub fn days_for(&self, chain_id: ChainId) -> Option<u64> {
+        self.per_chain.get(&chain_id).copied().or(self.default_days)
+    }
+
+    pub fn is_unbounded(&self) -> bool {
+        self.default_days.is_none() && self.per_chain.is_empty()
+    }
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct RetentionPolicy {
+    pub transactions: Retention,
+    pub responses: Retention,
+    /// Days rotated CSV files are kept for
+    pub csv_days: Option<u64>,
+    pub interval: Duration,
+}
+
+#[derive(Debug, Default, Clone, Copy)]
+pub struct PruneReport {
+    pub transactions: u64,
+    pub balance_snapshots: u64,
+    pub simulations: u64,
+    pub internal_calls: u64,
+    pub responses: u64,
+    pub csv_files: u64,
+}
+
+/// Deletes everything older than the policy allows.
+///
+/// The rollups are refreshed first, so pruned transactions stay accounted for in the
+/// aggregate statistics.
+pub async fn prune(
+    store: &dyn TransactionStore,
+    policy: &RetentionPolicy,
+) -> Result<PruneReport, AppError> {
+    let mut report = PruneReport::default();
+
+    if !policy.transactions.is_unbounded() {
+        store.refresh_rollups().await?;
+        let pruned = prune_transactions(store, &policy.transactions).await?;
+        report.transactions = pruned.transactions;
+        report.balance_snapshots = pruned.balance_snapshots;
+        report.simulations = pruned.simulations;
+        report.internal_calls = pruned.internal_calls;
+    }
+
+    let responses = policy.responses.clone();
+    let csv_days = policy.csv_days;
+    let (responses, csv_files) = tokio::task::spawn_blocking(move || {
+        let responses = prune_responses(Path::new(RESPONSES_DIR), &responses)?;
+        let csv_files = match csv_days {
+            Some(days) => prune_rotated_csv(Path::new(CSV_PATH), days)?,
+            None => 0,
+        };
+        Ok::<_, io::Error>((responses, csv_files))
+    })
+    .await
+    .map_err(|e| AppError::Other(e.to_string()))??;
+
+    report.responses = responses;
+    report.csv_files = csv_files;
+    Ok(report)
+}
+
+/// Prunes according to `policy` every `policy.interval` until `shutdown` is cancelled.
+pub async fn run_retention(
+    state: Arc<AppState>,
+    policy: RetentionPolicy,
+    shutdown: CancellationToken,
+) {
+    let mut ticker = interval(policy.interval);
+    loop {
+        tokio::select! {
+            _ = shutdown.cancelled() => return,
+            _ = ticker.tick() => {}
+        }
+        match prune(state.store.as_ref(), &policy).await {
+            Ok(report) => {
+                info!(
+                    "Pruned {} transactions, {} internal calls, {} balance snapshots, {} simulations, {} responses and {} CSV files",
+                    report.transactions,
+                    report.internal_calls,
+                    report.balance_snapshots,
+                    report.simulations,
+                    report.responses,
+                    report.csv_files
+                );
+                let pruned = &state.metrics.pruned;
+                pruned
+                    .with_label_values(&["transactions"])
+                    .inc_by(report.transactions);
+                pruned
+                    .with_label_values(&["internal_calls"])
+                    .inc_by(report.internal_calls);
+                pruned
+                    .with_label_values(&["balance_snapshots"])
+                    .inc_by(report.balance_snapshots);
+                pruned
+                    .with_label_values(&["simulations"])
+                    .inc_by(report.simulations);
+                pruned
+                    .with_label_values(&["responses"])
+                    .inc_by(report.responses);
+                pruned
+                    .with_label_values(&["csv_files"])
+                    .inc_by(report.csv_files);
+            }
+            Err(e) => error!("Error enforcing retention: {:?}", e),
+        }
+    }
+}
+
+async fn prune_transactions(
+    store: &dyn TransactionStore,
+    retention: &Retention,
+) -> Result<Pruned, AppError> {
+    let mut deleted = Pruned::default();
+
+    for (chain_id, days) in &retention.per_chain {
+        deleted += store
+            .delete_older_than(&PruneScope::Chain(*chain_id as i64), *days)
+            .await?;
+    }
+
+    if let Some(days) = retention.default_days {
+        let overridden = retention.per_chain.keys().map(|id| *id as i64).collect();
+        deleted += store
+            .delete_older_than(&PruneScope::AllExcept(overridden), days)
+            .await?;
+    }
+
+    Ok(deleted)
+}
+
+/// Removes response files older than their chain's retention.
+///
+/// Responses live in `<dir>/<chain id>/`. Files directly in `<dir>`, written before
+/// responses were split by chain, follow the default retention.
+fn prune_responses(dir: &Path, retention: &Retention) -> Result<u64, io::Error> {
+    if retention.is_unbounded() || !dir.exists() {
+        return Ok(0);
+    }
+
+    let mut removed = 0;
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.is_dir() {
+            let chain_id = path
+                .file_name()
+                .and_then(|name| name.to_str())
+                .and_then(|name| name.parse::<ChainId>().ok());
+            let days = match chain_id {
+                Some(chain_id) => retention.days_for(chain_id),
+                None => retention.default_days,
+            };
+            if let Some(days) = days {
+                removed += remove_older_than(&files_in(&path)?, days)?;
+            }
+        } else if let Some(days) = retention.default_days {
+            removed += remove_older_than(&[path], days)?;
+        }
+    }
+
+    Ok(removed)
+}
+
+/// Removes the rotated versions of `csv_path` older than `days`.
+fn prune_rotated_csv(csv_path: &Path, days: u64) -> Result<u64, io::Error> {
+    let dir = match csv_path.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent,
+        _ => Path::new("."),
+    };
+    let prefix = format!(
+        "{}-",
+        csv_path.file_stem().unwrap_or_default().to_string_lossy()
+    );
+
+    let rotated: Vec<PathBuf> = files_in(dir)?
+        .into_iter()
+        .filter(|path| {
+            let name = path.file_name().unwrap_or_default().to_string_lossy();
+            name.starts_with(&prefix) && (name.ends_with(".csv.gz") || name.ends_with(".csv"))
+        })
+        .collect();
+
+    remove_older_than(&rotated, days)
+}
+
+fn files_in(dir: &Path) -> Result<Vec<PathBuf>, io::Error> {
+    let mut files = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.is_file() {
+            files.push(path);
+        }
+    }
+    Ok(files)
+}
+
+fn remove_older_than(files: &[PathBuf], days: u64) -> Result<u64, io::Error> {
+    let cutoff = SystemTime::now() - Duration::from_secs(days * 86_400);
+    let mut removed = 0;
+
+    for file in files {
+        // Gone since it was listed, compressed or removed concurrently
+        let modified = match fs::metadata(file).and_then(|metadata| metadata.modified()) {
+            Ok(modified) => modified,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => continue,
+            Err(e) => return Err(e),
+        };
+        if modified < cutoff {
+            match fs::remove_file(file) {
+                Ok(()) => removed += 1,
+                Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    Ok(removed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn files_gone_since_listing_are_skipped() {
+        let missing = std::env::temp_dir().join("sentinel-retention-missing.csv.gz");
+        assert_eq!(remove_older_than(&[missing], 0).unwrap(), 0);
+    }
+}
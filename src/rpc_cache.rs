@@ -0,0 +1,343 @@
+//! Caches RPC responses that can no longer change, so repeated lookups of finalized
+//! blocks, mined transactions and their receipts never reach the upstream node.
+//!
+//! Blocks fetched by hash are cached as they are. Blocks fetched by number, transactions
+//! and receipts are only cached for good once their block is `finality_blocks` behind the
+//! chain head, and otherwise, like anything asked for by tag, for `latest_ttl`.
+
+use crate::{
+    model::{AppError, AppState},
+    rpc_queries::{get_block_query, get_transaction_query, get_transaction_receipt_query},
+};
+use alloy::{
+    primitives::{ChainId, TxHash},
+    rpc::types::eth::{Block, BlockId, BlockNumberOrTag, Transaction, TransactionReceipt},
+};
+use log::warn;
+use lru::LruCache;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use serde_json::Value;
+use std::{
+    future::Future,
+    num::NonZeroUsize,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RpcCacheConfig {
+    /// Responses kept in memory
+    pub capacity: usize,
+    /// How long responses that may still change are cached for
+    pub latest_ttl: Duration,
+    /// Depth past which a block is considered final
+    pub finality_blocks: u64,
+    /// Also keep final responses in the database, so they survive restarts
+    pub persist: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Kind {
+    Block,
+    Transaction,
+    Receipt,
+}
+
+impl Kind {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Kind::Block => "block",
+            Kind::Transaction => "transaction",
+            Kind::Receipt => "receipt",
+        }
+    }
+}
+
+/// How long a response may be cached for.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Freshness {
+    Final,
+    Ttl,
+    /// Not worth caching, e.g. an unknown block
+    Uncached,
+}
+
+struct Entry {
+    value: Value,
+    expires_at: Option<Instant>,
+}
+
+pub struct RpcCache {
+    entries: Mutex<LruCache<String, Entry>>,
+    latest_ttl: Duration,
+    finality_blocks: u64,
+    persist: bool,
+}
+
+impl RpcCache {
+    pub fn new(config: &RpcCacheConfig) -> Self {
+        let capacity = NonZeroUsize::new(config.capacity).unwrap_or(NonZeroUsize::MIN);
+        Self {
+            entries: Mutex::new(LruCache::new(capacity)),
+            latest_ttl: config.latest_ttl,
+            finality_blocks: config.finality_blocks,
+            persist: config.persist,
+        }
+    }
+
+    fn get_memory(&self, key: &str) -> Option<Value> {
+        let mut entries = self.entries.lock().ok()?;
+        match entries.get(key) {
+            Some(entry) if matches!(entry.expires_at, Some(at) if at <= Instant::now()) => {
+                entries.pop(key);
+                None
+            }
+            Some(entry) => Some(entry.value.clone()),
+            None => None,
+        }
+    }
+
+    fn put_memory(&self, key: String, value: Value, freshness: Freshness) {
+        let expires_at = match freshness {
+            Freshness::Final => None,
+            Freshness::Ttl => Some(Instant::now() + self.latest_ttl),
+            Freshness::Uncached => return,
+        };
+        if let Ok(mut entries) = self.entries.lock() {
+            entries.put(key, Entry { value, expires_at });
+        }
+    }
+
+    /// Whether a block is deep enough below the chain head to never change again.
+    fn is_final(&self, block_number: Option<u64>, head: Option<u64>) -> bool {
+        matches!(
+            (block_number, head),
+            (Some(number), Some(head)) if number + self.finality_blocks <= head
+        )
+    }
+}
+
+/// How long a block asked for by `block_id` may be cached, with the chain head at `head`.
+fn block_freshness(cache: &RpcCache, block_id: BlockId, head: Option<u64>) -> Freshness {
+    match block_id {
+        BlockId::Hash(_) => Freshness::Final,
+        BlockId::Number(BlockNumberOrTag::Number(number)) if cache.is_final(Some(number), head) => {
+            Freshness::Final
+        }
+        BlockId::Number(_) => Freshness::Ttl,
+    }
+}
+
+fn block_key(chain_id: ChainId, block_id: BlockId) -> String {
+    match block_id {
+        BlockId::Hash(hash) => format!("block:{}:{}", chain_id, hash.block_hash),
+        BlockId::Number(number) => format!("block:{}:{}", chain_id, number),
+    }
+}
+
+/// Looks `key` up in memory, then in the database, and fetches it upstream on a miss.
+/// `freshness` decides how long the fetched value may be kept.
+async fn get_or_fetch<T, Fut>(
+    state: &AppState,
+    kind: Kind,
+    key: String,
+    fetch: Fut,
+    freshness: impl Fn(&T) -> Freshness,
+) -> Result<T, AppError>
+where
+    T: Serialize + DeserializeOwned,
+    Fut: Future<Output = Result<T, AppError>>,
+{
+    let cache = &state.rpc_cache;
+    let metrics = &state.metrics;
+
+    if let Some(value) = cache.get_memory(&key) {
+        if let Ok(value) = serde_json::from_value(value) {
+            metrics
+                .rpc_cache_hits
+                .with_label_values(&[kind.as_str(), "memory"])
+                .inc();
+            return Ok(value);
+        }
+    }
+
+    if cache.persist {
+        match state.store.load_cached(&key).await {
+            Ok(Some(value)) => {
+                if let Ok(parsed) = serde_json::from_value(value.clone()) {
+                    metrics
+                        .rpc_cache_hits
+                        .with_label_values(&[kind.as_str(), "database"])
+                        .inc();
+                    cache.put_memory(key, value, Freshness::Final);
+                    return Ok(parsed);
+                }
+            }
+            Ok(None) => {}
+            Err(e) => warn!("Error reading the RPC cache: {:?}", e),
+        }
+    }
+
+    metrics
+        .rpc_cache_misses
+        .with_label_values(&[kind.as_str()])
+        .inc();
+    let fetched = fetch.await?;
+
+    let freshness = freshness(&fetched);
+    if freshness != Freshness::Uncached {
+        let value = serde_json::to_value(&fetched)?;
+        if cache.persist && freshness == Freshness::Final {
+            if let Err(e) = state.store.save_cached(&key, &value).await {
+                warn!("Error writing the RPC cache: {:?}", e);
+            }
+        }
+        cache.put_memory(key, value, freshness);
+    }
+    Ok(fetched)
+}
+
+pub async fn cached_block(
+    state: &AppState,
+    chain_id: ChainId,
+    block_id: BlockId,
+) -> Result<Option<Block>, AppError> {
+    let method = match block_id {
+        BlockId::Hash(_) => "eth_getBlockByHash",
+        BlockId::Number(_) => "eth_getBlockByNumber",
+    };
+    let fetch = state
+        .providers
+        .call(&state.metrics, chain_id, method, |provider| {
+            get_block_query(provider, block_id)
+        });
+
+    let head = state.providers.head(chain_id);
+    get_or_fetch(
+        state,
+        Kind::Block,
+        block_key(chain_id, block_id),
+        fetch,
+        |block| match block {
+            Some(_) => block_freshness(&state.rpc_cache, block_id, head),
+            None => Freshness::Uncached,
+        },
+    )
+    .await
+}
+
+pub async fn cached_transaction(
+    state: &AppState,
+    chain_id: ChainId,
+    tx_hash: TxHash,
+) -> Result<Transaction, AppError> {
+    let fetch = state.providers.call(
+        &state.metrics,
+        chain_id,
+        "eth_getTransactionByHash",
+        |provider| get_transaction_query(provider, tx_hash),
+    );
+
+    let head = state.providers.head(chain_id);
+    let key = format!("transaction:{}:{}", chain_id, tx_hash);
+    get_or_fetch(
+        state,
+        Kind::Transaction,
+        key,
+        fetch,
+        |transaction| match transaction {
+            Some(transaction) if state.rpc_cache.is_final(transaction.block_number, head) => {
+                Freshness::Final
+            }
+            Some(_) => Freshness::Ttl,
+            // Not broadcast yet, or dropped: the next request asks again
+            None => Freshness::Uncached,
+        },
+    )
+    .await?
+    .ok_or_else(|| AppError::NotFound(format!("Transaction {} not found", tx_hash)))
+}
+
+pub async fn cached_receipt(
+    state: &AppState,
+    chain_id: ChainId,
+    tx_hash: TxHash,
+) -> Result<Option<TransactionReceipt>, AppError> {
+    let fetch = state.providers.call(
+        &state.metrics,
+        chain_id,
+        "eth_getTransactionReceipt",
+        |provider| get_transaction_receipt_query(provider, tx_hash),
+    );
+
+    let head = state.providers.head(chain_id);
+    let key = format!("receipt:{}:{}", chain_id, tx_hash);
+    get_or_fetch(state, Kind::Receipt, key, fetch, |receipt| match receipt {
+        Some(receipt) if state.rpc_cache.is_final(receipt.block_number, head) => Freshness::Final,
+        Some(_) => Freshness::Ttl,
+        // Not mined yet
+        None => Freshness::Uncached,
+    })
+    .await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloy::primitives::B256;
+
+    fn cache(latest_ttl: Duration) -> RpcCache {
+        RpcCache::new(&RpcCacheConfig {
+            capacity: 2,
+            latest_ttl,
+            finality_blocks: 10,
+            persist: false,
+        })
+    }
+
+    #[test]
+    fn only_blocks_by_hash_or_deep_enough_are_final() {
+        let cache = cache(Duration::from_secs(2));
+        let by_number = |number| BlockId::Number(BlockNumberOrTag::Number(number));
+
+        assert_eq!(
+            block_freshness(&cache, BlockId::from(B256::ZERO), None),
+            Freshness::Final
+        );
+        assert_eq!(
+            block_freshness(&cache, by_number(90), Some(100)),
+            Freshness::Final
+        );
+        assert_eq!(
+            block_freshness(&cache, by_number(91), Some(100)),
+            Freshness::Ttl
+        );
+        assert_eq!(block_freshness(&cache, by_number(1), None), Freshness::Ttl);
+        assert_eq!(
+            block_freshness(&cache, BlockId::latest(), Some(100)),
+            Freshness::Ttl
+        );
+    }
+
+    #[test]
+    fn expired_entries_are_misses() {
+        let cache = cache(Duration::ZERO);
+        cache.put_memory("latest".into(), Value::from(1), Freshness::Ttl);
+        cache.put_memory("final".into(), Value::from(2), Freshness::Final);
+
+        assert_eq!(cache.get_memory("latest"), None);
+        assert_eq!(cache.get_memory("final"), Some(Value::from(2)));
+    }
+
+    #[test]
+    fn evicts_the_least_recently_used_entry() {
+        let cache = cache(Duration::from_secs(2));
+        cache.put_memory("a".into(), Value::from(1), Freshness::Final);
+        cache.put_memory("b".into(), Value::from(2), Freshness::Final);
+        cache.get_memory("a");
+        cache.put_memory("c".into(), Value::from(3), Freshness::Final);
+
+        assert_eq!(cache.get_memory("b"), None);
+        assert_eq!(cache.get_memory("a"), Some(Value::from(1)));
+    }
+}
@@ -1,12 +1,14 @@
 //! This module uses alloy to query the blockchain for information.
 
+use crate::providers::{ChainProvider, NotFound, QueryError};
 use alloy::{
-    primitives::{Address, TxHash, U256},
-    providers::{Provider, ProviderBuilder},
-    rpc::types::eth::{Block, BlockId, Transaction},
+    primitives::{Address, Bytes, TxHash, U256, U64},
+    providers::Provider,
+    rpc::types::eth::{Block, BlockId, Transaction, TransactionReceipt},
     sol,
+    transports::RpcError,
 };
-use std::error::Error;
+use serde_json::Value;
 
 // Codegen from artifact.
 sol!(
@@ -16,42 +18,269 @@ sol!(
     "src/abi/ERC20Abi.json",
 );
 
-pub async fn get_block_query(rpc_url: String, block_id: BlockId) -> Result<Block, Box<dyn Error>> {
-    let provider = ProviderBuilder::new().on_http(rpc_url.parse()?)?;
-    let block = provider.get_block(block_id, false).await?.unwrap();
+sol!(
+    #[allow(missing_docs)]
+    #[sol(rpc)]
+    ERC721Abi,
+    "src/abi/ERC721Abi.json",
+);
+
+sol!(
+    #[allow(missing_docs)]
+    #[sol(rpc)]
+    ERC1155Abi,
+    "src/abi/ERC1155Abi.json",
+);
+
+sol!(
+    #[allow(missing_docs)]
+    #[sol(rpc)]
+    Multicall3Abi,
+    "src/abi/Multicall3Abi.json",
+);
+
+/// Returns `None` when the node does not know the block.
+pub async fn get_block_query(
+    provider: ChainProvider,
+    block_id: BlockId,
+) -> Result<Option<Block>, QueryError> {
+    let block = provider.get_block(block_id, false).await?;
+
+    Ok(block)
+}
+
+pub async fn get_block_with_transactions_query(
+    provider: ChainProvider,
+    block_id: BlockId,
+) -> Result<Block, QueryError> {
+    let block = provider
+        .get_block(block_id, true)
+        .await?
+        .ok_or_else(|| NotFound(format!("Block {:?} not found", block_id)))?;
 
     Ok(block)
 }
 
+/// Returns `None` when the node does not know the transaction.
 pub async fn get_transaction_query(
-    rpc_url: String,
+    provider: ChainProvider,
     tx_hash: TxHash,
-) -> Result<Transaction, Box<dyn Error>> {
-    let provider = ProviderBuilder::new().on_http(rpc_url.parse()?)?;
-    let transaction = provider.get_transaction_by_hash(tx_hash).await?;
+) -> Result<Option<Transaction>, QueryError> {
+    let transaction = provider
+        .raw_request::<_, Option<Transaction>>("eth_getTransactionByHash".into(), (tx_hash,))
+        .await?;
 
     Ok(transaction)
 }
 
+/// Returns `None` until the transaction is mined.
+pub async fn get_transaction_receipt_query(
+    provider: ChainProvider,
+    tx_hash: TxHash,
+) -> Result<Option<TransactionReceipt>, QueryError> {
+    let receipt = provider.get_transaction_receipt(tx_hash).await?;
+
+    Ok(receipt)
+}
+
+pub async fn get_block_number_query(provider: ChainProvider) -> Result<u64, QueryError> {
+    let block_number = provider.get_block_number().await?;
+
+    Ok(block_number)
+}
+
 pub async fn get_native_balance_query(
-    rpc_url: String,
+    provider: ChainProvider,
     user_address: Address,
-) -> Result<U256, Box<dyn Error>> {
-    let provider = ProviderBuilder::new().on_http(rpc_url.parse()?)?;
-    let balance = provider
-        .get_balance(user_address, BlockId::latest())
-        .await?;
+    block_id: BlockId,
+) -> Result<U256, QueryError> {
+    let balance = provider.get_balance(user_address, block_id).await?;
 
     Ok(balance)
 }
 
 pub async fn get_erc20_balance_query(
-    rpc_url: String,
+    provider: ChainProvider,
     user_address: Address,
     contract_address: Address,
-) -> Result<U256, Box<dyn Error>> {
-    let provider = ProviderBuilder::new().on_http(rpc_url.parse()?)?;
+    block_id: BlockId,
+) -> Result<U256, QueryError> {
     let contract = ERC20Abi::new(contract_address, provider);
+    let balance = contract
+        .balanceOf(user_address)
+        .block(block_id)
+        .call()
+        .await?
+        ._0;
+    Ok(balance)
+}
+
+pub async fn get_erc721_owner_query(
+    provider: ChainProvider,
+    contract_address: Address,
+    token_id: U256,
+) -> Result<Address, QueryError> {
+    let contract = ERC721Abi::new(contract_address, provider);
+    let owner = contract.ownerOf(token_id).call().await?._0;
+    Ok(owner)
+}
+
+pub async fn get_erc721_balance_query(
+    provider: ChainProvider,
+    user_address: Address,
+    contract_address: Address,
+) -> Result<U256, QueryError> {
+    let contract = ERC721Abi::new(contract_address, provider);
     let balance = contract.balanceOf(user_address).call().await?._0;
     Ok(balance)
 }
+
+pub async fn get_erc721_token_uri_query(
+    provider: ChainProvider,
+    contract_address: Address,
+    token_id: U256,
+) -> Result<String, QueryError> {
+    let contract = ERC721Abi::new(contract_address, provider);
+    let uri = contract.tokenURI(token_id).call().await?._0;
+    Ok(uri)
+}
+
+pub async fn get_erc1155_balance_query(
+    provider: ChainProvider,
+    user_address: Address,
+    contract_address: Address,
+    token_id: U256,
+) -> Result<U256, QueryError> {
+    let contract = ERC1155Abi::new(contract_address, provider);
+    let balance = contract.balanceOf(user_address, token_id).call().await?._0;
+    Ok(balance)
+}
+
+/// Balance of each account in the token id at the same position.
+pub async fn get_erc1155_balance_batch_query(
+    provider: ChainProvider,
+    contract_address: Address,
+    user_addresses: Vec<Address>,
+    token_ids: Vec<U256>,
+) -> Result<Vec<U256>, QueryError> {
+    let contract = ERC1155Abi::new(contract_address, provider);
+    let balances = contract
+        .balanceOfBatch(user_addresses, token_ids)
+        .call()
+        .await?
+        ._0;
+    Ok(balances)
+}
+
+pub async fn get_code_query(
+    provider: ChainProvider,
+    address: Address,
+    block_id: BlockId,
+) -> Result<Bytes, QueryError> {
+    let code = provider.get_code_at(address, block_id).await?;
+
+    Ok(code)
+}
+
+pub async fn get_nonce_query(
+    provider: ChainProvider,
+    address: Address,
+    block_id: BlockId,
+) -> Result<u64, QueryError> {
+    let nonce = provider.get_transaction_count(address, block_id).await?;
+
+    Ok(nonce)
+}
+
+pub async fn get_storage_query(
+    provider: ChainProvider,
+    address: Address,
+    slot: U256,
+    block_id: BlockId,
+) -> Result<U256, QueryError> {
+    let value = provider.get_storage_at(address, slot, block_id).await?;
+
+    Ok(value)
+}
+
+/// Result of an `eth_call`, telling a revert apart from a failed request.
+#[derive(Debug, Clone, PartialEq)]
+pub enum CallOutcome {
+    Success(Bytes),
+    Reverted {
+        message: String,
+        /// Revert data, such as an encoded `Error(string)`
+        data: Option<Bytes>,
+    },
+}
+
+/// Runs `call`, a transaction request as JSON, against the latest state.
+pub async fn call_outcome_query(
+    provider: ChainProvider,
+    call: Value,
+) -> Result<CallOutcome, QueryError> {
+    match provider
+        .raw_request::<_, Bytes>("eth_call".into(), (call, "latest"))
+        .await
+    {
+        Ok(output) => Ok(CallOutcome::Success(output)),
+        Err(RpcError::ErrorResp(payload)) => {
+            let data = payload
+                .data
+                .as_ref()
+                .and_then(|data| serde_json::from_str::<Bytes>(data.get()).ok());
+            Ok(CallOutcome::Reverted {
+                message: payload.message.to_string(),
+                data,
+            })
+        }
+        Err(e) => Err(e.into()),
+    }
+}
+
+pub async fn estimate_gas_query(provider: ChainProvider, call: Value) -> Result<u64, QueryError> {
+    let gas = provider
+        .raw_request::<_, U64>("eth_estimateGas".into(), (call, "latest"))
+        .await?;
+
+    Ok(gas.to::<u64>())
+}
+
+/// Traces `call` against the latest state with `tracer`, e.g. `{"tracer": "callTracer"}`.
+/// Nodes without the debug namespace answer with a JSON-RPC error.
+pub async fn trace_call_query(
+    provider: ChainProvider,
+    call: Value,
+    tracer: Value,
+) -> Result<Value, QueryError> {
+    let trace = provider
+        .raw_request::<_, Value>("debug_traceCall".into(), (call, "latest", tracer))
+        .await?;
+
+    Ok(trace)
+}
+
+/// Traces the included transaction `tx_hash` with `tracer`. Nodes without the debug
+/// namespace answer with a JSON-RPC error.
+pub async fn trace_transaction_query(
+    provider: ChainProvider,
+    tx_hash: TxHash,
+    tracer: Value,
+) -> Result<Value, QueryError> {
+    let trace = provider
+        .raw_request::<_, Value>("debug_traceTransaction".into(), (tx_hash, tracer))
+        .await?;
+
+    Ok(trace)
+}
+
+/// Sends `calls` in a single Multicall3 `aggregate3`, answering one result per call.
+pub async fn multicall_query(
+    provider: ChainProvider,
+    multicall: Address,
+    calls: Vec<Multicall3Abi::Call3>,
+) -> Result<Vec<Multicall3Abi::Result>, QueryError> {
+    let contract = Multicall3Abi::new(multicall, provider);
+    let results = contract.aggregate3(calls).call().await?.returnData;
+    Ok(results)
+}
@@ -0,0 +1,178 @@
+//! HTTP routes for the REST and GraphQL API.
+
+use crate::{
+    chains::get_chains,
+    graphql::schema::{create_schema, AppSchema},
+    health::{healthz, readyz},
+    metrics::{get_metrics, track_requests},
+    model::{AppError, AppState, Config},
+    request_id::assign_request_id,
+    service::{
+        create_transaction, filter_transactions, get_account, get_address_internal_calls,
+        get_balance_history, get_balances, get_block, get_erc1155_balance,
+        get_erc1155_balance_batch, get_erc20_balance, get_erc721_balance, get_erc721_owner,
+        get_erc721_token_uri, get_gas_estimate, get_native_balance, get_nft_interfaces,
+        get_portfolio, get_simulation, get_transaction, get_transaction_by_hash,
+        get_transaction_by_id, get_transaction_internal_calls, get_transaction_receipt,
+        get_transaction_stats, get_transactions, simulate_transaction,
+    },
+};
+use async_graphql::http::{playground_source, GraphQLPlaygroundConfig};
+use async_graphql_axum::{GraphQLRequest, GraphQLResponse};
+use axum::{
+    http::Method,
+    middleware,
+    response::IntoResponse,
+    routing::{get, post},
+    Extension, Router,
+};
+use std::sync::Arc;
+use tokio::net::TcpListener;
+use tokio_util::sync::CancellationToken;
+use tower_http::cors::{Any, CorsLayer};
+
+#[axum::debug_handler]
+async fn graphql_handler(schema: Extension<AppSchema>, req: GraphQLRequest) -> GraphQLResponse {
+    schema.execute(req.into_inner()).await.into()
+}
+
+async fn graphql_playground() -> impl IntoResponse {
+    axum::response::Html(playground_source(GraphQLPlaygroundConfig::new("/graphql")))
+}
+
+pub fn router(app_state: Arc<AppState>) -> Router {
+    // CORS configuration allowing any origin, method, headers
+    let cors = CorsLayer::new()
+        .allow_origin(Any)
+        .allow_methods([Method::GET, Method::POST])
+        .allow_headers(Any);
+
+    let schema = create_schema(app_state.clone());
+
+    Router::new()
+        .route(
+            "/",
+            get(|| async { "Sentinel! A blockchain indexing tool." }),
+        )
+        .route("/chains", get(get_chains))
+        .route("/transactions", get(get_transactions))
+        .route("/transactions", post(create_transaction))
+        .route("/transactions/:id", get(get_transaction_by_id))
+        .route("/transactions/filter", get(filter_transactions))
+        .route(
+            "/transactions/hash/:chainid/:tx_hash",
+            get(get_transaction_by_hash),
+        )
+        .route("/get-block/:chainid/:block_number", get(get_block))
+        .route(
+            "/get-transaction/:chainid/:block_number/:transaction_hash",
+            get(get_transaction),
+        )
+        .route(
+            "/get-receipt/:chainid/:transaction_hash",
+            get(get_transaction_receipt),
+        )
+        .route(
+            "/get-native-balance/:chainid/:address",
+            get(get_native_balance),
+        )
+        .route(
+            "/get-erc20-balance/:chainid/:contract_address/:address",
+            get(get_erc20_balance),
+        )
+        .route("/account/:chainid/:address", get(get_account))
+        .route(
+            "/internal-calls/tx/:chainid/:tx_hash",
+            get(get_transaction_internal_calls),
+        )
+        .route(
+            "/internal-calls/address/:chainid/:address",
+            get(get_address_internal_calls),
+        )
+        .route("/simulate/:chainid/:tx_hash", get(simulate_transaction))
+        .route("/simulations/:chainid/:tx_hash", get(get_simulation))
+        .route(
+            "/get-nft-interfaces/:chainid/:contract_address",
+            get(get_nft_interfaces),
+        )
+        .route(
+            "/get-erc721-owner/:chainid/:contract_address/:token_id",
+            get(get_erc721_owner),
+        )
+        .route(
+            "/get-erc721-balance/:chainid/:contract_address/:address",
+            get(get_erc721_balance),
+        )
+        .route(
+            "/get-erc721-token-uri/:chainid/:contract_address/:token_id",
+            get(get_erc721_token_uri),
+        )
+        .route(
+            "/get-erc1155-balance/:chainid/:contract_address/:address/:token_id",
+            get(get_erc1155_balance),
+        )
+        .route(
+            "/get-erc1155-balance-batch/:chainid/:contract_address",
+            post(get_erc1155_balance_batch),
+        )
+        .route("/balances/:chainid", post(get_balances))
+        .route("/portfolio/:chainid/:address", get(get_portfolio))
+        .route(
+            "/balance-history/:chainid/:address",
+            get(get_balance_history),
+        )
+        .route("/transactions/stats", get(get_transaction_stats))
+        .route("/gas/estimate", get(get_gas_estimate))
+        .route("/graphql", get(graphql_playground).post(graphql_handler))
+        .route("/metrics", get(get_metrics))
+        .route("/healthz", get(healthz))
+        .route("/readyz", get(readyz))
+        .layer(Extension(schema))
+        .layer(cors)
+        .route_layer(middleware::from_fn_with_state(
+            app_state.clone(),
+            track_requests,
+        ))
+        .layer(middleware::from_fn(assign_request_id))
+        .with_state(app_state)
+}
+
+/// Routes served by `sentinel scan`, which has no API of its own.
+pub fn admin_router(app_state: Arc<AppState>) -> Router {
+    Router::new()
+        .route("/metrics", get(get_metrics))
+        .route("/healthz", get(healthz))
+        .route("/readyz", get(readyz))
+        .layer(middleware::from_fn(assign_request_id))
+        .with_state(app_state)
+}
+
+/// Serves the API until `shutdown` is cancelled, then lets in-flight requests finish.
+pub async fn serve(
+    config: &Config,
+    app_state: Arc<AppState>,
+    shutdown: CancellationToken,
+) -> Result<(), AppError> {
+    let listener = TcpListener::bind(&config.server_url).await?;
+    println!("listening on {}", listener.local_addr()?);
+
+    axum::serve(listener, router(app_state))
+        .with_graceful_shutdown(shutdown.cancelled_owned())
+        .await?;
+    Ok(())
+}
+
+/// Serves the scanner's admin routes until the listener fails or `shutdown` is cancelled.
+pub async fn serve_admin(
+    address: &str,
+    app_state: Arc<AppState>,
+    shutdown: CancellationToken,
+) -> Result<(), AppError> {
+    let listener = TcpListener::bind(address).await?;
+    println!("scanner admin listening on {}", listener.local_addr()?);
+
+    axum::serve(listener, admin_router(app_state))
+        .with_graceful_shutdown(shutdown.cancelled_owned())
+        .await?;
+    Ok(())
+}
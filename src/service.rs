@@ -1,59 +1,67 @@
 use crate::{
-    model::{AppError, AppState, Transaction, TransactionFilter},
-    rpc_queries::{
-        get_block_query, get_erc20_balance_query, get_native_balance_query, get_transaction_query,
+    account::{account, Account, AccountParams},
+    analytics::{
+        gas::{estimate_gas, GasEstimate, DEFAULT_WINDOW_SECONDS},
+        stats::StatsBucket,
     },
-    utils::get_rpc_url_with_chain_id,
+    balances::{batch_balances, Balance, BalancesRequest},
+    chains::ChainRef,
+    extract::{Json, Path, Query},
+    historical::{resolve_block, AtBlock},
+    internal_calls::InternalCall,
+    model::{AppError, AppState, GasEstimateParams, StatsParams, Transaction, TransactionFilter},
+    nft::{
+        detect_interfaces, erc1155_balance, erc1155_balance_batch, erc721_balance, erc721_owner,
+        erc721_token_uri, parse_token_id, BalanceBatchRequest, Interfaces,
+    },
+    portfolio::{portfolio, Portfolio},
+    rate_limit::Priority,
+    rpc_cache::{cached_block, cached_receipt, cached_transaction},
+    rpc_queries::{get_erc20_balance_query, get_native_balance_query},
+    simulation::{simulate_and_store, Simulation},
+    snapshots::{balance_history, BalanceSeries, HistoryParams},
 };
 use alloy::{
-    primitives::{Address, ChainId, TxHash, U256},
-    rpc::types::eth::{Block, BlockId, Transaction as AlloyTx},
-};
-use axum::{
-    extract::{Path, Query, State},
-    Json,
+    primitives::{Address, TxHash, U256},
+    rpc::types::eth::{Block, BlockId, Transaction as AlloyTx, TransactionReceipt},
 };
-use std::sync::Arc;
+use axum::{extract::State, http::StatusCode};
+use std::{sync::Arc, time::Instant};
 use uuid::Uuid;
 
+/// Stores a transaction, or merges it into the row already stored for its chain and hash.
+/// Answers `201 Created` for a new row and `200 OK` with the existing, merged row otherwise.
 #[axum::debug_handler]
 pub async fn create_transaction(
     State(state): State<Arc<AppState>>,
     Json(transaction): Json<Transaction>,
-) -> Result<Json<Transaction>, AppError> {
-    let result = sqlx::query_as::<_, Transaction>(
-        "INSERT INTO transaction (tx_hash, block_hash, block_number, from_sender, to_reciever, tx_value, gas, gas_price, input, nonce, mempool_time, contract_type) 
-        VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11, $12) 
-        RETURNING *")
-        .bind(transaction.tx_hash)
-        .bind(transaction.block_hash)
-        .bind(transaction.block_number)
-        .bind(transaction.from_sender)
-        .bind(transaction.to_reciever)
-        .bind(transaction.tx_value)
-        .bind(transaction.gas)
-        .bind(transaction.gas_price)
-        .bind(transaction.input)
-        .bind(transaction.nonce)
-        .bind(transaction.mempool_time)
-        .bind(transaction.contract_type.to_owned())
-        .fetch_one(&state.pool)
-        .await
-        .map_err(|e| AppError::DatabaseError(e.to_string()))?;
-
-    Ok(Json(result))
+) -> Result<(StatusCode, Json<Transaction>), AppError> {
+    let started = Instant::now();
+    let result = state.store.upsert(transaction).await;
+
+    state
+        .metrics
+        .db_insert_duration
+        .observe(started.elapsed().as_secs_f64());
+    let (transaction, inserted) = result.map_err(|e| {
+        state.metrics.db_insert_errors.inc();
+        e
+    })?;
+
+    let status = if inserted {
+        StatusCode::CREATED
+    } else {
+        StatusCode::OK
+    };
+    Ok((status, Json(transaction)))
 }
 
 #[axum::debug_handler]
 pub async fn get_transactions(
     State(state): State<Arc<AppState>>,
 ) -> Result<Json<Vec<Transaction>>, AppError> {
-    let transaction = sqlx::query_as::<_, Transaction>("SELECT * FROM transaction")
-        .fetch_all(&state.pool)
-        .await
-        .map_err(|e| AppError::DatabaseError(e.to_string()))?;
-
-    Ok(Json(transaction))
+    let transactions = state.store.list().await?;
+    Ok(Json(transactions))
 }
 
 #[axum::debug_handler]
@@ -61,13 +69,25 @@ pub async fn get_transaction_by_id(
     State(state): State<Arc<AppState>>,
     Path(id): Path<Uuid>,
 ) -> Result<Json<Transaction>, AppError> {
-    let transaction = sqlx::query_as::<_, Transaction>("SELECT * FROM transaction WHERE id = $1")
-        .bind(id)
-        .fetch_optional(&state.pool)
-        .await
-        .map_err(|e| AppError::DatabaseError(e.to_string()))?
-        .unwrap();
+    let transaction = state
+        .store
+        .get_by_id(id)
+        .await?
+        .ok_or_else(|| AppError::NotFound(format!("Transaction {} not found", id)))?;
+    Ok(Json(transaction))
+}
 
+#[axum::debug_handler]
+pub async fn get_transaction_by_hash(
+    State(state): State<Arc<AppState>>,
+    Path((chain, tx_hash)): Path<(ChainRef, String)>,
+) -> Result<Json<Transaction>, AppError> {
+    let chainid = state.chains.resolve(&chain)?;
+    let transaction = state
+        .store
+        .get_by_hash(chainid as i64, &tx_hash.to_lowercase())
+        .await?
+        .ok_or_else(|| AppError::NotFound(format!("Transaction {} not found", tx_hash)))?;
     Ok(Json(transaction))
 }
 
@@ -76,104 +96,299 @@ pub async fn filter_transactions(
     State(state): State<Arc<AppState>>,
     Query(filter): Query<TransactionFilter>,
 ) -> Result<Json<Vec<Transaction>>, AppError> {
-    let mut query = String::from("SELECT * FROM transactions WHERE 1=1");
-    let mut bindings = vec![];
-
-    if let Some(min) = filter.gas_price_min {
-        query += " AND gas_price >= $1";
-        bindings.push(min.to_string());
-    }
-
-    if let Some(max) = filter.gas_price_max {
-        query += &format!(" AND gas_price <= ${}", bindings.len() + 1);
-        bindings.push(max.to_string());
-    }
-
-    if let Some(contract_type) = filter.contract_type {
-        query += &format!(" AND contract_type = ${}", bindings.len() + 1);
-        bindings.push(contract_type);
-    }
-
-    if let Some(min) = filter.block_number_min {
-        query += &format!(" AND block_number >= ${}", bindings.len() + 1);
-        bindings.push(min.to_string());
-    }
-
-    if let Some(max) = filter.block_number_max {
-        query += &format!(" AND block_number <= ${}", bindings.len() + 1);
-        bindings.push(max.to_string());
-    }
-
-    if let Some(min) = filter.mempool_time_min {
-        query += &format!(" AND mempool_time >= ${}", bindings.len() + 1);
-        bindings.push(min.to_string());
-    }
-
-    if let Some(max) = filter.mempool_time_max {
-        query += &format!(" AND mempool_time <= ${}", bindings.len() + 1);
-        bindings.push(max.to_string());
-    }
-
-    let mut db_query = sqlx::query_as::<_, Transaction>(&query);
-    for binding in bindings {
-        db_query = db_query.bind(binding);
-    }
-
-    let transactions = db_query
-        .fetch_all(&state.pool)
-        .await
-        .expect("Failed to fetch filtered transactions");
-
+    let filter = filter.resolve_chain(&state.chains)?;
+    let transactions = state.store.filter(&filter).await?;
     Ok(Json(transactions))
 }
 
+fn to_u128(balance: U256) -> Result<u128, AppError> {
+    u128::try_from(balance)
+        .map_err(|_| AppError::Other(format!("Balance {} does not fit in 128 bits", balance)))
+}
+
 // append 0x to the block number
 #[axum::debug_handler]
 pub async fn get_block(
     State(state): State<Arc<AppState>>,
-    Path((chainid, block_number)): Path<(ChainId, BlockId)>,
+    Path((chain, block_number)): Path<(ChainRef, BlockId)>,
 ) -> Result<Json<Block>, AppError> {
-    let rpc_url = get_rpc_url_with_chain_id(chainid);
-    let block = get_block_query(rpc_url, block_number).await.unwrap();
+    let chainid = state.chains.resolve(&chain)?;
+    let block = cached_block(&state, chainid, block_number)
+        .await?
+        .ok_or_else(|| AppError::NotFound(format!("Block {} not found", block_number)))?;
     Ok(Json(block))
 }
 
 #[axum::debug_handler]
 pub async fn get_transaction(
     State(state): State<Arc<AppState>>,
-    Path((chainid, block_number, transaction_hash)): Path<(ChainId, BlockId, TxHash)>,
+    Path((chain, _block_number, transaction_hash)): Path<(ChainRef, BlockId, TxHash)>,
 ) -> Result<Json<AlloyTx>, AppError> {
-    let rpc_url = get_rpc_url_with_chain_id(chainid);
-    let transaction = get_transaction_query(rpc_url, transaction_hash)
-        .await
-        .unwrap();
+    let chainid = state.chains.resolve(&chain)?;
+    let transaction = cached_transaction(&state, chainid, transaction_hash).await?;
     Ok(Json(transaction))
 }
 
+#[axum::debug_handler]
+pub async fn get_transaction_receipt(
+    State(state): State<Arc<AppState>>,
+    Path((chain, transaction_hash)): Path<(ChainRef, TxHash)>,
+) -> Result<Json<TransactionReceipt>, AppError> {
+    let chainid = state.chains.resolve(&chain)?;
+    let receipt = cached_receipt(&state, chainid, transaction_hash)
+        .await?
+        .ok_or_else(|| AppError::NotFound(format!("Receipt of {} not found", transaction_hash)))?;
+    Ok(Json(receipt))
+}
+
+/// Native balance of an address, at the latest block or the `block` or `timestamp` given.
 #[axum::debug_handler]
 pub async fn get_native_balance(
     State(state): State<Arc<AppState>>,
-    Path((chainid, address)): Path<(ChainId, Address)>,
+    Path((chain, address)): Path<(ChainRef, Address)>,
+    Query(at): Query<AtBlock>,
 ) -> Result<Json<u128>, AppError> {
-    let rpc_url = get_rpc_url_with_chain_id(chainid);
-    let balance = get_native_balance_query(rpc_url, address).await.unwrap();
-    let native_balance_hex = U256::from(balance);
-    let native_balance: u128 = native_balance_hex.to::<u128>();
-    Ok(Json(native_balance))
+    let chainid = state.chains.resolve(&chain)?;
+    let block = resolve_block(&state, chainid, &at).await?;
+    let balance = state
+        .providers
+        .call(&state.metrics, chainid, "eth_getBalance", |provider| {
+            get_native_balance_query(provider, address, block)
+        })
+        .await?;
+    Ok(Json(to_u128(balance)?))
 }
 
+/// ERC-20 balance of an address, at the latest block or the `block` or `timestamp` given.
 #[axum::debug_handler]
 pub async fn get_erc20_balance(
     State(state): State<Arc<AppState>>,
-    Path((chainid, contract_address, address)): Path<(ChainId, Address, Address)>,
+    Path((chain, contract_address, address)): Path<(ChainRef, Address, Address)>,
+    Query(at): Query<AtBlock>,
+) -> Result<Json<u128>, AppError> {
+    let chainid = state.chains.resolve(&chain)?;
+    let block = resolve_block(&state, chainid, &at).await?;
+    let balance = state
+        .providers
+        .call(&state.metrics, chainid, "eth_call", |provider| {
+            get_erc20_balance_query(provider, address, contract_address, block)
+        })
+        .await?;
+    Ok(Json(to_u128(balance)?))
+}
+
+/// Nonce, balance, code and chosen storage slots of an address, at the latest block or
+/// the `block` given.
+#[axum::debug_handler]
+pub async fn get_account(
+    State(state): State<Arc<AppState>>,
+    Path((chain, address)): Path<(ChainRef, Address)>,
+    Query(params): Query<AccountParams>,
+) -> Result<Json<Account>, AppError> {
+    let chainid = state.chains.resolve(&chain)?;
+    Ok(Json(account(&state, chainid, address, &params).await?))
+}
+
+/// Internal calls of an included transaction, in call order.
+#[axum::debug_handler]
+pub async fn get_transaction_internal_calls(
+    State(state): State<Arc<AppState>>,
+    Path((chain, tx_hash)): Path<(ChainRef, TxHash)>,
+) -> Result<Json<Vec<InternalCall>>, AppError> {
+    let chainid = state.chains.resolve(&chain)?;
+    let calls = state
+        .store
+        .internal_calls(chainid as i64, &tx_hash.to_string())
+        .await?;
+    Ok(Json(calls))
+}
+
+/// Internal calls made from or to an address, ordered by block.
+#[axum::debug_handler]
+pub async fn get_address_internal_calls(
+    State(state): State<Arc<AppState>>,
+    Path((chain, address)): Path<(ChainRef, Address)>,
+) -> Result<Json<Vec<InternalCall>>, AppError> {
+    let chainid = state.chains.resolve(&chain)?;
+    let calls = state
+        .store
+        .address_internal_calls(chainid as i64, &address.to_string())
+        .await?;
+    Ok(Json(calls))
+}
+
+/// Replays a pending transaction against the latest state and stores the prediction.
+#[axum::debug_handler]
+pub async fn simulate_transaction(
+    State(state): State<Arc<AppState>>,
+    Path((chain, tx_hash)): Path<(ChainRef, TxHash)>,
+) -> Result<Json<Simulation>, AppError> {
+    let chainid = state.chains.resolve(&chain)?;
+    Ok(Json(
+        simulate_and_store(&state, chainid, tx_hash, Priority::Interactive).await?,
+    ))
+}
+
+/// Latest stored simulation of a transaction, from the API or the mempool scanner.
+#[axum::debug_handler]
+pub async fn get_simulation(
+    State(state): State<Arc<AppState>>,
+    Path((chain, tx_hash)): Path<(ChainRef, TxHash)>,
+) -> Result<Json<Simulation>, AppError> {
+    let chainid = state.chains.resolve(&chain)?;
+    let simulation = state
+        .store
+        .simulation(chainid as i64, &tx_hash.to_string())
+        .await?
+        .ok_or_else(|| AppError::NotFound(format!("No simulation of {}", tx_hash)))?;
+    Ok(Json(simulation))
+}
+
+/// Token standards a contract implements, detected through ERC-165.
+#[axum::debug_handler]
+pub async fn get_nft_interfaces(
+    State(state): State<Arc<AppState>>,
+    Path((chain, contract_address)): Path<(ChainRef, Address)>,
+) -> Result<Json<Interfaces>, AppError> {
+    let chainid = state.chains.resolve(&chain)?;
+    Ok(Json(
+        detect_interfaces(&state, chainid, contract_address).await?,
+    ))
+}
+
+#[axum::debug_handler]
+pub async fn get_erc721_owner(
+    State(state): State<Arc<AppState>>,
+    Path((chain, contract_address, token_id)): Path<(ChainRef, Address, String)>,
+) -> Result<Json<Address>, AppError> {
+    let chainid = state.chains.resolve(&chain)?;
+    let token_id = parse_token_id(&token_id)?;
+    Ok(Json(
+        erc721_owner(&state, chainid, contract_address, token_id).await?,
+    ))
+}
+
+#[axum::debug_handler]
+pub async fn get_erc721_balance(
+    State(state): State<Arc<AppState>>,
+    Path((chain, contract_address, address)): Path<(ChainRef, Address, Address)>,
 ) -> Result<Json<u128>, AppError> {
-    let rpc_url = get_rpc_url_with_chain_id(chainid);
-    let balance = get_erc20_balance_query(rpc_url, address, contract_address)
-        .await
-        .unwrap();
+    let chainid = state.chains.resolve(&chain)?;
+    let balance = erc721_balance(&state, chainid, contract_address, address).await?;
+    Ok(Json(to_u128(balance)?))
+}
 
-    let erc20_balance_hex = U256::from(balance);
-    let erc20_balance: u128 = erc20_balance_hex.to::<u128>();
+#[axum::debug_handler]
+pub async fn get_erc721_token_uri(
+    State(state): State<Arc<AppState>>,
+    Path((chain, contract_address, token_id)): Path<(ChainRef, Address, String)>,
+) -> Result<Json<String>, AppError> {
+    let chainid = state.chains.resolve(&chain)?;
+    let token_id = parse_token_id(&token_id)?;
+    Ok(Json(
+        erc721_token_uri(&state, chainid, contract_address, token_id).await?,
+    ))
+}
 
-    Ok(Json(erc20_balance))
+#[axum::debug_handler]
+pub async fn get_erc1155_balance(
+    State(state): State<Arc<AppState>>,
+    Path((chain, contract_address, address, token_id)): Path<(ChainRef, Address, Address, String)>,
+) -> Result<Json<u128>, AppError> {
+    let chainid = state.chains.resolve(&chain)?;
+    let token_id = parse_token_id(&token_id)?;
+    let balance = erc1155_balance(&state, chainid, contract_address, address, token_id).await?;
+    Ok(Json(to_u128(balance)?))
+}
+
+/// Balances of many accounts in one ERC-1155 `balanceOfBatch` call, in request order.
+#[axum::debug_handler]
+pub async fn get_erc1155_balance_batch(
+    State(state): State<Arc<AppState>>,
+    Path((chain, contract_address)): Path<(ChainRef, Address)>,
+    Json(request): Json<BalanceBatchRequest>,
+) -> Result<Json<Vec<u128>>, AppError> {
+    let chainid = state.chains.resolve(&chain)?;
+    let token_ids = request
+        .ids
+        .iter()
+        .map(|id| parse_token_id(id))
+        .collect::<Result<_, _>>()?;
+    let balances = erc1155_balance_batch(
+        &state,
+        chainid,
+        contract_address,
+        request.accounts,
+        token_ids,
+    )
+    .await?;
+    Ok(Json(
+        balances
+            .into_iter()
+            .map(to_u128)
+            .collect::<Result<_, _>>()?,
+    ))
+}
+
+/// Reads native and ERC-20 balances of many addresses in a few Multicall3 calls.
+#[axum::debug_handler]
+pub async fn get_balances(
+    State(state): State<Arc<AppState>>,
+    Path(chain): Path<ChainRef>,
+    Json(request): Json<BalancesRequest>,
+) -> Result<Json<Vec<Balance>>, AppError> {
+    let chainid = state.chains.resolve(&chain)?;
+    let balances = batch_balances(&state, chainid, &request).await?;
+    Ok(Json(balances))
+}
+
+/// Native and token holdings of an address, with decimals applied.
+#[axum::debug_handler]
+pub async fn get_portfolio(
+    State(state): State<Arc<AppState>>,
+    Path((chain, address)): Path<(ChainRef, Address)>,
+) -> Result<Json<Portfolio>, AppError> {
+    let chainid = state.chains.resolve(&chain)?;
+    Ok(Json(portfolio(&state, chainid, address).await?))
+}
+
+/// Snapshotted balance series of a watched address, flagging large changes.
+#[axum::debug_handler]
+pub async fn get_balance_history(
+    State(state): State<Arc<AppState>>,
+    Path((chain, address)): Path<(ChainRef, Address)>,
+    Query(params): Query<HistoryParams>,
+) -> Result<Json<Vec<BalanceSeries>>, AppError> {
+    let chainid = state.chains.resolve(&chain)?;
+    Ok(Json(
+        balance_history(&state, chainid, address, &params).await?,
+    ))
+}
+
+#[axum::debug_handler]
+pub async fn get_gas_estimate(
+    State(state): State<Arc<AppState>>,
+    Query(params): Query<GasEstimateParams>,
+) -> Result<Json<GasEstimate>, AppError> {
+    let chain_id = state.chains.resolve(&params.chainid)?;
+    let estimate = estimate_gas(
+        state.store.as_ref(),
+        chain_id,
+        params.target_seconds,
+        params.window_seconds.unwrap_or(DEFAULT_WINDOW_SECONDS),
+    )
+    .await?;
+
+    Ok(Json(estimate))
+}
+
+#[axum::debug_handler]
+pub async fn get_transaction_stats(
+    State(state): State<Arc<AppState>>,
+    Query(params): Query<StatsParams>,
+    Query(filter): Query<TransactionFilter>,
+) -> Result<Json<Vec<StatsBucket>>, AppError> {
+    let filter = filter.resolve_chain(&state.chains)?;
+    let buckets = state.store.stats(&params, &filter).await?;
+    Ok(Json(buckets))
 }
@@ -0,0 +1,51 @@
+use log::warn;
+use tokio::{
+    signal,
+    task::JoinHandle,
+    time::{timeout_at, Instant},
+};
+
+/// Resolves on Ctrl-C, or on SIGTERM on unix platforms.
+pub async fn shutdown_signal() {
+    let ctrl_c = async {
+        if let Err(e) = signal::ctrl_c().await {
+            warn!("Error listening for Ctrl-C: {:?}", e);
+            std::future::pending::<()>().await;
+        }
+    };
+
+    #[cfg(unix)]
+    let terminate = async {
+        match signal::unix::signal(signal::unix::SignalKind::terminate()) {
+            Ok(mut sigterm) => {
+                sigterm.recv().await;
+            }
+            Err(e) => {
+                warn!("Error listening for SIGTERM: {:?}", e);
+                std::future::pending::<()>().await;
+            }
+        }
+    };
+
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    tokio::select! {
+        _ = ctrl_c => {}
+        _ = terminate => {}
+    }
+}
+
+/// Waits for `tasks` to finish until `deadline`, then aborts the remaining ones.
+///
+/// Returns how many tasks had to be aborted.
+pub async fn drain(tasks: Vec<JoinHandle<()>>, deadline: Instant) -> usize {
+    let mut aborted = 0;
+    for mut task in tasks {
+        if timeout_at(deadline, &mut task).await.is_err() {
+            task.abort();
+            aborted += 1;
+        }
+    }
+    aborted
+}
@@ -0,0 +1,443 @@
+//! Predicts what a pending transaction will do by replaying it against the latest state.
+//!
+//! The transaction is traced with `debug_traceCall`: the call tracer tells whether it
+//! succeeds, the gas it uses and why it reverts, and the prestate tracer in diff mode which
+//! balances and storage slots it changes. Nodes without the debug namespace fall back to
+//! `eth_call` and `eth_estimateGas`, without diffs. Other pending transactions are not
+//! applied first, so one depending on an earlier transaction of its sender may be predicted
+//! to fail.
+
+use crate::{
+    model::{AppError, AppState},
+    rate_limit::Priority,
+    rpc_cache::cached_transaction,
+    rpc_queries::{
+        call_outcome_query, estimate_gas_query, get_block_number_query, trace_call_query,
+        CallOutcome,
+    },
+    utils::unix_seconds,
+};
+use alloy::{
+    primitives::{Bytes, ChainId, TxHash, B256, U256},
+    sol_types::decode_revert_reason,
+};
+use async_graphql::SimpleObject;
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Map, Value};
+use std::{collections::BTreeSet, str::FromStr, sync::Arc};
+
+/// Fields of a transaction that make up the call replaying it.
+const CALL_FIELDS: [&str; 9] = [
+    "from",
+    "to",
+    "gas",
+    "value",
+    "input",
+    "gasPrice",
+    "maxFeePerGas",
+    "maxPriorityFeePerGas",
+    "accessList",
+];
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct SimulationConfig {
+    /// Pending transactions moving at least this much wei are simulated by the scanner.
+    /// `None` leaves simulation to the API
+    pub min_value_wei: Option<U256>,
+}
+
+/// Native balance change of an address, in wei as decimal strings.
+#[derive(Serialize, Deserialize, SimpleObject, Debug, Clone, PartialEq)]
+pub struct BalanceDiff {
+    pub address: String,
+    pub before: String,
+    pub after: String,
+    /// Signed
+    pub delta: String,
+}
+
+/// Storage slot change, as 32-byte hex words. Empty slots read as zero.
+#[derive(Serialize, Deserialize, SimpleObject, Debug, Clone, PartialEq)]
+pub struct StorageDiff {
+    pub address: String,
+    pub slot: String,
+    pub before: String,
+    pub after: String,
+}
+
+#[derive(Serialize, Deserialize, SimpleObject, Debug, Clone, PartialEq)]
+pub struct Simulation {
+    pub chain_id: u64,
+    pub tx_hash: String,
+    /// Head of the chain when the transaction was replayed
+    pub block_number: u64,
+    pub success: bool,
+    pub gas_used: u64,
+    pub revert_reason: Option<String>,
+    /// Whether the node traced the call. Without a trace there are no diffs and
+    /// `gas_used` is an estimate
+    pub traced: bool,
+    pub balance_diffs: Vec<BalanceDiff>,
+    pub storage_diffs: Vec<StorageDiff>,
+    /// Unix seconds
+    pub simulated_at: i64,
+}
+
+/// Call replaying `transaction`, a transaction as returned by `eth_getTransactionByHash`.
+///
+/// The gas price is left out of EIP-1559 transactions, for which nodes report the fee cap
+/// as `gasPrice` and reject calls giving both.
+pub fn call_object(transaction: &Value) -> Value {
+    let mut call = Map::new();
+    for field in CALL_FIELDS {
+        if let Some(value) = transaction.get(field).filter(|value| !value.is_null()) {
+            call.insert(field.to_string(), value.clone());
+        }
+    }
+    if call.contains_key("maxFeePerGas") {
+        call.remove("gasPrice");
+    }
+    Value::Object(call)
+}
+
+/// Reason a call reverted: the decoded revert data when there is any, otherwise what the
+/// node said.
+fn revert_reason(message: Option<&str>, data: Option<&Bytes>) -> Option<String> {
+    data.and_then(|data| decode_revert_reason(data))
+        .or_else(|| message.map(str::to_string))
+}
+
+/// Success, gas used and revert reason of a `callTracer` frame.
+fn call_result(frame: &Value) -> (bool, u64, Option<String>) {
+    let gas_used = frame["gasUsed"]
+        .as_str()
+        .and_then(|gas| u64::from_str_radix(gas.trim_start_matches("0x"), 16).ok())
+        .unwrap_or(0);
+    let Some(error) = frame["error"].as_str() else {
+        return (true, gas_used, None);
+    };
+
+    let reason = match frame["revertReason"].as_str() {
+        Some(reason) => Some(reason.to_string()),
+        None => {
+            let output = frame["output"]
+                .as_str()
+                .and_then(|output| Bytes::from_str(output).ok());
+            revert_reason(Some(error), output.as_ref())
+        }
+    };
+    (false, gas_used, reason)
+}
+
+fn word(value: &Value) -> U256 {
+    value
+        .as_str()
+        .and_then(|value| U256::from_str(value).ok())
+        .unwrap_or_default()
+}
+
+/// Balance and storage changes of a `prestateTracer` trace in diff mode.
+///
+/// `post` only lists what changed, and leaves out accounts that were destroyed and
+/// slots that were cleared.
+fn state_diff(trace: &Value) -> (Vec<BalanceDiff>, Vec<StorageDiff>) {
+    let empty = Map::new();
+    let pre = trace["pre"].as_object().unwrap_or(&empty);
+    let post = trace["post"].as_object().unwrap_or(&empty);
+    let addresses: BTreeSet<&String> = pre.keys().chain(post.keys()).collect();
+
+    let mut balances = Vec::new();
+    let mut storage = Vec::new();
+    for address in addresses {
+        let before = pre.get(address.as_str()).unwrap_or(&Value::Null);
+        let after = post.get(address.as_str());
+
+        let balance_after = match after {
+            Some(after) if after.get("balance").is_some() => Some(word(&after["balance"])),
+            Some(_) => None,
+            None => Some(U256::ZERO),
+        };
+        let balance_before = word(&before["balance"]);
+        if let Some(balance_after) = balance_after.filter(|after| *after != balance_before) {
+            let delta = if balance_after >= balance_before {
+                (balance_after - balance_before).to_string()
+            } else {
+                format!("-{}", balance_before - balance_after)
+            };
+            balances.push(BalanceDiff {
+                address: address.clone(),
+                before: balance_before.to_string(),
+                after: balance_after.to_string(),
+                delta,
+            });
+        }
+
+        let slots_before = before["storage"].as_object().unwrap_or(&empty);
+        let slots_after = after
+            .and_then(|after| after["storage"].as_object())
+            .unwrap_or(&empty);
+        let slots: BTreeSet<&String> = slots_before.keys().chain(slots_after.keys()).collect();
+        for slot in slots {
+            let value_before = slots_before
+                .get(slot.as_str())
+                .map(word)
+                .unwrap_or_default();
+            let value_after = slots_after.get(slot.as_str()).map(word).unwrap_or_default();
+            if value_before != value_after {
+                storage.push(StorageDiff {
+                    address: address.clone(),
+                    slot: B256::from(word(&Value::String(slot.clone()))).to_string(),
+                    before: B256::from(value_before).to_string(),
+                    after: B256::from(value_after).to_string(),
+                });
+            }
+        }
+    }
+
+    (balances, storage)
+}
+
+/// Replays the pending transaction `tx_hash` against the latest state of `chain_id`.
+pub async fn simulate(
+    state: &AppState,
+    chain_id: ChainId,
+    tx_hash: TxHash,
+    priority: Priority,
+) -> Result<Simulation, AppError> {
+    let transaction = cached_transaction(state, chain_id, tx_hash).await?;
+    if let Some(block_number) = transaction.block_number {
+        return Err(AppError::Validation(format!(
+            "Transaction {} was already included in block {}",
+            tx_hash, block_number
+        )));
+    }
+    let call = call_object(&serde_json::to_value(&transaction)?);
+
+    let providers = &state.providers;
+    let metrics = &state.metrics;
+    let block_number = providers
+        .call_with_priority(
+            metrics,
+            chain_id,
+            "eth_blockNumber",
+            priority,
+            get_block_number_query,
+        )
+        .await?;
+
+    let frame = providers
+        .call_with_priority(metrics, chain_id, "debug_traceCall", priority, |provider| {
+            trace_call_query(provider, call.clone(), json!({ "tracer": "callTracer" }))
+        })
+        .await;
+
+    let (success, gas_used, revert_reason, traced, balance_diffs, storage_diffs) = match frame {
+        Ok(frame) => {
+            let (success, gas_used, revert_reason) = call_result(&frame);
+            let prestate = providers
+                .call_with_priority(metrics, chain_id, "debug_traceCall", priority, |provider| {
+                    trace_call_query(
+                        provider,
+                        call.clone(),
+                        json!({ "tracer": "prestateTracer", "tracerConfig": { "diffMode": true } }),
+                    )
+                })
+                .await?;
+            let (balance_diffs, storage_diffs) = state_diff(&prestate);
+            (
+                success,
+                gas_used,
+                revert_reason,
+                true,
+                balance_diffs,
+                storage_diffs,
+            )
+        }
+        Err(AppError::Upstream(e)) => {
+            warn!(
+                "Cannot trace {} on chain {}, falling back to eth_call: {}",
+                tx_hash, chain_id, e
+            );
+            let outcome = providers
+                .call_with_priority(metrics, chain_id, "eth_call", priority, |provider| {
+                    call_outcome_query(provider, call.clone())
+                })
+                .await?;
+            match outcome {
+                CallOutcome::Success(_) => {
+                    let gas_used = providers
+                        .call_with_priority(
+                            metrics,
+                            chain_id,
+                            "eth_estimateGas",
+                            priority,
+                            |provider| estimate_gas_query(provider, call.clone()),
+                        )
+                        .await?;
+                    (true, gas_used, None, false, Vec::new(), Vec::new())
+                }
+                CallOutcome::Reverted { message, data } => {
+                    let reason = revert_reason(Some(&message), data.as_ref());
+                    (false, 0, reason, false, Vec::new(), Vec::new())
+                }
+            }
+        }
+        Err(e) => return Err(e),
+    };
+
+    Ok(Simulation {
+        chain_id,
+        tx_hash: tx_hash.to_string(),
+        block_number,
+        success,
+        gas_used,
+        revert_reason,
+        traced,
+        balance_diffs,
+        storage_diffs,
+        simulated_at: unix_seconds() as i64,
+    })
+}
+
+/// Simulates `tx_hash` and stores the prediction, replacing any earlier one.
+pub async fn simulate_and_store(
+    state: &AppState,
+    chain_id: ChainId,
+    tx_hash: TxHash,
+    priority: Priority,
+) -> Result<Simulation, AppError> {
+    let simulation = simulate(state, chain_id, tx_hash, priority).await?;
+    state.store.save_simulation(&simulation).await?;
+
+    let outcome = if simulation.success {
+        "success"
+    } else {
+        "revert"
+    };
+    state
+        .metrics
+        .simulations
+        .with_label_values(&[&chain_id.to_string(), outcome])
+        .inc();
+    Ok(simulation)
+}
+
+/// Whether the scanner should simulate `transaction`, a pending transaction as returned
+/// by `eth_getTransactionByHash`.
+pub fn is_high_value(config: &SimulationConfig, transaction: &Value) -> bool {
+    match config.min_value_wei {
+        Some(min_value) => word(&transaction["value"]) >= min_value,
+        None => false,
+    }
+}
+
+/// Simulates a pending transaction seen by the scanner in the background, so the scan
+/// does not wait for the trace.
+pub fn enrich(state: Arc<AppState>, chain_id: ChainId, tx_hash: &str) {
+    let Ok(tx_hash) = TxHash::from_str(tx_hash) else {
+        return;
+    };
+    tokio::spawn(async move {
+        match simulate_and_store(&state, chain_id, tx_hash, Priority::Background).await {
+            Ok(simulation) => info!(
+                "Simulated pending transaction {} on chain {}: success {}",
+                tx_hash, chain_id, simulation.success
+            ),
+            Err(e) => warn!(
+                "Error simulating pending transaction {} on chain {}: {:?}",
+                tx_hash, chain_id, e
+            ),
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn replays_eip1559_transactions_without_gas_price() {
+        let transaction = json!({
+            "hash": "0xabc",
+            "from": "0x01",
+            "to": "0x02",
+            "gas": "0x5208",
+            "value": "0x1",
+            "input": "0x",
+            "gasPrice": "0x10",
+            "maxFeePerGas": "0x10",
+            "maxPriorityFeePerGas": "0x1",
+            "nonce": "0x7",
+            "blockHash": null
+        });
+
+        let call = call_object(&transaction);
+        assert_eq!(call["from"], "0x01");
+        assert_eq!(call["maxFeePerGas"], "0x10");
+        assert!(call.get("gasPrice").is_none());
+        assert!(call.get("nonce").is_none());
+        assert!(call.get("hash").is_none());
+
+        let legacy = call_object(&json!({ "from": "0x01", "to": null, "gasPrice": "0x10" }));
+        assert_eq!(legacy["gasPrice"], "0x10");
+        assert!(legacy.get("to").is_none());
+    }
+
+    #[test]
+    fn reads_success_gas_and_revert_reason_from_call_frames() {
+        assert_eq!(
+            call_result(&json!({ "gasUsed": "0x5208", "output": "0x" })),
+            (true, 21000, None)
+        );
+        assert_eq!(
+            call_result(&json!({
+                "gasUsed": "0x6000",
+                "error": "execution reverted",
+                "revertReason": "Ownable: caller is not the owner"
+            })),
+            (
+                false,
+                0x6000,
+                Some("Ownable: caller is not the owner".to_string())
+            )
+        );
+        assert_eq!(
+            call_result(&json!({ "gasUsed": "0x6000", "error": "out of gas" })),
+            (false, 0x6000, Some("out of gas".to_string()))
+        );
+    }
+
+    #[test]
+    fn diffs_balances_and_storage() {
+        let trace = json!({
+            "pre": {
+                "0xsender": { "balance": "0x64", "nonce": 1 },
+                "0xtoken": {
+                    "balance": "0x0",
+                    "storage": { "0x1": "0x5", "0x2": "0x9" }
+                }
+            },
+            "post": {
+                "0xsender": { "balance": "0x32", "nonce": 2 },
+                "0xtoken": { "storage": { "0x1": "0x6" } },
+                "0xcreated": { "balance": "0xa" }
+            }
+        });
+
+        let (balances, storage) = state_diff(&trace);
+
+        assert_eq!(balances.len(), 2);
+        assert_eq!(balances[0].address, "0xcreated");
+        assert_eq!(balances[0].delta, "10");
+        assert_eq!(balances[1].address, "0xsender");
+        assert_eq!(balances[1].before, "100");
+        assert_eq!(balances[1].delta, "-50");
+
+        assert_eq!(storage.len(), 2);
+        assert_eq!(storage[0].after, B256::from(U256::from(6)).to_string());
+        // Cleared slots are left out of `post`
+        assert_eq!(storage[1].before, B256::from(U256::from(9)).to_string());
+        assert_eq!(storage[1].after, B256::ZERO.to_string());
+    }
+}
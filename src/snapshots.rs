@@ -0,0 +1,351 @@
+//! Balance history of the addresses each chain watches.
+//!
+//! The snapshot job records the native balance and the balance of every registry token of
+//! each `watched` address, at block numbers that are multiples of the chain's interval, so
+//! a restarted job resumes on the same grid. Queries turn the snapshots into one time series
+//! per token, flagging changes larger than a share of the previous balance.
+
+use crate::{
+    model::{AppError, AppState},
+    rate_limit::Priority,
+    rpc_cache::cached_block,
+    rpc_queries::{get_block_number_query, get_erc20_balance_query, get_native_balance_query},
+};
+use alloy::{
+    primitives::{Address, ChainId, U256},
+    rpc::types::eth::BlockId,
+};
+use async_graphql::SimpleObject;
+use log::{error, info, warn};
+use serde::{Deserialize, Serialize};
+use sqlx::FromRow;
+use std::{collections::HashMap, num::NonZeroU64, str::FromStr, sync::Arc, time::Duration};
+use tokio::time::sleep;
+use tokio_util::sync::CancellationToken;
+
+/// Change, in percent of the previous balance, past which a snapshot is flagged.
+pub const DEFAULT_DELTA_PERCENT: u64 = 10;
+/// Shortest wait between two looks at the chain head.
+const MIN_POLL_INTERVAL: Duration = Duration::from_secs(1);
+/// Wait before trying again after a failed snapshot.
+const RETRY_DELAY: Duration = Duration::from_secs(30);
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SnapshotConfig {
+    /// Blocks between two snapshots, for chains without an override
+    pub interval_blocks: NonZeroU64,
+    pub per_chain: HashMap<ChainId, NonZeroU64>,
+}
+
+impl SnapshotConfig {
+    pub fn interval_blocks(&self, chain_id: ChainId) -> NonZeroU64 {
+        self.per_chain
+            .get(&chain_id)
+            .copied()
+            .unwrap_or(self.interval_blocks)
+    }
+}
+
+/// A balance as stored. Addresses are lowercase and `token` is empty for the native
+/// currency.
+#[derive(FromRow, Debug, Clone, PartialEq)]
+pub struct BalanceSnapshot {
+    pub chain_id: i64,
+    pub address: String,
+    pub token: String,
+    pub block_number: i64,
+    /// Unix seconds
+    pub block_timestamp: i64,
+    /// In the smallest unit, as a decimal string
+    pub balance: String,
+}
+
+#[derive(Serialize, SimpleObject, Debug, PartialEq)]
+pub struct SnapshotPoint {
+    pub block_number: i64,
+    pub block_timestamp: i64,
+    pub balance: String,
+    /// Signed change since the previous snapshot, `None` for the first one
+    pub delta: Option<String>,
+    /// Whether the change is at least the delta threshold
+    pub large_delta: bool,
+}
+
+#[derive(Serialize, SimpleObject, Debug, PartialEq)]
+pub struct BalanceSeries {
+    /// `None` for the native currency
+    pub token: Option<String>,
+    /// Oldest first
+    pub points: Vec<SnapshotPoint>,
+}
+
+#[derive(Deserialize, Debug, Default)]
+pub struct HistoryParams {
+    /// Only return the series of this token
+    pub token: Option<Address>,
+    /// Unix seconds
+    pub from: Option<i64>,
+    pub to: Option<i64>,
+    /// Flags changes of at least this percent of the previous balance
+    pub delta_percent: Option<u64>,
+}
+
+/// Balance series of `address`, native first then by token.
+pub async fn balance_history(
+    state: &AppState,
+    chain_id: ChainId,
+    address: Address,
+    params: &HistoryParams,
+) -> Result<Vec<BalanceSeries>, AppError> {
+    let mut snapshots = state
+        .store
+        .snapshots(
+            chain_id as i64,
+            &address.to_string().to_lowercase(),
+            params.from,
+            params.to,
+        )
+        .await?;
+    if let Some(token) = params.token {
+        let token = token.to_string().to_lowercase();
+        snapshots.retain(|snapshot| snapshot.token == token);
+    }
+
+    Ok(series(
+        snapshots,
+        params.delta_percent.unwrap_or(DEFAULT_DELTA_PERCENT),
+    ))
+}
+
+/// Groups snapshots ordered by token then block into series.
+fn series(snapshots: Vec<BalanceSnapshot>, delta_percent: u64) -> Vec<BalanceSeries> {
+    let mut series: Vec<BalanceSeries> = Vec::new();
+    let mut previous: Option<(String, U256)> = None;
+
+    for snapshot in snapshots {
+        let balance = U256::from_str(&snapshot.balance).unwrap_or_default();
+        let (delta, large_delta) = match &previous {
+            Some((token, previous)) if *token == snapshot.token => {
+                let (delta, large) = delta(*previous, balance, delta_percent);
+                (Some(delta), large)
+            }
+            _ => {
+                series.push(BalanceSeries {
+                    token: (!snapshot.token.is_empty()).then(|| snapshot.token.clone()),
+                    points: Vec::new(),
+                });
+                (None, false)
+            }
+        };
+
+        if let Some(current) = series.last_mut() {
+            current.points.push(SnapshotPoint {
+                block_number: snapshot.block_number,
+                block_timestamp: snapshot.block_timestamp,
+                balance: snapshot.balance,
+                delta,
+                large_delta,
+            });
+        }
+        previous = Some((snapshot.token, balance));
+    }
+
+    series
+}
+
+/// Signed change from `previous` to `current`, and whether it is at least `delta_percent`
+/// of `previous`. Any change from zero counts as large.
+fn delta(previous: U256, current: U256, delta_percent: u64) -> (String, bool) {
+    let (change, sign) = if current >= previous {
+        (current - previous, "")
+    } else {
+        (previous - current, "-")
+    };
+    let large = !change.is_zero()
+        && change.saturating_mul(U256::from(100))
+            >= previous.saturating_mul(U256::from(delta_percent));
+
+    (format!("{}{}", sign, change), large)
+}
+
+/// Snapshots the watched addresses of `chain_id` every `interval_blocks` blocks until
+/// `shutdown` is cancelled.
+pub async fn run_snapshots(
+    state: Arc<AppState>,
+    chain_id: ChainId,
+    interval_blocks: NonZeroU64,
+    shutdown: CancellationToken,
+) {
+    let block_time_ms = state
+        .chains
+        .get(chain_id)
+        .map_or(12_000, |chain| chain.block_time_ms);
+
+    loop {
+        let wait = match take_due_snapshot(&state, chain_id, interval_blocks).await {
+            Ok(blocks_left) => Duration::from_millis(block_time_ms.saturating_mul(blocks_left))
+                .max(MIN_POLL_INTERVAL),
+            Err(e) => {
+                error!("Error snapshotting balances on chain {}: {:?}", chain_id, e);
+                RETRY_DELAY
+            }
+        };
+
+        tokio::select! {
+            _ = shutdown.cancelled() => return,
+            _ = sleep(wait) => {}
+        }
+    }
+}
+
+/// Takes the last snapshot due at the chain head unless already stored, and returns the
+/// number of blocks until the next one.
+async fn take_due_snapshot(
+    state: &AppState,
+    chain_id: ChainId,
+    interval_blocks: NonZeroU64,
+) -> Result<u64, AppError> {
+    let head = state
+        .providers
+        .call_with_priority(
+            &state.metrics,
+            chain_id,
+            "eth_blockNumber",
+            Priority::Background,
+            get_block_number_query,
+        )
+        .await?;
+    let interval_blocks = interval_blocks.get();
+    let due = head - head % interval_blocks;
+
+    let latest = state.store.latest_snapshot_block(chain_id as i64).await?;
+    if latest < Some(due as i64) {
+        let saved = snapshot(state, chain_id, due).await?;
+        info!(
+            "Snapshotted {} balances on chain {} at block {}",
+            saved, chain_id, due
+        );
+        state
+            .metrics
+            .balance_snapshots
+            .with_label_values(&[&chain_id.to_string()])
+            .inc_by(saved);
+    }
+
+    Ok(due + interval_blocks - head)
+}
+
+/// Stores the balances of the watched addresses of `chain_id` at `block_number`.
+///
+/// Tokens whose balance cannot be read are logged and left out, so one broken contract
+/// does not stop the others from being recorded.
+async fn snapshot(state: &AppState, chain_id: ChainId, block_number: u64) -> Result<u64, AppError> {
+    let Some(chain) = state.chains.get(chain_id) else {
+        return Ok(0);
+    };
+    let block_id = BlockId::number(block_number);
+    let block_timestamp = cached_block(state, chain_id, block_id)
+        .await?
+        .ok_or_else(|| AppError::NotFound(format!("Block {} not found", block_number)))?
+        .header
+        .timestamp;
+
+    let row = |address: &Address, token: Option<&Address>, balance: U256| BalanceSnapshot {
+        chain_id: chain_id as i64,
+        address: address.to_string().to_lowercase(),
+        token: token
+            .map(|token| token.to_string().to_lowercase())
+            .unwrap_or_default(),
+        block_number: block_number as i64,
+        block_timestamp: block_timestamp as i64,
+        balance: balance.to_string(),
+    };
+
+    let mut snapshots = Vec::new();
+    for address in &chain.watched {
+        let native = state
+            .providers
+            .call_with_priority(
+                &state.metrics,
+                chain_id,
+                "eth_getBalance",
+                Priority::Background,
+                |provider| get_native_balance_query(provider, *address, block_id),
+            )
+            .await?;
+        snapshots.push(row(address, None, native));
+
+        for token in &chain.tokens {
+            let balance = state
+                .providers
+                .call_with_priority(
+                    &state.metrics,
+                    chain_id,
+                    "eth_call",
+                    Priority::Background,
+                    |provider| get_erc20_balance_query(provider, *address, *token, block_id),
+                )
+                .await;
+            match balance {
+                Ok(balance) => snapshots.push(row(address, Some(token), balance)),
+                Err(e) => warn!(
+                    "Cannot read the balance of {} in {} on chain {}: {:?}",
+                    address, token, chain_id, e
+                ),
+            }
+        }
+    }
+
+    state.store.save_snapshots(&snapshots).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn stored(token: &str, block_number: i64, balance: u64) -> BalanceSnapshot {
+        BalanceSnapshot {
+            chain_id: 1,
+            address: "0xaa".into(),
+            token: token.into(),
+            block_number,
+            block_timestamp: block_number * 12,
+            balance: balance.to_string(),
+        }
+    }
+
+    #[test]
+    fn flags_changes_past_the_threshold() {
+        assert_eq!(
+            delta(U256::from(100), U256::from(105), 10),
+            ("5".into(), false)
+        );
+        assert_eq!(
+            delta(U256::from(100), U256::from(90), 10),
+            ("-10".into(), true)
+        );
+        assert_eq!(delta(U256::ZERO, U256::from(1), 10), ("1".into(), true));
+        assert_eq!(delta(U256::from(7), U256::from(7), 0), ("0".into(), false));
+    }
+
+    #[test]
+    fn splits_series_by_token() {
+        let snapshots = vec![
+            stored("", 100, 1000),
+            stored("", 200, 400),
+            stored("0xtoken", 100, 5),
+            stored("0xtoken", 200, 5),
+        ];
+
+        let series = series(snapshots, DEFAULT_DELTA_PERCENT);
+
+        assert_eq!(series.len(), 2);
+        assert_eq!(series[0].token, None);
+        assert_eq!(series[0].points[0].delta, None);
+        assert_eq!(series[0].points[1].delta.as_deref(), Some("-600"));
+        assert!(series[0].points[1].large_delta);
+        assert_eq!(series[1].token.as_deref(), Some("0xtoken"));
+        assert_eq!(series[1].points[1].delta.as_deref(), Some("0"));
+        assert!(!series[1].points[1].large_delta);
+    }
+}
@@ -0,0 +1,544 @@
+use super::{is_token_transfer, merge, PruneScope, Pruned, TransactionStore};
+use crate::{
+    analytics::{gas::FeeSample, stats, stats::StatsBucket},
+    internal_calls::InternalCall,
+    model::{AppError, StatsParams, Transaction, TransactionFilter},
+    simulation::Simulation,
+    snapshots::BalanceSnapshot,
+    utils::unix_seconds,
+};
+use async_trait::async_trait;
+use std::{
+    collections::{HashMap, HashSet},
+    sync::{Mutex, MutexGuard},
+};
+use uuid::Uuid;
+
+#[derive(Default)]
+struct State {
+    /// Transactions with the unix time in seconds they were first stored at, in that order
+    transactions: Vec<(i64, Transaction)>,
+    pending: HashMap<(i64, String), i64>,
+    snapshots: Vec<BalanceSnapshot>,
+    simulations: HashMap<(i64, String), Simulation>,
+    internal_calls: Vec<InternalCall>,
+}
+
+/// Keeps everything in memory, for unit tests and throwaway runs.
+#[derive(Default)]
+pub struct InMemoryStore {
+    state: Mutex<State>,
+}
+
+impl InMemoryStore {
+    fn state(&self) -> Result<MutexGuard<'_, State>, AppError> {
+        self.state
+            .lock()
+            .map_err(|_| AppError::Other("In-memory store lock poisoned".into()))
+    }
+
+    /// Upserts as if it happened at `now`, in unix seconds.
+    fn upsert_at(
+        &self,
+        transaction: Transaction,
+        now: i64,
+    ) -> Result<(Transaction, bool), AppError> {
+        let mut state = self.state()?;
+        let existing = state.transactions.iter_mut().find(|(_, stored)| {
+            stored.chain_id == transaction.chain_id && stored.tx_hash == transaction.tx_hash
+        });
+
+        match existing {
+            Some((_, stored)) => {
+                merge(stored, transaction);
+                Ok((stored.clone(), false))
+            }
+            None => {
+                let transaction = Transaction {
+                    id: Uuid::new_v4(),
+                    ..transaction
+                };
+                state.transactions.push((now, transaction.clone()));
+                Ok((transaction, true))
+            }
+        }
+    }
+}
+
+#[async_trait]
+impl TransactionStore for InMemoryStore {
+    async fn migrate(&self) -> Result<(), AppError> {
+        Ok(())
+    }
+
+    async fn ping(&self) -> Result<(), AppError> {
+        self.state().map(|_| ())
+    }
+
+    async fn upsert(&self, transaction: Transaction) -> Result<(Transaction, bool), AppError> {
+        self.upsert_at(transaction, unix_seconds() as i64)
+    }
+
+    async fn upsert_batch(&self, transactions: &[Transaction]) -> Result<u64, AppError> {
+        let now = unix_seconds() as i64;
+        for transaction in transactions {
+            self.upsert_at(transaction.clone(), now)?;
+        }
+        Ok(transactions.len() as u64)
+    }
+
+    async fn get_by_id(&self, id: Uuid) -> Result<Option<Transaction>, AppError> {
+        Ok(self
+            .state()?
+            .transactions
+            .iter()
+            .find(|(_, transaction)| transaction.id == id)
+            .map(|(_, transaction)| transaction.clone()))
+    }
+
+    async fn get_by_hash(
+        &self,
+        chain_id: i64,
+        tx_hash: &str,
+    ) -> Result<Option<Transaction>, AppError> {
+        Ok(self
+            .state()?
+            .transactions
+            .iter()
+            .find(|(_, transaction)| {
+                transaction.chain_id == chain_id && transaction.tx_hash == tx_hash
+            })
+            .map(|(_, transaction)| transaction.clone()))
+    }
+
+    async fn filter(&self, filter: &TransactionFilter) -> Result<Vec<Transaction>, AppError> {
+        Ok(self
+            .state()?
+            .transactions
+            .iter()
+            .filter(|(_, transaction)| filter.matches(transaction))
+            .map(|(_, transaction)| transaction.clone())
+            .collect())
+    }
+
+    async fn stats(
+        &self,
+        params: &StatsParams,
+        filter: &TransactionFilter,
+    ) -> Result<Vec<StatsBucket>, AppError> {
+        let state = self.state()?;
+        let rows = state
+            .transactions
+            .iter()
+            .filter(|(_, transaction)| filter.matches(transaction))
+            .map(|(created_at, transaction)| (*created_at, transaction));
+        Ok(stats::aggregate(rows, params))
+    }
+
+    async fn fee_samples(
+        &self,
+        chain_id: i64,
+        window_seconds: i64,
+    ) -> Result<Vec<FeeSample>, AppError> {
+        let since = unix_seconds() as i64 - window_seconds;
+        Ok(self
+            .state()?
+            .transactions
+            .iter()
+            .filter(|(created_at, transaction)| {
+                transaction.chain_id == chain_id
+                    && transaction.mempool_time > 0
+                    && *created_at > since
+            })
+            .map(|(_, transaction)| FeeSample {
+                gas_price: transaction.gas_price,
+                priority_fee: transaction.priority_fee,
+                mempool_time: transaction.mempool_time,
+            })
+            .collect())
+    }
+
+    async fn token_contracts(&self, chain_id: i64, address: &str) -> Result<Vec<String>, AppError> {
+        let mut contracts: Vec<String> = self
+            .state()?
+            .transactions
+            .iter()
+            .filter(|(_, transaction)| {
+                transaction.chain_id == chain_id && is_token_transfer(transaction, address)
+            })
+            .map(|(_, transaction)| transaction.to_reciever.clone())
+            .collect();
+        contracts.sort();
+        contracts.dedup();
+        Ok(contracts)
+    }
+
+    async fn delete_older_than(&self, scope: &PruneScope, days: u64) -> Result<Pruned, AppError> {
+        let cutoff = unix_seconds() as i64 - days as i64 * 86400;
+        let mut state = self.state()?;
+
+        let transactions = state.transactions.len();
+        let mut deleted = HashSet::new();
+        state.transactions.retain(|(created_at, transaction)| {
+            let expired = scope.contains(transaction.chain_id) && *created_at < cutoff;
+            if expired {
+                deleted.insert((transaction.chain_id, transaction.tx_hash.to_lowercase()));
+            }
+            !expired
+        });
+        let internal_calls = state.internal_calls.len();
+        state
+            .internal_calls
+            .retain(|call| !deleted.contains(&(call.chain_id, call.tx_hash.clone())));
+        let snapshots = state.snapshots.len();
+        state.snapshots.retain(|snapshot| {
+            !(scope.contains(snapshot.chain_id) && snapshot.block_timestamp < cutoff)
+        });
+        let simulations = state.simulations.len();
+        state.simulations.retain(|(chain_id, _), simulation| {
+            !(scope.contains(*chain_id) && simulation.simulated_at < cutoff)
+        });
+
+        Ok(Pruned {
+            transactions: (transactions - state.transactions.len()) as u64,
+            balance_snapshots: (snapshots - state.snapshots.len()) as u64,
+            simulations: (simulations - state.simulations.len()) as u64,
+            internal_calls: (internal_calls - state.internal_calls.len()) as u64,
+        })
+    }
+
+    async fn save_snapshots(&self, snapshots: &[BalanceSnapshot]) -> Result<u64, AppError> {
+        let mut state = self.state()?;
+        let mut written = 0;
+        for snapshot in snapshots {
+            let stored = state.snapshots.iter().any(|stored| {
+                stored.chain_id == snapshot.chain_id
+                    && stored.address == snapshot.address
+                    && stored.token == snapshot.token
+                    && stored.block_number == snapshot.block_number
+            });
+            if !stored {
+                state.snapshots.push(snapshot.clone());
+                written += 1;
+            }
+        }
+        Ok(written)
+    }
+
+    async fn latest_snapshot_block(&self, chain_id: i64) -> Result<Option<i64>, AppError> {
+        Ok(self
+            .state()?
+            .snapshots
+            .iter()
+            .filter(|snapshot| snapshot.chain_id == chain_id)
+            .map(|snapshot| snapshot.block_number)
+            .max())
+    }
+
+    async fn snapshots(
+        &self,
+        chain_id: i64,
+        address: &str,
+        from: Option<i64>,
+        to: Option<i64>,
+    ) -> Result<Vec<BalanceSnapshot>, AppError> {
+        let address = address.to_lowercase();
+        let mut snapshots: Vec<BalanceSnapshot> = self
+            .state()?
+            .snapshots
+            .iter()
+            .filter(|snapshot| {
+                snapshot.chain_id == chain_id
+                    && snapshot.address == address
+                    && (from.unwrap_or(i64::MIN)..=to.unwrap_or(i64::MAX))
+                        .contains(&snapshot.block_timestamp)
+            })
+            .cloned()
+            .collect();
+        snapshots.sort_by(|a, b| (&a.token, a.block_number).cmp(&(&b.token, b.block_number)));
+        Ok(snapshots)
+    }
+
+    async fn save_internal_calls(&self, calls: &[InternalCall]) -> Result<u64, AppError> {
+        let mut state = self.state()?;
+        let mut written = 0;
+        for call in calls {
+            let stored = state.internal_calls.iter().any(|stored| {
+                stored.chain_id == call.chain_id
+                    && stored.tx_hash == call.tx_hash
+                    && stored.position == call.position
+            });
+            if !stored {
+                state.internal_calls.push(call.clone());
+                written += 1;
+            }
+        }
+        Ok(written)
+    }
+
+    async fn internal_calls(
+        &self,
+        chain_id: i64,
+        tx_hash: &str,
+    ) -> Result<Vec<InternalCall>, AppError> {
+        let tx_hash = tx_hash.to_lowercase();
+        let mut calls: Vec<InternalCall> = self
+            .state()?
+            .internal_calls
+            .iter()
+            .filter(|call| call.chain_id == chain_id && call.tx_hash == tx_hash)
+            .cloned()
+            .collect();
+        calls.sort_by_key(|call| call.position);
+        Ok(calls)
+    }
+
+    async fn address_internal_calls(
+        &self,
+        chain_id: i64,
+        address: &str,
+    ) -> Result<Vec<InternalCall>, AppError> {
+        let address = address.to_lowercase();
+        let mut calls: Vec<InternalCall> = self
+            .state()?
+            .internal_calls
+            .iter()
+            .filter(|call| {
+                call.chain_id == chain_id
+                    && (call.from_address == address || call.to_address == address)
+            })
+            .cloned()
+            .collect();
+        calls.sort_by(|a, b| {
+            (a.block_number, &a.tx_hash, a.position).cmp(&(b.block_number, &b.tx_hash, b.position))
+        });
+        Ok(calls)
+    }
+
+    async fn save_simulation(&self, simulation: &Simulation) -> Result<(), AppError> {
+        self.state()?.simulations.insert(
+            (
+                simulation.chain_id as i64,
+                simulation.tx_hash.to_lowercase(),
+            ),
+            simulation.clone(),
+        );
+        Ok(())
+    }
+
+    async fn simulation(
+        &self,
+        chain_id: i64,
+        tx_hash: &str,
+    ) -> Result<Option<Simulation>, AppError> {
+        Ok(self
+            .state()?
+            .simulations
+            .get(&(chain_id, tx_hash.to_lowercase()))
+            .cloned())
+    }
+
+    async fn load_pending(&self, chain_id: i64) -> Result<Vec<(String, i64)>, AppError> {
+        Ok(self
+            .state()?
+            .pending
+            .iter()
+            .filter(|((chain, _), _)| *chain == chain_id)
+            .map(|((_, tx_hash), first_seen_ms)| (tx_hash.clone(), *first_seen_ms))
+            .collect())
+    }
+
+    async fn save_pending(
+        &self,
+        chain_id: i64,
+        added: &[(String, i64)],
+        removed: &[String],
+    ) -> Result<(), AppError> {
+        let mut state = self.state()?;
+        for tx_hash in removed {
+            state.pending.remove(&(chain_id, tx_hash.clone()));
+        }
+        for (tx_hash, first_seen_ms) in added {
+            state
+                .pending
+                .entry((chain_id, tx_hash.clone()))
+                .or_insert(*first_seen_ms);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{model::Granularity, store::tests::transaction};
+
+    #[tokio::test]
+    async fn upserts_return_the_existing_row() {
+        let store = InMemoryStore::default();
+
+        let (first, inserted) = store
+            .upsert(Transaction {
+                mempool_time: 900,
+                ..transaction(1, "0xa", 0)
+            })
+            .await
+            .unwrap();
+        assert!(inserted);
+
+        let (second, inserted) = store.upsert(transaction(1, "0xa", 12)).await.unwrap();
+        assert!(!inserted);
+        assert_eq!(second.id, first.id);
+        assert_eq!(second.block_number, 12);
+        assert_eq!(second.mempool_time, 900);
+
+        let stored = store.get_by_hash(1, "0xa").await.unwrap().unwrap();
+        assert_eq!(stored.id, first.id);
+        assert_eq!(store.list().await.unwrap().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn filters_like_the_sql_backends() {
+        let store = InMemoryStore::default();
+        store
+            .upsert_batch(&[
+                Transaction {
+                    from_sender: "0xabc".into(),
+                    gas_price: 10,
+                    ..transaction(1, "0xa", 1)
+                },
+                Transaction {
+                    gas_price: 30,
+                    ..transaction(1, "0xb", 2)
+                },
+                transaction(8453, "0xc", 3),
+            ])
+            .await
+            .unwrap();
+
+        let by_address = TransactionFilter {
+            address: Some("0xABC".into()),
+            ..Default::default()
+        };
+        let hashes: Vec<_> = store
+            .filter(&by_address)
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|tx| tx.tx_hash)
+            .collect();
+        assert_eq!(hashes, vec!["0xa"]);
+
+        let by_chain_and_gas = TransactionFilter {
+            chain_id: Some(1),
+            gas_price_min: Some(20),
+            contract_type: Some("externallyownedaccount".into()),
+            ..Default::default()
+        };
+        let hashes: Vec<_> = store
+            .filter(&by_chain_and_gas)
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|tx| tx.tx_hash)
+            .collect();
+        assert_eq!(hashes, vec!["0xb"]);
+    }
+
+    #[tokio::test]
+    async fn aggregates_and_prunes_by_storage_time() {
+        let store = InMemoryStore::default();
+        let now = unix_seconds() as i64;
+        store
+            .upsert_at(transaction(1, "0xold", 1), now - 3 * 86400)
+            .unwrap();
+        store.upsert_at(transaction(1, "0xnew", 2), now).unwrap();
+        store
+            .upsert_at(transaction(8453, "0xother", 3), now - 3 * 86400)
+            .unwrap();
+
+        let params = StatsParams {
+            granularity: Granularity::Day,
+            from: None,
+            to: None,
+        };
+        let chain = TransactionFilter {
+            chain_id: Some(1),
+            ..Default::default()
+        };
+        let buckets = store.stats(&params, &chain).await.unwrap();
+        assert_eq!(buckets.len(), 2);
+        assert!(buckets.iter().all(|bucket| bucket.tx_count == 1));
+
+        let snapshot = |block_number: i64, block_timestamp: i64| BalanceSnapshot {
+            chain_id: 1,
+            address: "0xabc".into(),
+            token: String::new(),
+            block_number,
+            block_timestamp,
+            balance: "1".into(),
+        };
+        store
+            .save_snapshots(&[snapshot(10, now - 3 * 86400), snapshot(20, now)])
+            .await
+            .unwrap();
+        let simulation = |tx_hash: &str, simulated_at: i64| Simulation {
+            chain_id: 1,
+            tx_hash: tx_hash.into(),
+            block_number: 1,
+            success: true,
+            gas_used: 21_000,
+            revert_reason: None,
+            traced: true,
+            balance_diffs: Vec::new(),
+            storage_diffs: Vec::new(),
+            simulated_at,
+        };
+        store
+            .save_simulation(&simulation("0xold", now - 3 * 86400))
+            .await
+            .unwrap();
+        store
+            .save_simulation(&simulation("0xnew", now))
+            .await
+            .unwrap();
+        let call = |tx_hash: &str| InternalCall {
+            chain_id: 1,
+            tx_hash: tx_hash.into(),
+            block_number: 1,
+            position: 0,
+            depth: 1,
+            call_type: "CALL".into(),
+            from_address: "0xrouter".into(),
+            to_address: "0xvault".into(),
+            value: "0".into(),
+            error: None,
+        };
+        store
+            .save_internal_calls(&[call("0xold"), call("0xnew")])
+            .await
+            .unwrap();
+
+        let deleted = store
+            .delete_older_than(&PruneScope::AllExcept(vec![8453]), 2)
+            .await
+            .unwrap();
+        assert_eq!(
+            deleted,
+            Pruned {
+                transactions: 1,
+                balance_snapshots: 1,
+                simulations: 1,
+                internal_calls: 1,
+            }
+        );
+        assert!(store.get_by_hash(1, "0xold").await.unwrap().is_none());
+        assert!(store.get_by_hash(8453, "0xother").await.unwrap().is_some());
+        let kept = store.snapshots(1, "0xabc", None, None).await.unwrap();
+        assert_eq!(kept, vec![snapshot(20, now)]);
+        assert!(store.simulation(1, "0xold").await.unwrap().is_none());
+        assert!(store.simulation(1, "0xnew").await.unwrap().is_some());
+        assert!(store.internal_calls(1, "0xold").await.unwrap().is_empty());
+        assert_eq!(store.internal_calls(1, "0xnew").await.unwrap().len(), 1);
+    }
+}
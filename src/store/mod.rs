@@ -0,0 +1,454 @@
+//! Storage of the indexed transactions, behind a backend-agnostic trait.
+//!
+//! Postgres is the production backend, SQLite serves single-node deployments and the
+//! in-memory store backs unit tests. The backend is picked from the `DATABASE_URL` scheme.
+
+pub mod memory;
+pub mod postgres;
+pub mod sqlite;
+
+use crate::{
+    analytics::{gas::FeeSample, stats::StatsBucket},
+    internal_calls::InternalCall,
+    model::{AppError, ContractType, StatsParams, Transaction, TransactionFilter},
+    simulation::Simulation,
+    snapshots::BalanceSnapshot,
+};
+use async_trait::async_trait;
+use serde_json::Value;
+use std::{collections::HashSet, ops::AddAssign, sync::Arc};
+use uuid::Uuid;
+
+pub use memory::InMemoryStore;
+pub use postgres::PostgresStore;
+pub use sqlite::SqliteStore;
+
+/// Transactions a retention period applies to.
+#[derive(Debug, Clone)]
+pub enum PruneScope {
+    Chain(i64),
+    /// Chains without an override of the default retention
+    AllExcept(Vec<i64>),
+}
+
+impl PruneScope {
+    pub fn contains(&self, chain_id: i64) -> bool {
+        match self {
+            PruneScope::Chain(id) => *id == chain_id,
+            PruneScope::AllExcept(ids) => !ids.contains(&chain_id),
+        }
+    }
+}
+
+/// Rows `delete_older_than` removed, by table.
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct Pruned {
+    pub transactions: u64,
+    pub balance_snapshots: u64,
+    pub simulations: u64,
+    pub internal_calls: u64,
+}
+
+impl AddAssign for Pruned {
+    fn add_assign(&mut self, other: Self) {
+        self.transactions += other.transactions;
+        self.balance_snapshots += other.balance_snapshots;
+        self.simulations += other.simulations;
+        self.internal_calls += other.internal_calls;
+    }
+}
+
+#[async_trait]
+pub trait TransactionStore: Send + Sync {
+    /// Applies the pending schema migrations.
+    async fn migrate(&self) -> Result<(), AppError>;
+
+    /// Checks that the backend answers.
+    async fn ping(&self) -> Result<(), AppError>;
+
+    /// Waits for in-flight queries and releases the connections.
+    async fn close(&self) {}
+
+    /// Stores a transaction, or merges it into the copy already stored for its chain and
+    /// hash as `merge` does. Returns the stored row and whether it was newly inserted.
+    async fn upsert(&self, transaction: Transaction) -> Result<(Transaction, bool), AppError>;
+
+    /// Upserts many transactions at once, returning how many rows were written.
+    async fn upsert_batch(&self, transactions: &[Transaction]) -> Result<u64, AppError>;
+
+    async fn get_by_id(&self, id: Uuid) -> Result<Option<Transaction>, AppError>;
+
+    async fn get_by_hash(
+        &self,
+        chain_id: i64,
+        tx_hash: &str,
+    ) -> Result<Option<Transaction>, AppError>;
+
+    /// Every transaction matching `filter`, in the order they were stored.
+    async fn filter(&self, filter: &TransactionFilter) -> Result<Vec<Transaction>, AppError>;
+
+    async fn list(&self) -> Result<Vec<Transaction>, AppError> {
+        self.filter(&TransactionFilter::default()).await
+    }
+
+    /// Buckets of the transactions matching `filter`, oldest first.
+    async fn stats(
+        &self,
+        params: &StatsParams,
+        filter: &TransactionFilter,
+    ) -> Result<Vec<StatsBucket>, AppError>;
+
+    /// Fees and inclusion times of the transactions of a chain seen pending and stored
+    /// within the last `window_seconds`.
+    async fn fee_samples(
+        &self,
+        chain_id: i64,
+        window_seconds: i64,
+    ) -> Result<Vec<FeeSample>, AppError>;
+
+    /// Brings precomputed aggregates up to date, returning how many buckets changed.
+    /// Backends aggregating on the fly have nothing to refresh.
+    async fn refresh_rollups(&self) -> Result<u64, AppError> {
+        Ok(0)
+    }
+
+    /// Contracts of the ERC-20 transfers `address` sent or received in the indexed
+    /// transactions of a chain, as matched by `is_token_transfer`.
+    async fn token_contracts(&self, chain_id: i64, address: &str) -> Result<Vec<String>, AppError>;
+
+    /// Deletes the transactions of `scope` stored more than `days` ago with their internal
+    /// calls, the balance snapshots of blocks mined more than `days` ago and the simulations
+    /// run more than `days` ago.
+    async fn delete_older_than(&self, scope: &PruneScope, days: u64) -> Result<Pruned, AppError>;
+
+    /// RPC response the cache persisted under `key`. Only Postgres persists them.
+    async fn load_cached(&self, _key: &str) -> Result<Option<Value>, AppError> {
+        Ok(None)
+    }
+
+    async fn save_cached(&self, _key: &str, _value: &Value) -> Result<(), AppError> {
+        Ok(())
+    }
+
+    /// Stores balance snapshots, skipping those already stored for their chain, address,
+    /// token and block. Returns how many rows were written.
+    async fn save_snapshots(&self, snapshots: &[BalanceSnapshot]) -> Result<u64, AppError>;
+
+    /// Block of the latest balance snapshot of a chain.
+    async fn latest_snapshot_block(&self, chain_id: i64) -> Result<Option<i64>, AppError>;
+
+    /// Balance snapshots of `address` with a block timestamp within `from..=to`, ordered by
+    /// token then block, the native currency first.
+    async fn snapshots(
+        &self,
+        chain_id: i64,
+        address: &str,
+        from: Option<i64>,
+        to: Option<i64>,
+    ) -> Result<Vec<BalanceSnapshot>, AppError>;
+
+    /// Stores internal calls, skipping those already stored for their transaction and
+    /// position. Returns how many rows were written.
+    async fn save_internal_calls(&self, calls: &[InternalCall]) -> Result<u64, AppError>;
+
+    /// Internal calls of a transaction, in call order.
+    async fn internal_calls(
+        &self,
+        chain_id: i64,
+        tx_hash: &str,
+    ) -> Result<Vec<InternalCall>, AppError>;
+
+    /// Internal calls made from or to `address`, ordered by block then call order.
+    async fn address_internal_calls(
+        &self,
+        chain_id: i64,
+        address: &str,
+    ) -> Result<Vec<InternalCall>, AppError>;
+
+    /// Stores a simulation, replacing the one stored for its chain and transaction.
+    async fn save_simulation(&self, simulation: &Simulation) -> Result<(), AppError>;
+
+    /// Latest simulation of a transaction.
+    async fn simulation(
+        &self,
+        chain_id: i64,
+        tx_hash: &str,
+    ) -> Result<Option<Simulation>, AppError>;
+
+    /// Pending transactions of a chain with their first-seen time in unix milliseconds.
+    async fn load_pending(&self, chain_id: i64) -> Result<Vec<(String, i64)>, AppError>;
+
+    async fn save_pending(
+        &self,
+        chain_id: i64,
+        added: &[(String, i64)],
+        removed: &[String],
+    ) -> Result<(), AppError>;
+}
+
+/// Connects to the backend `DATABASE_URL` points at: `postgres://`, `sqlite:` or `memory:`.
+pub async fn connect_store(db_url: &str) -> Result<Arc<dyn TransactionStore>, AppError> {
+    match db_url.split(':').next() {
+        Some("postgres" | "postgresql") => Ok(Arc::new(PostgresStore::connect(db_url).await?)),
+        Some("sqlite") => Ok(Arc::new(SqliteStore::connect(db_url).await?)),
+        Some("memory") => Ok(Arc::new(InMemoryStore::default())),
+        _ => Err(AppError::Other(
+            "DATABASE_URL must start with postgres://, sqlite: or memory:".into(),
+        )),
+    }
+}
+
+/// Merges a newer copy of a transaction into the stored one.
+///
+/// Each field of the newer copy wins unless it is missing: empty, 0, without a priority
+/// fee, or classified as an externally owned account, the default when the code was not
+/// looked up. A poorer copy, such as a manual `POST /transactions`, so never erases what
+/// the scanner stored. The stored id and the first measured `mempool_time` are kept.
+pub fn merge(stored: &mut Transaction, newer: Transaction) {
+    fn text(newer: String, stored: &mut String) {
+        if !newer.is_empty() {
+            *stored = newer;
+        }
+    }
+    fn number(newer: i64, stored: &mut i64) {
+        if newer != 0 {
+            *stored = newer;
+        }
+    }
+
+    text(newer.block_hash, &mut stored.block_hash);
+    number(newer.block_number, &mut stored.block_number);
+    text(newer.from_sender, &mut stored.from_sender);
+    text(newer.to_reciever, &mut stored.to_reciever);
+    if newer.tx_value != 0 {
+        stored.tx_value = newer.tx_value;
+        stored.tx_value_overflow = newer.tx_value_overflow;
+    }
+    number(newer.gas, &mut stored.gas);
+    number(newer.gas_price, &mut stored.gas_price);
+    stored.priority_fee = newer.priority_fee.or(stored.priority_fee);
+    text(newer.input, &mut stored.input);
+    number(newer.nonce, &mut stored.nonce);
+    if stored.mempool_time <= 0 {
+        stored.mempool_time = newer.mempool_time;
+    }
+    if newer.contract_type != ContractType::ExternallyOwnedAccount {
+        stored.contract_type = newer.contract_type;
+    }
+}
+
+/// Selector of ERC-20 `transfer(address,uint256)`.
+const TRANSFER: &str = "0xa9059cbb";
+/// Selector of ERC-20 `transferFrom(address,address,uint256)`.
+const TRANSFER_FROM: &str = "0x23b872dd";
+
+/// `address` as an ABI encoded word, without `0x`.
+fn address_word(address: &str) -> String {
+    format!("{:0>64}", address.trim_start_matches("0x").to_lowercase())
+}
+
+/// SQL `LIKE` patterns: one matching the input of the transfers any account sends, then
+/// those matching transfers to or from `address` whoever sends them.
+pub(crate) fn transfer_patterns(address: &str) -> (Vec<String>, Vec<String>) {
+    let word = address_word(address);
+    (
+        vec![format!("{}%", TRANSFER), format!("{}%", TRANSFER_FROM)],
+        vec![
+            format!("{}{}%", TRANSFER, word),
+            format!("{}{}%", TRANSFER_FROM, word),
+            format!("{}{}{}%", TRANSFER_FROM, "_".repeat(64), word),
+        ],
+    )
+}
+
+/// Whether `transaction` is an ERC-20 transfer sent by `address`, or moving its tokens
+/// in or out. Agrees with `transfer_patterns`.
+pub fn is_token_transfer(transaction: &Transaction, address: &str) -> bool {
+    let address = address.to_lowercase();
+    let input = transaction.input.as_str();
+    let word = address_word(&address);
+    let argument = |index: usize| input.get(10 + index * 64..10 + (index + 1) * 64);
+
+    if input.starts_with(TRANSFER) {
+        transaction.from_sender == address || argument(0) == Some(word.as_str())
+    } else if input.starts_with(TRANSFER_FROM) {
+        transaction.from_sender == address
+            || argument(0) == Some(word.as_str())
+            || argument(1) == Some(word.as_str())
+    } else {
+        false
+    }
+}
+
+/// Keeps the last copy of each transaction, as a statement cannot upsert the same row twice.
+pub(crate) fn latest_per_hash(transactions: &[Transaction]) -> Vec<&Transaction> {
+    let mut seen = HashSet::new();
+    let mut latest: Vec<&Transaction> = transactions
+        .iter()
+        .rev()
+        .filter(|transaction| seen.insert((transaction.chain_id, transaction.tx_hash.as_str())))
+        .collect();
+    latest.reverse();
+    latest
+}
+
+/// Maps a database error, telling apart failures worth retrying: lost connections, an
+/// exhausted pool and conflicts between concurrent transactions.
+pub(crate) fn db_error(error: sqlx::Error) -> AppError {
+    let transient = match &error {
+        sqlx::Error::Io(_) | sqlx::Error::PoolTimedOut | sqlx::Error::Tls(_) => true,
+        sqlx::Error::Database(e) => matches!(
+            e.code().as_deref(),
+            Some(code) if code.starts_with("08") || code.starts_with("57P") || code == "40001" || code == "40P01"
+        ),
+        _ => false,
+    };
+
+    if transient {
+        AppError::DatabaseUnavailable(error.to_string())
+    } else {
+        AppError::DatabaseError(error.to_string())
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    pub(crate) fn transaction(chain_id: i64, tx_hash: &str, block_number: i64) -> Transaction {
+        Transaction {
+            id: Uuid::default(),
+            chain_id,
+            tx_hash: tx_hash.into(),
+            block_hash: String::new(),
+            block_number,
+            from_sender: String::new(),
+            to_reciever: String::new(),
+            tx_value: 0,
+            tx_value_overflow: false,
+            gas: 0,
+            gas_price: 0,
+            priority_fee: None,
+            input: String::new(),
+            nonce: 0,
+            mempool_time: 0,
+            contract_type: ContractType::ExternallyOwnedAccount,
+        }
+    }
+
+    #[test]
+    fn merge_keeps_measured_mempool_time_and_known_priority_fee() {
+        let mut stored = Transaction {
+            id: Uuid::new_v4(),
+            mempool_time: 1200,
+            priority_fee: Some(7),
+            ..transaction(1, "0xa", 10)
+        };
+        let id = stored.id;
+
+        merge(&mut stored, transaction(1, "0xa", 11));
+
+        assert_eq!(stored.id, id);
+        assert_eq!(stored.block_number, 11);
+        assert_eq!(stored.mempool_time, 1200);
+        assert_eq!(stored.priority_fee, Some(7));
+    }
+
+    #[test]
+    fn merge_keeps_fields_a_poorer_copy_is_missing() {
+        let mut stored = Transaction {
+            block_hash: "0xblock".into(),
+            from_sender: "0xsender".into(),
+            tx_value: 5,
+            input: "0xa9059cbb".into(),
+            contract_type: ContractType::ContractAccount,
+            ..transaction(1, "0xa", 10)
+        };
+
+        merge(
+            &mut stored,
+            Transaction {
+                gas: 21_000,
+                ..transaction(1, "0xa", 0)
+            },
+        );
+
+        assert_eq!(stored.block_hash, "0xblock");
+        assert_eq!(stored.block_number, 10);
+        assert_eq!(stored.from_sender, "0xsender");
+        assert_eq!(stored.tx_value, 5);
+        assert_eq!(stored.gas, 21_000);
+        assert_eq!(stored.input, "0xa9059cbb");
+        assert_eq!(stored.contract_type, ContractType::ContractAccount);
+    }
+
+    #[test]
+    fn keeps_the_last_copy_of_each_transaction() {
+        let batch = vec![
+            transaction(1, "0xa", 10),
+            transaction(1, "0xb", 10),
+            transaction(8453, "0xa", 10),
+            transaction(1, "0xa", 11),
+        ];
+
+        let latest: Vec<_> = latest_per_hash(&batch)
+            .into_iter()
+            .map(|tx| (tx.chain_id, tx.tx_hash.as_str(), tx.block_number))
+            .collect();
+
+        assert_eq!(
+            latest,
+            vec![(1, "0xb", 10), (8453, "0xa", 10), (1, "0xa", 11)]
+        );
+    }
+
+    #[test]
+    fn matches_transfers_by_sender_or_arguments() {
+        let holder = "0x00000000000000000000000000000000000000aa";
+        let other = "0x00000000000000000000000000000000000000bb";
+        let word = |address: &str| address_word(address);
+        let transfer = |from: &str, input: String| Transaction {
+            from_sender: from.into(),
+            to_reciever: "0xtoken".into(),
+            input,
+            ..transaction(1, "0xa", 10)
+        };
+
+        let sent = transfer(
+            holder,
+            format!("{}{}{}", TRANSFER, word(other), "0".repeat(64)),
+        );
+        let received = transfer(
+            other,
+            format!("{}{}{}", TRANSFER, word(holder), "0".repeat(64)),
+        );
+        let pulled = transfer(
+            other,
+            format!("{}{}{}", TRANSFER_FROM, word(other), word(holder)),
+        );
+        let unrelated = transfer(other, format!("{}{}", TRANSFER, word(other)));
+        let not_a_transfer = transfer(holder, "0x".into());
+
+        assert!(is_token_transfer(&sent, holder));
+        assert!(is_token_transfer(&received, holder));
+        assert!(is_token_transfer(&pulled, holder));
+        assert!(!is_token_transfer(&unrelated, holder));
+        assert!(!is_token_transfer(&not_a_transfer, holder));
+
+        let (sent_patterns, involving) = transfer_patterns(holder);
+        assert_eq!(sent_patterns, vec!["0xa9059cbb%", "0x23b872dd%"]);
+        assert_eq!(involving[2].len(), 10 + 64 + 64 + 1);
+    }
+
+    #[test]
+    fn only_pool_and_connection_errors_are_transient() {
+        assert!(matches!(
+            db_error(sqlx::Error::PoolTimedOut),
+            AppError::DatabaseUnavailable(_)
+        ));
+        assert!(matches!(
+            db_error(sqlx::Error::RowNotFound),
+            AppError::DatabaseError(_)
+        ));
+    }
+}
@@ -0,0 +1,558 @@
+use super::{db_error, latest_per_hash, transfer_patterns, PruneScope, Pruned, TransactionStore};
+use crate::{
+    analytics::{gas::FeeSample, rollup, stats, stats::StatsBucket},
+    internal_calls::InternalCall,
+    model::{AppError, StatsParams, Transaction, TransactionFilter},
+    simulation::Simulation,
+    snapshots::BalanceSnapshot,
+};
+use async_trait::async_trait;
+use serde_json::Value;
+use sqlx::{postgres::PgPoolOptions, FromRow, PgPool, Postgres, QueryBuilder};
+use uuid::Uuid;
+
+/// Columns written when storing a transaction, in bind order.
+const TRANSACTION_COLUMNS: &str = "chain_id, tx_hash, block_hash, block_number, from_sender, to_reciever, tx_value, tx_value_overflow, gas, gas_price, priority_fee, input, nonce, mempool_time, contract_type";
+
+/// Merges a transaction into the row already stored for its chain and hash, like `merge`.
+const UPSERT_ON_CONFLICT: &str = "ON CONFLICT (chain_id, tx_hash) DO UPDATE SET
+    block_hash = COALESCE(NULLIF(EXCLUDED.block_hash, ''), transaction.block_hash),
+    block_number = COALESCE(NULLIF(EXCLUDED.block_number, 0), transaction.block_number),
+    from_sender = COALESCE(NULLIF(EXCLUDED.from_sender, ''), transaction.from_sender),
+    to_reciever = COALESCE(NULLIF(EXCLUDED.to_reciever, ''), transaction.to_reciever),
+    tx_value = COALESCE(NULLIF(EXCLUDED.tx_value, 0), transaction.tx_value),
+    tx_value_overflow = CASE WHEN EXCLUDED.tx_value = 0 THEN transaction.tx_value_overflow ELSE EXCLUDED.tx_value_overflow END,
+    gas = COALESCE(NULLIF(EXCLUDED.gas, 0), transaction.gas),
+    gas_price = COALESCE(NULLIF(EXCLUDED.gas_price, 0), transaction.gas_price),
+    priority_fee = COALESCE(EXCLUDED.priority_fee, transaction.priority_fee),
+    input = COALESCE(NULLIF(EXCLUDED.input, ''), transaction.input),
+    nonce = COALESCE(NULLIF(EXCLUDED.nonce, 0), transaction.nonce),
+    mempool_time = CASE WHEN transaction.mempool_time > 0 THEN transaction.mempool_time ELSE EXCLUDED.mempool_time END,
+    contract_type = CASE WHEN EXCLUDED.contract_type = 'externallyownedaccount' THEN transaction.contract_type ELSE EXCLUDED.contract_type END";
+
+/// Rows per statement, keeping the binds per row under Postgres' bind limit.
+const STATEMENT_ROWS: usize = 1000;
+/// Rows deleted per statement, so pruning never holds long locks.
+const DELETE_BATCH_SIZE: i64 = 10_000;
+
+/// A row returned by the upsert, with whether it was newly inserted.
+#[derive(FromRow)]
+struct Upserted {
+    #[sqlx(flatten)]
+    transaction: Transaction,
+    inserted: bool,
+}
+
+pub struct PostgresStore {
+    pool: PgPool,
+}
+
+impl PostgresStore {
+    pub async fn connect(db_url: &str) -> Result<Self, AppError> {
+        let pool = PgPoolOptions::new()
+            .max_connections(5)
+            .connect(db_url)
+            .await
+            .map_err(db_error)?;
+        Ok(Self { pool })
+    }
+
+    /// Deletes the rows of `table` in `scope` matching `condition`, which binds `days` as
+    /// `$2`, in batches of `DELETE_BATCH_SIZE` rows picked by their `key` column.
+    async fn delete_batched(
+        &self,
+        table: &str,
+        key: &str,
+        condition: &str,
+        scope: &PruneScope,
+        days: u64,
+    ) -> Result<u64, AppError> {
+        let scope_condition = match scope {
+            PruneScope::Chain(_) => "chain_id = $1",
+            PruneScope::AllExcept(_) => "chain_id <> ALL($1)",
+        };
+        let statement = format!(
+            "DELETE FROM {0} WHERE {1} IN (
+                SELECT {1} FROM {0}
+                WHERE {2} AND {3}
+                LIMIT $3
+            )",
+            table, key, scope_condition, condition
+        );
+
+        let mut deleted = 0;
+        loop {
+            let query = match scope {
+                PruneScope::Chain(chain_id) => sqlx::query(&statement).bind(*chain_id),
+                PruneScope::AllExcept(chain_ids) => sqlx::query(&statement).bind(chain_ids.clone()),
+            };
+            let rows = query
+                .bind(days as i32)
+                .bind(DELETE_BATCH_SIZE)
+                .execute(&self.pool)
+                .await
+                .map_err(db_error)?
+                .rows_affected();
+
+            deleted += rows;
+            if rows < DELETE_BATCH_SIZE as u64 {
+                return Ok(deleted);
+            }
+        }
+    }
+}
+
+#[async_trait]
+impl TransactionStore for PostgresStore {
+    async fn migrate(&self) -> Result<(), AppError> {
+        sqlx::migrate!("./migrations")
+            .run(&self.pool)
+            .await
+            .map_err(|e| AppError::DatabaseError(e.to_string()))
+    }
+
+    async fn ping(&self) -> Result<(), AppError> {
+        sqlx::query("SELECT 1")
+            .execute(&self.pool)
+            .await
+            .map_err(db_error)?;
+        Ok(())
+    }
+
+    async fn close(&self) {
+        self.pool.close().await;
+    }
+
+    async fn upsert(&self, transaction: Transaction) -> Result<(Transaction, bool), AppError> {
+        let sql = format!(
+            "INSERT INTO transaction ({}) VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11, $12, $13, $14, $15) {} RETURNING *, (xmax = 0) AS inserted",
+            TRANSACTION_COLUMNS, UPSERT_ON_CONFLICT
+        );
+        let upserted = sqlx::query_as::<_, Upserted>(&sql)
+            .bind(transaction.chain_id)
+            .bind(transaction.tx_hash)
+            .bind(transaction.block_hash)
+            .bind(transaction.block_number)
+            .bind(transaction.from_sender)
+            .bind(transaction.to_reciever)
+            .bind(transaction.tx_value)
+            .bind(transaction.tx_value_overflow)
+            .bind(transaction.gas)
+            .bind(transaction.gas_price)
+            .bind(transaction.priority_fee)
+            .bind(transaction.input)
+            .bind(transaction.nonce)
+            .bind(transaction.mempool_time)
+            .bind(transaction.contract_type)
+            .fetch_one(&self.pool)
+            .await
+            .map_err(db_error)?;
+
+        Ok((upserted.transaction, upserted.inserted))
+    }
+
+    async fn upsert_batch(&self, transactions: &[Transaction]) -> Result<u64, AppError> {
+        let transactions = latest_per_hash(transactions);
+        let mut written = 0;
+
+        for chunk in transactions.chunks(STATEMENT_ROWS) {
+            let mut query = QueryBuilder::<Postgres>::new(format!(
+                "INSERT INTO transaction ({}) ",
+                TRANSACTION_COLUMNS
+            ));
+            query.push_values(chunk, |mut row, transaction| {
+                row.push_bind(transaction.chain_id)
+                    .push_bind(&transaction.tx_hash)
+                    .push_bind(&transaction.block_hash)
+                    .push_bind(transaction.block_number)
+                    .push_bind(&transaction.from_sender)
+                    .push_bind(&transaction.to_reciever)
+                    .push_bind(transaction.tx_value)
+                    .push_bind(transaction.tx_value_overflow)
+                    .push_bind(transaction.gas)
+                    .push_bind(transaction.gas_price)
+                    .push_bind(transaction.priority_fee)
+                    .push_bind(&transaction.input)
+                    .push_bind(transaction.nonce)
+                    .push_bind(transaction.mempool_time)
+                    .push_bind(transaction.contract_type);
+            });
+            query.push(" ").push(UPSERT_ON_CONFLICT);
+
+            written += query
+                .build()
+                .execute(&self.pool)
+                .await
+                .map_err(db_error)?
+                .rows_affected();
+        }
+
+        Ok(written)
+    }
+
+    async fn get_by_id(&self, id: Uuid) -> Result<Option<Transaction>, AppError> {
+        sqlx::query_as::<_, Transaction>("SELECT * FROM transaction WHERE id = $1")
+            .bind(id)
+            .fetch_optional(&self.pool)
+            .await
+            .map_err(db_error)
+    }
+
+    async fn get_by_hash(
+        &self,
+        chain_id: i64,
+        tx_hash: &str,
+    ) -> Result<Option<Transaction>, AppError> {
+        sqlx::query_as::<_, Transaction>(
+            "SELECT * FROM transaction WHERE chain_id = $1 AND tx_hash = $2",
+        )
+        .bind(chain_id)
+        .bind(tx_hash)
+        .fetch_optional(&self.pool)
+        .await
+        .map_err(db_error)
+    }
+
+    async fn filter(&self, filter: &TransactionFilter) -> Result<Vec<Transaction>, AppError> {
+        let mut query = QueryBuilder::<Postgres>::new("SELECT * FROM transaction WHERE 1=1");
+        filter.push_conditions(&mut query);
+        query.push(" ORDER BY created_at");
+
+        query
+            .build_query_as::<Transaction>()
+            .fetch_all(&self.pool)
+            .await
+            .map_err(db_error)
+    }
+
+    async fn stats(
+        &self,
+        params: &StatsParams,
+        filter: &TransactionFilter,
+    ) -> Result<Vec<StatsBucket>, AppError> {
+        stats::transaction_stats(&self.pool, params, filter).await
+    }
+
+    async fn fee_samples(
+        &self,
+        chain_id: i64,
+        window_seconds: i64,
+    ) -> Result<Vec<FeeSample>, AppError> {
+        // Backfilled transactions were never seen pending and have no mempool_time
+        sqlx::query_as::<_, FeeSample>(
+            "SELECT gas_price, priority_fee, mempool_time FROM transaction
+            WHERE chain_id = $1 AND mempool_time > 0 AND created_at > NOW() - make_interval(secs => $2)",
+        )
+        .bind(chain_id)
+        .bind(window_seconds as f64)
+        .fetch_all(&self.pool)
+        .await
+        .map_err(db_error)
+    }
+
+    async fn refresh_rollups(&self) -> Result<u64, AppError> {
+        rollup::refresh_rollups(&self.pool).await
+    }
+
+    async fn token_contracts(&self, chain_id: i64, address: &str) -> Result<Vec<String>, AppError> {
+        let (sent, involving) = transfer_patterns(address);
+        sqlx::query_scalar::<_, String>(
+            "SELECT DISTINCT to_reciever FROM transaction
+            WHERE chain_id = $1
+            AND ((from_sender = $2 AND (input LIKE $3 OR input LIKE $4))
+                OR input LIKE $5 OR input LIKE $6 OR input LIKE $7)
+            ORDER BY to_reciever",
+        )
+        .bind(chain_id)
+        .bind(address.to_lowercase())
+        .bind(&sent[0])
+        .bind(&sent[1])
+        .bind(&involving[0])
+        .bind(&involving[1])
+        .bind(&involving[2])
+        .fetch_all(&self.pool)
+        .await
+        .map_err(db_error)
+    }
+
+    async fn delete_older_than(&self, scope: &PruneScope, days: u64) -> Result<Pruned, AppError> {
+        // Before their transactions, which identify them
+        let internal_calls = self
+            .delete_batched(
+                "internal_call",
+                "ctid",
+                "(chain_id, tx_hash) IN (
+                    SELECT chain_id, LOWER(tx_hash) FROM transaction
+                    WHERE created_at < NOW() - make_interval(days => $2)
+                )",
+                scope,
+                days,
+            )
+            .await?;
+        Ok(Pruned {
+            internal_calls,
+            transactions: self
+                .delete_batched(
+                    "transaction",
+                    "id",
+                    "created_at < NOW() - make_interval(days => $2)",
+                    scope,
+                    days,
+                )
+                .await?,
+            balance_snapshots: self
+                .delete_batched(
+                    "balance_snapshot",
+                    "ctid",
+                    "block_timestamp < EXTRACT(EPOCH FROM NOW() - make_interval(days => $2))",
+                    scope,
+                    days,
+                )
+                .await?,
+            simulations: self
+                .delete_batched(
+                    "simulation",
+                    "ctid",
+                    "simulated_at < EXTRACT(EPOCH FROM NOW() - make_interval(days => $2))",
+                    scope,
+                    days,
+                )
+                .await?,
+        })
+    }
+
+    async fn load_cached(&self, key: &str) -> Result<Option<Value>, AppError> {
+        sqlx::query_scalar::<_, Value>("SELECT value FROM rpc_cache WHERE key = $1")
+            .bind(key)
+            .fetch_optional(&self.pool)
+            .await
+            .map_err(db_error)
+    }
+
+    async fn save_cached(&self, key: &str, value: &Value) -> Result<(), AppError> {
+        sqlx::query(
+            "INSERT INTO rpc_cache (key, value) VALUES ($1, $2) ON CONFLICT (key) DO NOTHING",
+        )
+        .bind(key)
+        .bind(value)
+        .execute(&self.pool)
+        .await
+        .map_err(db_error)?;
+        Ok(())
+    }
+
+    async fn save_snapshots(&self, snapshots: &[BalanceSnapshot]) -> Result<u64, AppError> {
+        let mut written = 0;
+
+        for chunk in snapshots.chunks(STATEMENT_ROWS) {
+            let mut query = QueryBuilder::<Postgres>::new(
+                "INSERT INTO balance_snapshot (chain_id, address, token, block_number, block_timestamp, balance) ",
+            );
+            query.push_values(chunk, |mut row, snapshot| {
+                row.push_bind(snapshot.chain_id)
+                    .push_bind(&snapshot.address)
+                    .push_bind(&snapshot.token)
+                    .push_bind(snapshot.block_number)
+                    .push_bind(snapshot.block_timestamp)
+                    .push_bind(&snapshot.balance);
+            });
+            query.push(" ON CONFLICT (chain_id, address, token, block_number) DO NOTHING");
+
+            written += query
+                .build()
+                .execute(&self.pool)
+                .await
+                .map_err(db_error)?
+                .rows_affected();
+        }
+
+        Ok(written)
+    }
+
+    async fn latest_snapshot_block(&self, chain_id: i64) -> Result<Option<i64>, AppError> {
+        sqlx::query_scalar::<_, Option<i64>>(
+            "SELECT MAX(block_number) FROM balance_snapshot WHERE chain_id = $1",
+        )
+        .bind(chain_id)
+        .fetch_one(&self.pool)
+        .await
+        .map_err(db_error)
+    }
+
+    async fn snapshots(
+        &self,
+        chain_id: i64,
+        address: &str,
+        from: Option<i64>,
+        to: Option<i64>,
+    ) -> Result<Vec<BalanceSnapshot>, AppError> {
+        let mut query =
+            QueryBuilder::<Postgres>::new("SELECT * FROM balance_snapshot WHERE chain_id = ");
+        query
+            .push_bind(chain_id)
+            .push(" AND address = ")
+            .push_bind(address.to_lowercase());
+        if let Some(from) = from {
+            query.push(" AND block_timestamp >= ").push_bind(from);
+        }
+        if let Some(to) = to {
+            query.push(" AND block_timestamp <= ").push_bind(to);
+        }
+        query.push(" ORDER BY token, block_number");
+
+        query
+            .build_query_as::<BalanceSnapshot>()
+            .fetch_all(&self.pool)
+            .await
+            .map_err(db_error)
+    }
+
+    async fn save_internal_calls(&self, calls: &[InternalCall]) -> Result<u64, AppError> {
+        let mut written = 0;
+
+        for chunk in calls.chunks(STATEMENT_ROWS) {
+            let mut query = QueryBuilder::<Postgres>::new(
+                "INSERT INTO internal_call (chain_id, tx_hash, block_number, position, depth, call_type, from_address, to_address, value, error) ",
+            );
+            query.push_values(chunk, |mut row, call| {
+                row.push_bind(call.chain_id)
+                    .push_bind(&call.tx_hash)
+                    .push_bind(call.block_number)
+                    .push_bind(call.position)
+                    .push_bind(call.depth)
+                    .push_bind(&call.call_type)
+                    .push_bind(&call.from_address)
+                    .push_bind(&call.to_address)
+                    .push_bind(&call.value)
+                    .push_bind(&call.error);
+            });
+            query.push(" ON CONFLICT (chain_id, tx_hash, position) DO NOTHING");
+
+            written += query
+                .build()
+                .execute(&self.pool)
+                .await
+                .map_err(db_error)?
+                .rows_affected();
+        }
+
+        Ok(written)
+    }
+
+    async fn internal_calls(
+        &self,
+        chain_id: i64,
+        tx_hash: &str,
+    ) -> Result<Vec<InternalCall>, AppError> {
+        sqlx::query_as::<_, InternalCall>(
+            "SELECT * FROM internal_call WHERE chain_id = $1 AND tx_hash = $2 ORDER BY position",
+        )
+        .bind(chain_id)
+        .bind(tx_hash.to_lowercase())
+        .fetch_all(&self.pool)
+        .await
+        .map_err(db_error)
+    }
+
+    async fn address_internal_calls(
+        &self,
+        chain_id: i64,
+        address: &str,
+    ) -> Result<Vec<InternalCall>, AppError> {
+        let address = address.to_lowercase();
+        sqlx::query_as::<_, InternalCall>(
+            "SELECT * FROM internal_call WHERE chain_id = $1 AND (from_address = $2 OR to_address = $3)
+            ORDER BY block_number, tx_hash, position",
+        )
+        .bind(chain_id)
+        .bind(&address)
+        .bind(&address)
+        .fetch_all(&self.pool)
+        .await
+        .map_err(db_error)
+    }
+
+    async fn save_simulation(&self, simulation: &Simulation) -> Result<(), AppError> {
+        sqlx::query(
+            "INSERT INTO simulation (chain_id, tx_hash, block_number, success, gas_used, result, simulated_at)
+            VALUES ($1, $2, $3, $4, $5, $6, $7)
+            ON CONFLICT (chain_id, tx_hash) DO UPDATE SET
+                block_number = EXCLUDED.block_number,
+                success = EXCLUDED.success,
+                gas_used = EXCLUDED.gas_used,
+                result = EXCLUDED.result,
+                simulated_at = EXCLUDED.simulated_at",
+        )
+        .bind(simulation.chain_id as i64)
+        .bind(simulation.tx_hash.to_lowercase())
+        .bind(simulation.block_number as i64)
+        .bind(simulation.success)
+        .bind(simulation.gas_used as i64)
+        .bind(serde_json::to_value(simulation)?)
+        .bind(simulation.simulated_at)
+        .execute(&self.pool)
+        .await
+        .map_err(db_error)?;
+        Ok(())
+    }
+
+    async fn simulation(
+        &self,
+        chain_id: i64,
+        tx_hash: &str,
+    ) -> Result<Option<Simulation>, AppError> {
+        let result = sqlx::query_scalar::<_, Value>(
+            "SELECT result FROM simulation WHERE chain_id = $1 AND tx_hash = $2",
+        )
+        .bind(chain_id)
+        .bind(tx_hash.to_lowercase())
+        .fetch_optional(&self.pool)
+        .await
+        .map_err(db_error)?;
+
+        Ok(result.map(serde_json::from_value).transpose()?)
+    }
+
+    async fn load_pending(&self, chain_id: i64) -> Result<Vec<(String, i64)>, AppError> {
+        sqlx::query_as::<_, (String, i64)>(
+            "SELECT tx_hash, first_seen_ms FROM pending_transaction WHERE chain_id = $1",
+        )
+        .bind(chain_id)
+        .fetch_all(&self.pool)
+        .await
+        .map_err(db_error)
+    }
+
+    async fn save_pending(
+        &self,
+        chain_id: i64,
+        added: &[(String, i64)],
+        removed: &[String],
+    ) -> Result<(), AppError> {
+        for chunk in removed.chunks(STATEMENT_ROWS) {
+            sqlx::query(
+                "DELETE FROM pending_transaction WHERE chain_id = $1 AND tx_hash = ANY($2)",
+            )
+            .bind(chain_id)
+            .bind(chunk)
+            .execute(&self.pool)
+            .await
+            .map_err(db_error)?;
+        }
+
+        for chunk in added.chunks(STATEMENT_ROWS) {
+            let mut query = QueryBuilder::<Postgres>::new(
+                "INSERT INTO pending_transaction (chain_id, tx_hash, first_seen_ms) ",
+            );
+            query.push_values(chunk, |mut row, (tx_hash, first_seen_ms)| {
+                row.push_bind(chain_id)
+                    .push_bind(tx_hash)
+                    .push_bind(*first_seen_ms);
+            });
+            query.push(" ON CONFLICT (chain_id, tx_hash) DO NOTHING");
+            query.build().execute(&self.pool).await.map_err(db_error)?;
+        }
+
+        Ok(())
+    }
+}
@@ -0,0 +1,634 @@
+use super::{db_error, latest_per_hash, transfer_patterns, PruneScope, Pruned, TransactionStore};
+use crate::{
+    analytics::{gas::FeeSample, stats, stats::StatsBucket},
+    internal_calls::InternalCall,
+    model::{AppError, ContractType, StatsParams, Transaction, TransactionFilter},
+    simulation::Simulation,
+    snapshots::BalanceSnapshot,
+    utils::unix_seconds,
+};
+use async_trait::async_trait;
+use serde_json::Value;
+use sqlx::{
+    sqlite::{SqliteConnectOptions, SqlitePoolOptions},
+    FromRow, QueryBuilder, Sqlite, SqlitePool,
+};
+use std::str::FromStr;
+use uuid::Uuid;
+
+/// Columns written when storing a transaction, in bind order.
+const TRANSACTION_COLUMNS: &str = "id, chain_id, tx_hash, block_hash, block_number, from_sender, to_reciever, tx_value, tx_value_overflow, gas, gas_price, priority_fee, input, nonce, mempool_time, contract_type";
+
+/// Same merge as the Postgres backend. Unqualified columns are the stored row.
+const UPSERT_ON_CONFLICT: &str = "ON CONFLICT (chain_id, tx_hash) DO UPDATE SET
+    block_hash = COALESCE(NULLIF(excluded.block_hash, ''), block_hash),
+    block_number = COALESCE(NULLIF(excluded.block_number, 0), block_number),
+    from_sender = COALESCE(NULLIF(excluded.from_sender, ''), from_sender),
+    to_reciever = COALESCE(NULLIF(excluded.to_reciever, ''), to_reciever),
+    tx_value = COALESCE(NULLIF(excluded.tx_value, 0), tx_value),
+    tx_value_overflow = CASE WHEN excluded.tx_value = 0 THEN tx_value_overflow ELSE excluded.tx_value_overflow END,
+    gas = COALESCE(NULLIF(excluded.gas, 0), gas),
+    gas_price = COALESCE(NULLIF(excluded.gas_price, 0), gas_price),
+    priority_fee = COALESCE(excluded.priority_fee, priority_fee),
+    input = COALESCE(NULLIF(excluded.input, ''), input),
+    nonce = COALESCE(NULLIF(excluded.nonce, 0), nonce),
+    mempool_time = CASE WHEN mempool_time > 0 THEN mempool_time ELSE excluded.mempool_time END,
+    contract_type = CASE WHEN excluded.contract_type = 'externallyownedaccount' THEN contract_type ELSE excluded.contract_type END";
+
+/// Rows per statement, keeping the binds per row under SQLite's bind limit.
+const STATEMENT_ROWS: usize = 1000;
+
+/// A stored row, with the contract type as its lowercase name.
+#[derive(FromRow)]
+struct Row {
+    id: Uuid,
+    chain_id: i64,
+    tx_hash: String,
+    block_hash: String,
+    block_number: i64,
+    from_sender: String,
+    to_reciever: String,
+    tx_value: i64,
+    tx_value_overflow: bool,
+    gas: i64,
+    gas_price: i64,
+    priority_fee: Option<i64>,
+    input: String,
+    nonce: i64,
+    mempool_time: i64,
+    contract_type: String,
+    created_at: i64,
+}
+
+impl Row {
+    /// Returns the transaction with the unix time in seconds it was stored at.
+    fn into_transaction(self) -> Result<(i64, Transaction), AppError> {
+        let contract_type = [
+            ContractType::ExternallyOwnedAccount,
+            ContractType::ContractAccount,
+            ContractType::SpecialCaseContract,
+        ]
+        .into_iter()
+        .find(|contract_type| {
+            contract_type
+                .as_str()
+                .eq_ignore_ascii_case(&self.contract_type)
+        })
+        .ok_or_else(|| {
+            AppError::DatabaseError(format!("Unknown contract type: {}", self.contract_type))
+        })?;
+
+        Ok((
+            self.created_at,
+            Transaction {
+                id: self.id,
+                chain_id: self.chain_id,
+                tx_hash: self.tx_hash,
+                block_hash: self.block_hash,
+                block_number: self.block_number,
+                from_sender: self.from_sender,
+                to_reciever: self.to_reciever,
+                tx_value: self.tx_value,
+                tx_value_overflow: self.tx_value_overflow,
+                gas: self.gas,
+                gas_price: self.gas_price,
+                priority_fee: self.priority_fee,
+                input: self.input,
+                nonce: self.nonce,
+                mempool_time: self.mempool_time,
+                contract_type,
+            },
+        ))
+    }
+}
+
+fn contract_type_name(contract_type: ContractType) -> String {
+    contract_type.as_str().to_lowercase()
+}
+
+/// Single-node backend, aggregating statistics on the fly instead of keeping rollups.
+pub struct SqliteStore {
+    pool: SqlitePool,
+}
+
+impl SqliteStore {
+    pub async fn connect(db_url: &str) -> Result<Self, AppError> {
+        let options = SqliteConnectOptions::from_str(db_url)
+            .map_err(db_error)?
+            .create_if_missing(true);
+        let pool = SqlitePoolOptions::new()
+            .max_connections(5)
+            .connect_with(options)
+            .await
+            .map_err(db_error)?;
+        Ok(Self { pool })
+    }
+
+    async fn rows(&self, filter: &TransactionFilter) -> Result<Vec<(i64, Transaction)>, AppError> {
+        let mut query = QueryBuilder::<Sqlite>::new("SELECT * FROM \"transaction\" WHERE 1=1");
+        filter.push_conditions(&mut query);
+        query.push(" ORDER BY created_at, rowid");
+
+        query
+            .build_query_as::<Row>()
+            .fetch_all(&self.pool)
+            .await
+            .map_err(db_error)?
+            .into_iter()
+            .map(Row::into_transaction)
+            .collect()
+    }
+
+    async fn find(
+        &self,
+        mut query: QueryBuilder<'_, Sqlite>,
+    ) -> Result<Option<Transaction>, AppError> {
+        let row = query
+            .build_query_as::<Row>()
+            .fetch_optional(&self.pool)
+            .await
+            .map_err(db_error)?;
+
+        row.map(|row| row.into_transaction().map(|(_, transaction)| transaction))
+            .transpose()
+    }
+
+    /// Deletes the rows of `table` in `scope` whose unix time `column` is before `cutoff`.
+    async fn delete_before(
+        &self,
+        table: &str,
+        column: &str,
+        scope: &PruneScope,
+        cutoff: i64,
+    ) -> Result<u64, AppError> {
+        let mut query =
+            QueryBuilder::<Sqlite>::new(format!("DELETE FROM {} WHERE {} < ", table, column));
+        query.push_bind(cutoff);
+        push_scope(&mut query, scope);
+
+        Ok(query
+            .build()
+            .execute(&self.pool)
+            .await
+            .map_err(db_error)?
+            .rows_affected())
+    }
+
+    /// Deletes the internal calls of the transactions `delete_before` is about to delete.
+    async fn delete_internal_calls_before(
+        &self,
+        scope: &PruneScope,
+        cutoff: i64,
+    ) -> Result<u64, AppError> {
+        let mut query = QueryBuilder::<Sqlite>::new(
+            "DELETE FROM internal_call WHERE (chain_id, tx_hash) IN (
+                SELECT chain_id, LOWER(tx_hash) FROM \"transaction\" WHERE created_at < ",
+        );
+        query.push_bind(cutoff);
+        push_scope(&mut query, scope);
+        query.push(")");
+
+        Ok(query
+            .build()
+            .execute(&self.pool)
+            .await
+            .map_err(db_error)?
+            .rows_affected())
+    }
+}
+
+/// Restricts a query to the chains of `scope`.
+fn push_scope(query: &mut QueryBuilder<'_, Sqlite>, scope: &PruneScope) {
+    match scope {
+        PruneScope::Chain(chain_id) => {
+            query.push(" AND chain_id = ").push_bind(*chain_id);
+        }
+        PruneScope::AllExcept(chain_ids) if !chain_ids.is_empty() => {
+            query.push(" AND chain_id NOT IN (");
+            let mut separated = query.separated(", ");
+            for chain_id in chain_ids {
+                separated.push_bind(*chain_id);
+            }
+            separated.push_unseparated(")");
+        }
+        PruneScope::AllExcept(_) => {}
+    }
+}
+
+#[async_trait]
+impl TransactionStore for SqliteStore {
+    async fn migrate(&self) -> Result<(), AppError> {
+        sqlx::migrate!("./migrations_sqlite")
+            .run(&self.pool)
+            .await
+            .map_err(|e| AppError::DatabaseError(e.to_string()))
+    }
+
+    async fn ping(&self) -> Result<(), AppError> {
+        sqlx::query("SELECT 1")
+            .execute(&self.pool)
+            .await
+            .map_err(db_error)?;
+        Ok(())
+    }
+
+    async fn close(&self) {
+        self.pool.close().await;
+    }
+
+    async fn upsert(&self, transaction: Transaction) -> Result<(Transaction, bool), AppError> {
+        let mut tx = self.pool.begin().await.map_err(db_error)?;
+
+        let existing: i64 = sqlx::query_scalar(
+            "SELECT COUNT(*) FROM \"transaction\" WHERE chain_id = ? AND tx_hash = ?",
+        )
+        .bind(transaction.chain_id)
+        .bind(&transaction.tx_hash)
+        .fetch_one(&mut *tx)
+        .await
+        .map_err(db_error)?;
+
+        let sql = format!(
+            "INSERT INTO \"transaction\" ({}) VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?) {} RETURNING *",
+            TRANSACTION_COLUMNS, UPSERT_ON_CONFLICT
+        );
+        let row = sqlx::query_as::<_, Row>(&sql)
+            .bind(Uuid::new_v4())
+            .bind(transaction.chain_id)
+            .bind(transaction.tx_hash)
+            .bind(transaction.block_hash)
+            .bind(transaction.block_number)
+            .bind(transaction.from_sender)
+            .bind(transaction.to_reciever)
+            .bind(transaction.tx_value)
+            .bind(transaction.tx_value_overflow)
+            .bind(transaction.gas)
+            .bind(transaction.gas_price)
+            .bind(transaction.priority_fee)
+            .bind(transaction.input)
+            .bind(transaction.nonce)
+            .bind(transaction.mempool_time)
+            .bind(contract_type_name(transaction.contract_type))
+            .fetch_one(&mut *tx)
+            .await
+            .map_err(db_error)?;
+
+        tx.commit().await.map_err(db_error)?;
+
+        let (_, transaction) = row.into_transaction()?;
+        Ok((transaction, existing == 0))
+    }
+
+    async fn upsert_batch(&self, transactions: &[Transaction]) -> Result<u64, AppError> {
+        let transactions = latest_per_hash(transactions);
+        let mut written = 0;
+
+        for chunk in transactions.chunks(STATEMENT_ROWS) {
+            let mut query = QueryBuilder::<Sqlite>::new(format!(
+                "INSERT INTO \"transaction\" ({}) ",
+                TRANSACTION_COLUMNS
+            ));
+            query.push_values(chunk, |mut row, transaction| {
+                row.push_bind(Uuid::new_v4())
+                    .push_bind(transaction.chain_id)
+                    .push_bind(&transaction.tx_hash)
+                    .push_bind(&transaction.block_hash)
+                    .push_bind(transaction.block_number)
+                    .push_bind(&transaction.from_sender)
+                    .push_bind(&transaction.to_reciever)
+                    .push_bind(transaction.tx_value)
+                    .push_bind(transaction.tx_value_overflow)
+                    .push_bind(transaction.gas)
+                    .push_bind(transaction.gas_price)
+                    .push_bind(transaction.priority_fee)
+                    .push_bind(&transaction.input)
+                    .push_bind(transaction.nonce)
+                    .push_bind(transaction.mempool_time)
+                    .push_bind(contract_type_name(transaction.contract_type));
+            });
+            query.push(" ").push(UPSERT_ON_CONFLICT);
+
+            written += query
+                .build()
+                .execute(&self.pool)
+                .await
+                .map_err(db_error)?
+                .rows_affected();
+        }
+
+        Ok(written)
+    }
+
+    async fn get_by_id(&self, id: Uuid) -> Result<Option<Transaction>, AppError> {
+        let mut query = QueryBuilder::<Sqlite>::new("SELECT * FROM \"transaction\" WHERE id = ");
+        query.push_bind(id);
+        self.find(query).await
+    }
+
+    async fn get_by_hash(
+        &self,
+        chain_id: i64,
+        tx_hash: &str,
+    ) -> Result<Option<Transaction>, AppError> {
+        let mut query =
+            QueryBuilder::<Sqlite>::new("SELECT * FROM \"transaction\" WHERE chain_id = ");
+        query
+            .push_bind(chain_id)
+            .push(" AND tx_hash = ")
+            .push_bind(tx_hash.to_string());
+        self.find(query).await
+    }
+
+    async fn filter(&self, filter: &TransactionFilter) -> Result<Vec<Transaction>, AppError> {
+        Ok(self
+            .rows(filter)
+            .await?
+            .into_iter()
+            .map(|(_, transaction)| transaction)
+            .collect())
+    }
+
+    async fn stats(
+        &self,
+        params: &StatsParams,
+        filter: &TransactionFilter,
+    ) -> Result<Vec<StatsBucket>, AppError> {
+        let rows = self.rows(filter).await?;
+        Ok(stats::aggregate(
+            rows.iter()
+                .map(|(created_at, transaction)| (*created_at, transaction)),
+            params,
+        ))
+    }
+
+    async fn fee_samples(
+        &self,
+        chain_id: i64,
+        window_seconds: i64,
+    ) -> Result<Vec<FeeSample>, AppError> {
+        sqlx::query_as::<_, FeeSample>(
+            "SELECT gas_price, priority_fee, mempool_time FROM \"transaction\"
+            WHERE chain_id = ? AND mempool_time > 0 AND created_at > ?",
+        )
+        .bind(chain_id)
+        .bind(unix_seconds() as i64 - window_seconds)
+        .fetch_all(&self.pool)
+        .await
+        .map_err(db_error)
+    }
+
+    async fn token_contracts(&self, chain_id: i64, address: &str) -> Result<Vec<String>, AppError> {
+        let (sent, involving) = transfer_patterns(address);
+        sqlx::query_scalar::<_, String>(
+            "SELECT DISTINCT to_reciever FROM \"transaction\"
+            WHERE chain_id = ?
+            AND ((from_sender = ? AND (input LIKE ? OR input LIKE ?))
+                OR input LIKE ? OR input LIKE ? OR input LIKE ?)
+            ORDER BY to_reciever",
+        )
+        .bind(chain_id)
+        .bind(address.to_lowercase())
+        .bind(&sent[0])
+        .bind(&sent[1])
+        .bind(&involving[0])
+        .bind(&involving[1])
+        .bind(&involving[2])
+        .fetch_all(&self.pool)
+        .await
+        .map_err(db_error)
+    }
+
+    async fn delete_older_than(&self, scope: &PruneScope, days: u64) -> Result<Pruned, AppError> {
+        let cutoff = unix_seconds() as i64 - days as i64 * 86400;
+        // Before their transactions, which identify them
+        let internal_calls = self.delete_internal_calls_before(scope, cutoff).await?;
+        Ok(Pruned {
+            internal_calls,
+            transactions: self
+                .delete_before("\"transaction\"", "created_at", scope, cutoff)
+                .await?,
+            balance_snapshots: self
+                .delete_before("balance_snapshot", "block_timestamp", scope, cutoff)
+                .await?,
+            simulations: self
+                .delete_before("simulation", "simulated_at", scope, cutoff)
+                .await?,
+        })
+    }
+
+    async fn save_snapshots(&self, snapshots: &[BalanceSnapshot]) -> Result<u64, AppError> {
+        let mut written = 0;
+
+        for chunk in snapshots.chunks(STATEMENT_ROWS) {
+            let mut query = QueryBuilder::<Sqlite>::new(
+                "INSERT INTO balance_snapshot (chain_id, address, token, block_number, block_timestamp, balance) ",
+            );
+            query.push_values(chunk, |mut row, snapshot| {
+                row.push_bind(snapshot.chain_id)
+                    .push_bind(&snapshot.address)
+                    .push_bind(&snapshot.token)
+                    .push_bind(snapshot.block_number)
+                    .push_bind(snapshot.block_timestamp)
+                    .push_bind(&snapshot.balance);
+            });
+            query.push(" ON CONFLICT (chain_id, address, token, block_number) DO NOTHING");
+
+            written += query
+                .build()
+                .execute(&self.pool)
+                .await
+                .map_err(db_error)?
+                .rows_affected();
+        }
+
+        Ok(written)
+    }
+
+    async fn latest_snapshot_block(&self, chain_id: i64) -> Result<Option<i64>, AppError> {
+        sqlx::query_scalar::<_, Option<i64>>(
+            "SELECT MAX(block_number) FROM balance_snapshot WHERE chain_id = ?",
+        )
+        .bind(chain_id)
+        .fetch_one(&self.pool)
+        .await
+        .map_err(db_error)
+    }
+
+    async fn snapshots(
+        &self,
+        chain_id: i64,
+        address: &str,
+        from: Option<i64>,
+        to: Option<i64>,
+    ) -> Result<Vec<BalanceSnapshot>, AppError> {
+        let mut query =
+            QueryBuilder::<Sqlite>::new("SELECT * FROM balance_snapshot WHERE chain_id = ");
+        query
+            .push_bind(chain_id)
+            .push(" AND address = ")
+            .push_bind(address.to_lowercase());
+        if let Some(from) = from {
+            query.push(" AND block_timestamp >= ").push_bind(from);
+        }
+        if let Some(to) = to {
+            query.push(" AND block_timestamp <= ").push_bind(to);
+        }
+        query.push(" ORDER BY token, block_number");
+
+        query
+            .build_query_as::<BalanceSnapshot>()
+            .fetch_all(&self.pool)
+            .await
+            .map_err(db_error)
+    }
+
+    async fn save_internal_calls(&self, calls: &[InternalCall]) -> Result<u64, AppError> {
+        let mut written = 0;
+
+        for chunk in calls.chunks(STATEMENT_ROWS) {
+            let mut query = QueryBuilder::<Sqlite>::new(
+                "INSERT INTO internal_call (chain_id, tx_hash, block_number, position, depth, call_type, from_address, to_address, value, error) ",
+            );
+            query.push_values(chunk, |mut row, call| {
+                row.push_bind(call.chain_id)
+                    .push_bind(&call.tx_hash)
+                    .push_bind(call.block_number)
+                    .push_bind(call.position)
+                    .push_bind(call.depth)
+                    .push_bind(&call.call_type)
+                    .push_bind(&call.from_address)
+                    .push_bind(&call.to_address)
+                    .push_bind(&call.value)
+                    .push_bind(&call.error);
+            });
+            query.push(" ON CONFLICT (chain_id, tx_hash, position) DO NOTHING");
+
+            written += query
+                .build()
+                .execute(&self.pool)
+                .await
+                .map_err(db_error)?
+                .rows_affected();
+        }
+
+        Ok(written)
+    }
+
+    async fn internal_calls(
+        &self,
+        chain_id: i64,
+        tx_hash: &str,
+    ) -> Result<Vec<InternalCall>, AppError> {
+        sqlx::query_as::<_, InternalCall>(
+            "SELECT * FROM internal_call WHERE chain_id = ? AND tx_hash = ? ORDER BY position",
+        )
+        .bind(chain_id)
+        .bind(tx_hash.to_lowercase())
+        .fetch_all(&self.pool)
+        .await
+        .map_err(db_error)
+    }
+
+    async fn address_internal_calls(
+        &self,
+        chain_id: i64,
+        address: &str,
+    ) -> Result<Vec<InternalCall>, AppError> {
+        let address = address.to_lowercase();
+        sqlx::query_as::<_, InternalCall>(
+            "SELECT * FROM internal_call WHERE chain_id = ? AND (from_address = ? OR to_address = ?)
+            ORDER BY block_number, tx_hash, position",
+        )
+        .bind(chain_id)
+        .bind(&address)
+        .bind(&address)
+        .fetch_all(&self.pool)
+        .await
+        .map_err(db_error)
+    }
+
+    async fn save_simulation(&self, simulation: &Simulation) -> Result<(), AppError> {
+        sqlx::query(
+            "INSERT INTO simulation (chain_id, tx_hash, block_number, success, gas_used, result, simulated_at)
+            VALUES (?, ?, ?, ?, ?, ?, ?)
+            ON CONFLICT (chain_id, tx_hash) DO UPDATE SET
+                block_number = EXCLUDED.block_number,
+                success = EXCLUDED.success,
+                gas_used = EXCLUDED.gas_used,
+                result = EXCLUDED.result,
+                simulated_at = EXCLUDED.simulated_at",
+        )
+        .bind(simulation.chain_id as i64)
+        .bind(simulation.tx_hash.to_lowercase())
+        .bind(simulation.block_number as i64)
+        .bind(simulation.success)
+        .bind(simulation.gas_used as i64)
+        .bind(serde_json::to_value(simulation)?)
+        .bind(simulation.simulated_at)
+        .execute(&self.pool)
+        .await
+        .map_err(db_error)?;
+        Ok(())
+    }
+
+    async fn simulation(
+        &self,
+        chain_id: i64,
+        tx_hash: &str,
+    ) -> Result<Option<Simulation>, AppError> {
+        let result = sqlx::query_scalar::<_, Value>(
+            "SELECT result FROM simulation WHERE chain_id = ? AND tx_hash = ?",
+        )
+        .bind(chain_id)
+        .bind(tx_hash.to_lowercase())
+        .fetch_optional(&self.pool)
+        .await
+        .map_err(db_error)?;
+
+        Ok(result.map(serde_json::from_value).transpose()?)
+    }
+
+    async fn load_pending(&self, chain_id: i64) -> Result<Vec<(String, i64)>, AppError> {
+        sqlx::query_as::<_, (String, i64)>(
+            "SELECT tx_hash, first_seen_ms FROM pending_transaction WHERE chain_id = ?",
+        )
+        .bind(chain_id)
+        .fetch_all(&self.pool)
+        .await
+        .map_err(db_error)
+    }
+
+    async fn save_pending(
+        &self,
+        chain_id: i64,
+        added: &[(String, i64)],
+        removed: &[String],
+    ) -> Result<(), AppError> {
+        for chunk in removed.chunks(STATEMENT_ROWS) {
+            let mut query =
+                QueryBuilder::<Sqlite>::new("DELETE FROM pending_transaction WHERE chain_id = ");
+            query.push_bind(chain_id).push(" AND tx_hash IN (");
+            let mut separated = query.separated(", ");
+            for tx_hash in chunk {
+                separated.push_bind(tx_hash);
+            }
+            separated.push_unseparated(")");
+            query.build().execute(&self.pool).await.map_err(db_error)?;
+        }
+
+        for chunk in added.chunks(STATEMENT_ROWS) {
+            let mut query = QueryBuilder::<Sqlite>::new(
+                "INSERT INTO pending_transaction (chain_id, tx_hash, first_seen_ms) ",
+            );
+            query.push_values(chunk, |mut row, (tx_hash, first_seen_ms)| {
+                row.push_bind(chain_id)
+                    .push_bind(tx_hash)
+                    .push_bind(*first_seen_ms);
+            });
+            query.push(" ON CONFLICT (chain_id, tx_hash) DO NOTHING");
+            query.build().execute(&self.pool).await.map_err(db_error)?;
+        }
+
+        Ok(())
+    }
+}
@@ -1,24 +1,39 @@
 use crate::model::AppError;
-use alloy::primitives::ChainId;
+use alloy::primitives::U256;
 use csv::{Writer, WriterBuilder};
+use flate2::{write::GzEncoder, Compression};
+use log::{error, info};
 use serde_json::Value;
 use std::{
-    env::var,
-    fs::{File, OpenOptions},
-    path::Path,
+    fs::{self, File, OpenOptions},
+    io,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::{SystemTime, UNIX_EPOCH},
 };
 
+pub const CSV_PATH: &str = "transactions.csv";
+pub const RESPONSES_DIR: &str = "responses";
+
 pub fn trim_str(data: &Value) -> String {
     data.to_string().trim_matches('"').to_string()
 }
 
+/// Parses a `0x`-prefixed JSON-RPC quantity, failing on anything that is not one or does
+/// not fit in an `i64`.
 pub fn hex_to_int64(data: &Value) -> Result<i64, AppError> {
-    if let Some(hex_str) = data.as_str() {
-        let decimal = i64::from_str_radix(&hex_str[2..], 16).unwrap_or(0);
-        Ok(decimal)
-    } else {
-        Err(AppError::Other("Block number is not a valid string".into()))
-    }
+    let digits = data
+        .as_str()
+        .and_then(|hex_str| hex_str.strip_prefix("0x"))
+        .ok_or_else(|| AppError::Other(format!("Not a hex quantity: {}", data)))?;
+    i64::from_str_radix(digits, 16)
+        .map_err(|e| AppError::Other(format!("Invalid hex quantity {}: {}", data, e)))
+}
+
+/// A value in wei as stored in `tx_value`, with whether it overflowed. Values that do not
+/// fit are saturated at `i64::MAX`, by the scanner and backfills alike.
+pub fn saturating_tx_value(value: U256) -> (i64, bool) {
+    i64::try_from(value).map_or((i64::MAX, true), |value| (value, false))
 }
 
 pub fn csv_writer(file_path: &str) -> Result<Writer<File>, std::io::Error> {
@@ -46,25 +61,126 @@ pub fn csv_writer(file_path: &str) -> Result<Writer<File>, std::io::Error> {
     Ok(wtr)
 }
 
-pub fn get_rpc_url_with_chain_id(chain_id: ChainId) -> String {
-    match chain_id {
-        1 => var("MAINNET_WEB_SOCKET_URL").unwrap(),
-        11155111 => var("SEPOLIA_WEB_SOCKET_URL").unwrap(),
-        534352 => var("SCROLL_WEB_SOCKET_URL").unwrap(),
-        534351 => var("SCROLL_SEPOLIA_WEB_SOCKET_URL").unwrap(),
-        8453 => var("BASE_WEB_SOCKET_URL").unwrap(),
-        84531 => var("BASE_SEPOLIA_WEB_SOCKET_URL").unwrap(),
-        10 => var("OP_WEB_SOCKET_URL").unwrap(),
-        420 => var("OP_SEPOLIA_WEB_SOCKET_URL").unwrap(),
-        56 => var("BINANCE_WEB_SOCKET_URL").unwrap(),
-        137 => var("POLYGON_POS_WEB_SOCKET_URL").unwrap(),
-        1101 => var("POLYGON_ZKEVM_WEB_SOCKET_URL").unwrap(),
-        42161 => var("ARBITRUMONE_WEB_SOCKET_URL").unwrap(),
-        421614 => var("ARBITRUM_SEPOLIA_WEB_SOCKET_URL").unwrap(),
-        324 => var("ZKSYNC_WEB_SOCKET_URL").unwrap(),
-        // sn_main => var("STARKNET_WEB_SOCKET_URL").unwrap(),
-        // sn_sepolia => var("STARKNET_SEPOLIA_WEB_SOCKET_URL").unwrap(),
-        // 900 => var("SOLANA_WEB_SOCKET_URL").unwrap(),
-        _ => "No corresponding rpc url for given chain Id".to_owned(),
+/// CSV writer shared by the scanners of every chain.
+pub type SharedCsvWriter = Arc<Mutex<RotatingCsvWriter>>;
+
+/// Appends to a CSV file and rotates it once it grows past `max_bytes` or a day goes by.
+///
+/// Rotated files are renamed to `<stem>-<unix seconds>.csv` next to the original and
+/// gzip compressed in the background.
+pub struct RotatingCsvWriter {
+    path: PathBuf,
+    max_bytes: u64,
+    writer: Writer<File>,
+    opened_day: u64,
+}
+
+impl RotatingCsvWriter {
+    pub fn open(path: impl Into<PathBuf>, max_bytes: u64) -> Result<Self, AppError> {
+        let path = path.into();
+        // A file last written to on an earlier day is rotated on the first write
+        let opened_day = fs::metadata(&path)
+            .and_then(|metadata| metadata.modified())
+            .map(day_of)
+            .unwrap_or_else(|_| day_of(SystemTime::now()));
+        let writer = csv_writer(&path.to_string_lossy())?;
+
+        Ok(Self {
+            path,
+            max_bytes,
+            writer,
+            opened_day,
+        })
+    }
+
+    pub fn write_record<I, T>(&mut self, record: I) -> Result<(), AppError>
+    where
+        I: IntoIterator<Item = T>,
+        T: AsRef<[u8]>,
+    {
+        self.rotate_if_needed()?;
+        self.writer.write_record(record)?;
+        Ok(())
+    }
+
+    pub fn flush(&mut self) -> Result<(), AppError> {
+        self.writer.flush()?;
+        Ok(())
+    }
+
+    fn rotate_if_needed(&mut self) -> Result<(), AppError> {
+        self.writer.flush()?;
+        let size = fs::metadata(&self.path).map(|m| m.len()).unwrap_or(0);
+        if size < self.max_bytes && self.opened_day == day_of(SystemTime::now()) {
+            return Ok(());
+        }
+
+        let rotated = rotated_csv_path(&self.path, unix_seconds());
+        fs::rename(&self.path, &rotated)?;
+        self.writer = csv_writer(&self.path.to_string_lossy())?;
+        self.opened_day = day_of(SystemTime::now());
+        info!("Rotated {} to {}", self.path.display(), rotated.display());
+
+        tokio::task::spawn_blocking(move || {
+            if let Err(e) = gzip_file(&rotated) {
+                error!("Error compressing {}: {:?}", rotated.display(), e);
+            }
+        });
+        Ok(())
+    }
+}
+
+/// Path a CSV file is renamed to when rotated, e.g. `transactions-1724112000.csv`.
+pub fn rotated_csv_path(path: &Path, timestamp: u64) -> PathBuf {
+    let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+    path.with_file_name(format!("{}-{}.csv", stem, timestamp))
+}
+
+/// Replaces `path` with a gzip compressed `<path>.gz`.
+fn gzip_file(path: &Path) -> Result<(), io::Error> {
+    let mut compressed = path.as_os_str().to_owned();
+    compressed.push(".gz");
+
+    let mut input = File::open(path)?;
+    let mut encoder = GzEncoder::new(File::create(&compressed)?, Compression::default());
+    io::copy(&mut input, &mut encoder)?;
+    encoder.finish()?;
+    fs::remove_file(path)
+}
+
+fn day_of(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+        / 86_400
+}
+
+pub fn unix_seconds() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn parses_hex_quantities_and_rejects_the_rest() {
+        assert_eq!(hex_to_int64(&json!("0x2a")).unwrap(), 42);
+        assert!(hex_to_int64(&json!("0x")).is_err());
+        assert!(hex_to_int64(&json!("2a")).is_err());
+        assert!(hex_to_int64(&json!("")).is_err());
+        assert!(hex_to_int64(&json!("0x8000000000000000")).is_err());
+        assert!(hex_to_int64(&json!(42)).is_err());
+    }
+
+    #[test]
+    fn saturates_values_past_tx_value() {
+        assert_eq!(saturating_tx_value(U256::from(42)), (42, false));
+        let ten_eth = U256::from(10).pow(U256::from(19));
+        assert_eq!(saturating_tx_value(ten_eth), (i64::MAX, true));
     }
 }
@@ -0,0 +1,125 @@
+//! Buffers transactions from the scanners and stores them with multi-row upserts.
+
+use log::{error, warn};
+use serde::{Deserialize, Serialize};
+use std::{slice, sync::Arc, time::Duration};
+use tokio::{
+    sync::mpsc::{self, Receiver, Sender},
+    task::JoinHandle,
+    time::{sleep, timeout_at, Instant},
+};
+
+use crate::{
+    mempool::supervisor::Backoff,
+    model::{AppError, AppState, Transaction},
+};
+
+/// Transactions waiting for a batch before senders have to wait.
+const QUEUE_CAPACITY: usize = 10_000;
+const MAX_ATTEMPTS: u32 = 5;
+const RETRY_INITIAL_BACKOFF: Duration = Duration::from_millis(200);
+const RETRY_MAX_BACKOFF: Duration = Duration::from_secs(5);
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BatchConfig {
+    /// Rows that trigger a write as soon as they are buffered
+    pub max_rows: usize,
+    /// Longest a buffered transaction waits for its batch to fill
+    pub max_delay: Duration,
+}
+
+/// Handle the scanners send transactions through. The writer task stops, after
+/// writing what is buffered, once every handle is dropped.
+#[derive(Clone)]
+pub struct BatchWriter {
+    sender: Sender<Transaction>,
+}
+
+impl BatchWriter {
+    pub fn spawn(state: Arc<AppState>, config: BatchConfig) -> (Self, JoinHandle<()>) {
+        let (sender, receiver) = mpsc::channel(QUEUE_CAPACITY);
+        let task = tokio::spawn(run(state, config, receiver));
+        (Self { sender }, task)
+    }
+
+    /// Queues a transaction, only waiting when the queue is full.
+    pub async fn send(&self, transaction: Transaction) -> Result<(), AppError> {
+        self.sender
+            .send(transaction)
+            .await
+            .map_err(|_| AppError::Other("Batch writer stopped".into()))
+    }
+}
+
+async fn run(state: Arc<AppState>, config: BatchConfig, mut receiver: Receiver<Transaction>) {
+    let mut batch = Vec::with_capacity(config.max_rows);
+    // The first transaction of a batch starts its window
+    while let Some(transaction) = receiver.recv().await {
+        batch.push(transaction);
+        let deadline = Instant::now() + config.max_delay;
+        while batch.len() < config.max_rows {
+            match timeout_at(deadline, receiver.recv()).await {
+                Ok(Some(transaction)) => batch.push(transaction),
+                Ok(None) | Err(_) => break,
+            }
+        }
+
+        state.metrics.db_write_queue.set(receiver.len() as i64);
+        write_batch(&state, &batch).await;
+        batch.clear();
+    }
+}
+
+/// Writes a batch, retrying transient failures. A batch that fails otherwise is
+/// written row by row, so one bad row does not take the rest down with it.
+async fn write_batch(state: &AppState, batch: &[Transaction]) {
+    let metrics = &state.metrics;
+    metrics.db_batch_size.observe(batch.len() as f64);
+
+    let mut backoff = Backoff::new(RETRY_INITIAL_BACKOFF, RETRY_MAX_BACKOFF);
+    for attempt in 1..=MAX_ATTEMPTS {
+        let started = std::time::Instant::now();
+        let result = state.store.upsert_batch(batch).await;
+        metrics
+            .db_batch_duration
+            .observe(started.elapsed().as_secs_f64());
+
+        match result {
+            Ok(_) => return,
+            Err(e) if is_transient(&e) && attempt < MAX_ATTEMPTS => {
+                warn!(
+                    "Transient error writing {} transactions, attempt {}: {:?}",
+                    batch.len(),
+                    attempt,
+                    e
+                );
+                metrics.db_batch_retries.inc();
+                sleep(backoff.next_delay()).await;
+            }
+            Err(e) if batch.len() > 1 && !is_transient(&e) => {
+                warn!(
+                    "Error writing {} transactions, retrying row by row: {:?}",
+                    batch.len(),
+                    e
+                );
+                for transaction in batch {
+                    if let Err(e) = state.store.upsert_batch(slice::from_ref(transaction)).await {
+                        error!("Error writing transaction {}: {:?}", transaction.tx_hash, e);
+                        metrics.db_insert_errors.inc();
+                    }
+                }
+                return;
+            }
+            Err(e) => {
+                error!("Error writing {} transactions: {:?}", batch.len(), e);
+                metrics.db_insert_errors.inc_by(batch.len() as u64);
+                return;
+            }
+        }
+    }
+}
+
+/// Failures worth retrying, which the stores report as an unavailable database.
+fn is_transient(error: &AppError) -> bool {
+    matches!(error, AppError::DatabaseUnavailable(_))
+}
@@ -0,0 +1,127 @@
+//! Simulates pending transactions against a local Anvil node, which needs the `anvil`
+//! binary on the `PATH`: `cargo test --test simulation -- --ignored`.
+
+use alloy::{
+    node_bindings::{Anvil, AnvilInstance},
+    primitives::{Address, TxHash, U256},
+    providers::Provider,
+};
+use sentinel::{
+    connection::load_config, model::AppState, providers::QueryError, rate_limit::Priority,
+    simulation::simulate_and_store, store::connect_store,
+};
+use serde::de::DeserializeOwned;
+use serde_json::{json, Value};
+use std::{env, str::FromStr};
+
+const ANVIL_CHAIN_ID: u64 = 31337;
+/// Init code deploying `PUSH1 0 PUSH1 0 REVERT`, a contract reverting on every call
+const REVERTER_INIT_CODE: &str = "0x6460006000fd6000526005601bf3";
+
+/// Anvil holding sent transactions in its mempool, with an app state pointed at it.
+async fn setup() -> (AnvilInstance, AppState) {
+    let anvil = Anvil::new().arg("--no-mining").spawn();
+    env::set_var("DATABASE_URL", "memory:");
+
+    let mut config = load_config().unwrap();
+    config
+        .providers
+        .endpoints
+        .insert(ANVIL_CHAIN_ID, vec![anvil.endpoint()]);
+    let store = connect_store(&config.db_url).await.unwrap();
+    let state = AppState::new(store, &config).unwrap();
+    (anvil, state)
+}
+
+async fn rpc<T: DeserializeOwned + Send + 'static>(
+    state: &AppState,
+    method: &'static str,
+    params: Value,
+) -> T {
+    state
+        .providers
+        .call(&state.metrics, ANVIL_CHAIN_ID, method, |provider| {
+            let params = params.clone();
+            async move {
+                provider
+                    .raw_request::<_, T>(method.into(), params)
+                    .await
+                    .map_err(QueryError::from)
+            }
+        })
+        .await
+        .unwrap()
+}
+
+#[tokio::test]
+#[ignore = "needs the anvil binary"]
+async fn predicts_a_pending_transfer() {
+    let (anvil, state) = setup().await;
+    let sender = anvil.addresses()[0];
+    let recipient = anvil.addresses()[1];
+    let value = U256::from(10).pow(U256::from(18));
+
+    let tx_hash: TxHash = rpc(
+        &state,
+        "eth_sendTransaction",
+        json!([{ "from": sender, "to": recipient, "value": value }]),
+    )
+    .await;
+
+    let simulation = simulate_and_store(&state, ANVIL_CHAIN_ID, tx_hash, Priority::Interactive)
+        .await
+        .unwrap();
+
+    assert!(simulation.success);
+    assert!(simulation.traced);
+    assert_eq!(simulation.gas_used, 21_000);
+    assert_eq!(simulation.revert_reason, None);
+
+    let diff = |address: Address| {
+        simulation
+            .balance_diffs
+            .iter()
+            .find(|diff| Address::from_str(&diff.address).unwrap() == address)
+            .unwrap()
+    };
+    assert_eq!(diff(recipient).delta, value.to_string());
+    assert!(diff(sender).delta.starts_with('-'));
+
+    let stored = state
+        .store
+        .simulation(ANVIL_CHAIN_ID as i64, &tx_hash.to_string())
+        .await
+        .unwrap();
+    assert_eq!(stored, Some(simulation));
+}
+
+#[tokio::test]
+#[ignore = "needs the anvil binary"]
+async fn predicts_a_pending_revert() {
+    let (anvil, state) = setup().await;
+    let sender = anvil.addresses()[0];
+    let reverter = sender.create(0);
+
+    let _: TxHash = rpc(
+        &state,
+        "eth_sendTransaction",
+        json!([{ "from": sender, "input": REVERTER_INIT_CODE }]),
+    )
+    .await;
+    let _: Value = rpc(&state, "evm_mine", json!([])).await;
+
+    let tx_hash: TxHash = rpc(
+        &state,
+        "eth_sendTransaction",
+        json!([{ "from": sender, "to": reverter, "gas": "0x30000" }]),
+    )
+    .await;
+
+    let simulation = simulate_and_store(&state, ANVIL_CHAIN_ID, tx_hash, Priority::Interactive)
+        .await
+        .unwrap();
+
+    assert!(!simulation.success);
+    assert!(simulation.revert_reason.is_some());
+    assert!(simulation.storage_diffs.is_empty());
+}